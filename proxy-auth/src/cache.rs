@@ -0,0 +1,89 @@
+use std::{
+    collections::HashMap,
+    io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use futures::future::BoxFuture;
+use sha2::{Digest, Sha256};
+
+use crate::{AsyncAuthenticator, Identity};
+
+/// The number of the cached results, the expired ones are purged once it
+/// is full.
+const CACHE_CAPACITY: usize = 4096;
+
+/// The result and the time when it expires.
+type Entry = (Instant, Option<Identity>);
+
+/// An authenticator which caches the results of the inner authenticator
+/// for the TTL, so that the backend is not asked for every connection.
+///
+/// Only the verdicts are cached, the backend which fails is asked again
+/// next time, so that its outage does not reject the users for the TTL.
+///
+/// The passwords are never kept in memory, the results are keyed by the
+/// digest of the credentials instead.
+#[derive(Debug)]
+pub struct Cached<A> {
+    inner: A,
+    ttl: Duration,
+    entries: Mutex<HashMap<[u8; 32], Entry>>,
+}
+
+impl<A> Cached<A> {
+    pub fn new(inner: A, ttl: Duration) -> Self {
+        Cached {
+            inner,
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, key: &[u8; 32]) -> Option<Option<Identity>> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((expires, identity)) if *expires > Instant::now() => Some(identity.clone()),
+            _ => None,
+        }
+    }
+
+    fn insert(&self, key: [u8; 32], identity: Option<Identity>) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= CACHE_CAPACITY {
+            entries.retain(|_, (expires, _)| *expires > now);
+            if entries.len() >= CACHE_CAPACITY {
+                entries.clear();
+            }
+        }
+        entries.insert(key, (now + self.ttl, identity));
+    }
+}
+
+fn cache_key(user: &str, pass: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(user.as_bytes());
+    hasher.update([0]);
+    hasher.update(pass.as_bytes());
+    hasher.finalize().into()
+}
+
+impl<A: AsyncAuthenticator> AsyncAuthenticator for Cached<A> {
+    fn verify<'a>(
+        &'a self,
+        user: &'a str,
+        pass: &'a str,
+    ) -> BoxFuture<'a, io::Result<Option<Identity>>> {
+        Box::pin(async move {
+            let key = cache_key(user, pass);
+            if let Some(identity) = self.get(&key) {
+                return Ok(identity);
+            }
+            let identity = self.inner.verify(user, pass).await?;
+            self.insert(key, identity.clone());
+            Ok(identity)
+        })
+    }
+}
@@ -0,0 +1,148 @@
+use std::{io, process::Stdio, time::Duration};
+
+use futures::future::BoxFuture;
+use log::{debug, warn};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use crate::{AsyncAuthenticator, Identity};
+
+/// The default time to wait for the command to exit.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An authenticator which runs the command in the style of checkpassword.
+///
+/// The command reads `username\0password\0` from the stdin, and accepts the
+/// user by exiting with 0. Any other exit code rejects the user, while the
+/// command which fails to run or does not exit in time is an error.
+#[derive(Debug, Clone)]
+pub struct CommandAuthenticator {
+    program: String,
+    args: Vec<String>,
+    timeout: Duration,
+}
+
+impl CommandAuthenticator {
+    pub fn new(program: String, args: Vec<String>) -> Self {
+        CommandAuthenticator {
+            program,
+            args,
+            timeout: COMMAND_TIMEOUT,
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout
+    }
+
+    async fn run(&self, user: &str, pass: &str) -> io::Result<bool> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            let mut input = Vec::with_capacity(user.len() + pass.len() + 2);
+            input.extend_from_slice(user.as_bytes());
+            input.push(0);
+            input.extend_from_slice(pass.as_bytes());
+            input.push(0);
+            // The command may exit without reading the stdin.
+            if let Err(e) = stdin.write_all(&input).await {
+                debug!("unable to write credentials to {}, {}", &self.program, e);
+            }
+        }
+        let status = tokio::time::timeout(self.timeout, child.wait()).await??;
+        Ok(status.success())
+    }
+}
+
+impl AsyncAuthenticator for CommandAuthenticator {
+    fn verify<'a>(
+        &'a self,
+        user: &'a str,
+        pass: &'a str,
+    ) -> BoxFuture<'a, io::Result<Option<Identity>>> {
+        Box::pin(async move {
+            match self.run(user, pass).await {
+                Ok(true) => Ok(Some(Identity::new(user.to_string()))),
+                Ok(false) => Ok(None),
+                Err(e) => {
+                    warn!("unable to run {} for {}, {}", &self.program, user, &e);
+                    Err(e)
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{AsyncAuthenticator, Cached};
+
+    use super::CommandAuthenticator;
+
+    #[tokio::test]
+    async fn test_command() {
+        let command = CommandAuthenticator::new(
+            "sh".to_string(),
+            vec![
+                "-c".to_string(),
+                r#"[ "$(tr '\0' :)" = "alice:secret:" ]"#.to_string(),
+            ],
+        );
+        let identity = command.verify("alice", "secret").await.unwrap().unwrap();
+        assert_eq!(identity.username, "alice");
+        assert!(command.verify("alice", "guess").await.unwrap().is_none());
+        assert!(command.verify("bob", "secret").await.unwrap().is_none());
+
+        let mut command = CommandAuthenticator::new(
+            "sh".to_string(),
+            vec!["-c".to_string(), "sleep 5".to_string()],
+        );
+        command.set_timeout(Duration::from_millis(100));
+        assert!(command.verify("alice", "secret").await.is_err());
+        let command = CommandAuthenticator::new("/nonexistent".to_string(), vec![]);
+        assert!(command.verify("alice", "secret").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cached_command() {
+        let dir = std::env::temp_dir().join(format!("proxy-auth-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("accept");
+        // Accepts if the file exists, or times out otherwise.
+        let command = |script: &str| {
+            let mut command = CommandAuthenticator::new(
+                "sh".to_string(),
+                vec![
+                    "-c".to_string(),
+                    script.to_string(),
+                    file.display().to_string(),
+                ],
+            );
+            command.set_timeout(Duration::from_millis(100));
+            command
+        };
+
+        // The verdict comes from the cache once the file is removed.
+        std::fs::write(&file, b"").unwrap();
+        let cached = Cached::new(command(r#"[ -e "$0" ]"#), Duration::from_secs(60));
+        assert!(cached.verify("alice", "secret").await.unwrap().is_some());
+        std::fs::remove_file(&file).unwrap();
+        assert!(cached.verify("alice", "secret").await.unwrap().is_some());
+        assert!(cached.verify("bob", "secret").await.unwrap().is_none());
+
+        // The failure is not cached, so the command is run again.
+        let cached = Cached::new(
+            command(r#"[ -e "$0" ] || sleep 5"#),
+            Duration::from_secs(60),
+        );
+        assert!(cached.verify("alice", "secret").await.is_err());
+        std::fs::write(&file, b"").unwrap();
+        assert!(cached.verify("alice", "secret").await.unwrap().is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
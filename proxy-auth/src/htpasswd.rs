@@ -0,0 +1,187 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use futures::future::BoxFuture;
+use log::{info, warn};
+
+use crate::{AsyncAuthenticator, Authenticator, Identity, Users};
+
+/// The default interval to check whether the file changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// An authenticator backed by the htpasswd file, which is reloaded once the
+/// file changes.
+///
+/// Both the reload and the verification of the hashed passwords are done on
+/// the blocking threads, out of the lock of the users. The clones share the
+/// same users.
+#[derive(Debug, Clone)]
+pub struct Htpasswd {
+    path: PathBuf,
+    interval: Duration,
+    state: Arc<RwLock<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    users: Arc<Users>,
+    modified: Option<SystemTime>,
+    checked: Instant,
+}
+
+impl Htpasswd {
+    /// Loads the htpasswd file, see [`Users::load`] for the format.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let modified = modified(&path);
+        let users = Users::load(&path)?;
+        Ok(Htpasswd {
+            path,
+            interval: RELOAD_INTERVAL,
+            state: Arc::new(RwLock::new(State {
+                users: Arc::new(users),
+                modified,
+                checked: Instant::now(),
+            })),
+        })
+    }
+
+    /// Sets the interval to check whether the file changes.
+    pub fn set_reload_interval(&mut self, interval: Duration) {
+        self.interval = interval
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reloads the file if it changes since the last load, returning whether
+    /// the users are reloaded.
+    ///
+    /// The users are kept if the file fails to load. It blocks on reading
+    /// the file.
+    pub fn reload(&self) -> io::Result<bool> {
+        let modified = modified(&self.path);
+        if modified == self.state.read().unwrap().modified {
+            return Ok(false);
+        }
+
+        let users = Users::load(&self.path)?;
+        info!("reload {} users from {}", users.len(), self.path.display());
+        let mut state = self.state.write().unwrap();
+        state.users = Arc::new(users);
+        state.modified = modified;
+        Ok(true)
+    }
+
+    /// Returns whether it is time to check the file, only one of the
+    /// concurrent callers is told so.
+    fn should_check(&self) -> bool {
+        if self.state.read().unwrap().checked.elapsed() < self.interval {
+            return false;
+        }
+        let mut state = self.state.write().unwrap();
+        if state.checked.elapsed() < self.interval {
+            return false;
+        }
+        state.checked = Instant::now();
+        true
+    }
+
+    async fn check(&self) {
+        if !self.should_check() {
+            return;
+        }
+        let htpasswd = self.clone();
+        match tokio::task::spawn_blocking(move || htpasswd.reload()).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => warn!("unable to reload {}, {}", self.path.display(), e),
+            Err(e) => warn!("unable to reload {}, {}", self.path.display(), e),
+        }
+    }
+}
+
+impl AsyncAuthenticator for Htpasswd {
+    fn verify<'a>(
+        &'a self,
+        user: &'a str,
+        pass: &'a str,
+    ) -> BoxFuture<'a, io::Result<Option<Identity>>> {
+        Box::pin(async move {
+            self.check().await;
+            let users = self.state.read().unwrap().users.clone();
+            let (user, pass) = (user.to_string(), pass.to_string());
+            tokio::task::spawn_blocking(move || users.identify(&user, &pass))
+                .await
+                .map_err(io::Error::other)
+        })
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    path.metadata().and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Duration};
+
+    use argon2::{password_hash::SaltString, Argon2, PasswordHasher};
+    use sha1::{Digest, Sha1};
+
+    use super::Htpasswd;
+    use crate::AsyncAuthenticator;
+
+    async fn authenticate(htpasswd: &Htpasswd, user: &str, pass: &str) -> bool {
+        htpasswd.verify(user, pass).await.unwrap().is_some()
+    }
+
+    #[tokio::test]
+    async fn test_htpasswd() {
+        let bcrypt = bcrypt::hash("b-secret", 4).unwrap();
+        let salt = SaltString::new("c29tZXNhbHQ").unwrap();
+        let argon2 = Argon2::default()
+            .hash_password(b"a-secret", &salt)
+            .unwrap()
+            .to_string();
+        let sha = base64::encode(Sha1::digest(b"s-secret"));
+
+        let path = std::env::temp_dir().join(format!("htpasswd-{}", std::process::id()));
+        fs::write(
+            &path,
+            format!(
+                "# users\nbob:{}\nalice:{}\ncarol:{{SHA}}{}\ndave:plain\n",
+                bcrypt, argon2, sha
+            ),
+        )
+        .unwrap();
+
+        let mut htpasswd = Htpasswd::load(&path).unwrap();
+        htpasswd.set_reload_interval(Duration::ZERO);
+        assert!(authenticate(&htpasswd, "bob", "b-secret").await);
+        assert!(authenticate(&htpasswd, "alice", "a-secret").await);
+        assert!(authenticate(&htpasswd, "carol", "s-secret").await);
+        assert!(authenticate(&htpasswd, "dave", "plain").await);
+        assert!(!authenticate(&htpasswd, "bob", "a-secret").await);
+        assert!(!authenticate(&htpasswd, "alice", "b-secret").await);
+        assert!(!authenticate(&htpasswd, "carol", "plain").await);
+        assert!(!authenticate(&htpasswd, "eve", "plain").await);
+
+        // Make sure the modified time changes.
+        std::thread::sleep(Duration::from_millis(20));
+        fs::write(&path, "eve:plain\n").unwrap();
+        assert!(authenticate(&htpasswd, "eve", "plain").await);
+        assert!(!authenticate(&htpasswd, "dave", "plain").await);
+
+        // The users are kept if the file is broken.
+        std::thread::sleep(Duration::from_millis(20));
+        fs::write(&path, "broken\n").unwrap();
+        assert!(authenticate(&htpasswd, "eve", "plain").await);
+
+        fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,101 @@
+use std::{collections::BTreeMap, io, time::Duration};
+
+use futures::future::BoxFuture;
+use hyper::{client::HttpConnector, header, Body, Method, Request, Uri};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{AsyncAuthenticator, Identity};
+
+/// The default time to wait for the endpoint to respond.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An authenticator which posts the credentials to the HTTP endpoint.
+///
+/// The credentials are posted as the JSON object with `username` and
+/// `password`, and the endpoint accepts the user by responding with 2xx,
+/// any other status but 5xx rejects the user.
+/// The response body may be a JSON object with `groups` and `attributes`,
+/// which are assigned to the identity.
+#[derive(Debug, Clone)]
+pub struct HttpAuthenticator {
+    uri: Uri,
+    timeout: Duration,
+    client: hyper::Client<HttpConnector>,
+}
+
+#[derive(Serialize)]
+struct Credentials<'a> {
+    username: &'a str,
+    password: &'a str,
+}
+
+#[derive(Deserialize)]
+struct Verdict {
+    #[serde(default)]
+    groups: Vec<String>,
+    #[serde(default)]
+    attributes: BTreeMap<String, String>,
+}
+
+impl HttpAuthenticator {
+    pub fn new(uri: Uri) -> Self {
+        HttpAuthenticator {
+            uri,
+            timeout: HTTP_TIMEOUT,
+            client: hyper::Client::new(),
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout
+    }
+
+    async fn post(&self, user: &str, pass: &str) -> io::Result<Option<Identity>> {
+        let body = serde_json::to_vec(&Credentials {
+            username: user,
+            password: pass,
+        })?;
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(self.uri.clone())
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let resp = tokio::time::timeout(self.timeout, self.client.request(req))
+            .await?
+            .map_err(io::Error::other)?;
+        if resp.status().is_server_error() {
+            return Err(io::Error::other(format!("responded {}", resp.status())));
+        }
+        if !resp.status().is_success() {
+            return Ok(None);
+        }
+
+        let body = tokio::time::timeout(self.timeout, hyper::body::to_bytes(resp.into_body()))
+            .await?
+            .map_err(io::Error::other)?;
+        let mut identity = Identity::new(user.to_string());
+        if let Ok(verdict) = serde_json::from_slice::<Verdict>(&body) {
+            identity.groups = verdict.groups;
+            identity.attributes = verdict.attributes;
+        }
+        Ok(Some(identity))
+    }
+}
+
+impl AsyncAuthenticator for HttpAuthenticator {
+    fn verify<'a>(
+        &'a self,
+        user: &'a str,
+        pass: &'a str,
+    ) -> BoxFuture<'a, io::Result<Option<Identity>>> {
+        Box::pin(async move {
+            let verdict = self.post(user, pass).await;
+            if let Err(e) = &verdict {
+                warn!("unable to post to {} for {}, {}", &self.uri, user, e);
+            }
+            verdict
+        })
+    }
+}
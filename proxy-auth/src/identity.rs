@@ -0,0 +1,34 @@
+use std::{collections::BTreeMap, fmt};
+
+/// The identity of the authenticated user, which is passed along with the
+/// request for routing, quotas and audit logs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Identity {
+    pub username: String,
+    pub groups: Vec<String>,
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl Identity {
+    pub fn new(username: String) -> Self {
+        Identity {
+            username,
+            groups: Vec::new(),
+            attributes: BTreeMap::new(),
+        }
+    }
+
+    pub fn in_group(&self, group: &str) -> bool {
+        self.groups.iter().any(|g| g == group)
+    }
+
+    pub fn attribute(&self, key: &str) -> Option<&str> {
+        self.attributes.get(key).map(|v| v.as_str())
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.username)
+    }
+}
@@ -1,13 +1,173 @@
+use std::{collections::HashMap, io, sync::Arc};
+
+use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
 
+mod cache;
+mod command;
+mod htpasswd;
+mod http;
+mod identity;
+mod password;
+mod users;
+
+pub use cache::*;
+pub use command::*;
+pub use htpasswd::*;
+pub use http::*;
+pub use identity::*;
+pub use password::*;
+pub use users::*;
+
 pub trait Authenticator {
     fn authenticate(&self, user: &str, pass: &str) -> bool;
+
+    /// Authenticates the user, returning who is authenticated.
+    ///
+    /// The identity only carries the username by default.
+    fn identify(&self, user: &str, pass: &str) -> Option<Identity> {
+        if self.authenticate(user, pass) {
+            Some(Identity::new(user.to_string()))
+        } else {
+            None
+        }
+    }
 }
 
 impl<T: Authenticator> Authenticator for &T {
     fn authenticate(&self, user: &str, pass: &str) -> bool {
         T::authenticate(self, user, pass)
     }
+
+    fn identify(&self, user: &str, pass: &str) -> Option<Identity> {
+        T::identify(self, user, pass)
+    }
+}
+
+impl<T: Authenticator + ?Sized> Authenticator for Arc<T> {
+    fn authenticate(&self, user: &str, pass: &str) -> bool {
+        T::authenticate(self, user, pass)
+    }
+
+    fn identify(&self, user: &str, pass: &str) -> Option<Identity> {
+        T::identify(self, user, pass)
+    }
+}
+
+impl<T: Authenticator + ?Sized> Authenticator for Box<T> {
+    fn authenticate(&self, user: &str, pass: &str) -> bool {
+        T::authenticate(self, user, pass)
+    }
+
+    fn identify(&self, user: &str, pass: &str) -> Option<Identity> {
+        T::identify(self, user, pass)
+    }
+}
+
+/// Authenticates if any of the authenticators accepts.
+impl<T: Authenticator> Authenticator for Vec<T> {
+    fn authenticate(&self, user: &str, pass: &str) -> bool {
+        self.iter().any(|a| a.authenticate(user, pass))
+    }
+
+    fn identify(&self, user: &str, pass: &str) -> Option<Identity> {
+        self.iter().find_map(|a| a.identify(user, pass))
+    }
+}
+
+/// The asynchronous authenticator, which is able to ask the backends out of
+/// the process, such as a command or an HTTP endpoint.
+///
+/// Every [`Authenticator`] is also an `AsyncAuthenticator`.
+pub trait AsyncAuthenticator: Send + Sync {
+    /// Authenticates the user, returning who is authenticated, or `None` if
+    /// the user is rejected.
+    ///
+    /// The error is for the backend which fails to give the verdict, such as
+    /// the command which does not exit in time.
+    fn verify<'a>(
+        &'a self,
+        user: &'a str,
+        pass: &'a str,
+    ) -> BoxFuture<'a, io::Result<Option<Identity>>>;
+}
+
+impl<T: Authenticator + Send + Sync> AsyncAuthenticator for T {
+    fn verify<'a>(
+        &'a self,
+        user: &'a str,
+        pass: &'a str,
+    ) -> BoxFuture<'a, io::Result<Option<Identity>>> {
+        Box::pin(futures::future::ready(Ok(self.identify(user, pass))))
+    }
+}
+
+/// A list of asynchronous authenticators which are tried in order, the
+/// first identity returned wins.
+///
+/// The user rejected by all of them is rejected, unless any of them fails,
+/// which is returned as the error instead.
+///
+/// It is cheap to clone, since the authenticators are shared.
+#[derive(Clone, Default)]
+pub struct Authenticators {
+    authenticators: Vec<Arc<dyn AsyncAuthenticator>>,
+    groups: Arc<HashMap<String, Vec<String>>>,
+}
+
+impl Authenticators {
+    pub fn new() -> Self {
+        Authenticators::default()
+    }
+
+    pub fn push<A: AsyncAuthenticator + 'static>(&mut self, authenticator: A) {
+        self.authenticators.push(Arc::new(authenticator))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.authenticators.is_empty()
+    }
+
+    /// Adds the user to the group, which is assigned to the identity no
+    /// matter which authenticator accepts the user.
+    pub fn add_group(&mut self, group: String, username: String) {
+        let groups = Arc::make_mut(&mut self.groups).entry(username).or_default();
+        if !groups.contains(&group) {
+            groups.push(group);
+        }
+    }
+}
+
+impl AsyncAuthenticator for Authenticators {
+    fn verify<'a>(
+        &'a self,
+        user: &'a str,
+        pass: &'a str,
+    ) -> BoxFuture<'a, io::Result<Option<Identity>>> {
+        Box::pin(async move {
+            let mut failure = None;
+            for authenticator in &self.authenticators {
+                match authenticator.verify(user, pass).await {
+                    Ok(Some(mut identity)) => {
+                        if let Some(groups) = self.groups.get(&identity.username) {
+                            for group in groups {
+                                if !identity.in_group(group) {
+                                    identity.groups.push(group.clone());
+                                }
+                            }
+                        }
+                        return Ok(Some(identity));
+                    }
+                    Ok(None) => {}
+                    Err(e) => failure = Some(e),
+                }
+            }
+            match failure {
+                Some(e) => Err(e),
+                None => Ok(None),
+            }
+        })
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
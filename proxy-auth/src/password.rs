@@ -0,0 +1,84 @@
+use std::{fmt, io};
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use sha1::{Digest, Sha1};
+use subtle::ConstantTimeEq;
+
+/// The password of a user, in one of the formats which htpasswd produces.
+#[derive(Clone)]
+pub enum Password {
+    /// The plaintext password.
+    Plain(String),
+    /// The SHA-1 digest of the password, in the form of `{SHA}base64`.
+    Sha1(Vec<u8>),
+    /// The bcrypt hash of the password, in the form of `$2y$...`.
+    Bcrypt(String),
+    /// The argon2 hash of the password in the PHC string format, like
+    /// `$argon2id$v=19$...`.
+    Argon2(String),
+}
+
+impl Password {
+    /// Parses the password field of the htpasswd entry.
+    ///
+    /// The field is treated as plaintext if it is not in any of the known
+    /// hash formats.
+    pub fn parse(s: &str) -> io::Result<Password> {
+        if let Some(digest) = s.strip_prefix("{SHA}") {
+            let digest = base64::decode(digest)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Ok(Password::Sha1(digest))
+        } else if s.starts_with("$2a$")
+            || s.starts_with("$2b$")
+            || s.starts_with("$2x$")
+            || s.starts_with("$2y$")
+        {
+            Ok(Password::Bcrypt(s.to_string()))
+        } else if s.starts_with("$argon2") {
+            PasswordHash::new(s).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid argon2 hash, {}", e),
+                )
+            })?;
+            Ok(Password::Argon2(s.to_string()))
+        } else if s.starts_with("$apr1$") || s.starts_with("$1$") {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "md5 password is not supported",
+            ))
+        } else {
+            Ok(Password::Plain(s.to_string()))
+        }
+    }
+
+    /// Verifies the password, comparing in constant time.
+    pub fn verify(&self, pass: &str) -> bool {
+        match self {
+            Password::Plain(p) => p.as_bytes().ct_eq(pass.as_bytes()).into(),
+            Password::Sha1(digest) => {
+                let actual = Sha1::digest(pass.as_bytes());
+                actual.as_slice().ct_eq(digest).into()
+            }
+            Password::Bcrypt(hash) => bcrypt::verify(pass, hash).unwrap_or(false),
+            Password::Argon2(hash) => match PasswordHash::new(hash) {
+                Ok(hash) => Argon2::default()
+                    .verify_password(pass.as_bytes(), &hash)
+                    .is_ok(),
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never print the password or its hash.
+        match self {
+            Password::Plain(_) => f.write_str("Plain(..)"),
+            Password::Sha1(_) => f.write_str("Sha1(..)"),
+            Password::Bcrypt(_) => f.write_str("Bcrypt(..)"),
+            Password::Argon2(_) => f.write_str("Argon2(..)"),
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::{collections::HashMap, fs, io, path::Path};
+
+use crate::{Authenticator, Password};
+
+/// A set of users which authenticates with the username and password.
+#[derive(Debug, Clone, Default)]
+pub struct Users {
+    users: HashMap<String, Password>,
+}
+
+impl Users {
+    pub fn new() -> Self {
+        Users::default()
+    }
+
+    /// Adds the user with the plaintext password, replacing the password if
+    /// the user already exists.
+    pub fn insert(&mut self, username: String, password: String) {
+        self.insert_password(username, Password::Plain(password));
+    }
+
+    /// Adds the user with the password which may be hashed.
+    pub fn insert_password(&mut self, username: String, password: Password) {
+        self.users.insert(username, password);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    /// Loads the users from the htpasswd file.
+    ///
+    /// Each line of the file is a `username:password` pair, where the
+    /// password is either plaintext or hashed by bcrypt, argon2 or SHA-1.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut users = Users::new();
+        users.extend_from_file(path)?;
+        Ok(users)
+    }
+
+    /// Adds the users from the htpasswd file.
+    pub fn extend_from_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let content = fs::read_to_string(path)?;
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (username, password) = line.split_once(':').ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid credentials at line {}", n + 1),
+                )
+            })?;
+            let password = Password::parse(password)
+                .map_err(|e| io::Error::new(e.kind(), format!("{} at line {}", e, n + 1)))?;
+            self.insert_password(username.to_string(), password);
+        }
+        Ok(())
+    }
+}
+
+impl Authenticator for Users {
+    fn authenticate(&self, user: &str, pass: &str) -> bool {
+        match self.users.get(user) {
+            Some(password) => password.verify(pass),
+            None => {
+                // The unknown user is verified against any password all the
+                // same, so that it takes as long as the known users and the
+                // usernames are not revealed by the timing.
+                if let Some(password) = self.users.values().next() {
+                    password.verify(pass);
+                }
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Compares relaying a loopback TCP connection by `Duplex`, which copies the
+//! bytes through its buffers, and by `SpliceDuplex`, which splices them.
+//!
+//! The relay runs on its own thread, so the CPU time of the thread is the
+//! cost of relaying, without the client and the server.
+//!
+//! ```sh
+//! cargo bench -p proxy-io --bench relay
+//! ```
+
+#[cfg(target_os = "linux")]
+fn main() {
+    linux::main()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("the relay benchmark runs on Linux only");
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        future::Future,
+        io, mem, thread,
+        time::{Duration, Instant},
+    };
+
+    use proxy_io::{Duplex, DuplexStats, SpliceDuplex};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        runtime::{Builder, Runtime},
+    };
+
+    /// The bytes sent through the relay each round.
+    const TOTAL: u64 = 1 << 30;
+
+    const ROUNDS: usize = 3;
+
+    const CHUNK: usize = 64 * 1024;
+
+    #[derive(Debug, Clone, Copy)]
+    enum Mode {
+        Copy,
+        Splice,
+    }
+
+    struct Round {
+        elapsed: Duration,
+        cpu: Duration,
+    }
+
+    pub fn main() {
+        let rt = Builder::new_multi_thread().enable_all().build().unwrap();
+        println!("{:<8} {:>12} {:>14}", "relay", "MiB/s", "cpu ms/GiB");
+        for mode in [Mode::Copy, Mode::Splice] {
+            // The best round is reported, which is the least disturbed.
+            let best = (0..ROUNDS)
+                .map(|_| round(&rt, mode))
+                .min_by_key(|round| round.elapsed)
+                .unwrap();
+            let mib = TOTAL as f64 / (1 << 20) as f64;
+            println!(
+                "{:<8} {:>12.1} {:>14.1}",
+                format!("{:?}", mode).to_lowercase(),
+                mib / best.elapsed.as_secs_f64(),
+                best.cpu.as_secs_f64() * 1000.0 / (mib / 1024.0),
+            );
+        }
+    }
+
+    /// Sends `TOTAL` bytes from the client to the server through the relay.
+    fn round(rt: &Runtime, mode: Mode) -> Round {
+        let ((mut client, relay_in), (relay_out, mut server)) =
+            rt.block_on(async { (tcp_pair().await, tcp_pair().await) });
+        let relay_in = relay_in.into_std().unwrap();
+        let relay_out = relay_out.into_std().unwrap();
+        let relay = thread::spawn(move || {
+            let rt = Builder::new_current_thread().enable_all().build().unwrap();
+            let _guard = rt.enter();
+            let relay_in = TcpStream::from_std(relay_in).unwrap();
+            let relay_out = TcpStream::from_std(relay_out).unwrap();
+            let cpu = thread_cpu();
+            let stats = match mode {
+                Mode::Copy => run(&rt, Duplex::new(relay_in, relay_out)),
+                Mode::Splice => run(&rt, SpliceDuplex::new(relay_in, relay_out).unwrap()),
+            };
+            assert_eq!(stats.sent, TOTAL);
+            thread_cpu() - cpu
+        });
+
+        let start = Instant::now();
+        rt.block_on(async {
+            let send = tokio::spawn(async move {
+                let chunk = vec![0u8; CHUNK];
+                let mut sent = 0;
+                while sent < TOTAL {
+                    client.write_all(&chunk).await.unwrap();
+                    sent += CHUNK as u64;
+                }
+                client.shutdown().await.unwrap();
+                // Waits for the relay to shut down the other direction.
+                client.read_u8().await.unwrap_err();
+            });
+            let mut buf = vec![0u8; CHUNK];
+            let mut received = 0;
+            loop {
+                match server.read(&mut buf).await.unwrap() {
+                    0 => break,
+                    n => received += n as u64,
+                }
+            }
+            assert_eq!(received, TOTAL);
+            server.shutdown().await.unwrap();
+            send.await.unwrap();
+        });
+        let elapsed = start.elapsed();
+        Round {
+            elapsed,
+            cpu: relay.join().unwrap(),
+        }
+    }
+
+    fn run<F>(rt: &Runtime, relay: F) -> DuplexStats
+    where
+        F: Future<Output = io::Result<DuplexStats>>,
+    {
+        rt.block_on(relay).unwrap()
+    }
+
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (connected, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    /// Returns the user and system CPU time of the current thread.
+    fn thread_cpu() -> Duration {
+        // Safety: the usage is written by getrusage, which is zeroed anyway.
+        let usage = unsafe {
+            let mut usage: libc::rusage = mem::zeroed();
+            assert_eq!(libc::getrusage(libc::RUSAGE_THREAD, &mut usage), 0);
+            usage
+        };
+        let time = |t: libc::timeval| {
+            Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+        };
+        time(usage.ru_utime) + time(usage.ru_stime)
+    }
+}
@@ -4,8 +4,10 @@ use std::{
     vec,
 };
 
-use log::{debug, error};
-use tokio::net::lookup_host;
+use log::error;
+use proxy_auth::Identity;
+
+use crate::{DnsCache, Resolver};
 
 #[derive(Debug, Clone)]
 pub enum TargetAddr {
@@ -46,20 +48,71 @@ impl From<SocketAddr> for TargetAddr {
 }
 
 impl TargetAddr {
-    pub async fn resolve_dns(&self) -> io::Result<TargetAddr> {
+    /// Resolves the domain to the first address by the system resolver,
+    /// whose addresses are cached in the process.
+    pub async fn resolve_dns(&self) -> io::Result<SocketAddr> {
+        self.resolve(&DnsCache::system()).await
+    }
+
+    /// Resolves the domain to the first address by the resolver.
+    pub async fn resolve<R>(&self, resolver: &R) -> io::Result<SocketAddr>
+    where
+        R: Resolver + ?Sized,
+    {
+        Ok(self.resolve_all(resolver).await?[0])
+    }
+
+    /// Resolves the domain to all of the addresses by the resolver.
+    ///
+    /// Returns `HostUnreachable` if the domain has no addresses.
+    pub async fn resolve_all<R>(&self, resolver: &R) -> io::Result<Vec<SocketAddr>>
+    where
+        R: Resolver + ?Sized,
+    {
         match self {
-            TargetAddr::SocketAddr(addr) => Ok(TargetAddr::SocketAddr(*addr)),
+            TargetAddr::SocketAddr(addr) => Ok(vec![*addr]),
             TargetAddr::Domain(d, p) => {
-                debug!("resolve the ip for {}:{} with native dns", d, p);
-                lookup_host((&d[..], *p))
-                    .await?
-                    .next()
-                    .ok_or_else(|| {
-                        error!("unable to resolve dns for {}:{}", d, p);
-                        io::ErrorKind::HostUnreachable.into()
-                    })
-                    .map(|addr| TargetAddr::SocketAddr(addr))
+                // The domain may be an IP literal, like the host of the HTTP
+                // request.
+                if let Ok(ip) = d.parse::<IpAddr>() {
+                    return Ok(vec![SocketAddr::new(ip, *p)]);
+                }
+                let ips = resolver.resolve(d).await?;
+                if ips.is_empty() {
+                    error!("unable to resolve dns for {}:{}", d, p);
+                    return Err(io::ErrorKind::HostUnreachable.into());
+                }
+                Ok(ips.into_iter().map(|ip| SocketAddr::new(ip, *p)).collect())
             }
         }
     }
 }
+
+/// The request for the servers to connect to the target on behalf of the
+/// authenticated identity.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectRequest {
+    pub target: TargetAddr,
+    pub identity: Option<Identity>,
+}
+
+impl ConnectRequest {
+    pub fn new(target: TargetAddr, identity: Option<Identity>) -> Self {
+        ConnectRequest { target, identity }
+    }
+}
+
+impl From<TargetAddr> for ConnectRequest {
+    fn from(target: TargetAddr) -> Self {
+        ConnectRequest::new(target, None)
+    }
+}
+
+impl fmt::Display for ConnectRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.identity {
+            Some(identity) => write!(f, "{} by {}", self.target, identity),
+            None => self.target.fmt(f),
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    fmt, io,
+    net::IpAddr,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+use log::{debug, warn};
+use lru::LruCache;
+use tokio::time::Instant;
+use trust_dns_proto::{
+    op::Message,
+    rr::{Name, RecordType},
+};
+
+use crate::{Lookup, Resolver, SystemResolver};
+
+/// The default number of the hosts in the cache.
+const CACHE_CAPACITY: usize = 1024;
+
+/// The TTL of the addresses if the resolver does not know.
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+const MAX_TTL: Duration = Duration::from_secs(3600);
+
+const NEGATIVE_TTL: Duration = Duration::from_secs(30);
+
+/// The entry is prefetched if it is hit at least so many times.
+const PREFETCH_HITS: u64 = 3;
+
+/// The entry is prefetched in the last tenth of its TTL.
+const PREFETCH_RATIO: u32 = 10;
+
+struct Entry {
+    ips: Vec<IpAddr>,
+    ttl: Duration,
+    expires: Instant,
+    hits: u64,
+    prefetching: bool,
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    negative_hits: AtomicU64,
+    misses: AtomicU64,
+    prefetches: AtomicU64,
+}
+
+/// The counters of the cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The lookups answered by the cached addresses.
+    pub hits: u64,
+    /// The lookups answered by the cached absence of the addresses.
+    pub negative_hits: u64,
+    /// The lookups sent to the resolver.
+    pub misses: u64,
+    /// The entries refreshed before they expire.
+    pub prefetches: u64,
+    /// The hosts in the cache.
+    pub entries: usize,
+}
+
+/// A bounded cache of the resolved addresses in front of any resolver.
+///
+/// The addresses are cached for the TTL of the records, clamped to the
+/// minimum and maximum TTL. The hosts without addresses are cached too, but
+/// never longer than the negative TTL. The least recently used host is
+/// evicted if the cache is full, and the hot hosts are resolved again in
+/// background shortly before they expire.
+///
+/// The errors are never cached. The clones share the same entries.
+pub struct DnsCache<R> {
+    resolver: Arc<R>,
+    entries: Arc<Mutex<LruCache<String, Entry>>>,
+    counters: Arc<Counters>,
+    min_ttl: Duration,
+    max_ttl: Duration,
+    negative_ttl: Duration,
+    prefetch: bool,
+}
+
+impl<R> Clone for DnsCache<R> {
+    fn clone(&self) -> Self {
+        DnsCache {
+            resolver: self.resolver.clone(),
+            entries: self.entries.clone(),
+            counters: self.counters.clone(),
+            min_ttl: self.min_ttl,
+            max_ttl: self.max_ttl,
+            negative_ttl: self.negative_ttl,
+            prefetch: self.prefetch,
+        }
+    }
+}
+
+impl<R> fmt::Debug for DnsCache<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DnsCache")
+            .field("stats", &self.stats())
+            .field("min_ttl", &self.min_ttl)
+            .field("max_ttl", &self.max_ttl)
+            .field("negative_ttl", &self.negative_ttl)
+            .field("prefetch", &self.prefetch)
+            .finish()
+    }
+}
+
+impl DnsCache<SystemResolver> {
+    /// Returns the cache of the system resolver, which is shared in the
+    /// process.
+    pub fn system() -> Self {
+        static SYSTEM: OnceLock<DnsCache<SystemResolver>> = OnceLock::new();
+        SYSTEM
+            .get_or_init(|| DnsCache::new(SystemResolver::new()))
+            .clone()
+    }
+}
+
+impl<R> DnsCache<R> {
+    pub fn new(resolver: R) -> Self {
+        DnsCache {
+            resolver: Arc::new(resolver),
+            entries: Arc::new(Mutex::new(LruCache::new(
+                NonZeroUsize::new(CACHE_CAPACITY).unwrap(),
+            ))),
+            counters: Arc::new(Counters::default()),
+            min_ttl: Duration::ZERO,
+            max_ttl: MAX_TTL,
+            negative_ttl: NEGATIVE_TTL,
+            prefetch: true,
+        }
+    }
+
+    /// Sets the maximum number of the hosts, which is shared by the clones.
+    pub fn set_capacity(&mut self, capacity: NonZeroUsize) {
+        self.entries.lock().unwrap().resize(capacity)
+    }
+
+    pub fn set_min_ttl(&mut self, ttl: Duration) {
+        self.min_ttl = ttl
+    }
+
+    pub fn set_max_ttl(&mut self, ttl: Duration) {
+        self.max_ttl = ttl
+    }
+
+    /// Sets the maximum TTL of the hosts without addresses, zero disables
+    /// the negative caching.
+    pub fn set_negative_ttl(&mut self, ttl: Duration) {
+        self.negative_ttl = ttl
+    }
+
+    pub fn set_prefetch(&mut self, prefetch: bool) {
+        self.prefetch = prefetch
+    }
+
+    pub fn resolver(&self) -> &R {
+        &self.resolver
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            negative_hits: self.counters.negative_hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            prefetches: self.counters.prefetches.load(Ordering::Relaxed),
+            entries: self.entries.lock().unwrap().len(),
+        }
+    }
+
+    /// Removes all of the hosts.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear()
+    }
+
+    /// Returns the cached lookup of the host, and whether it should be
+    /// prefetched.
+    fn get(&self, host: &str) -> Option<(Lookup, bool)> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(host)?;
+        if entry.expires <= now {
+            entries.pop(host);
+            return None;
+        }
+        entry.hits += 1;
+        let remaining = entry.expires - now;
+        let prefetch = self.prefetch
+            && !entry.prefetching
+            && entry.hits >= PREFETCH_HITS
+            && remaining * PREFETCH_RATIO <= entry.ttl;
+        if prefetch {
+            entry.prefetching = true;
+        }
+        let counter = if entry.ips.is_empty() {
+            &self.counters.negative_hits
+        } else {
+            &self.counters.hits
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        let lookup = Lookup {
+            ips: entry.ips.clone(),
+            ttl: Some(remaining),
+        };
+        Some((lookup, prefetch))
+    }
+
+    fn ttl(&self, lookup: &Lookup) -> Duration {
+        let ttl = lookup.ttl.unwrap_or(DEFAULT_TTL);
+        if lookup.ips.is_empty() {
+            ttl.min(self.negative_ttl)
+        } else {
+            ttl.clamp(self.min_ttl, self.max_ttl.max(self.min_ttl))
+        }
+    }
+}
+
+impl<R: Resolver> DnsCache<R> {
+    /// Resolves the host by the resolver, and caches the addresses.
+    async fn refresh(&self, host: &str) -> io::Result<Lookup> {
+        let lookup = match self.resolver.lookup(host).await {
+            Ok(lookup) => lookup,
+            Err(e) => {
+                if let Some(entry) = self.entries.lock().unwrap().peek_mut(host) {
+                    entry.prefetching = false;
+                }
+                return Err(e);
+            }
+        };
+        let ttl = self.ttl(&lookup);
+        if !ttl.is_zero() {
+            let entry = Entry {
+                ips: lookup.ips.clone(),
+                ttl,
+                expires: Instant::now() + ttl,
+                hits: 0,
+                prefetching: false,
+            };
+            self.entries.lock().unwrap().put(host.to_string(), entry);
+        }
+        Ok(Lookup {
+            ips: lookup.ips,
+            ttl: Some(ttl),
+        })
+    }
+}
+
+impl<R: Resolver + 'static> Resolver for DnsCache<R> {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        Box::pin(async move { Ok(self.lookup(host).await?.ips) })
+    }
+
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Lookup>> {
+        Box::pin(async move {
+            let host = host.trim_end_matches('.').to_ascii_lowercase();
+            if let Some((lookup, prefetch)) = self.get(&host) {
+                if prefetch {
+                    debug!("prefetch the ip for {}", &host);
+                    self.counters.prefetches.fetch_add(1, Ordering::Relaxed);
+                    let cache = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = cache.refresh(&host).await {
+                            warn!("unable to prefetch the ip for {}, {}", &host, e);
+                        }
+                    });
+                }
+                return Ok(lookup);
+            }
+            self.counters.misses.fetch_add(1, Ordering::Relaxed);
+            self.refresh(&host).await
+        })
+    }
+
+    /// Queries the inner resolver, the records of other types are not cached.
+    fn query<'a>(
+        &'a self,
+        name: &'a Name,
+        rtype: RecordType,
+    ) -> BoxFuture<'a, io::Result<Message>> {
+        self.resolver.query(name, rtype)
+    }
+}
@@ -0,0 +1,795 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{
+    future::{self, BoxFuture},
+    stream, FutureExt, StreamExt,
+};
+use hyper::{
+    client::conn::{self, SendRequest},
+    header::{ACCEPT, CONTENT_TYPE, HOST},
+    http::uri::Scheme,
+    Body, Request, StatusCode, Uri,
+};
+use log::{debug, warn};
+use proxy::Service;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::UdpSocket,
+    sync::{mpsc, oneshot},
+};
+use trust_dns_proto::{
+    op::{Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{Name, RData, RecordType},
+};
+
+use crate::{Lookup, Resolver, TargetAddr, TokioConnect};
+
+/// The default time to wait for the response of a nameserver.
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The maximum size of the DNS message over UDP.
+const MAX_UDP_SIZE: usize = 4096;
+
+/// The media type of the DNS message in DNS-over-HTTPS.
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// How long the connections to the nameservers are kept while idle.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The most idle connections kept to each nameserver over HTTPS.
+const MAX_IDLE_HTTPS: usize = 4;
+
+/// The upstream nameserver and the transport to query it.
+#[derive(Debug, Clone)]
+pub enum Nameserver {
+    /// Queries over UDP, and retries over TCP if the response is truncated.
+    Udp(SocketAddr),
+    /// Queries over TCP only.
+    Tcp(SocketAddr),
+    /// Queries over TLS (RFC 7858), the host is the name of the server
+    /// certificate.
+    Tls(TargetAddr),
+    /// Queries over HTTPS (RFC 8484) by POST to the URI.
+    Https(Uri),
+}
+
+impl From<SocketAddr> for Nameserver {
+    fn from(addr: SocketAddr) -> Self {
+        Nameserver::Udp(addr)
+    }
+}
+
+impl fmt::Display for Nameserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Nameserver::Udp(addr) => write!(f, "udp://{}", addr),
+            Nameserver::Tcp(addr) => write!(f, "tcp://{}", addr),
+            Nameserver::Tls(target) => write!(f, "tls://{}", target),
+            Nameserver::Https(uri) => uri.fmt(f),
+        }
+    }
+}
+
+/// Parses the nameserver like `8.8.8.8`, `tcp://8.8.8.8:53`,
+/// `tls://dns.google` or `https://dns.google/dns-query`.
+///
+/// The nameserver without the scheme is queried over UDP.
+impl FromStr for Nameserver {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = s.split_once("://").unwrap_or(("udp", s));
+        match scheme.to_ascii_lowercase().as_str() {
+            "udp" => Ok(Nameserver::Udp(parse_socket_addr(rest, 53)?)),
+            "tcp" => Ok(Nameserver::Tcp(parse_socket_addr(rest, 53)?)),
+            "tls" => Ok(Nameserver::Tls(parse_target_addr(rest, 853)?)),
+            "https" => {
+                let uri: Uri = s
+                    .parse()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                if uri.host().is_none() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("no host in the nameserver {}", s),
+                    ));
+                }
+                Ok(Nameserver::Https(uri))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown scheme of the nameserver {}", s),
+            )),
+        }
+    }
+}
+
+fn parse_socket_addr(s: &str, port: u16) -> io::Result<SocketAddr> {
+    match parse_target_addr(s, port)? {
+        TargetAddr::SocketAddr(addr) => Ok(addr),
+        TargetAddr::Domain(..) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the nameserver {} is not an ip address", s),
+        )),
+    }
+}
+
+fn parse_target_addr(s: &str, port: u16) -> io::Result<TargetAddr> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok(TargetAddr::SocketAddr(addr));
+    }
+    let host = s.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(TargetAddr::SocketAddr(SocketAddr::new(ip, port)));
+    }
+    let (host, port) = match s.rsplit_once(':') {
+        Some((host, p)) => {
+            let port = p.parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid port of the nameserver {}", s),
+                )
+            })?;
+            (host, port)
+        }
+        None => (s, port),
+    };
+    if host.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("no host in the nameserver {}", s),
+        ));
+    }
+    Ok(TargetAddr::Domain(host.to_string(), port))
+}
+
+/// The stream to the nameserver.
+pub trait DnsStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> DnsStream for T {}
+
+/// Dials the stream to the nameservers over TCP, TLS and HTTPS,
+/// which makes it possible to send the queries through the proxy.
+pub trait Dial: Send + Sync {
+    fn dial<'a>(&'a self, target: &'a TargetAddr) -> BoxFuture<'a, io::Result<Box<dyn DnsStream>>>;
+}
+
+/// Dials with the connect service, such as [`TokioConnect`] or the client
+/// of the upstream proxy.
+#[derive(Debug, Clone)]
+pub struct ServiceDial<C> {
+    connect: C,
+}
+
+impl<C> ServiceDial<C> {
+    pub fn new(connect: C) -> Self {
+        ServiceDial { connect }
+    }
+}
+
+impl<C> Dial for ServiceDial<C>
+where
+    C: Service<TargetAddr> + Clone + Send + Sync,
+    C::Response: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    C::Error: Into<io::Error>,
+{
+    fn dial<'a>(&'a self, target: &'a TargetAddr) -> BoxFuture<'a, io::Result<Box<dyn DnsStream>>> {
+        let mut connect = self.connect.clone();
+        Box::pin(async move {
+            let stream = connect.call(target.clone()).await.map_err(Into::into)?;
+            Ok(Box::new(stream) as Box<dyn DnsStream>)
+        })
+    }
+}
+
+/// A DNS client which queries the upstream nameservers.
+///
+/// The nameservers are tried in order until one of them responds, or all
+/// of them are queried at the same time and the first response wins if
+/// racing is enabled.
+///
+/// The connections over TCP, TLS and HTTPS are kept for the later queries,
+/// and the clones share them.
+#[derive(Clone)]
+pub struct DnsClient {
+    nameservers: Vec<Nameserver>,
+    connections: Arc<[Connections]>,
+    race: bool,
+    timeout: Duration,
+    dial: Arc<dyn Dial>,
+    #[cfg(feature = "tokio-native-tls")]
+    tls: Option<tokio_native_tls::TlsConnector>,
+}
+
+impl fmt::Debug for DnsClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DnsClient")
+            .field("nameservers", &self.nameservers)
+            .field("race", &self.race)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl DnsClient {
+    pub fn new(nameservers: Vec<Nameserver>) -> Self {
+        DnsClient {
+            connections: nameservers.iter().map(|_| Connections::default()).collect(),
+            nameservers,
+            race: false,
+            timeout: DNS_TIMEOUT,
+            dial: Arc::new(ServiceDial::new(TokioConnect::new())),
+            #[cfg(feature = "tokio-native-tls")]
+            tls: None,
+        }
+    }
+
+    /// Queries all the nameservers at the same time, instead of one by
+    /// one.
+    pub fn set_race(&mut self, race: bool) {
+        self.race = race
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout
+    }
+
+    /// Sets how to dial the nameservers over TCP, TLS and HTTPS, which are
+    /// dialed directly by default.
+    pub fn set_dial<D: Dial + 'static>(&mut self, dial: D) {
+        self.dial = Arc::new(dial)
+    }
+
+    /// Sets the TLS connector for the encrypted nameservers, such as the
+    /// one trusting the private root certificate.
+    #[cfg(feature = "tokio-native-tls")]
+    pub fn set_tls_connector(&mut self, connector: tokio_native_tls::native_tls::TlsConnector) {
+        self.tls = Some(connector.into())
+    }
+
+    pub fn nameservers(&self) -> &[Nameserver] {
+        &self.nameservers
+    }
+
+    /// Queries the records of the type.
+    pub async fn query(&self, name: &Name, rtype: RecordType) -> io::Result<Message> {
+        let request = query_message(name, rtype);
+        if self.race && self.nameservers.len() > 1 {
+            let queries = self.nameservers.iter().zip(self.connections.iter()).map(
+                |(nameserver, connections)| {
+                    Box::pin(self.query_nameserver(nameserver, connections, &request))
+                },
+            );
+            return future::select_ok(queries)
+                .await
+                .map(|(response, _)| response);
+        }
+
+        let mut last_err = io::Error::new(io::ErrorKind::InvalidInput, "no nameservers");
+        for (nameserver, connections) in self.nameservers.iter().zip(self.connections.iter()) {
+            match self
+                .query_nameserver(nameserver, connections, &request)
+                .await
+            {
+                Ok(response) => return Ok(response),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+
+    async fn query_nameserver(
+        &self,
+        nameserver: &Nameserver,
+        connections: &Connections,
+        request: &Message,
+    ) -> io::Result<Message> {
+        let exchange = self.exchange(nameserver, connections, request);
+        let response = tokio::time::timeout(self.timeout, exchange)
+            .await
+            .map_err(io::Error::from)
+            .and_then(|r| r)
+            .and_then(|response| match response.response_code() {
+                ResponseCode::NoError | ResponseCode::NXDomain => Ok(response),
+                code => Err(io::Error::other(format!(
+                    "the nameserver responds {}",
+                    code
+                ))),
+            });
+        if let Err(e) = &response {
+            if let Some(query) = request.queries().first() {
+                warn!(
+                    "unable to query {} {} on {}, {}",
+                    query.name(),
+                    query.query_type(),
+                    nameserver,
+                    e
+                );
+            }
+        }
+        response
+    }
+
+    async fn exchange(
+        &self,
+        nameserver: &Nameserver,
+        connections: &Connections,
+        request: &Message,
+    ) -> io::Result<Message> {
+        match nameserver {
+            Nameserver::Udp(addr) => {
+                let response = self.exchange_udp(*addr, request).await?;
+                if response.truncated() {
+                    debug!("the response from {} is truncated, retry over tcp", addr);
+                    let target = TargetAddr::SocketAddr(*addr);
+                    self.exchange_stream(connections, &target, None, request)
+                        .await
+                } else {
+                    Ok(response)
+                }
+            }
+            Nameserver::Tcp(addr) => {
+                let target = TargetAddr::SocketAddr(*addr);
+                self.exchange_stream(connections, &target, None, request)
+                    .await
+            }
+            Nameserver::Tls(target) => {
+                let host = host_of(target);
+                self.exchange_stream(connections, target, Some(&host), request)
+                    .await
+            }
+            Nameserver::Https(uri) => self.exchange_https(connections, uri, request).await,
+        }
+    }
+
+    /// Sends the request on the stream kept to the nameserver, and dials
+    /// again if the kept one is closed meanwhile.
+    async fn exchange_stream(
+        &self,
+        connections: &Connections,
+        target: &TargetAddr,
+        tls: Option<&str>,
+        request: &Message,
+    ) -> io::Result<Message> {
+        let (pipeline, kept) = self.pipeline(connections, target, tls, false).await?;
+        match pipeline.exchange(request).await {
+            Err(e) if kept && e.kind() == io::ErrorKind::ConnectionAborted => {
+                debug!("the kept connection to {} is closed, dial again", target);
+                let (pipeline, _) = self.pipeline(connections, target, tls, true).await?;
+                pipeline.exchange(request).await
+            }
+            response => response,
+        }
+    }
+
+    /// Returns the stream kept to the nameserver, or dials a new one, and
+    /// whether it is kept from the earlier queries.
+    async fn pipeline(
+        &self,
+        connections: &Connections,
+        target: &TargetAddr,
+        tls: Option<&str>,
+        redial: bool,
+    ) -> io::Result<(Pipeline, bool)> {
+        // The queries at the same time wait for the same dial.
+        let mut stream = connections.stream.lock().await;
+        if let Some(pipeline) = stream.as_ref().filter(|p| !redial && !p.is_closed()) {
+            return Ok((pipeline.clone(), true));
+        }
+        let mut conn = self.dial.dial(target).await?;
+        if let Some(host) = tls {
+            conn = self.connect_tls(host, conn).await?;
+        }
+        let pipeline = Pipeline::new(conn);
+        *stream = Some(pipeline.clone());
+        Ok((pipeline, false))
+    }
+
+    async fn exchange_udp(&self, nameserver: SocketAddr, request: &Message) -> io::Result<Message> {
+        let bind: SocketAddr = match nameserver {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(nameserver).await?;
+        socket.send(&request.to_vec()?).await?;
+        let mut buf = vec![0u8; MAX_UDP_SIZE];
+        loop {
+            let n = socket.recv(&mut buf).await?;
+            // Drops the responses which do not belong to the request.
+            match Message::from_vec(&buf[..n]) {
+                Ok(response) if is_response_of(&response, request) => return Ok(response),
+                Ok(_) => debug!("drop the mismatched response from {}", nameserver),
+                Err(e) => debug!("drop the invalid response from {}, {}", nameserver, e),
+            }
+        }
+    }
+
+    async fn exchange_https(
+        &self,
+        connections: &Connections,
+        uri: &Uri,
+        request: &Message,
+    ) -> io::Result<Message> {
+        // The id should be zero to be friendly to the HTTP caches.
+        let mut message = request.clone();
+        message.set_id(0);
+        let body = message.to_vec()?;
+        let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        let authority = uri
+            .authority()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no host in the uri"))?;
+        let post = || {
+            Request::post(path)
+                .header(HOST, authority.as_str())
+                .header(CONTENT_TYPE, DNS_MESSAGE)
+                .header(ACCEPT, DNS_MESSAGE)
+                .body(Body::from(body.clone()))
+                .map_err(other)
+        };
+
+        let (mut sender, kept) = match connections.take_https().await {
+            Some(sender) => (sender, true),
+            None => (self.dial_https(uri).await?, false),
+        };
+        let res = match sender.send_request(post()?).await {
+            Err(e) if kept => {
+                debug!(
+                    "the kept connection to {} is closed, dial again, {}",
+                    uri, e
+                );
+                sender = self.dial_https(uri).await?;
+                sender.send_request(post()?).await.map_err(other)?
+            }
+            res => res.map_err(other)?,
+        };
+        if res.status() != StatusCode::OK {
+            return Err(io::Error::other(format!(
+                "the nameserver responds {}",
+                res.status()
+            )));
+        }
+        let body = hyper::body::to_bytes(res.into_body())
+            .await
+            .map_err(other)?;
+        connections.put_https(sender);
+        let mut response = Message::from_vec(&body)?;
+        response.set_id(request.id());
+        Ok(response)
+    }
+
+    /// Dials the nameserver over HTTPS, or HTTP if the scheme says so.
+    async fn dial_https(&self, uri: &Uri) -> io::Result<SendRequest<Body>> {
+        let host = uri
+            .host()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no host in the uri"))?;
+        let https = uri.scheme() != Some(&Scheme::HTTP);
+        let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let target = match host.parse::<IpAddr>() {
+            Ok(ip) => TargetAddr::SocketAddr(SocketAddr::new(ip, port)),
+            Err(_) => TargetAddr::Domain(host.to_string(), port),
+        };
+        let mut stream = self.dial.dial(&target).await?;
+        if https {
+            stream = Box::new(self.connect_tls(host, stream).await?);
+        }
+
+        let (sender, connection) = conn::handshake(stream).await.map_err(other)?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("the connection to the nameserver is closed, {}", e);
+            }
+        });
+        Ok(sender)
+    }
+
+    #[cfg(feature = "tokio-native-tls")]
+    async fn connect_tls(
+        &self,
+        host: &str,
+        stream: Box<dyn DnsStream>,
+    ) -> io::Result<Box<dyn DnsStream>> {
+        let stream = match &self.tls {
+            Some(tls) => tls.connect(host, stream).await,
+            None => {
+                let tls = tokio_native_tls::native_tls::TlsConnector::new().map_err(other)?;
+                tokio_native_tls::TlsConnector::from(tls)
+                    .connect(host, stream)
+                    .await
+            }
+        };
+        Ok(Box::new(stream.map_err(other)?))
+    }
+
+    #[cfg(not(feature = "tokio-native-tls"))]
+    async fn connect_tls(
+        &self,
+        _host: &str,
+        _stream: Box<dyn DnsStream>,
+    ) -> io::Result<Box<dyn DnsStream>> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl Resolver for DnsClient {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        Box::pin(async move { Ok(self.lookup(host).await?.ips) })
+    }
+
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Lookup>> {
+        Box::pin(async move {
+            debug!("resolve the ip for {} with {:?}", host, &self.nameservers);
+            let mut name = Name::from_utf8(host)?;
+            name.set_fqdn(true);
+            let (a, aaaa) = futures::join!(
+                self.query(&name, RecordType::A),
+                self.query(&name, RecordType::AAAA)
+            );
+            // The IPv4 addresses come first.
+            match (a, aaaa) {
+                (Err(e), Err(_)) => Err(e),
+                (a, aaaa) => Ok(Lookup {
+                    ips: a.iter().chain(aaaa.iter()).flat_map(answer_ips).collect(),
+                    ttl: a.iter().chain(aaaa.iter()).filter_map(message_ttl).min(),
+                }),
+            }
+        })
+    }
+
+    fn query<'a>(
+        &'a self,
+        name: &'a Name,
+        rtype: RecordType,
+    ) -> BoxFuture<'a, io::Result<Message>> {
+        Box::pin(DnsClient::query(self, name, rtype))
+    }
+}
+
+/// Builds the recursive query for the records of the name.
+pub fn query_message(name: &Name, rtype: RecordType) -> Message {
+    let mut message = Message::new();
+    message
+        .set_id(random_id())
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(Query::query(name.clone(), rtype));
+    message
+}
+
+/// Returns the IP addresses in the answers of the response.
+pub fn answer_ips(message: &Message) -> Vec<IpAddr> {
+    message
+        .answers()
+        .iter()
+        .filter_map(|r| match r.data() {
+            Some(RData::A(v4)) => Some(IpAddr::V4(*v4)),
+            Some(RData::AAAA(v6)) => Some(IpAddr::V6(*v6)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Returns how long the response can be cached, which is the minimum TTL
+/// of the answers, or the SOA minimum if there are no answers.
+pub fn message_ttl(message: &Message) -> Option<Duration> {
+    let ttl = if message.answers().is_empty() {
+        message
+            .name_servers()
+            .iter()
+            .filter_map(|r| match r.data() {
+                Some(RData::SOA(soa)) => Some(r.ttl().min(soa.minimum())),
+                _ => None,
+            })
+            .min()
+    } else {
+        message.answers().iter().map(|r| r.ttl()).min()
+    };
+    ttl.map(|ttl| Duration::from_secs(ttl.into()))
+}
+
+/// Reads the DNS message prefixed with the two-byte length, which is the
+/// framing over TCP.
+pub async fn read_tcp_message<S>(stream: &mut S) -> io::Result<Message>
+where
+    S: AsyncReadExt + Unpin,
+{
+    let len = stream.read_u16().await?;
+    let mut buf = vec![0u8; len as usize];
+    stream.read_exact(&mut buf).await?;
+    Ok(Message::from_vec(&buf)?)
+}
+
+/// Writes the DNS message prefixed with the two-byte length.
+pub async fn write_tcp_message<S>(stream: &mut S, message: &Message) -> io::Result<()>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    let buf = message.to_vec()?;
+    let len = u16::try_from(buf.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "the message is too large"))?;
+    let mut framed = Vec::with_capacity(buf.len() + 2);
+    framed.extend_from_slice(&len.to_be_bytes());
+    framed.extend_from_slice(&buf);
+    stream.write_all(&framed).await?;
+    stream.flush().await
+}
+
+/// Returns the unpredictable id of the query, so that the forged responses
+/// have to guess it.
+fn random_id() -> u16 {
+    rand::random()
+}
+
+/// Returns whether the response answers the request, by both the id and
+/// the questions.
+fn is_response_of(response: &Message, request: &Message) -> bool {
+    response.id() == request.id() && response.queries() == request.queries()
+}
+
+/// The connections kept to a nameserver, which the later queries reuse
+/// instead of dialing again.
+#[derive(Default)]
+struct Connections {
+    /// The stream over TCP or TLS, which the queries are pipelined on.
+    stream: tokio::sync::Mutex<Option<Pipeline>>,
+    /// The idle connections over HTTPS, with since when they are idle.
+    https: Mutex<Vec<(SendRequest<Body>, Instant)>>,
+}
+
+impl Connections {
+    /// Takes an idle connection over HTTPS which is still open.
+    async fn take_https(&self) -> Option<SendRequest<Body>> {
+        loop {
+            let (mut sender, idle_since) = self.https.lock().unwrap().pop()?;
+            if idle_since.elapsed() < IDLE_TIMEOUT
+                && future::poll_fn(|cx| sender.poll_ready(cx)).await.is_ok()
+            {
+                return Some(sender);
+            }
+        }
+    }
+
+    /// Puts the connection over HTTPS back once the response is read, which
+    /// is closed if enough are idle.
+    fn put_https(&self, sender: SendRequest<Body>) {
+        let mut https = self.https.lock().unwrap();
+        if https.len() < MAX_IDLE_HTTPS {
+            https.push((sender, Instant::now()));
+        }
+    }
+}
+
+/// The request sent to the pipeline, with where to reply the response.
+type PipelineRequest = (Message, oneshot::Sender<io::Result<Message>>);
+
+/// The stream to a nameserver which the queries are pipelined on (RFC
+/// 7766), the responses are matched to the queries by the ids.
+///
+/// The stream is closed once it is idle for [`IDLE_TIMEOUT`].
+#[derive(Clone)]
+struct Pipeline {
+    requests: mpsc::UnboundedSender<PipelineRequest>,
+}
+
+impl Pipeline {
+    fn new(stream: Box<dyn DnsStream>) -> Self {
+        let (requests, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run_pipeline(stream, rx));
+        Pipeline { requests }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.requests.is_closed()
+    }
+
+    async fn exchange(&self, request: &Message) -> io::Result<Message> {
+        let (reply, response) = oneshot::channel();
+        self.requests
+            .send((request.clone(), reply))
+            .map_err(|_| pipeline_closed())?;
+        response.await.map_err(|_| pipeline_closed())?
+    }
+}
+
+fn pipeline_closed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "the connection to the nameserver is closed",
+    )
+}
+
+/// The query waiting for the response on the pipeline.
+struct InFlight {
+    /// The request as sent, whose id is changed if another query in flight
+    /// has the same one.
+    request: Message,
+    id: u16,
+    reply: oneshot::Sender<io::Result<Message>>,
+}
+
+/// Writes the requests to the stream and replies the responses, until the
+/// stream is closed or idle, or the pipeline is dropped.
+async fn run_pipeline(
+    stream: Box<dyn DnsStream>,
+    mut requests: mpsc::UnboundedReceiver<PipelineRequest>,
+) {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let responses = stream::unfold(reader, |mut reader| async move {
+        let response = read_tcp_message(&mut reader).await;
+        Some((response, reader))
+    })
+    .fuse();
+    futures::pin_mut!(responses);
+    let mut in_flight: HashMap<u16, InFlight> = HashMap::new();
+    loop {
+        // The queries which have timed out are given up.
+        in_flight.retain(|_, query| !query.reply.is_closed());
+        let idle = in_flight.is_empty();
+        let idle_timeout = async move {
+            if idle {
+                tokio::time::sleep(IDLE_TIMEOUT).await
+            } else {
+                future::pending().await
+            }
+        };
+        futures::select! {
+            request = requests.recv().fuse() => match request {
+                Some((mut request, reply)) => {
+                    let id = request.id();
+                    while in_flight.contains_key(&request.id()) {
+                        request.set_id(random_id());
+                    }
+                    if let Err(e) = write_tcp_message(&mut writer, &request).await {
+                        debug!("unable to write to the nameserver, {}", e);
+                        break;
+                    }
+                    in_flight.insert(request.id(), InFlight { request, id, reply });
+                }
+                None => break,
+            },
+            response = responses.next() => match response {
+                Some(Ok(mut response)) => match in_flight.remove(&response.id()) {
+                    Some(query) if is_response_of(&response, &query.request) => {
+                        response.set_id(query.id);
+                        let _ = query.reply.send(Ok(response));
+                    }
+                    Some(query) => {
+                        let _ = query.reply.send(Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "the response does not match the request",
+                        )));
+                    }
+                    None => debug!("drop the response of no query in flight"),
+                },
+                Some(Err(e)) => {
+                    debug!("the connection to the nameserver is closed, {}", e);
+                    break;
+                }
+                None => break,
+            },
+            _ = idle_timeout.fuse() => break,
+        }
+    }
+}
+
+fn host_of(target: &TargetAddr) -> String {
+    match target {
+        TargetAddr::SocketAddr(addr) => addr.ip().to_string(),
+        TargetAddr::Domain(host, _) => host.clone(),
+    }
+}
+
+fn other<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::other(e)
+}
@@ -0,0 +1,99 @@
+use std::{io, sync::Arc};
+
+use futures::future::BoxFuture;
+use log::{debug, warn};
+use tokio::net::{TcpListener, UdpSocket};
+use trust_dns_proto::op::{Message, MessageType};
+
+use crate::{read_tcp_message, write_tcp_message};
+
+/// The maximum size of the DNS message over UDP.
+const MAX_UDP_SIZE: usize = 4096;
+
+/// Answers the DNS queries of the local DNS server.
+pub trait DnsHandler: Send + Sync {
+    fn handle<'a>(&'a self, request: &'a Message) -> BoxFuture<'a, Message>;
+}
+
+impl<T: DnsHandler + ?Sized> DnsHandler for Arc<T> {
+    fn handle<'a>(&'a self, request: &'a Message) -> BoxFuture<'a, Message> {
+        (**self).handle(request)
+    }
+}
+
+/// Returns the response to the request without any record.
+pub fn response_of(request: &Message) -> Message {
+    let mut response = Message::new();
+    response
+        .set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(request.op_code())
+        .set_recursion_desired(request.recursion_desired())
+        .set_recursion_available(true);
+    for query in request.queries() {
+        response.add_query(query.clone());
+    }
+    response
+}
+
+/// Serves the DNS queries over UDP, each query is answered concurrently.
+pub async fn serve_dns_udp<H>(socket: UdpSocket, handler: H) -> io::Result<()>
+where
+    H: DnsHandler + Clone + 'static,
+{
+    let socket = Arc::new(socket);
+    let mut buf = vec![0u8; MAX_UDP_SIZE];
+    loop {
+        let (n, peer) = socket.recv_from(&mut buf).await?;
+        let request = match Message::from_vec(&buf[..n]) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("drop the invalid query from {}, {}", peer, e);
+                continue;
+            }
+        };
+        let socket = socket.clone();
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let response = match handler.handle(&request).await.to_vec() {
+                Ok(response) => response,
+                Err(e) => {
+                    warn!("unable to encode the answer to {}, {}", peer, e);
+                    return;
+                }
+            };
+            if let Err(e) = socket.send_to(&response, peer).await {
+                warn!("unable to answer {}, {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Serves the DNS queries over TCP, the queries on the same connection are
+/// answered one by one.
+pub async fn serve_dns_tcp<H>(listener: TcpListener, handler: H) -> io::Result<()>
+where
+    H: DnsHandler + Clone + 'static,
+{
+    loop {
+        let (mut stream, peer) = listener.accept().await?;
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            loop {
+                let request = match read_tcp_message(&mut stream).await {
+                    Ok(request) => request,
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => {
+                        debug!("unable to read the query from {}, {}", peer, e);
+                        break;
+                    }
+                };
+                let response = handler.handle(&request).await;
+                if let Err(e) = write_tcp_message(&mut stream, &response).await {
+                    debug!("unable to answer {}, {}", peer, e);
+                    break;
+                }
+            }
+        });
+    }
+}
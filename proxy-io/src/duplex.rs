@@ -1,33 +1,191 @@
+use std::cmp;
+use std::error::Error;
+use std::fmt;
 use std::future::Future;
 use std::io;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::task::{ready, Context, Poll};
+use std::time::Duration;
 
 use bytes::{Buf, BufMut};
-use log::{error, trace};
+use log::{debug, error, trace};
 use pin_project_lite::pin_project;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{Instant, Sleep};
 use tokio_util::io::{poll_read_buf, poll_write_buf};
 
+use crate::{BufferPool, MAX_BUFFER_SIZE, MIN_BUFFER_SIZE};
+
 pin_project! {
+    /// Copies the bytes between the two IOs until both of them are closed,
+    /// and then resolves to the `DuplexStats`.
+    ///
+    /// The relay is shut down with the `Expired` error once it is idle for
+    /// the idle timeout, or lives longer than the max lifetime.
     pub struct Duplex<I, O> {
         half_in: HalfDuplex<I>,
         half_out: HalfDuplex<O>,
+        tracker: Tracker,
+    }
+}
+
+/// The side of the relay, `In` is the IO passed first which is usually the
+/// client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    In,
+    Out,
+}
+
+/// What the relay did once it completes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DuplexStats {
+    /// The bytes copied from `In` to `Out`.
+    pub sent: u64,
+    /// The bytes copied from `Out` to `In`.
+    pub received: u64,
+    pub duration: Duration,
+    /// The side which sent EOF first.
+    pub first_closed: Option<Side>,
+}
+
+impl fmt::Display for DuplexStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent {}B, received {}B in {:?}",
+            self.sent, self.received, self.duration
+        )?;
+        match self.first_closed {
+            Some(Side::In) => f.write_str(", closed by in"),
+            Some(Side::Out) => f.write_str(", closed by out"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The bytes copied by the running relays, which are shared with the other
+/// tasks to read.
+///
+/// The counters are added up as the bytes are written, so the same counters
+/// may be set to many relays to account the traffic of them all.
+#[derive(Debug, Clone, Default)]
+pub struct DuplexCounters {
+    inner: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl DuplexCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the bytes copied from `In` to `Out`.
+    pub fn sent(&self) -> u64 {
+        self.inner.sent.load(Ordering::Relaxed)
     }
+
+    /// Returns the bytes copied from `Out` to `In`.
+    pub fn received(&self) -> u64 {
+        self.inner.received.load(Ordering::Relaxed)
+    }
+
+    fn add(&self, sent: u64, received: u64) {
+        if sent > 0 {
+            self.inner.sent.fetch_add(sent, Ordering::Relaxed);
+        }
+        if received > 0 {
+            self.inner.received.fetch_add(received, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The timeouts of the relayed connections, which never expire by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DuplexTimeouts {
+    /// The time without any byte in either direction.
+    pub idle: Option<Duration>,
+    /// The time since the relay starts, however active it is.
+    pub max_lifetime: Option<Duration>,
+}
+
+/// The reason why the relay is shut down before the IOs are closed, which
+/// is the inner error of the `TimedOut` error returned by `Duplex`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expired {
+    Idle(Duration),
+    Lifetime(Duration),
+}
+
+impl Expired {
+    /// Returns the reason if the error is returned by an expired relay.
+    pub fn of(e: &io::Error) -> Option<Expired> {
+        e.get_ref()
+            .and_then(|e| e.downcast_ref::<Expired>())
+            .copied()
+    }
+}
+
+impl fmt::Display for Expired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expired::Idle(timeout) => write!(f, "relay idle for {:?}", timeout),
+            Expired::Lifetime(lifetime) => {
+                write!(f, "relay exceeds the max lifetime {:?}", lifetime)
+            }
+        }
+    }
+}
+
+impl Error for Expired {}
+
+/// The timers and the counters of a relay, which are the same however the
+/// bytes are copied.
+pub(crate) struct Tracker {
+    idle: Option<Idle>,
+    lifetime: Option<(Duration, Pin<Box<Sleep>>)>,
+    expired: Option<Expired>,
+    counters: DuplexCounters,
+    counted: (u64, u64),
+    started: Instant,
+    first_closed: Option<Side>,
+}
+
+/// The timer which is reset whenever the bytes are copied.
+struct Idle {
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+    copied: u64,
 }
 
 pin_project! {
     pub struct HalfDuplex<T> {
+        // The buffer is only held while the bytes are in flight.
         buf: Option<CopyBuf>,
+        pool: BufferPool,
+        // The size of the buffer to take, which grows while the reads fill
+        // the buffer and shrinks while they are small.
+        size: usize,
+        eof: bool,
         is_shutdown: bool,
         #[pin]
         io: T,
         direction: &'static str,
         flushing: bool,
+        read: u64,
+        written: u64,
     }
 }
 
-/// A buffer used to copy bytes from one IO to another.
+/// A buffer used to copy bytes from one IO to another, which is taken from
+/// the pool.
 ///
 /// Keeps read and write positions.
 struct CopyBuf {
@@ -54,10 +212,56 @@ where
 {
     pub fn new(in_io: I, out_io: O) -> Self {
         Duplex {
-            half_in: HalfDuplex::new(in_io, "client->server"),
-            half_out: HalfDuplex::new(out_io, "server->client"),
+            half_in: HalfDuplex::new(in_io, "client->server", BufferPool::shared()),
+            half_out: HalfDuplex::new(out_io, "server->client", BufferPool::shared()),
+            tracker: Tracker::new(),
         }
     }
+
+    fn copy(self: Pin<&mut Self>, cx: &mut Context<'_>) -> io::Result<()> {
+        let this = self.project();
+        // This purposefully ignores the Async part, since we don't want to
+        // return early if the first half isn't ready, but the other half
+        // could make progress.
+        let _ = this.half_in.copy_into(this.half_out, cx)?;
+        let _ = this.half_out.copy_into(this.half_in, cx)?;
+        Ok(())
+    }
+
+    /// Takes the buffers from the pool rather than the shared one.
+    pub fn set_buffer_pool(&mut self, pool: BufferPool) {
+        self.half_in.set_pool(pool.clone());
+        self.half_out.set_pool(pool);
+    }
+
+    /// Returns the live counters of the relay.
+    pub fn counters(&self) -> DuplexCounters {
+        self.tracker.counters()
+    }
+
+    /// Adds the bytes of the relay to the counters rather than its own, the
+    /// bytes already copied are added too.
+    pub fn set_counters(&mut self, counters: DuplexCounters) {
+        self.tracker.set_counters(counters)
+    }
+
+    /// Shuts down the relay once no byte is copied in either direction for
+    /// the timeout.
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        let copied = self.copied();
+        self.tracker.set_idle_timeout(timeout, copied)
+    }
+
+    /// Shuts down the relay once it lives for the lifetime, which starts
+    /// when this is called.
+    pub fn set_max_lifetime(&mut self, lifetime: Duration) {
+        self.tracker.set_max_lifetime(lifetime)
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: DuplexTimeouts) {
+        let copied = self.copied();
+        self.tracker.set_timeouts(timeouts, copied)
+    }
 }
 
 impl<I, O> Future for Duplex<I, O>
@@ -65,34 +269,191 @@ where
     I: AsyncRead + AsyncWrite + Unpin,
     O: AsyncRead + AsyncWrite + Unpin,
 {
-    type Output = io::Result<()>;
+    type Output = io::Result<DuplexStats>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if !self.tracker.is_expired() {
+            // The bytes are counted even if the copy fails.
+            let copied = self.as_mut().copy(cx);
+            self.as_mut().count();
+            copied?;
+            if self.half_in.is_done() && self.half_out.is_done() {
+                return Poll::Ready(Ok(self.stats()));
+            }
+            let copied = self.copied();
+            if self
+                .as_mut()
+                .project()
+                .tracker
+                .poll_expired(copied, cx)
+                .is_none()
+            {
+                return Poll::Pending;
+            }
+        }
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Shuts down both sides so that the peers know the relay is gone,
+        // the errors are ignored as the relay fails anyway.
         let this = self.project();
-        // This purposefully ignores the Async part, since we don't want to
-        // return early if the first half isn't ready, but the other half
-        // could make progress.
-        let _ = this.half_in.copy_into(this.half_out, cx)?;
-        let _ = this.half_out.copy_into(this.half_in, cx)?;
-        if this.half_in.is_done() && this.half_out.is_done() {
-            Poll::Ready(Ok(()))
+        let in_closed = this.half_in.poll_close(cx).is_ready();
+        let out_closed = this.half_out.poll_close(cx).is_ready();
+        if in_closed && out_closed {
+            Poll::Ready(Err(this.tracker.error()))
         } else {
             Poll::Pending
         }
     }
 }
 
+impl<I, O> Duplex<I, O> {
+    fn count(self: Pin<&mut Self>) {
+        let this = self.project();
+        this.tracker.count(
+            (this.half_out.written, this.half_in.written),
+            (this.half_in.is_eof(), this.half_out.is_eof()),
+        )
+    }
+
+    fn stats(&self) -> DuplexStats {
+        self.tracker
+            .stats(self.half_out.written, self.half_in.written)
+    }
+
+    /// Returns the bytes read and written in both directions.
+    fn copied(&self) -> u64 {
+        self.half_in.read + self.half_in.written + self.half_out.read + self.half_out.written
+    }
+}
+
+impl Tracker {
+    pub(crate) fn new() -> Self {
+        Tracker {
+            idle: None,
+            lifetime: None,
+            expired: None,
+            counters: DuplexCounters::new(),
+            counted: (0, 0),
+            started: Instant::now(),
+            first_closed: None,
+        }
+    }
+
+    pub(crate) fn counters(&self) -> DuplexCounters {
+        self.counters.clone()
+    }
+
+    pub(crate) fn set_counters(&mut self, counters: DuplexCounters) {
+        let (sent, received) = self.counted;
+        counters.add(sent, received);
+        self.counters = counters;
+    }
+
+    /// Sets the idle timeout, `copied` is the bytes the relay has copied.
+    pub(crate) fn set_idle_timeout(&mut self, timeout: Duration, copied: u64) {
+        self.idle = Some(Idle {
+            timeout,
+            sleep: Box::pin(tokio::time::sleep(timeout)),
+            copied,
+        })
+    }
+
+    pub(crate) fn set_max_lifetime(&mut self, lifetime: Duration) {
+        self.lifetime = Some((lifetime, Box::pin(tokio::time::sleep(lifetime))))
+    }
+
+    pub(crate) fn set_timeouts(&mut self, timeouts: DuplexTimeouts, copied: u64) {
+        if let Some(timeout) = timeouts.idle {
+            self.set_idle_timeout(timeout, copied);
+        }
+        if let Some(lifetime) = timeouts.max_lifetime {
+            self.set_max_lifetime(lifetime);
+        }
+    }
+
+    /// Adds the bytes written since the last call to the counters, and
+    /// records the side which closes first by whether each side is EOF.
+    pub(crate) fn count(&mut self, (sent, received): (u64, u64), (in_eof, out_eof): (bool, bool)) {
+        self.counters
+            .add(sent - self.counted.0, received - self.counted.1);
+        self.counted = (sent, received);
+        if self.first_closed.is_none() {
+            if in_eof {
+                self.first_closed = Some(Side::In);
+            } else if out_eof {
+                self.first_closed = Some(Side::Out);
+            }
+        }
+    }
+
+    pub(crate) fn stats(&self, sent: u64, received: u64) -> DuplexStats {
+        DuplexStats {
+            sent,
+            received,
+            duration: self.started.elapsed(),
+            first_closed: self.first_closed,
+        }
+    }
+
+    /// Returns whether the relay is expired.
+    pub(crate) fn is_expired(&self) -> bool {
+        self.expired.is_some()
+    }
+
+    /// Returns the reason if either timer fires, the idle timer is reset
+    /// first if the `copied` bytes change since the last poll.
+    ///
+    /// The reason is kept once the relay is expired.
+    pub(crate) fn poll_expired(&mut self, copied: u64, cx: &mut Context<'_>) -> Option<Expired> {
+        if self.expired.is_none() {
+            self.expired = self.poll_timers(copied, cx);
+            if let Some(expired) = self.expired {
+                debug!("shut down the relay, {}", expired);
+            }
+        }
+        self.expired
+    }
+
+    fn poll_timers(&mut self, copied: u64, cx: &mut Context<'_>) -> Option<Expired> {
+        if let Some(idle) = &mut self.idle {
+            if idle.copied != copied {
+                idle.copied = copied;
+                idle.sleep.as_mut().reset(Instant::now() + idle.timeout);
+            }
+            if idle.sleep.as_mut().poll(cx).is_ready() {
+                return Some(Expired::Idle(idle.timeout));
+            }
+        }
+        if let Some((lifetime, sleep)) = &mut self.lifetime {
+            if sleep.as_mut().poll(cx).is_ready() {
+                return Some(Expired::Lifetime(*lifetime));
+            }
+        }
+        None
+    }
+
+    /// Returns the error of the expired relay.
+    pub(crate) fn error(&self) -> io::Error {
+        let expired = self.expired.expect("the relay is expired");
+        io::Error::new(io::ErrorKind::TimedOut, expired)
+    }
+}
+
 impl<T> HalfDuplex<T>
 where
     T: AsyncRead + Unpin,
 {
-    fn new(io: T, direction: &'static str) -> Self {
+    fn new(io: T, direction: &'static str, pool: BufferPool) -> Self {
         Self {
-            buf: Some(CopyBuf::new()),
+            buf: None,
+            pool,
+            size: MIN_BUFFER_SIZE,
+            eof: false,
             is_shutdown: false,
             io,
             direction,
             flushing: false,
+            read: 0,
+            written: 0,
         }
     }
 
@@ -155,6 +516,11 @@ where
                         // before the destination became pending. Try to flush
                         // the written data to get capacity.
                         Drained::Partial(_) => {
+                            // Reads more into the free capacity while the
+                            // destination is not ready.
+                            if !self.eof {
+                                let _ = self.poll_read_more(cx)?;
+                            }
                             ready!(self.poll_flush(dst, cx))?;
                             // If the flush completed, try writing again to
                             // ensure that we have a notification registered. If
@@ -186,38 +552,84 @@ where
     }
 
     /// Attempts to read and buffer data from the underlying stream, returning
-    /// the number of bytes read. If the buffer already has data, no new data
-    /// will be read.
+    /// the number of bytes read. If the stream has no data to read, but the
+    /// buffer has, returns `NotEmpty`.
+    ///
+    /// The buffer is put back to the pool once it is empty and the stream
+    /// has no data to read.
     fn poll_buffer(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Buffered>> {
-        // Buffer data only if no data is buffered.
-        //
-        // TODO should we read more data as long as there's buffer capacity?
-        // To do this, we'd have to get more complex about handling EOF.
-        if let Some(buf) = self.buf.as_mut() {
-            if buf.has_remaining() {
-                // Data was already buffered, so just return immediately.
-                trace!(
-                    "{} remains {}, skipping read",
-                    self.direction,
-                    buf.remaining()
-                );
-                return Poll::Ready(Ok(Buffered::NotEmpty));
+        if !self.eof {
+            if let Poll::Ready(sz) = self.poll_read_more(cx)? {
+                if sz > 0 {
+                    return Poll::Ready(Ok(Buffered::Read(sz)));
+                }
+            }
+        }
+
+        match &self.buf {
+            Some(buf) if buf.has_remaining() => {
+                trace!("{} remains {}B", self.direction, buf.remaining());
+                Poll::Ready(Ok(Buffered::NotEmpty))
             }
+            _ if self.eof => {
+                trace!("eof");
+                self.release();
+                Poll::Ready(Ok(Buffered::Eof))
+            }
+            _ => {
+                self.release();
+                Poll::Pending
+            }
+        }
+    }
 
+    /// Reads into the free capacity of the buffer, taking a buffer from the
+    /// pool if none is held. Returns 0 if the buffer is full or the stream
+    /// reaches EOF.
+    fn poll_read_more(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        if let Some(buf) = &self.buf {
+            if !buf.has_remaining() && buf.capacity() != self.size {
+                self.release();
+            }
+        }
+        let (pool, size) = (&self.pool, self.size);
+        let buf = self
+            .buf
+            .get_or_insert_with(|| CopyBuf::new(pool.take(size)));
+        if !buf.has_remaining() {
             buf.reset();
-            trace!("{} reading", self.direction);
-            let sz = ready!(poll_read_buf(Pin::new(&mut self.io), cx, buf))?;
-            trace!("{} read {}B", self.direction, sz);
-            // If data was read, return the number of bytes read.
-            if sz > 0 {
-                return Poll::Ready(Ok(Buffered::Read(sz)));
+        } else if !buf.has_remaining_mut() {
+            if buf.read_pos == 0 {
+                return Poll::Ready(Ok(0));
+            }
+            buf.compact();
+        }
+
+        // Only the reads into the empty buffer tell how bulky the stream
+        // is, the others are limited by the bytes in flight.
+        let capacity = (!buf.has_remaining()).then(|| buf.capacity());
+        trace!("{} reading", self.direction);
+        let sz = ready!(poll_read_buf(Pin::new(&mut self.io), cx, buf))?;
+        trace!("{} read {}B", self.direction, sz);
+        if sz == 0 {
+            self.eof = true;
+        } else {
+            self.read += sz as u64;
+            if let Some(capacity) = capacity {
+                self.adapt(sz, capacity);
             }
         }
+        Poll::Ready(Ok(sz))
+    }
 
-        // No more data can be read.
-        trace!("eof");
-        self.buf = None;
-        Poll::Ready(Ok(Buffered::Eof))
+    /// Grows the next buffer if the read fills the buffer, or shrinks it if
+    /// the read is small. The buffer is replaced once it is empty.
+    fn adapt(&mut self, sz: usize, capacity: usize) {
+        if sz == capacity {
+            self.size = cmp::min(capacity * 2, MAX_BUFFER_SIZE);
+        } else if sz < capacity / 4 {
+            self.size = cmp::max(capacity / 2, MIN_BUFFER_SIZE);
+        }
     }
 
     /// Attempts to flush the destination. `self.flushing` is set to true iff the
@@ -256,6 +668,7 @@ where
                     return Err(write_zero());
                 }
                 sz += n;
+                dst.written += n as u64;
             }
         }
 
@@ -271,24 +684,72 @@ where
     }
 }
 
+impl<T> HalfDuplex<T> {
+    /// Returns true once the IO has nothing more to read.
+    fn is_eof(&self) -> bool {
+        self.eof
+    }
+
+    /// Puts the buffer back to the pool.
+    fn release(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            debug_assert!(!buf.has_remaining(), "release a buffer in flight");
+            self.pool.put(buf.buf);
+        }
+    }
+
+    fn set_pool(&mut self, pool: BufferPool) {
+        self.release();
+        self.pool = pool;
+    }
+}
+
+impl<T> HalfDuplex<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    /// Shuts down the IO unless it is already shut down.
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.is_shutdown {
+            if let Err(e) = ready!(Pin::new(&mut self.io).poll_shutdown(cx)) {
+                debug!("unable to shut down {}, {}", self.direction, e);
+            }
+            self.is_shutdown = true;
+        }
+        Poll::Ready(())
+    }
+}
+
 fn write_zero() -> io::Error {
     io::Error::new(io::ErrorKind::WriteZero, "write zero bytes")
 }
 
 impl CopyBuf {
-    fn new() -> Self {
+    fn new(buf: Box<[u8]>) -> Self {
         CopyBuf {
-            buf: Box::new([0; 8 * 1024]),
+            buf,
             read_pos: 0,
             write_pos: 0,
         }
     }
 
+    fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
     fn reset(&mut self) {
         debug_assert_eq!(self.read_pos, self.write_pos);
         self.read_pos = 0;
         self.write_pos = 0;
     }
+
+    /// Moves the bytes not written yet to the front, so that the buffer
+    /// reads more.
+    fn compact(&mut self) {
+        self.buf.copy_within(self.read_pos..self.write_pos, 0);
+        self.write_pos -= self.read_pos;
+        self.read_pos = 0;
+    }
 }
 
 impl Buf for CopyBuf {
@@ -0,0 +1,101 @@
+use std::{error::Error, fmt, io, net::SocketAddr, time::Duration};
+
+use futures::{stream::FuturesUnordered, StreamExt};
+use log::debug;
+use tokio::net::TcpStream;
+
+/// The delay between the connection attempts recommended by RFC 8305.
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// The failures of all the addresses of the host.
+#[derive(Debug)]
+pub struct ConnectError {
+    errors: Vec<(SocketAddr, io::Error)>,
+}
+
+impl ConnectError {
+    /// Returns the failure of each address, in the order of the attempts.
+    pub fn errors(&self) -> &[(SocketAddr, io::Error)] {
+        &self.errors
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("unable to connect any address")?;
+        for (i, (addr, e)) in self.errors.iter().enumerate() {
+            let sep = if i == 0 { ", " } else { "; " };
+            write!(f, "{}{}: {}", sep, addr, e)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConnectError {}
+
+/// Reorders the addresses so that the families alternate, starting with the
+/// family of the first address (RFC 8305 section 4).
+pub fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let prefer_v6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return addrs,
+    };
+    let (preferred, other): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == prefer_v6);
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut addrs = Vec::with_capacity(preferred.len() + other.len());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return addrs,
+            (a, b) => addrs.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connects the addresses by Happy Eyeballs (RFC 8305).
+///
+/// The addresses are interleaved by family and attempted one after another,
+/// each attempt starts if the previous one fails or is not connected after
+/// the delay. The first connected stream wins and the other attempts are
+/// cancelled. If all of the attempts fail, the error carries a
+/// [`ConnectError`] with the failure of each address.
+pub async fn connect_happy_eyeballs(
+    addrs: Vec<SocketAddr>,
+    delay: Duration,
+) -> io::Result<TcpStream> {
+    if addrs.len() == 1 {
+        return TcpStream::connect(addrs[0]).await;
+    }
+
+    let mut pending = interleave(addrs).into_iter().peekable();
+    let mut attempts = FuturesUnordered::new();
+    let mut errors = Vec::new();
+    loop {
+        let next = if pending.peek().is_some() {
+            tokio::time::timeout(delay, attempts.next()).await
+        } else {
+            Ok(attempts.next().await)
+        };
+        match next {
+            Ok(Some((_, Ok(stream)))) => return Ok(stream),
+            Ok(Some((addr, Err(e)))) => {
+                debug!("unable to connect {}, {}", addr, &e);
+                errors.push((addr, e));
+            }
+            Ok(None) | Err(_) => {}
+        }
+        // Starts the next attempt once an attempt fails or the delay elapses.
+        match pending.next() {
+            Some(addr) => attempts.push(async move { (addr, TcpStream::connect(addr).await) }),
+            None if attempts.is_empty() => break,
+            None => {}
+        }
+    }
+
+    let kind = match errors.last() {
+        Some((_, e)) => e.kind(),
+        None => io::ErrorKind::HostUnreachable,
+    };
+    Err(io::Error::new(kind, ConnectError { errors }))
+}
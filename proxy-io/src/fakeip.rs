@@ -0,0 +1,312 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    num::NonZeroUsize,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use futures::future::{ready, BoxFuture};
+use ipnet::IpNet;
+use log::debug;
+use lru::LruCache;
+use trust_dns_proto::{
+    op::{Message, OpCode, ResponseCode},
+    rr::{RData, Record, RecordType},
+};
+
+use crate::{response_of, DnsHandler, TargetAddr};
+
+/// The default maximum number of the domains in each pool.
+const FAKE_IP_CAPACITY: usize = 65536;
+
+/// The TTL of the fake addresses, which is short so that the clients ask
+/// again rather than keep the evicted addresses.
+const FAKE_IP_TTL: u32 = 1;
+
+/// The pool of the fake addresses of one family.
+struct Pool {
+    net: IpNet,
+    /// The domain behind each address, the least recently used is reused
+    /// once the pool is full.
+    entries: LruCache<IpAddr, String>,
+    domains: HashMap<String, IpAddr>,
+    /// The offset of the next unused address in the network.
+    next: u128,
+}
+
+impl Pool {
+    fn new(net: IpNet) -> Self {
+        Pool {
+            net,
+            entries: LruCache::new(NonZeroUsize::new(FAKE_IP_CAPACITY).unwrap()),
+            domains: HashMap::new(),
+            next: 0,
+        }
+    }
+
+    /// Returns the number of the usable addresses, excluding the network
+    /// address and the broadcast address of IPv4.
+    fn size(&self) -> u128 {
+        match self.net {
+            IpNet::V4(net) => (1u128 << (32 - net.prefix_len())).saturating_sub(2).max(1),
+            IpNet::V6(net) => {
+                let bits = 128 - u32::from(net.prefix_len());
+                1u128.checked_shl(bits).map_or(u128::MAX, |n| n - 1).max(1)
+            }
+        }
+    }
+
+    fn offset(&self, ip: IpAddr) -> u128 {
+        match (self.net, ip) {
+            (IpNet::V4(net), IpAddr::V4(ip)) => (u32::from(ip) - u32::from(net.network())) as u128,
+            (IpNet::V6(net), IpAddr::V6(ip)) => u128::from(ip) - u128::from(net.network()),
+            _ => 0,
+        }
+    }
+
+    fn nth(&self, offset: u128) -> IpAddr {
+        // Skips the network address.
+        match self.net {
+            IpNet::V4(net) => {
+                IpAddr::V4(Ipv4Addr::from(u32::from(net.network()) + 1 + offset as u32))
+            }
+            IpNet::V6(net) => IpAddr::V6(Ipv6Addr::from(u128::from(net.network()) + 1 + offset)),
+        }
+    }
+
+    fn allocate(&mut self, domain: &str) -> IpAddr {
+        if let Some(ip) = self.domains.get(domain) {
+            self.entries.promote(ip);
+            return *ip;
+        }
+        let ip = if self.entries.len() < self.entries.cap().get() && self.next < self.size() {
+            let ip = self.nth(self.next);
+            self.next += 1;
+            ip
+        } else {
+            match self.entries.pop_lru() {
+                Some((ip, evicted)) => {
+                    debug!("reuse the fake ip {} of {}", ip, evicted);
+                    self.domains.remove(&evicted);
+                    ip
+                }
+                None => self.nth(0),
+            }
+        };
+        self.insert(ip, domain.to_string());
+        ip
+    }
+
+    fn insert(&mut self, ip: IpAddr, domain: String) {
+        if let Some((_, evicted)) = self.entries.push(ip, domain.clone()) {
+            self.domains.remove(&evicted);
+        }
+        self.domains.insert(domain, ip);
+        self.next = self.next.max(self.offset(ip));
+    }
+}
+
+/// The fake addresses which are answered to the DNS queries instead of the
+/// real ones, and translated back to the domains when they are connected.
+///
+/// The clones share the same pools.
+#[derive(Clone)]
+pub struct FakeIp {
+    pools: Arc<Vec<Mutex<Pool>>>,
+    /// Whether any domain is allocated since the last save.
+    dirty: Arc<AtomicBool>,
+}
+
+impl fmt::Debug for FakeIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let nets: Vec<IpNet> = self.pools.iter().map(|p| p.lock().unwrap().net).collect();
+        f.debug_struct("FakeIp").field("nets", &nets).finish()
+    }
+}
+
+impl FakeIp {
+    /// Creates the pools of the networks, like `198.18.0.0/15`, at most one
+    /// for each family.
+    pub fn new(nets: Vec<IpNet>) -> Self {
+        let pools = nets
+            .into_iter()
+            .map(|net| Mutex::new(Pool::new(net)))
+            .collect();
+        FakeIp {
+            pools: Arc::new(pools),
+            dirty: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Sets the maximum number of the domains in each pool.
+    pub fn set_capacity(&mut self, capacity: NonZeroUsize) {
+        for pool in self.pools.iter() {
+            pool.lock().unwrap().entries.resize(capacity);
+        }
+    }
+
+    fn pool(&self, ipv6: bool) -> Option<&Mutex<Pool>> {
+        self.pools.iter().find(|p| {
+            let net = p.lock().unwrap().net;
+            matches!(net, IpNet::V6(_)) == ipv6
+        })
+    }
+
+    /// Returns the fake address of the domain, allocating one if it is new.
+    ///
+    /// Returns `None` if there is no pool of the family.
+    pub fn allocate(&self, domain: &str, ipv6: bool) -> Option<IpAddr> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let mut pool = self.pool(ipv6)?.lock().unwrap();
+        if !pool.domains.contains_key(&domain) {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        Some(pool.allocate(&domain))
+    }
+
+    /// Returns whether any domain is allocated since the last save, so that
+    /// the unchanged mappings are not saved again.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether the address is in any of the pools.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.pools
+            .iter()
+            .any(|p| p.lock().unwrap().net.contains(&ip))
+    }
+
+    /// Returns the domain behind the fake address.
+    pub fn domain(&self, ip: IpAddr) -> Option<String> {
+        self.pools.iter().find_map(|p| {
+            let mut pool = p.lock().unwrap();
+            if pool.net.contains(&ip) {
+                pool.entries.get(&ip).cloned()
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Translates the fake address of the target back to the domain.
+    ///
+    /// Returns `None` if the target is not a fake address, and
+    /// `HostUnreachable` if the domain behind it is unknown.
+    pub fn rewrite(&self, target: &TargetAddr) -> Option<io::Result<TargetAddr>> {
+        // The HTTP requests may carry the address as the domain.
+        let (ip, port) = match target {
+            TargetAddr::SocketAddr(addr) => (addr.ip(), addr.port()),
+            TargetAddr::Domain(host, port) => (host.parse().ok()?, *port),
+        };
+        if !self.contains(ip) {
+            return None;
+        }
+        Some(match self.domain(ip) {
+            Some(domain) => Ok(TargetAddr::Domain(domain, port)),
+            None => Err(io::Error::new(
+                io::ErrorKind::HostUnreachable,
+                format!("unknown fake ip {}", ip),
+            )),
+        })
+    }
+
+    /// Answers the query with the fake addresses.
+    ///
+    /// The queries other than A and AAAA are answered without records.
+    pub fn answer(&self, request: &Message) -> Message {
+        let mut response = response_of(request);
+        if request.op_code() != OpCode::Query {
+            response.set_response_code(ResponseCode::NotImp);
+            return response;
+        }
+        for query in request.queries() {
+            let domain = query.name().to_ascii();
+            let ip = match query.query_type() {
+                RecordType::A => self.allocate(&domain, false),
+                RecordType::AAAA => self.allocate(&domain, true),
+                _ => None,
+            };
+            let rdata = match ip {
+                Some(IpAddr::V4(ip)) => RData::A(ip),
+                Some(IpAddr::V6(ip)) => RData::AAAA(ip),
+                None => continue,
+            };
+            debug!("answer the fake ip {} for {}", ip.unwrap(), &domain);
+            response.add_answer(Record::from_rdata(query.name().clone(), FAKE_IP_TTL, rdata));
+        }
+        response
+    }
+
+    /// Saves the mappings to the file, one `ip domain` per line from the
+    /// least recently used.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        // Cleared before the mappings are taken, so that the domains
+        // allocated meanwhile are saved the next time.
+        self.dirty.store(false, Ordering::Relaxed);
+        let saved = self.write(path.as_ref());
+        if saved.is_err() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        saved
+    }
+
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        for pool in self.pools.iter() {
+            let entries: Vec<(IpAddr, String)> = {
+                let pool = pool.lock().unwrap();
+                pool.entries
+                    .iter()
+                    .rev()
+                    .map(|(ip, domain)| (*ip, domain.clone()))
+                    .collect()
+            };
+            for (ip, domain) in entries {
+                writeln!(writer, "{} {}", ip, domain)?;
+            }
+        }
+        writer.flush()?;
+        drop(writer);
+        std::fs::rename(tmp, path)
+    }
+
+    /// Loads the mappings saved by [`FakeIp::save`], skipping the addresses
+    /// out of the pools.
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let reader = BufReader::new(File::open(path)?);
+        for line in reader.lines() {
+            let line = line?;
+            let (ip, domain) = match line.split_once(' ') {
+                Some((ip, domain)) => (ip, domain.trim()),
+                None => continue,
+            };
+            let ip: IpAddr = match ip.parse() {
+                Ok(ip) => ip,
+                Err(_) => continue,
+            };
+            if let Some(pool) = self
+                .pools
+                .iter()
+                .find(|p| p.lock().unwrap().net.contains(&ip))
+            {
+                pool.lock().unwrap().insert(ip, domain.to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DnsHandler for FakeIp {
+    fn handle<'a>(&'a self, request: &'a Message) -> BoxFuture<'a, Message> {
+        Box::pin(ready(self.answer(request)))
+    }
+}
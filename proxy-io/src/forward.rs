@@ -0,0 +1,212 @@
+use std::{fmt, io, net::IpAddr, sync::Arc, time::Duration};
+
+use futures::future::BoxFuture;
+use log::{debug, warn};
+use proxy_rules::{Decision, Policy};
+use trust_dns_proto::{
+    op::{Message, OpCode, ResponseCode},
+    rr::{Name, RData, Record, RecordType},
+};
+
+use crate::{response_of, DnsHandler, Lookup, Resolver};
+
+/// The TTL of the answers if the resolver does not know.
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+/// The local DNS forwarder which resolves the domains the same way as they
+/// are connected.
+///
+/// The domains routed to the proxy by the policy are resolved by the remote
+/// resolver, which is usually the nameservers over TCP through the proxy,
+/// and the others by the direct resolver. The denied domains are refused.
+///
+/// A and AAAA are answered from the addresses, which both resolvers are
+/// expected to cache. The other types are forwarded to the resolver of the
+/// route and its answer is relayed, or answered NotImp if the resolver only
+/// knows the addresses, like the system one.
+pub struct DnsForwarder<P> {
+    direct: Arc<dyn Resolver>,
+    remote: Arc<dyn Resolver>,
+    policy: Option<Arc<P>>,
+    force_proxy: bool,
+    default_proxy: bool,
+}
+
+impl<P> Clone for DnsForwarder<P> {
+    fn clone(&self) -> Self {
+        DnsForwarder {
+            direct: self.direct.clone(),
+            remote: self.remote.clone(),
+            policy: self.policy.clone(),
+            force_proxy: self.force_proxy,
+            default_proxy: self.default_proxy,
+        }
+    }
+}
+
+impl<P: fmt::Debug> fmt::Debug for DnsForwarder<P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DnsForwarder")
+            .field("policy", &self.policy)
+            .field("force_proxy", &self.force_proxy)
+            .field("default_proxy", &self.default_proxy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<P> DnsForwarder<P> {
+    pub fn new<D, R>(direct: D, remote: R) -> Self
+    where
+        D: Resolver + 'static,
+        R: Resolver + 'static,
+    {
+        DnsForwarder {
+            direct: Arc::new(direct),
+            remote: Arc::new(remote),
+            policy: None,
+            force_proxy: false,
+            default_proxy: false,
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: P) {
+        self.policy = Some(Arc::new(policy))
+    }
+
+    pub fn set_force_proxy(&mut self, force_proxy: bool) {
+        self.force_proxy = force_proxy
+    }
+
+    pub fn set_default_proxy(&mut self, default_proxy: bool) {
+        self.default_proxy = default_proxy
+    }
+}
+
+impl<P: Policy> DnsForwarder<P> {
+    /// Chooses the resolver of the host by its route, along with the
+    /// addresses if they are already resolved directly for the IP rules.
+    ///
+    /// Returns `None` if the host is denied.
+    async fn route(&self, host: &str) -> io::Result<Option<(&dyn Resolver, Option<Lookup>)>> {
+        let mut direct = None;
+        let decision = match &self.policy {
+            _ if self.force_proxy => Decision::Proxy { remote_dns: true },
+            Some(p) if p.needs_resolve(host) => {
+                // Resolves the domain for the IP rules, which is answered
+                // if the domain connects directly.
+                let lookup = self.direct.lookup(host).await?;
+                let decision = p.enforce_resolved(host, &lookup.ips);
+                direct = Some(lookup);
+                decision
+            }
+            Some(p) => p.enforce(host),
+            None => Decision::Default,
+        };
+        let proxy = match decision {
+            Decision::Direct => false,
+            Decision::Proxy { .. } => true,
+            Decision::Default => self.default_proxy,
+            Decision::Deny => return Ok(None),
+        };
+        if proxy {
+            debug!("forward {} to the remote resolver", host);
+            Ok(Some((self.remote.as_ref(), None)))
+        } else {
+            Ok(Some((self.direct.as_ref(), direct)))
+        }
+    }
+
+    /// Resolves the host by the resolver of its route.
+    ///
+    /// Returns `None` if the host is denied.
+    async fn lookup(&self, host: &str) -> io::Result<Option<Lookup>> {
+        match self.route(host).await? {
+            Some((_, Some(lookup))) => Ok(Some(lookup)),
+            Some((resolver, None)) => resolver.lookup(host).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Queries the records of other types by the resolver of the route.
+    ///
+    /// Returns `None` if the host is denied.
+    async fn query(
+        &self,
+        host: &str,
+        name: &Name,
+        rtype: RecordType,
+    ) -> io::Result<Option<Message>> {
+        match self.route(host).await? {
+            Some((resolver, _)) => resolver.query(name, rtype).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Answers the queries of the request, the A and AAAA ones from the
+    /// addresses and the others from the forwarded responses.
+    pub async fn answer(&self, request: &Message) -> Message {
+        let mut response = response_of(request);
+        if request.op_code() != OpCode::Query {
+            response.set_response_code(ResponseCode::NotImp);
+            return response;
+        }
+        for query in request.queries() {
+            let host = query.name().to_ascii();
+            let host = host.trim_end_matches('.');
+            let ipv6 = match query.query_type() {
+                RecordType::A => false,
+                RecordType::AAAA => true,
+                rtype => {
+                    match self.query(host, query.name(), rtype).await {
+                        Ok(Some(forwarded)) => {
+                            response.set_response_code(forwarded.response_code());
+                            response.add_answers(forwarded.answers().iter().cloned());
+                            response.add_name_servers(forwarded.name_servers().iter().cloned());
+                        }
+                        Ok(None) => {
+                            debug!("refuse the query of {}", host);
+                            response.set_response_code(ResponseCode::Refused);
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                            debug!("unable to forward the {} query of {}", rtype, host);
+                            response.set_response_code(ResponseCode::NotImp);
+                        }
+                        Err(e) => {
+                            warn!("unable to query {} of {}, {}", rtype, host, e);
+                            response.set_response_code(ResponseCode::ServFail);
+                        }
+                    }
+                    continue;
+                }
+            };
+            let lookup = match self.lookup(host).await {
+                Ok(Some(lookup)) => lookup,
+                Ok(None) => {
+                    debug!("refuse the query of {}", host);
+                    response.set_response_code(ResponseCode::Refused);
+                    continue;
+                }
+                Err(e) => {
+                    warn!("unable to resolve {}, {}", host, e);
+                    response.set_response_code(ResponseCode::ServFail);
+                    continue;
+                }
+            };
+            let ttl = lookup.ttl.unwrap_or(DEFAULT_TTL).as_secs() as u32;
+            for ip in lookup.ips.into_iter().filter(|ip| ip.is_ipv6() == ipv6) {
+                let rdata = match ip {
+                    IpAddr::V4(ip) => RData::A(ip),
+                    IpAddr::V6(ip) => RData::AAAA(ip),
+                };
+                response.add_answer(Record::from_rdata(query.name().clone(), ttl, rdata));
+            }
+        }
+        response
+    }
+}
+
+impl<P: Policy + Send + Sync + 'static> DnsHandler for DnsForwarder<P> {
+    fn handle<'a>(&'a self, request: &'a Message) -> BoxFuture<'a, Message> {
+        Box::pin(self.answer(request))
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    str::FromStr,
+};
+
+use crate::TargetAddr;
+
+/// What the host is mapped to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostMapping {
+    /// Connects the IP address without resolving the host.
+    Ip(IpAddr),
+    /// Connects the other domain instead.
+    Domain(String),
+}
+
+impl fmt::Display for HostMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostMapping::Ip(ip) => ip.fmt(f),
+            HostMapping::Domain(domain) => f.write_str(domain),
+        }
+    }
+}
+
+impl FromStr for HostMapping {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(ip) = s.parse() {
+            return Ok(HostMapping::Ip(ip));
+        }
+        let domain = normalize(s);
+        if domain.is_empty() || domain.contains(|c: char| c.is_whitespace() || c == '*') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid host {}", s),
+            ));
+        }
+        Ok(HostMapping::Domain(domain))
+    }
+}
+
+/// The static mapping of the hosts, like `/etc/hosts`.
+///
+/// The pattern is either the exact host, or the wildcard like
+/// `*.dev.internal` which matches all of the subdomains. The exact host wins,
+/// and then the longest wildcard.
+#[derive(Debug, Clone, Default)]
+pub struct Hosts {
+    exact: HashMap<String, HostMapping>,
+    wildcards: HashMap<String, HostMapping>,
+}
+
+impl Hosts {
+    pub fn new() -> Self {
+        Hosts::default()
+    }
+
+    /// Parses the hosts file, each line is the IP address followed by the
+    /// hosts, and `#` starts the comment.
+    ///
+    /// The first line wins if the host appears more than once.
+    pub fn parse(content: &str) -> io::Result<Hosts> {
+        let mut hosts = Hosts::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let ip = match fields.next() {
+                Some(ip) => ip.parse::<IpAddr>().map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid ip at line {}, {}", i + 1, e),
+                    )
+                })?,
+                None => continue,
+            };
+            for host in fields {
+                if hosts.get(host).is_none() {
+                    hosts.insert(host, HostMapping::Ip(ip));
+                }
+            }
+        }
+        Ok(hosts)
+    }
+
+    /// Loads the hosts file, see [`Hosts::parse`].
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Hosts> {
+        Hosts::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Maps the host or the wildcard pattern, replacing the previous one.
+    pub fn insert(&mut self, pattern: &str, mapping: HostMapping) {
+        match pattern.strip_prefix("*.") {
+            Some(suffix) => self.wildcards.insert(normalize(suffix), mapping),
+            None => self.exact.insert(normalize(pattern), mapping),
+        };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.wildcards.is_empty()
+    }
+
+    /// Returns what the host is mapped to.
+    pub fn get(&self, host: &str) -> Option<&HostMapping> {
+        let host = normalize(host);
+        if let Some(mapping) = self.exact.get(&host) {
+            return Some(mapping);
+        }
+        let mut suffix = host.as_str();
+        while let Some((_, parent)) = suffix.split_once('.') {
+            if let Some(mapping) = self.wildcards.get(parent) {
+                return Some(mapping);
+            }
+            suffix = parent;
+        }
+        None
+    }
+
+    /// Rewrites the domain of the target by the mapping, keeping the port.
+    ///
+    /// Returns `None` if the target is not mapped.
+    pub fn rewrite(&self, target: &TargetAddr) -> Option<TargetAddr> {
+        let (host, port) = match target {
+            TargetAddr::Domain(host, port) => (host, *port),
+            TargetAddr::SocketAddr(_) => return None,
+        };
+        match self.get(host)? {
+            HostMapping::Ip(ip) => Some(TargetAddr::SocketAddr(SocketAddr::new(*ip, port))),
+            HostMapping::Domain(domain) => Some(TargetAddr::Domain(domain.clone(), port)),
+        }
+    }
+}
+
+fn normalize(host: &str) -> String {
+    host.trim().trim_end_matches('.').to_ascii_lowercase()
+}
@@ -1,15 +1,45 @@
 #![feature(io_error_more)]
 #![feature(type_alias_impl_trait)]
 mod addr;
+mod cache;
+mod dns;
+mod dns_server;
 mod duplex;
 mod either;
+mod eyeballs;
+mod fakeip;
 mod fixed_read;
+mod forward;
+mod hosts;
 mod memio;
+mod pool;
+mod relay;
+mod resolve;
+mod rewind;
+#[cfg(target_os = "linux")]
+mod splice;
 mod stream;
+mod throttle;
+mod timeout;
 
 pub use addr::*;
+pub use cache::*;
+pub use dns::*;
+pub use dns_server::*;
 pub use duplex::*;
 pub use either::*;
+pub use eyeballs::*;
+pub use fakeip::*;
 pub use fixed_read::*;
+pub use forward::*;
+pub use hosts::*;
 pub use memio::*;
+pub use pool::*;
+pub use relay::*;
+pub use resolve::*;
+pub use rewind::*;
+#[cfg(target_os = "linux")]
+pub use splice::SpliceDuplex;
 pub use stream::*;
+pub use throttle::*;
+pub use timeout::*;
@@ -0,0 +1,108 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
+
+/// The smallest buffer, which the relays start with.
+pub const MIN_BUFFER_SIZE: usize = 4 * 1024;
+
+/// The largest buffer, which the relays grow to for the bulk transfers.
+pub const MAX_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The bytes of the free buffers which the shared pool keeps.
+const SHARED_POOL_SIZE: usize = 4 * 1024 * 1024;
+
+/// The sizes of the buffers are the powers of two between the smallest and
+/// the largest.
+const SIZES: usize = (MAX_BUFFER_SIZE / MIN_BUFFER_SIZE).trailing_zeros() as usize + 1;
+
+/// The free buffers which the relays take while the bytes are in flight,
+/// and put back once the bytes are written, so that the idle relays hold no
+/// buffer.
+///
+/// The clones share the same buffers.
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    free: [Mutex<Vec<Box<[u8]>>>; SIZES],
+    free_bytes: AtomicUsize,
+    max_free_bytes: usize,
+}
+
+impl BufferPool {
+    /// Creates a pool which keeps the free buffers up to `max_free_bytes`,
+    /// the others are freed once they are put back.
+    pub fn new(max_free_bytes: usize) -> Self {
+        BufferPool {
+            inner: Arc::new(Inner {
+                free: Default::default(),
+                free_bytes: AtomicUsize::new(0),
+                max_free_bytes,
+            }),
+        }
+    }
+
+    /// Returns the pool shared by the relays of the process.
+    pub fn shared() -> BufferPool {
+        static SHARED: OnceLock<BufferPool> = OnceLock::new();
+        SHARED
+            .get_or_init(|| BufferPool::new(SHARED_POOL_SIZE))
+            .clone()
+    }
+
+    /// Returns the bytes of the free buffers.
+    pub fn free_bytes(&self) -> usize {
+        self.inner.free_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Takes a free buffer of the size, which is rounded up to the sizes of
+    /// the pool, or allocates one.
+    pub(crate) fn take(&self, size: usize) -> Box<[u8]> {
+        let index = index_of(size);
+        match self.inner.free[index].lock().unwrap().pop() {
+            Some(buf) => {
+                self.inner
+                    .free_bytes
+                    .fetch_sub(buf.len(), Ordering::Relaxed);
+                buf
+            }
+            None => vec![0; MIN_BUFFER_SIZE << index].into_boxed_slice(),
+        }
+    }
+
+    /// Puts the buffer back, which is freed if the pool keeps enough.
+    pub(crate) fn put(&self, buf: Box<[u8]>) {
+        let len = buf.len();
+        let index = index_of(len);
+        if MIN_BUFFER_SIZE << index != len {
+            return;
+        }
+        let free_bytes = self.inner.free_bytes.fetch_add(len, Ordering::Relaxed);
+        if free_bytes + len > self.inner.max_free_bytes {
+            self.inner.free_bytes.fetch_sub(len, Ordering::Relaxed);
+            return;
+        }
+        self.inner.free[index].lock().unwrap().push(buf);
+    }
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("free_bytes", &self.free_bytes())
+            .field("max_free_bytes", &self.inner.max_free_bytes)
+            .finish()
+    }
+}
+
+/// Returns the index of the smallest size which holds `size`.
+fn index_of(size: usize) -> usize {
+    let size = size.clamp(MIN_BUFFER_SIZE, MAX_BUFFER_SIZE);
+    (size.next_power_of_two() / MIN_BUFFER_SIZE).trailing_zeros() as usize
+}
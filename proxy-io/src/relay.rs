@@ -0,0 +1,122 @@
+use std::io;
+
+#[cfg(target_os = "linux")]
+use bytes::Bytes;
+#[cfg(target_os = "linux")]
+use log::debug;
+use proxy_auth::Identity;
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(target_os = "linux")]
+use tokio::net::TcpStream;
+
+#[cfg(target_os = "linux")]
+use crate::{splice::into_tcp, Rewind, Side, SpliceDuplex};
+use crate::{Duplex, DuplexStats, DuplexTimeouts, Either, Throttle};
+
+/// Relays the client and the remote after the request succeeds, with the
+/// timeouts and the throttle of the server.
+#[derive(Debug, Clone, Default)]
+pub struct Relay {
+    timeouts: DuplexTimeouts,
+    throttle: Option<Throttle>,
+}
+
+impl Relay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: DuplexTimeouts) {
+        self.timeouts = timeouts
+    }
+
+    pub fn timeouts(&self) -> DuplexTimeouts {
+        self.timeouts
+    }
+
+    /// Throttles the client, the connections are not throttled by default
+    /// so they are copied without the overhead.
+    pub fn set_throttle(&mut self, throttle: Throttle) {
+        self.throttle = Some(throttle)
+    }
+
+    pub fn throttle(&self) -> Option<&Throttle> {
+        self.throttle.as_ref()
+    }
+
+    /// Copies the bytes between the client and the remote until both of
+    /// them are closed.
+    ///
+    /// On Linux, the bytes are spliced if both of them are plain TCP streams
+    /// and the client is not throttled, see [`SpliceDuplex`].
+    pub async fn run<I, O>(
+        &self,
+        client: I,
+        remote: O,
+        identity: Option<&Identity>,
+    ) -> io::Result<DuplexStats>
+    where
+        I: AsyncRead + AsyncWrite + Unpin + 'static,
+        O: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        #[cfg(target_os = "linux")]
+        if self.throttle.is_none() {
+            let (client, client_pre) = match into_tcp(client) {
+                Ok(client) => client,
+                Err(client) => return self.copy(client, remote, identity).await,
+            };
+            let (remote, remote_pre) = match into_tcp(remote) {
+                Ok(remote) => remote,
+                Err(remote) => {
+                    let client = Rewind::new_buffered(client, client_pre);
+                    return self.copy(client, remote, identity).await;
+                }
+            };
+            return self.splice(client, client_pre, remote, remote_pre).await;
+        }
+        self.copy(client, remote, identity).await
+    }
+
+    async fn copy<I, O>(
+        &self,
+        client: I,
+        remote: O,
+        identity: Option<&Identity>,
+    ) -> io::Result<DuplexStats>
+    where
+        I: AsyncRead + AsyncWrite + Unpin,
+        O: AsyncRead + AsyncWrite + Unpin,
+    {
+        let client = match &self.throttle {
+            Some(throttle) => Either::Left(throttle.wrap(client, identity)),
+            None => Either::Right(client),
+        };
+        let mut duplex = Duplex::new(client, remote);
+        duplex.set_timeouts(self.timeouts);
+        duplex.await
+    }
+
+    /// Splices the streams, or copies them if the pipes are unavailable.
+    #[cfg(target_os = "linux")]
+    async fn splice(
+        &self,
+        client: TcpStream,
+        client_pre: Bytes,
+        remote: TcpStream,
+        remote_pre: Bytes,
+    ) -> io::Result<DuplexStats> {
+        let mut duplex = match SpliceDuplex::try_new(client, remote) {
+            Ok(duplex) => duplex,
+            Err((e, client, remote)) => {
+                debug!("unable to splice, fall back to copy, {}", e);
+                let client = Rewind::new_buffered(client, client_pre);
+                let remote = Rewind::new_buffered(remote, remote_pre);
+                return self.copy(client, remote, None).await;
+            }
+        };
+        duplex.set_buffered(Side::In, client_pre);
+        duplex.set_buffered(Side::Out, remote_pre);
+        duplex.set_timeouts(self.timeouts);
+        duplex.await
+    }
+}
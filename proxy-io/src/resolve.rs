@@ -0,0 +1,113 @@
+use std::{io, net::IpAddr, sync::Arc, time::Duration};
+
+use futures::future::{self, BoxFuture};
+use log::debug;
+use tokio::net::lookup_host;
+use trust_dns_proto::{
+    op::Message,
+    rr::{Name, RecordType},
+};
+
+/// Resolver resolves the domain to the IP addresses.
+pub trait Resolver: Send + Sync {
+    /// Resolves all of the A and AAAA records of the host.
+    ///
+    /// Returns an empty list if the host has no records, the error is only
+    /// for the failure to resolve.
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>>;
+
+    /// Resolves like [`Resolver::resolve`], along with how long the
+    /// addresses are valid if the resolver knows.
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Lookup>> {
+        Box::pin(async move {
+            Ok(Lookup {
+                ips: self.resolve(host).await?,
+                ttl: None,
+            })
+        })
+    }
+
+    /// Queries the records of any type, returning the response of the
+    /// nameserver.
+    ///
+    /// Returns `Unsupported` by default, for the resolver which only knows
+    /// the addresses.
+    fn query<'a>(
+        &'a self,
+        _name: &'a Name,
+        _rtype: RecordType,
+    ) -> BoxFuture<'a, io::Result<Message>> {
+        Box::pin(future::ready(Err(io::ErrorKind::Unsupported.into())))
+    }
+}
+
+/// The addresses of the host, and the TTL of the records.
+///
+/// The TTL of the host without addresses is how long the absence can be
+/// cached, like the SOA minimum of NXDOMAIN.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Lookup {
+    pub ips: Vec<IpAddr>,
+    pub ttl: Option<Duration>,
+}
+
+impl<T: Resolver + ?Sized> Resolver for Arc<T> {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        T::resolve(self, host)
+    }
+
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Lookup>> {
+        T::lookup(self, host)
+    }
+
+    fn query<'a>(
+        &'a self,
+        name: &'a Name,
+        rtype: RecordType,
+    ) -> BoxFuture<'a, io::Result<Message>> {
+        T::query(self, name, rtype)
+    }
+}
+
+impl<T: Resolver + ?Sized> Resolver for Box<T> {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        T::resolve(self, host)
+    }
+
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Lookup>> {
+        T::lookup(self, host)
+    }
+
+    fn query<'a>(
+        &'a self,
+        name: &'a Name,
+        rtype: RecordType,
+    ) -> BoxFuture<'a, io::Result<Message>> {
+        T::query(self, name, rtype)
+    }
+}
+
+/// A resolver which asks the system, like `getaddrinfo`.
+#[derive(Debug, Clone, Default)]
+pub struct SystemResolver;
+
+impl SystemResolver {
+    pub fn new() -> Self {
+        SystemResolver
+    }
+}
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        Box::pin(async move {
+            debug!("resolve the ip for {} with native dns", host);
+            let mut ips: Vec<IpAddr> = Vec::new();
+            for addr in lookup_host((host, 0)).await? {
+                if !ips.contains(&addr.ip()) {
+                    ips.push(addr.ip());
+                }
+            }
+            Ok(ips)
+        })
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    cmp, io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+use pin_project_lite::pin_project;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use crate::SocketStream;
+
+pin_project! {
+    /// A stream which buffers the peeked bytes and replays them to the
+    /// following reads, so that the protocol of the stream can be detected
+    /// before it is handed over to the server.
+    #[derive(Debug)]
+    pub struct Rewind<T> {
+        pre: BytesMut,
+        #[pin]
+        inner: T,
+    }
+}
+
+impl<T> Rewind<T> {
+    pub fn new(inner: T) -> Self {
+        Rewind {
+            pre: BytesMut::new(),
+            inner,
+        }
+    }
+
+    /// Creates a stream which replays the `pre` bytes before reading from
+    /// the inner stream.
+    pub fn new_buffered(inner: T, pre: Bytes) -> Self {
+        Rewind {
+            pre: BytesMut::from(&pre[..]),
+            inner,
+        }
+    }
+
+    /// Pushes the bytes back to the front of the stream.
+    pub fn rewind(&mut self, bs: &[u8]) {
+        let mut pre = BytesMut::with_capacity(bs.len() + self.pre.len());
+        pre.extend_from_slice(bs);
+        pre.extend_from_slice(&self.pre);
+        self.pre = pre;
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Consumes the stream, returning the inner stream and the bytes which
+    /// are not read yet.
+    pub fn into_inner(self) -> (T, Bytes) {
+        (self.inner, self.pre.freeze())
+    }
+}
+
+impl<T> Rewind<T>
+where
+    T: AsyncRead + Unpin,
+{
+    /// Reads from the inner stream until `len` bytes are buffered, and
+    /// returns them without consuming.
+    ///
+    /// The returned slice is shorter than `len` only if the inner stream
+    /// reaches EOF.
+    pub async fn peek(&mut self, len: usize) -> io::Result<&[u8]> {
+        while self.pre.len() < len {
+            self.pre.reserve(len - self.pre.len());
+            if self.inner.read_buf(&mut self.pre).await? == 0 {
+                break;
+            }
+        }
+        let n = cmp::min(len, self.pre.len());
+        Ok(&self.pre[..n])
+    }
+}
+
+impl<T> AsyncRead for Rewind<T>
+where
+    T: AsyncRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        if !this.pre.is_empty() {
+            let n = cmp::min(this.pre.len(), buf.remaining());
+            buf.put_slice(&this.pre[..n]);
+            this.pre.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        this.inner.poll_read(cx, buf)
+    }
+}
+
+impl<T> AsyncWrite for Rewind<T>
+where
+    T: AsyncWrite,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<Result<usize, io::Error>> {
+        self.project().inner.poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+impl<T> SocketStream for Rewind<T>
+where
+    T: SocketStream,
+{
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
@@ -0,0 +1,394 @@
+use std::{
+    any::Any,
+    future::Future,
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    pin::Pin,
+    ptr,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+use hyper::upgrade::Upgraded;
+use log::{debug, trace};
+use tokio::{
+    io::{AsyncWrite, Interest},
+    net::TcpStream,
+};
+
+use crate::{
+    duplex::Tracker, Connection, DuplexCounters, DuplexStats, DuplexTimeouts, ProxyStream, Rewind,
+    Side,
+};
+
+/// The bytes to splice at once, which is the default capacity of a pipe.
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// Relays two TCP streams by splice(2) through a pipe in each direction, so
+/// that the bytes are moved by the kernel rather than copied through the
+/// buffers of `Duplex`.
+///
+/// It works the same as `Duplex` otherwise, including the timeouts, the
+/// counters and the stats.
+pub struct SpliceDuplex {
+    half_in: SpliceHalf,
+    half_out: SpliceHalf,
+    tracker: Tracker,
+}
+
+struct SpliceHalf {
+    io: TcpStream,
+    /// The bytes read before the relay, which are written before splicing.
+    pre: Bytes,
+    /// The pipe which the bytes of `io` are spliced into, `None` once `io`
+    /// reaches EOF.
+    pipe: Option<Pipe>,
+    /// The bytes in the pipe.
+    piped: usize,
+    is_shutdown: bool,
+    direction: &'static str,
+    read: u64,
+    written: u64,
+}
+
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl SpliceDuplex {
+    /// Creates the relay, which fails if the pipes can not be created, such
+    /// as running out of the file descriptors.
+    pub fn new(in_io: TcpStream, out_io: TcpStream) -> io::Result<Self> {
+        Self::try_new(in_io, out_io).map_err(|(e, _, _)| e)
+    }
+
+    /// Creates the relay, or returns the streams back with the error so
+    /// that they can be relayed in another way.
+    pub(crate) fn try_new(
+        in_io: TcpStream,
+        out_io: TcpStream,
+    ) -> Result<Self, (io::Error, TcpStream, TcpStream)> {
+        let pipes = Pipe::new().and_then(|pipe_in| Ok((pipe_in, Pipe::new()?)));
+        match pipes {
+            Ok((pipe_in, pipe_out)) => Ok(SpliceDuplex {
+                half_in: SpliceHalf::new(in_io, pipe_in, "client->server"),
+                half_out: SpliceHalf::new(out_io, pipe_out, "server->client"),
+                tracker: Tracker::new(),
+            }),
+            Err(e) => Err((e, in_io, out_io)),
+        }
+    }
+
+    /// Sets the bytes which are read from the side before the relay, they
+    /// are written to the other side first.
+    pub fn set_buffered(&mut self, side: Side, pre: Bytes) {
+        let half = match side {
+            Side::In => &mut self.half_in,
+            Side::Out => &mut self.half_out,
+        };
+        half.read += pre.len() as u64;
+        half.pre = pre;
+    }
+
+    /// Returns the live counters of the relay.
+    pub fn counters(&self) -> DuplexCounters {
+        self.tracker.counters()
+    }
+
+    /// Adds the bytes of the relay to the counters rather than its own, see
+    /// [`crate::Duplex::set_counters`].
+    pub fn set_counters(&mut self, counters: DuplexCounters) {
+        self.tracker.set_counters(counters)
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: DuplexTimeouts) {
+        let copied = self.copied();
+        self.tracker.set_timeouts(timeouts, copied)
+    }
+
+    fn copy(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        // Either half may make progress while the other is pending.
+        let _ = self.half_in.splice_into(&mut self.half_out, cx)?;
+        let _ = self.half_out.splice_into(&mut self.half_in, cx)?;
+        Ok(())
+    }
+
+    fn count(&mut self) {
+        self.tracker.count(
+            (self.half_out.written, self.half_in.written),
+            (self.half_in.is_eof(), self.half_out.is_eof()),
+        )
+    }
+
+    fn copied(&self) -> u64 {
+        self.half_in.read + self.half_in.written + self.half_out.read + self.half_out.written
+    }
+}
+
+impl Future for SpliceDuplex {
+    type Output = io::Result<DuplexStats>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if !this.tracker.is_expired() {
+            let copied = this.copy(cx);
+            this.count();
+            copied?;
+            if this.half_in.is_shutdown && this.half_out.is_shutdown {
+                return Poll::Ready(Ok(this
+                    .tracker
+                    .stats(this.half_out.written, this.half_in.written)));
+            }
+            let copied = this.copied();
+            if this.tracker.poll_expired(copied, cx).is_none() {
+                return Poll::Pending;
+            }
+        }
+
+        let in_closed = this.half_in.poll_close(cx).is_ready();
+        let out_closed = this.half_out.poll_close(cx).is_ready();
+        if in_closed && out_closed {
+            Poll::Ready(Err(this.tracker.error()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl SpliceHalf {
+    fn new(io: TcpStream, pipe: Pipe, direction: &'static str) -> Self {
+        SpliceHalf {
+            io,
+            pre: Bytes::new(),
+            pipe: Some(pipe),
+            piped: 0,
+            is_shutdown: false,
+            direction,
+            read: 0,
+            written: 0,
+        }
+    }
+
+    /// Splices the bytes of `self` into `dst` until `self` reaches EOF, and
+    /// then shuts down `dst`.
+    fn splice_into(&mut self, dst: &mut SpliceHalf, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if dst.is_shutdown {
+            return Poll::Ready(Ok(()));
+        }
+
+        while self.pre.has_remaining() {
+            let n = ready!(Pin::new(&mut dst.io).poll_write(cx, &self.pre))?;
+            if n == 0 {
+                return Poll::Ready(Err(write_zero()));
+            }
+            self.pre.advance(n);
+            dst.written += n as u64;
+        }
+
+        loop {
+            let pipe = match &self.pipe {
+                Some(pipe) => pipe,
+                None => {
+                    trace!("{} shutting down", self.direction);
+                    ready!(Pin::new(&mut dst.io).poll_shutdown(cx))?;
+                    dst.is_shutdown = true;
+                    return Poll::Ready(Ok(()));
+                }
+            };
+
+            // The pipe is only filled once it is empty, so the splice never
+            // blocks on the pipe but on the socket.
+            if self.piped == 0 {
+                let (fd_in, fd_out) = (self.io.as_raw_fd(), pipe.write.as_raw_fd());
+                let n = ready!(poll_splice(
+                    &self.io,
+                    Interest::READABLE,
+                    fd_in,
+                    fd_out,
+                    PIPE_SIZE,
+                    cx
+                ))?;
+                trace!("{} spliced {}B in", self.direction, n);
+                if n == 0 {
+                    self.pipe = None;
+                    continue;
+                }
+                self.piped = n;
+                self.read += n as u64;
+            }
+
+            while self.piped > 0 {
+                let (fd_in, fd_out) = (pipe.read.as_raw_fd(), dst.io.as_raw_fd());
+                let n = ready!(poll_splice(
+                    &dst.io,
+                    Interest::WRITABLE,
+                    fd_in,
+                    fd_out,
+                    self.piped,
+                    cx
+                ))?;
+                trace!("{} spliced {}B out", self.direction, n);
+                if n == 0 {
+                    return Poll::Ready(Err(write_zero()));
+                }
+                self.piped -= n;
+                dst.written += n as u64;
+            }
+        }
+    }
+
+    fn is_eof(&self) -> bool {
+        self.pipe.is_none()
+    }
+
+    fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.is_shutdown {
+            if let Err(e) = ready!(Pin::new(&mut self.io).poll_shutdown(cx)) {
+                debug!("unable to shut down {}, {}", self.direction, e);
+            }
+            self.is_shutdown = true;
+        }
+        Poll::Ready(())
+    }
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        // Safety: pipe2 writes two file descriptors on success, which are
+        // owned by the pipe from now on.
+        unsafe {
+            if libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Pipe {
+                read: OwnedFd::from_raw_fd(fds[0]),
+                write: OwnedFd::from_raw_fd(fds[1]),
+            })
+        }
+    }
+}
+
+/// Splices once the stream is ready for the interest, the readiness is
+/// cleared if the splice would block.
+fn poll_splice(
+    stream: &TcpStream,
+    interest: Interest,
+    fd_in: RawFd,
+    fd_out: RawFd,
+    len: usize,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<usize>> {
+    loop {
+        if interest.is_readable() {
+            ready!(stream.poll_read_ready(cx))?;
+        } else {
+            ready!(stream.poll_write_ready(cx))?;
+        }
+        match stream.try_io(interest, || splice(fd_in, fd_out, len)) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            res => return Poll::Ready(res),
+        }
+    }
+}
+
+fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> io::Result<usize> {
+    // Safety: both of the file descriptors are open while the relay lives.
+    let n = unsafe {
+        libc::splice(
+            fd_in,
+            ptr::null_mut(),
+            fd_out,
+            ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+fn write_zero() -> io::Error {
+    io::Error::new(io::ErrorKind::WriteZero, "write zero bytes")
+}
+
+/// Takes the TCP stream out of the stream which relays the bytes as they
+/// are, together with the bytes which are read but not consumed yet.
+///
+/// Only the streams which the servers relay are known, they are
+/// `TcpStream`, `Rewind`, `ProxyStream` without TLS, `Connection` and the
+/// upgraded HTTP connection of them. The other streams are returned back.
+pub(crate) fn into_tcp<T: 'static>(stream: T) -> Result<(TcpStream, Bytes), T> {
+    let stream = match downcast::<T, TcpStream>(stream) {
+        Ok(stream) => return Ok((stream, Bytes::new())),
+        Err(stream) => stream,
+    };
+    let stream = match downcast::<T, Rewind<TcpStream>>(stream) {
+        Ok(stream) => return Ok(stream.into_inner()),
+        Err(stream) => stream,
+    };
+    let stream = match downcast::<T, ProxyStream<TcpStream>>(stream) {
+        Ok(stream) => return proxy_into_tcp(stream).map_err(upcast),
+        Err(stream) => stream,
+    };
+    let stream = match downcast::<T, Connection<TcpStream>>(stream) {
+        Ok(Connection::Direct(stream)) => return Ok((stream, Bytes::new())),
+        Ok(Connection::Proxy(stream)) => {
+            return proxy_into_tcp(stream).map_err(|stream| upcast(Connection::Proxy(stream)))
+        }
+        Err(stream) => stream,
+    };
+    match downcast::<T, Upgraded>(stream) {
+        Ok(upgraded) => upgraded_into_tcp(upgraded).map_err(upcast),
+        Err(stream) => Err(stream),
+    }
+}
+
+fn proxy_into_tcp(
+    stream: ProxyStream<TcpStream>,
+) -> Result<(TcpStream, Bytes), ProxyStream<TcpStream>> {
+    match stream {
+        ProxyStream::Tcp(stream) => Ok((stream, Bytes::new())),
+        #[allow(unreachable_patterns)]
+        stream => Err(stream),
+    }
+}
+
+/// The bytes which hyper reads ahead are replayed before the ones left in
+/// the `Rewind`.
+fn upgraded_into_tcp(upgraded: Upgraded) -> Result<(TcpStream, Bytes), Upgraded> {
+    let upgraded = match upgraded.downcast::<TcpStream>() {
+        Ok(parts) => return Ok((parts.io, parts.read_buf)),
+        Err(upgraded) => upgraded,
+    };
+    let parts = upgraded.downcast::<Rewind<TcpStream>>()?;
+    let (stream, pre) = parts.io.into_inner();
+    if parts.read_buf.is_empty() {
+        return Ok((stream, pre));
+    }
+    let mut buffered = BytesMut::with_capacity(parts.read_buf.len() + pre.len());
+    buffered.extend_from_slice(&parts.read_buf);
+    buffered.extend_from_slice(&pre);
+    Ok((stream, buffered.freeze()))
+}
+
+/// Moves the value out if it is of the type `U`, otherwise returns it back.
+fn downcast<T: 'static, U: 'static>(value: T) -> Result<U, T> {
+    let mut value = Some(value);
+    match (&mut value as &mut dyn Any).downcast_mut::<Option<U>>() {
+        Some(u) => Ok(u.take().expect("the value is taken once")),
+        None => Err(value.expect("the value is not taken")),
+    }
+}
+
+/// Moves the value back to the type it is downcast from.
+fn upcast<U: 'static, T: 'static>(value: U) -> T {
+    match downcast(value) {
+        Ok(value) => value,
+        Err(_) => unreachable!("the value is downcast from the type"),
+    }
+}
@@ -1,22 +1,49 @@
 use std::{
+    collections::HashMap,
+    fmt,
     future::Future,
     io,
+    net::{IpAddr, SocketAddr},
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
+    time::Duration,
 };
 
-use futures::TryFutureExt;
+use futures::{future::BoxFuture, TryFutureExt};
 use log::{debug, error};
 use proxy::Service;
-use proxy_rules::{Decision, Policy};
+use proxy_rules::{AccessControl, Decision, Policy};
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadBuf},
-    net::{lookup_host, TcpStream},
+    net::TcpStream,
 };
 #[cfg(feature = "tokio-native-tls")]
 use tokio_native_tls::TlsStream;
 
-use crate::TargetAddr;
+use crate::{
+    connect_happy_eyeballs, with_timeout, ConnectRequest, DnsCache, FakeIp, Hosts, Resolver,
+    TargetAddr, CONNECTION_ATTEMPT_DELAY, CONNECT_TIMEOUT, TLS_HANDSHAKE_TIMEOUT,
+};
+
+/// A stream which is backed by a socket and knows the addresses of both ends.
+pub trait SocketStream {
+    /// Returns the local address that this stream is bound to.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Returns the remote address that this stream is connected to.
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl SocketStream for TcpStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+}
 
 #[derive(Debug)]
 pub enum ProxyStream<S> {
@@ -95,6 +122,27 @@ where
     }
 }
 
+impl<S> SocketStream for ProxyStream<S>
+where
+    S: SocketStream + AsyncRead + AsyncWrite + Unpin,
+{
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            ProxyStream::Tcp(s) => s.local_addr(),
+            #[cfg(feature = "tokio-native-tls")]
+            ProxyStream::Tls(s) => s.get_ref().get_ref().get_ref().local_addr(),
+        }
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            ProxyStream::Tcp(s) => s.peer_addr(),
+            #[cfg(feature = "tokio-native-tls")]
+            ProxyStream::Tls(s) => s.get_ref().get_ref().get_ref().peer_addr(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Connection<S> {
     Proxy(ProxyStream<S>),
@@ -165,14 +213,51 @@ where
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct TokioConnect {
-    // TODO: provide a dns resolver to resolve the domain
+    resolver: Arc<dyn Resolver>,
+    delay: Duration,
+    timeout: Duration,
 }
 
 impl TokioConnect {
     pub fn new() -> Self {
-        TokioConnect {}
+        TokioConnect {
+            resolver: Arc::new(DnsCache::system()),
+            delay: CONNECTION_ATTEMPT_DELAY,
+            timeout: CONNECT_TIMEOUT,
+        }
+    }
+
+    /// Sets the time to resolve and connect the host, which fails with
+    /// `TimedOut` once it elapses.
+    pub fn set_connect_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout
+    }
+
+    /// Sets the delay between the attempts to connect the addresses of the
+    /// host, see [`connect_happy_eyeballs`].
+    pub fn set_happy_eyeballs_delay(&mut self, delay: Duration) {
+        self.delay = delay
+    }
+
+    /// Sets the resolver to resolve the domain, which is the cached system
+    /// resolver by default.
+    pub fn set_resolver<R: Resolver + 'static>(&mut self, resolver: R) {
+        self.resolver = Arc::new(resolver)
+    }
+
+    pub fn resolver(&self) -> &Arc<dyn Resolver> {
+        &self.resolver
+    }
+}
+
+impl fmt::Debug for TokioConnect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokioConnect")
+            .field("delay", &self.delay)
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
     }
 }
 
@@ -191,25 +276,41 @@ impl Service<TargetAddr> for TokioConnect {
 
     fn call(&mut self, target: TargetAddr) -> Self::Future<'_> {
         Box::pin(async move {
-            match &target {
-                TargetAddr::SocketAddr(addr) => TcpStream::connect(addr).await,
-                TargetAddr::Domain(d, p) => {
-                    debug!("resolve the ip for {}:{} with native dns", d, p);
-                    let addr = lookup_host((&d[..], *p)).await?.next().ok_or_else(|| {
-                        error!("unable to resolve dns for {}:{}", d, p);
-                        Into::<io::Error>::into(io::ErrorKind::HostUnreachable)
-                    })?;
-                    TcpStream::connect(addr).await
-                }
-            }
+            let connect = async {
+                let addrs = target.resolve_all(&self.resolver).await?;
+                connect_happy_eyeballs(addrs, self.delay).await
+            };
+            with_timeout(self.timeout, || format!("connect {}", target), connect)
+                .await
+                .inspect_err(|e| debug!("unable to connect {}, {}", &target, e))
         })
     }
 }
 
+impl Service<ConnectRequest> for TokioConnect {
+    type Response = TcpStream;
+
+    type Error = io::Error;
+
+    type Future<'a>
+        = <Self as Service<TargetAddr>>::Future<'a>
+    where
+        Self: 'a;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Service::<TargetAddr>::poll_ready(self, cx)
+    }
+
+    fn call(&mut self, req: ConnectRequest) -> Self::Future<'_> {
+        Service::<TargetAddr>::call(self, req.target)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct StreamConnect<C> {
     connect: C,
     tls: bool,
+    tls_timeout: Duration,
     target: TargetAddr,
 }
 
@@ -218,6 +319,7 @@ impl<C> StreamConnect<C> {
         Self {
             connect,
             tls: false,
+            tls_timeout: TLS_HANDSHAKE_TIMEOUT,
             target,
         }
     }
@@ -226,9 +328,19 @@ impl<C> StreamConnect<C> {
         self.tls = tls
     }
 
+    /// Sets the time of the TLS handshake, which fails with `TimedOut` once
+    /// it elapses.
+    pub fn set_tls_timeout(&mut self, timeout: Duration) {
+        self.tls_timeout = timeout
+    }
+
     pub fn set_target(&mut self, target: TargetAddr) {
         self.target = target
     }
+
+    pub fn target(&self) -> &TargetAddr {
+        &self.target
+    }
 }
 
 impl<C> Service<()> for StreamConnect<C>
@@ -252,6 +364,8 @@ where
     fn call(&mut self, _req: ()) -> Self::Future<'_> {
         let future = self.connect.call(self.target.clone());
         let tls = self.tls;
+        #[cfg(feature = "tokio-native-tls")]
+        let tls_timeout = self.tls_timeout;
         let target = self.target.clone();
         Box::pin(async move {
             let stream = future.await.map_err(Into::into)?;
@@ -261,16 +375,25 @@ where
                     #[cfg(feature = "tokio-native-tls")]
                     {
                         match tokio_native_tls::native_tls::TlsConnector::builder().build() {
-                            Ok(cx) => match tokio_native_tls::TlsConnector::from(cx)
-                                .connect(host, stream)
+                            Ok(cx) => {
+                                let connector = tokio_native_tls::TlsConnector::from(cx);
+                                let handshake = connector
+                                    .connect(host, stream)
+                                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+                                match with_timeout(
+                                    tls_timeout,
+                                    || format!("tls handshake with {}", host),
+                                    handshake,
+                                )
                                 .await
-                            {
-                                Ok(s) => Ok(ProxyStream::Tls(s)),
-                                Err(e) => {
-                                    error!("unable to connect {} with tls, {}", host, &e);
-                                    Err(io::Error::new(io::ErrorKind::Other, e))
+                                {
+                                    Ok(s) => Ok(ProxyStream::Tls(s)),
+                                    Err(e) => {
+                                        error!("unable to connect {} with tls, {}", host, &e);
+                                        Err(e)
+                                    }
                                 }
-                            },
+                            }
                             Err(e) => {
                                 error!("unable to initialize TlsConnector, {}", &e);
                                 Err(io::Error::new(io::ErrorKind::Other, e))
@@ -290,21 +413,52 @@ where
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct ProxyConnect<C, PC, P> {
     connect: C,
     proxy_connect: PC,
     policy: Option<P>,
+    access: Option<Arc<AccessControl>>,
+    upstreams: Arc<HashMap<String, PC>>,
+    hosts: Option<Arc<Hosts>>,
+    fake_ip: Option<FakeIp>,
+    resolver: Arc<dyn Resolver>,
     force_proxy: bool,
     defaut_proxy: bool,
 }
 
+impl<C, PC, P> fmt::Debug for ProxyConnect<C, PC, P>
+where
+    C: fmt::Debug,
+    PC: fmt::Debug,
+    P: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyConnect")
+            .field("connect", &self.connect)
+            .field("proxy_connect", &self.proxy_connect)
+            .field("policy", &self.policy)
+            .field("access", &self.access)
+            .field("upstreams", &self.upstreams)
+            .field("hosts", &self.hosts)
+            .field("fake_ip", &self.fake_ip)
+            .field("force_proxy", &self.force_proxy)
+            .field("defaut_proxy", &self.defaut_proxy)
+            .finish_non_exhaustive()
+    }
+}
+
 impl<C, PC, P> ProxyConnect<C, PC, P> {
     pub fn new(connect: C, proxy_connect: PC) -> Self {
         Self {
             connect,
             proxy_connect,
             policy: None,
+            access: None,
+            upstreams: Arc::new(HashMap::new()),
+            hosts: None,
+            fake_ip: None,
+            resolver: Arc::new(DnsCache::system()),
             force_proxy: false,
             defaut_proxy: false,
         }
@@ -321,16 +475,200 @@ impl<C, PC, P> ProxyConnect<C, PC, P> {
     pub fn set_default_proxy(&mut self, default_proxy: bool) {
         self.defaut_proxy = default_proxy
     }
+
+    /// Sets the access control lists which are enforced for the
+    /// authenticated users before the policy.
+    pub fn set_access_control(&mut self, access: AccessControl) {
+        self.access = Some(Arc::new(access))
+    }
+
+    /// Sets the static mapping of the hosts, which rewrites the target before
+    /// the access control lists and the policy.
+    pub fn set_hosts(&mut self, hosts: Hosts) {
+        self.hosts = Some(Arc::new(hosts))
+    }
+
+    /// Sets the fake addresses answered by the fake DNS, which are
+    /// translated back to the domains before the hosts and the rules.
+    pub fn set_fake_ip(&mut self, fake_ip: FakeIp) {
+        self.fake_ip = Some(fake_ip)
+    }
+
+    /// Sets the resolver to resolve the domain locally, so that the IP
+    /// rules can match it and the proxy without `force-remote-dns` gets the
+    /// address. It is the cached system resolver by default.
+    pub fn set_resolver<R: Resolver + 'static>(&mut self, resolver: R) {
+        self.resolver = Arc::new(resolver)
+    }
 }
 
-impl<S, C, PC, P> Service<TargetAddr> for ProxyConnect<C, PC, P>
+impl<C, PC: Clone, P> ProxyConnect<C, PC, P> {
+    /// Adds the named upstream proxy which the access control lists refer to.
+    pub fn add_upstream(&mut self, name: String, proxy_connect: PC) {
+        Arc::make_mut(&mut self.upstreams).insert(name, proxy_connect);
+    }
+}
+
+/// The route decided for the request, before it is connected.
+struct Decided<PC> {
+    /// The target which is translated from the fake IP and by the hosts.
+    target: TargetAddr,
+    decision: Decision,
+    proxy: bool,
+    /// The upstream proxy of the access control list.
+    upstream: Option<PC>,
+    /// The addresses of the domain if it is resolved for the IP rules.
+    addrs: Option<Vec<SocketAddr>>,
+}
+
+impl<C, PC: Clone, P: Policy> ProxyConnect<C, PC, P> {
+    /// Decides how the target of the request is reached by the fake IP, the
+    /// hosts, the access control lists and the policy.
+    async fn decide(&self, req: &ConnectRequest) -> io::Result<Decided<PC>> {
+        let target = match self.fake_ip.as_ref().and_then(|f| f.rewrite(&req.target)) {
+            Some(Ok(target)) => {
+                debug!("translate the fake ip {} to {}", &req.target, &target);
+                target
+            }
+            // The fake ip is unknown if it is evicted, or answered before
+            // the restart without persistence.
+            Some(Err(e)) => return Err(e),
+            None => req.target.clone(),
+        };
+        let target = match self.hosts.as_ref().and_then(|h| h.rewrite(&target)) {
+            Some(rewritten) => {
+                debug!("rewrite {} to {} by the hosts", &target, &rewritten);
+                rewritten
+            }
+            None => target,
+        };
+        let dst = target.to_string();
+        let mut addrs = None;
+        let access = match (&self.access, &req.identity) {
+            (Some(access), Some(identity)) => {
+                let (username, groups) = (&identity.username, &identity.groups);
+                if access.needs_resolve(username, groups, &dst) {
+                    // Resolves the domain for the IP rules of the lists,
+                    // and fails rather than lets the domain through
+                    // unchecked.
+                    let resolved = target.resolve_all(&self.resolver).await?;
+                    let ips: Vec<IpAddr> = resolved.iter().map(|a| a.ip()).collect();
+                    addrs = Some(resolved);
+                    access.enforce_resolved(username, groups, &dst, &ips)
+                } else {
+                    access.enforce(username, groups, &dst)
+                }
+            }
+            _ => None,
+        };
+        // The upstream proxy of the access control list is used instead of
+        // the default one.
+        let upstream = access
+            .and_then(|(_, proxy)| proxy)
+            .and_then(|proxy| self.upstreams.get(proxy).cloned());
+        let access = access.map(|(decision, _)| decision);
+
+        let decision = match (access, &self.policy) {
+            // DIRECT of the access control lists never bypasses the forced
+            // proxy, while DENY and the upstream proxies of them still apply.
+            (Some(Decision::Direct), _) if self.force_proxy => Decision::Proxy { remote_dns: true },
+            (Some(decision), _) if !decision.is_default() => decision,
+            _ if self.force_proxy => Decision::Proxy { remote_dns: true },
+            (_, Some(p)) if p.needs_resolve(&dst) => {
+                // Resolves the domain for the IP rules, unless the lists
+                // have resolved it.
+                let resolved = match addrs.take() {
+                    Some(resolved) => Ok(resolved),
+                    None => target.resolve_all(&self.resolver).await,
+                };
+                match resolved {
+                    Ok(resolved) => {
+                        let ips: Vec<IpAddr> = resolved.iter().map(|a| a.ip()).collect();
+                        addrs = Some(resolved);
+                        p.enforce_resolved(&dst, &ips)
+                    }
+                    Err(e) => {
+                        debug!("unable to resolve {} for the rules, {}", &target, e);
+                        p.enforce(&dst)
+                    }
+                }
+            }
+            (_, Some(p)) => p.enforce(&dst),
+            (_, None) => Decision::Default,
+        };
+        let proxy = match decision {
+            Decision::Direct => false,
+            Decision::Proxy { .. } => true,
+            Decision::Default => self.defaut_proxy,
+            Decision::Deny => {
+                debug!("deny connect {}", req);
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{} is denied by the rules", target),
+                ));
+            }
+        };
+        Ok(Decided {
+            target,
+            decision,
+            proxy,
+            upstream,
+            addrs,
+        })
+    }
+}
+
+/// How the target of the request is reached.
+#[derive(Debug, Clone)]
+pub enum Route {
+    /// Reaches the target directly.
+    Direct(TargetAddr),
+    /// Reaches the target through the proxy.
+    Proxy(TargetAddr),
+}
+
+/// Decides the route of the request without connecting it, so that the
+/// relays which are not connected by the connector, like the BIND and UDP
+/// ASSOCIATE of SOCKS, are enforced by the same rules.
+pub trait Router: Send + Sync {
+    /// Returns the route to the target which is translated from the fake IP
+    /// and by the hosts, or `PermissionDenied` if the request is denied.
+    fn route<'a>(&'a self, req: &'a ConnectRequest) -> BoxFuture<'a, io::Result<Route>>;
+
+    /// Resolves the target of the direct route.
+    fn resolve<'a>(&'a self, target: &'a TargetAddr) -> BoxFuture<'a, io::Result<SocketAddr>>;
+}
+
+impl<C, PC, P> Router for ProxyConnect<C, PC, P>
+where
+    C: Send + Sync,
+    PC: Clone + Send + Sync,
+    P: Policy + Send + Sync,
+{
+    fn route<'a>(&'a self, req: &'a ConnectRequest) -> BoxFuture<'a, io::Result<Route>> {
+        Box::pin(async move {
+            let decided = self.decide(req).await?;
+            Ok(if decided.proxy {
+                Route::Proxy(decided.target)
+            } else {
+                Route::Direct(decided.target)
+            })
+        })
+    }
+
+    fn resolve<'a>(&'a self, target: &'a TargetAddr) -> BoxFuture<'a, io::Result<SocketAddr>> {
+        Box::pin(target.resolve(&self.resolver))
+    }
+}
+
+impl<S, C, PC, P> Service<ConnectRequest> for ProxyConnect<C, PC, P>
 where
     S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
-    C: Service<TargetAddr, Response = S> + Send + 'static,
+    C: Service<TargetAddr, Response = S> + Send + Sync + 'static,
     C::Error: Into<io::Error> + Send,
-    PC: Service<TargetAddr, Response = ProxyStream<S>> + Send + 'static,
+    PC: Service<TargetAddr, Response = ProxyStream<S>> + Clone + Send + Sync + 'static,
     PC::Error: Into<io::Error> + Send,
-    P: Policy + Send,
+    P: Policy + Send + Sync,
 {
     type Response = Connection<S>;
 
@@ -348,40 +686,65 @@ where
         }
     }
 
-    fn call(&mut self, target: TargetAddr) -> Self::Future<'_> {
-        let decision = if self.force_proxy {
-            Decision::Proxy { remote_dns: true }
-        } else if let Some(p) = &self.policy {
-            p.enforce(&target.to_string())
-        } else {
-            Decision::Default
-        };
-
+    fn call(&mut self, req: ConnectRequest) -> Self::Future<'_> {
         Box::pin(async move {
-            match decision {
-                Decision::Direct => {
-                    debug!("direct connect {}", &target);
-                    let future = self.connect.call(target).map_err(Into::into);
-                    Ok(Connection::Direct(future.await?))
-                }
-                Decision::Proxy { .. } => {
-                    debug!("proxy connect {}", &target);
-                    let future = self.proxy_connect.call(target).map_err(Into::into);
-                    Ok(Connection::Proxy(future.await?))
-                }
-                Decision::Default => {
-                    if self.defaut_proxy {
-                        debug!("proxy connect {}", &target);
-                        let future = self.proxy_connect.call(target).map_err(Into::into);
-                        Ok(Connection::Proxy(future.await?))
-                    } else {
-                        debug!("direct connect {}", &target);
-                        let future = self.connect.call(target).map_err(Into::into);
-                        Ok(Connection::Direct(future.await?))
+            let Decided {
+                target,
+                decision,
+                proxy,
+                mut upstream,
+                addrs,
+            } = self.decide(&req).await?;
+            if proxy {
+                // The domain is resolved locally unless the proxy is forced
+                // to resolve it.
+                let target = match (decision, addrs) {
+                    (Decision::Proxy { remote_dns: false }, Some(addrs)) => {
+                        TargetAddr::SocketAddr(addrs[0])
                     }
-                }
-                Decision::Deny => Err(io::ErrorKind::HostUnreachable.into()),
+                    (Decision::Proxy { remote_dns: false }, None) => {
+                        TargetAddr::SocketAddr(target.resolve(&self.resolver).await?)
+                    }
+                    _ => target,
+                };
+                debug!("proxy connect {} by {}", &req, &target);
+                let future = match &mut upstream {
+                    Some(upstream) => upstream.call(target),
+                    None => self.proxy_connect.call(target),
+                };
+                Ok(Connection::Proxy(future.map_err(Into::into).await?))
+            } else {
+                debug!("direct connect {}", &req);
+                let future = self.connect.call(target).map_err(Into::into);
+                Ok(Connection::Direct(future.await?))
             }
         })
     }
 }
+
+impl<S, C, PC, P> Service<TargetAddr> for ProxyConnect<C, PC, P>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    C: Service<TargetAddr, Response = S> + Send + Sync + 'static,
+    C::Error: Into<io::Error> + Send,
+    PC: Service<TargetAddr, Response = ProxyStream<S>> + Clone + Send + Sync + 'static,
+    PC::Error: Into<io::Error> + Send,
+    P: Policy + Send + Sync,
+{
+    type Response = Connection<S>;
+
+    type Error = io::Error;
+
+    type Future<'a>
+        = <Self as Service<ConnectRequest>>::Future<'a>
+    where
+        Self: 'a;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Service::<ConnectRequest>::poll_ready(self, cx)
+    }
+
+    fn call(&mut self, target: TargetAddr) -> Self::Future<'_> {
+        Service::<ConnectRequest>::call(self, target.into())
+    }
+}
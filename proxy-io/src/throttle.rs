@@ -0,0 +1,410 @@
+use std::{
+    cmp,
+    collections::HashMap,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use pin_project_lite::pin_project;
+use proxy_auth::Identity;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+/// The smallest burst of the bucket, so that the slow rates still copy the
+/// bytes in reasonable chunks.
+const MIN_BURST: u64 = 16 * 1024;
+
+/// The bytes to wait for before the IO is resumed, unless it wants fewer.
+const MIN_CHUNK: u64 = 4 * 1024;
+
+/// The rates in bytes per second, which are unlimited if `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Bandwidth {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+}
+
+/// A token bucket whose rate is changeable at runtime, the clones share the
+/// same bucket.
+///
+/// The bucket holds the bytes of one second at most, which is the burst
+/// after the bucket is idle.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    rate: Arc<AtomicU64>,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Default for Bucket {
+    fn default() -> Self {
+        Bucket {
+            tokens: 0.0,
+            updated: Instant::now(),
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        let limiter = RateLimiter::default();
+        limiter.set_rate(rate);
+        limiter.bucket.lock().unwrap().tokens = burst(limiter.rate.load(Ordering::Relaxed));
+        limiter
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        match self.rate.load(Ordering::Relaxed) {
+            0 => None,
+            rate => Some(rate),
+        }
+    }
+
+    /// Changes the rate, which applies to the streams being throttled.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        self.rate.store(rate.unwrap_or(0), Ordering::Relaxed)
+    }
+
+    /// Returns a limiter with its own bucket, which follows the rate of this
+    /// one.
+    pub fn fork(&self) -> Self {
+        RateLimiter {
+            rate: self.rate.clone(),
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: burst(self.rate.load(Ordering::Relaxed)),
+                updated: Instant::now(),
+            })),
+        }
+    }
+
+    /// Returns how many of the `want` bytes are available now, or how long
+    /// to wait for them.
+    fn available(&self, want: usize) -> Result<usize, Duration> {
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate == 0 {
+            return Ok(want);
+        }
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = f64::min(bucket.tokens + elapsed * rate as f64, burst(rate));
+        bucket.updated = now;
+
+        let need = cmp::min(want as u64, cmp::min(MIN_CHUNK, rate)) as f64;
+        if bucket.tokens >= need {
+            Ok(cmp::min(want, bucket.tokens as usize))
+        } else {
+            Err(Duration::from_secs_f64(
+                (need - bucket.tokens) / rate as f64,
+            ))
+        }
+    }
+
+    /// Takes the bytes from the bucket, which may go into debt if the bucket
+    /// is shared by the other streams.
+    fn consume(&self, n: usize) {
+        if n > 0 && self.rate.load(Ordering::Relaxed) != 0 {
+            self.bucket.lock().unwrap().tokens -= n as f64;
+        }
+    }
+}
+
+fn burst(rate: u64) -> f64 {
+    cmp::max(rate, MIN_BURST) as f64
+}
+
+/// Returns the bytes available in all of the limiters, or the longest time
+/// to wait.
+fn acquire(limiters: &[RateLimiter], want: usize) -> Result<usize, Duration> {
+    let mut granted = want;
+    let mut wait = Duration::ZERO;
+    for limiter in limiters {
+        match limiter.available(want) {
+            Ok(n) => granted = cmp::min(granted, n),
+            Err(d) => wait = cmp::max(wait, d),
+        }
+    }
+    if wait.is_zero() {
+        Ok(granted)
+    } else {
+        Err(wait)
+    }
+}
+
+/// The limiters of both directions.
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthLimiter {
+    upload: RateLimiter,
+    download: RateLimiter,
+}
+
+impl BandwidthLimiter {
+    pub fn new(bandwidth: Bandwidth) -> Self {
+        BandwidthLimiter {
+            upload: RateLimiter::new(bandwidth.upload),
+            download: RateLimiter::new(bandwidth.download),
+        }
+    }
+
+    pub fn bandwidth(&self) -> Bandwidth {
+        Bandwidth {
+            upload: self.upload.rate(),
+            download: self.download.rate(),
+        }
+    }
+
+    pub fn set_bandwidth(&self, bandwidth: Bandwidth) {
+        self.upload.set_rate(bandwidth.upload);
+        self.download.set_rate(bandwidth.download);
+    }
+
+    fn fork(&self) -> Self {
+        BandwidthLimiter {
+            upload: self.upload.fork(),
+            download: self.download.fork(),
+        }
+    }
+}
+
+/// The bandwidth of the users, each user shares one limiter by all of its
+/// connections.
+#[derive(Debug, Default)]
+struct Users {
+    default: Bandwidth,
+    bandwidths: HashMap<String, Bandwidth>,
+    limiters: HashMap<String, BandwidthLimiter>,
+}
+
+impl Users {
+    fn bandwidth(&self, username: &str) -> Bandwidth {
+        self.bandwidths
+            .get(username)
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    fn update(&self) {
+        for (username, limiter) in &self.limiters {
+            limiter.set_bandwidth(self.bandwidth(username));
+        }
+    }
+}
+
+/// Throttles the relayed connections of a listener, by the bandwidth of
+/// each connection, the listener, the authenticated user and all of the
+/// listeners.
+///
+/// All of the limits are changeable at runtime, which apply to the
+/// connections being relayed.
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    global: BandwidthLimiter,
+    listener: BandwidthLimiter,
+    connection: BandwidthLimiter,
+    users: Arc<Mutex<Users>>,
+}
+
+impl Throttle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the throttle of another listener, which shares the global
+    /// and the user limits with this one.
+    pub fn new_listener(&self) -> Self {
+        Throttle {
+            global: self.global.clone(),
+            listener: BandwidthLimiter::default(),
+            connection: BandwidthLimiter::default(),
+            users: self.users.clone(),
+        }
+    }
+
+    /// Returns the limiter of all the listeners.
+    pub fn global(&self) -> &BandwidthLimiter {
+        &self.global
+    }
+
+    pub fn listener(&self) -> &BandwidthLimiter {
+        &self.listener
+    }
+
+    /// Returns the limiter which each connection follows the rates of.
+    pub fn connection(&self) -> &BandwidthLimiter {
+        &self.connection
+    }
+
+    /// Sets the bandwidth of each user which is not set by `set_users`.
+    pub fn set_user_default(&self, bandwidth: Bandwidth) {
+        let mut users = self.users.lock().unwrap();
+        users.default = bandwidth;
+        users.update();
+    }
+
+    /// Replaces the bandwidth of the users.
+    pub fn set_users(&self, bandwidths: HashMap<String, Bandwidth>) {
+        let mut users = self.users.lock().unwrap();
+        users.bandwidths = bandwidths;
+        users.update();
+    }
+
+    /// Throttles the stream of the client, the reads are the upload and the
+    /// writes are the download.
+    pub fn wrap<S>(&self, stream: S, identity: Option<&Identity>) -> Throttled<S> {
+        let limiters = self.limiters(identity);
+        Throttled::new(
+            stream,
+            limiters.iter().map(|l| l.upload.clone()).collect(),
+            limiters.into_iter().map(|l| l.download).collect(),
+        )
+    }
+
+    /// Throttles the stream to the remote for the client, the reads are the
+    /// download and the writes are the upload, which is for the requests
+    /// forwarded rather than relayed.
+    pub fn wrap_remote<S>(&self, stream: S, identity: Option<&Identity>) -> Throttled<S> {
+        let limiters = self.limiters(identity);
+        Throttled::new(
+            stream,
+            limiters.iter().map(|l| l.download.clone()).collect(),
+            limiters.into_iter().map(|l| l.upload).collect(),
+        )
+    }
+
+    /// Returns the limiters of a new connection of the user.
+    fn limiters(&self, identity: Option<&Identity>) -> Vec<BandwidthLimiter> {
+        let mut limiters = vec![
+            self.connection.fork(),
+            self.listener.clone(),
+            self.global.clone(),
+        ];
+        if let Some(identity) = identity {
+            let mut users = self.users.lock().unwrap();
+            let bandwidth = users.bandwidth(&identity.username);
+            let limiter = users
+                .limiters
+                .entry(identity.username.clone())
+                .or_insert_with(|| BandwidthLimiter::new(bandwidth));
+            limiters.push(limiter.clone());
+        }
+        limiters
+    }
+}
+
+pin_project! {
+    /// A stream whose reads and writes are limited by the token buckets.
+    #[derive(Debug)]
+    pub struct Throttled<S> {
+        #[pin]
+        inner: S,
+        read: Vec<RateLimiter>,
+        write: Vec<RateLimiter>,
+        read_delay: Option<Pin<Box<Sleep>>>,
+        write_delay: Option<Pin<Box<Sleep>>>,
+    }
+}
+
+impl<S> Throttled<S> {
+    fn new(inner: S, read: Vec<RateLimiter>, write: Vec<RateLimiter>) -> Self {
+        Throttled {
+            inner,
+            read,
+            write,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+/// Waits until the limiters have any of the `want` bytes.
+fn poll_acquire(
+    limiters: &[RateLimiter],
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    want: usize,
+    cx: &mut Context<'_>,
+) -> Poll<usize> {
+    loop {
+        if let Some(sleep) = delay {
+            ready!(sleep.as_mut().poll(cx));
+            *delay = None;
+        }
+        match acquire(limiters, want) {
+            Ok(n) => return Poll::Ready(n),
+            Err(wait) => *delay = Some(Box::pin(tokio::time::sleep(wait))),
+        }
+    }
+}
+
+impl<S> AsyncRead for Throttled<S>
+where
+    S: AsyncRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let n = ready!(poll_acquire(
+            this.read,
+            this.read_delay,
+            buf.remaining(),
+            cx
+        ));
+        let mut limited = buf.take(n);
+        ready!(this.inner.poll_read(cx, &mut limited))?;
+        let filled = limited.filled().len();
+        // Safety: the bytes are initialized by the inner stream.
+        unsafe { buf.assume_init(filled) };
+        buf.advance(filled);
+        this.read.iter().for_each(|l| l.consume(filled));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for Throttled<S>
+where
+    S: AsyncWrite,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.project();
+        let n = ready!(poll_acquire(this.write, this.write_delay, buf.len(), cx));
+        let written = ready!(this.inner.poll_write(cx, &buf[..n]))?;
+        this.write.iter().for_each(|l| l.consume(written));
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
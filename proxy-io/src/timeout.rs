@@ -0,0 +1,34 @@
+use std::{future::Future, io, time::Duration};
+
+/// The default time to connect the host, including resolving it.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The default time of the TLS handshake with the proxy server.
+pub const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The default time of the SOCKS or HTTP CONNECT handshake with the proxy
+/// server.
+pub const PROXY_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The default time for the client to send its SOCKS handshake or HTTP
+/// request head.
+pub const CLIENT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Awaits the future, failing with `TimedOut` if it does not complete in
+/// time.
+///
+/// The error tells what timed out, such as `connect example.com:443`, which
+/// is only formatted once it times out.
+pub async fn with_timeout<F, T, W>(duration: Duration, what: W, future: F) -> io::Result<T>
+where
+    F: Future<Output = io::Result<T>>,
+    W: FnOnce() -> String,
+{
+    match tokio::time::timeout(duration, future).await {
+        Ok(res) => res,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{} timed out after {:?}", what(), duration),
+        )),
+    }
+}
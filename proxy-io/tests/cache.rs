@@ -0,0 +1,145 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr},
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+use proxy_io::{DnsCache, Lookup, Resolver};
+
+/// Resolves `*.test` to 10.0.0.1 with the TTL, and the others to nothing,
+/// counting the lookups.
+#[derive(Clone)]
+struct StubResolver {
+    ttl: Option<Duration>,
+    lookups: Arc<AtomicUsize>,
+}
+
+impl StubResolver {
+    fn new(ttl: Option<Duration>) -> Self {
+        StubResolver {
+            ttl,
+            lookups: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn lookups(&self) -> usize {
+        self.lookups.load(Ordering::SeqCst)
+    }
+}
+
+impl Resolver for StubResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        Box::pin(async move { Ok(self.lookup(host).await?.ips) })
+    }
+
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Lookup>> {
+        self.lookups.fetch_add(1, Ordering::SeqCst);
+        let ips = if host.ends_with(".test") {
+            vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]
+        } else {
+            vec![]
+        };
+        Box::pin(futures::future::ready(Ok(Lookup { ips, ttl: self.ttl })))
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn cache_until_expired() {
+    let stub = StubResolver::new(Some(Duration::from_secs(30)));
+    let cache = DnsCache::new(stub.clone());
+
+    assert_eq!(cache.resolve("example.test").await.unwrap().len(), 1);
+    assert_eq!(cache.resolve("Example.Test.").await.unwrap().len(), 1);
+    assert_eq!(stub.lookups(), 1);
+
+    tokio::time::advance(Duration::from_secs(31)).await;
+    cache.resolve("example.test").await.unwrap();
+    assert_eq!(stub.lookups(), 2);
+
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 1));
+}
+
+#[tokio::test(start_paused = true)]
+async fn clamp_ttl() {
+    let stub = StubResolver::new(Some(Duration::from_secs(1)));
+    let mut cache = DnsCache::new(stub.clone());
+    cache.set_min_ttl(Duration::from_secs(10));
+
+    let lookup = cache.lookup("example.test").await.unwrap();
+    assert_eq!(lookup.ttl, Some(Duration::from_secs(10)));
+    tokio::time::advance(Duration::from_secs(5)).await;
+    cache.resolve("example.test").await.unwrap();
+    assert_eq!(stub.lookups(), 1);
+
+    let stub = StubResolver::new(Some(Duration::from_secs(86400)));
+    let mut cache = DnsCache::new(stub.clone());
+    cache.set_max_ttl(Duration::from_secs(60));
+    let lookup = cache.lookup("example.test").await.unwrap();
+    assert_eq!(lookup.ttl, Some(Duration::from_secs(60)));
+}
+
+#[tokio::test(start_paused = true)]
+async fn cache_negative() {
+    let stub = StubResolver::new(Some(Duration::from_secs(300)));
+    let mut cache = DnsCache::new(stub.clone());
+    cache.set_negative_ttl(Duration::from_secs(5));
+
+    assert!(cache.resolve("missing.invalid").await.unwrap().is_empty());
+    assert!(cache.resolve("missing.invalid").await.unwrap().is_empty());
+    assert_eq!(stub.lookups(), 1);
+    assert_eq!(cache.stats().negative_hits, 1);
+
+    tokio::time::advance(Duration::from_secs(6)).await;
+    cache.resolve("missing.invalid").await.unwrap();
+    assert_eq!(stub.lookups(), 2);
+}
+
+#[tokio::test(start_paused = true)]
+async fn prefetch_hot_host() {
+    let stub = StubResolver::new(Some(Duration::from_secs(100)));
+    let cache = DnsCache::new(stub.clone());
+
+    cache.resolve("example.test").await.unwrap();
+    for _ in 0..3 {
+        cache.resolve("example.test").await.unwrap();
+    }
+    // Not prefetched until the last tenth of the TTL.
+    assert_eq!(stub.lookups(), 1);
+
+    tokio::time::advance(Duration::from_secs(95)).await;
+    cache.resolve("example.test").await.unwrap();
+    tokio::task::yield_now().await;
+    assert_eq!(stub.lookups(), 2);
+    assert_eq!(cache.stats().prefetches, 1);
+
+    // The prefetched entry lives for another TTL.
+    tokio::time::advance(Duration::from_secs(50)).await;
+    cache.resolve("example.test").await.unwrap();
+    assert_eq!(stub.lookups(), 2);
+    assert_eq!(cache.stats().misses, 1);
+}
+
+#[tokio::test]
+async fn evict_least_recently_used() {
+    let stub = StubResolver::new(None);
+    let mut cache = DnsCache::new(stub.clone());
+    cache.set_capacity(NonZeroUsize::new(2).unwrap());
+
+    cache.resolve("a.test").await.unwrap();
+    cache.resolve("b.test").await.unwrap();
+    cache.resolve("a.test").await.unwrap();
+    cache.resolve("c.test").await.unwrap();
+    assert_eq!(cache.stats().entries, 2);
+
+    cache.resolve("a.test").await.unwrap();
+    assert_eq!(stub.lookups(), 3);
+    cache.resolve("b.test").await.unwrap();
+    assert_eq!(stub.lookups(), 4);
+}
@@ -0,0 +1,194 @@
+use std::{io, time::Duration};
+
+use proxy_io::{
+    BufferPool, Duplex, DuplexCounters, DuplexStats, Expired, Side, MAX_BUFFER_SIZE,
+    MIN_BUFFER_SIZE,
+};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream},
+    time::Instant,
+};
+
+/// Relays between two in-memory pipes, returning the ends of the client and
+/// the server.
+fn relay<F>(
+    setup: F,
+) -> (
+    DuplexStream,
+    DuplexStream,
+    tokio::task::JoinHandle<io::Result<DuplexStats>>,
+)
+where
+    F: FnOnce(&mut Duplex<DuplexStream, DuplexStream>),
+{
+    let (client, relay_in) = duplex(1024);
+    let (relay_out, server) = duplex(1024);
+    let mut duplex = Duplex::new(relay_in, relay_out);
+    setup(&mut duplex);
+    (client, server, tokio::spawn(duplex))
+}
+
+/// Reads until the peer shuts down, returning the bytes read.
+async fn read_to_end(stream: &mut DuplexStream) -> Vec<u8> {
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).await.unwrap();
+    buf
+}
+
+#[tokio::test(start_paused = true)]
+async fn close_idle_relay() {
+    let (mut client, mut server, relay) = relay(|d| d.set_idle_timeout(Duration::from_secs(60)));
+    let start = Instant::now();
+
+    // The bytes keep the relay alive.
+    for _ in 0..3 {
+        tokio::time::sleep(Duration::from_secs(40)).await;
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).await.unwrap();
+    }
+
+    // Both sides are shut down once it is idle.
+    assert!(read_to_end(&mut client).await.is_empty());
+    assert!(read_to_end(&mut server).await.is_empty());
+    assert_eq!(start.elapsed(), Duration::from_secs(180));
+    let err = relay.await.unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert_eq!(
+        Expired::of(&err),
+        Some(Expired::Idle(Duration::from_secs(60)))
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn close_relay_at_max_lifetime() {
+    let (mut client, mut server, relay) = relay(|d| {
+        d.set_idle_timeout(Duration::from_secs(60));
+        d.set_max_lifetime(Duration::from_secs(100));
+    });
+    let start = Instant::now();
+
+    let mut buf = [0u8; 4];
+    for _ in 0..2 {
+        tokio::time::sleep(Duration::from_secs(40)).await;
+        server.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+    }
+
+    // The relay is closed however active it is.
+    assert!(read_to_end(&mut client).await.is_empty());
+    assert_eq!(start.elapsed(), Duration::from_secs(100));
+    let err = relay.await.unwrap().unwrap_err();
+    assert_eq!(
+        Expired::of(&err),
+        Some(Expired::Lifetime(Duration::from_secs(100)))
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn complete_before_expired() {
+    let (mut client, mut server, relay) = relay(|d| d.set_idle_timeout(Duration::from_secs(60)));
+
+    client.write_all(b"bye").await.unwrap();
+    client.shutdown().await.unwrap();
+    assert_eq!(read_to_end(&mut server).await, b"bye");
+    server.shutdown().await.unwrap();
+    assert!(read_to_end(&mut client).await.is_empty());
+    relay.await.unwrap().unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn report_stats() {
+    let (mut client, mut server, relay) = relay(|_| {});
+
+    client.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    server.read_exact(&mut buf).await.unwrap();
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    server.write_all(b"hi").await.unwrap();
+    server.shutdown().await.unwrap();
+    assert_eq!(read_to_end(&mut client).await, b"hi");
+    client.shutdown().await.unwrap();
+
+    let stats = relay.await.unwrap().unwrap();
+    assert_eq!(
+        stats,
+        DuplexStats {
+            sent: 5,
+            received: 2,
+            duration: Duration::from_secs(3),
+            first_closed: Some(Side::Out),
+        }
+    );
+}
+
+#[tokio::test]
+async fn share_live_counters() {
+    let counters = DuplexCounters::new();
+    let (mut client1, mut server1, _relay1) = relay(|d| d.set_counters(counters.clone()));
+    let (mut client2, mut server2, _relay2) = relay(|d| d.set_counters(counters.clone()));
+
+    let mut buf = [0u8; 4];
+    client1.write_all(b"ping").await.unwrap();
+    server1.read_exact(&mut buf).await.unwrap();
+    client2.write_all(b"ping").await.unwrap();
+    server2.read_exact(&mut buf).await.unwrap();
+    server2.write_all(b"pong").await.unwrap();
+    client2.read_exact(&mut buf).await.unwrap();
+
+    // Counted while the relays are running.
+    assert_eq!(counters.sent(), 8);
+    assert_eq!(counters.received(), 4);
+}
+
+#[tokio::test]
+async fn release_buffer_when_idle() {
+    let pool = BufferPool::new(1 << 20);
+    let (mut client, mut server, _relay) = relay(|d| d.set_buffer_pool(pool.clone()));
+
+    let mut buf = [0u8; 4];
+    for _ in 0..3 {
+        client.write_all(b"ping").await.unwrap();
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        tokio::task::yield_now().await;
+        // The only buffer is back once the bytes are written.
+        assert_eq!(pool.free_bytes(), MIN_BUFFER_SIZE);
+    }
+}
+
+#[tokio::test]
+async fn grow_buffer_for_bulk_transfer() {
+    let pool = BufferPool::new(1 << 20);
+    let (mut client, relay_in) = duplex(1 << 20);
+    let (relay_out, mut server) = duplex(1 << 20);
+    let mut duplex = Duplex::new(relay_in, relay_out);
+    duplex.set_buffer_pool(pool.clone());
+    let relay = tokio::spawn(duplex);
+
+    client.write_all(&[0u8; 1 << 20]).await.unwrap();
+    client.shutdown().await.unwrap();
+    assert_eq!(read_to_end(&mut server).await.len(), 1 << 20);
+    server.shutdown().await.unwrap();
+    relay.await.unwrap().unwrap();
+    assert!(pool.free_bytes() >= MAX_BUFFER_SIZE, "{:?}", pool);
+}
+
+#[tokio::test]
+async fn read_ahead_of_slow_server() {
+    let (mut client, relay_in) = duplex(1 << 20);
+    let (relay_out, mut server) = duplex(1024);
+    let relay = tokio::spawn(Duplex::new(relay_in, relay_out));
+
+    // The relay reads ahead and then sees EOF before the bytes are written.
+    let data: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+    client.write_all(&data).await.unwrap();
+    client.shutdown().await.unwrap();
+    assert!(read_to_end(&mut server).await == data);
+    server.shutdown().await.unwrap();
+    assert!(read_to_end(&mut client).await.is_empty());
+
+    let stats = relay.await.unwrap().unwrap();
+    assert_eq!(stats.sent, 1 << 20);
+}
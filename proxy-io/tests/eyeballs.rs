@@ -0,0 +1,129 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use futures::future::BoxFuture;
+use proxy::Service;
+use proxy_io::{interleave, ConnectError, Resolver, TargetAddr, TokioConnect};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+/// Resolves every host to the addresses.
+struct StaticResolver(Vec<IpAddr>);
+
+impl Resolver for StaticResolver {
+    fn resolve<'a>(&'a self, _host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        Box::pin(futures::future::ready(Ok(self.0.clone())))
+    }
+}
+
+/// Returns a port which nothing listens on.
+async fn closed_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+#[test]
+fn interleave_families() {
+    let addrs: Vec<SocketAddr> = vec![
+        "[::1]:80".parse().unwrap(),
+        "[::2]:80".parse().unwrap(),
+        "[::3]:80".parse().unwrap(),
+        "10.0.0.1:80".parse().unwrap(),
+        "10.0.0.2:80".parse().unwrap(),
+    ];
+    let expected: Vec<SocketAddr> = vec![
+        "[::1]:80".parse().unwrap(),
+        "10.0.0.1:80".parse().unwrap(),
+        "[::2]:80".parse().unwrap(),
+        "10.0.0.2:80".parse().unwrap(),
+        "[::3]:80".parse().unwrap(),
+    ];
+    assert_eq!(interleave(addrs), expected);
+}
+
+/// Listens on the address with the full backlog, so that the following
+/// connections hang.
+async fn stalled_listener(addr: SocketAddr) -> io::Result<(TcpListener, Vec<TcpStream>)> {
+    let socket = TcpSocket::new_v4()?;
+    socket.bind(addr)?;
+    let listener = socket.listen(1)?;
+    let addr = listener.local_addr()?;
+    let mut streams = Vec::new();
+    while let Ok(Ok(stream)) =
+        tokio::time::timeout(Duration::from_millis(100), TcpStream::connect(addr)).await
+    {
+        streams.push(stream);
+    }
+    Ok((listener, streams))
+}
+
+#[tokio::test]
+async fn skip_unresponsive_address() {
+    let (stalled, _streams, listener) = loop {
+        let (stalled, streams) = stalled_listener("127.0.0.2:0".parse().unwrap())
+            .await
+            .unwrap();
+        let port = stalled.local_addr().unwrap().port();
+        if let Ok(listener) = TcpListener::bind(("127.0.0.1", port)).await {
+            break (stalled, streams, listener);
+        }
+    };
+    let port = listener.local_addr().unwrap().port();
+
+    let mut connect = TokioConnect::new();
+    connect.set_resolver(StaticResolver(vec![
+        stalled.local_addr().unwrap().ip(),
+        IpAddr::V4(Ipv4Addr::LOCALHOST),
+    ]));
+    connect.set_happy_eyeballs_delay(Duration::from_millis(50));
+
+    let start = Instant::now();
+    let stream = connect
+        .call(TargetAddr::Domain("dual.test".to_string(), port))
+        .await
+        .unwrap();
+    assert_eq!(stream.peer_addr().unwrap().ip(), Ipv4Addr::LOCALHOST);
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn report_all_failures() {
+    let port = closed_port().await;
+    let mut connect = TokioConnect::new();
+    connect.set_resolver(StaticResolver(vec![
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)),
+    ]));
+
+    let err = connect
+        .call(TargetAddr::Domain("closed.test".to_string(), port))
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    let errors = err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<ConnectError>())
+        .unwrap()
+        .errors();
+    assert_eq!(errors.len(), 2);
+    assert!(err.to_string().contains(&format!("127.0.0.2:{}", port)));
+}
+
+#[tokio::test]
+async fn time_out_unresponsive_host() {
+    let (stalled, _streams) = stalled_listener("127.0.0.2:0".parse().unwrap())
+        .await
+        .unwrap();
+    let mut connect = TokioConnect::new();
+    connect.set_connect_timeout(Duration::from_millis(200));
+
+    let start = Instant::now();
+    let err = connect
+        .call(TargetAddr::SocketAddr(stalled.local_addr().unwrap()))
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
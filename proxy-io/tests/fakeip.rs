@@ -0,0 +1,165 @@
+use std::{
+    io,
+    net::IpAddr,
+    num::NonZeroUsize,
+    task::{Context, Poll},
+};
+
+use futures::future::{ready, Ready};
+use proxy::Service;
+use proxy_io::{
+    serve_dns_tcp, serve_dns_udp, Connection, DnsClient, FakeIp, Nameserver, ProxyConnect,
+    ProxyStream, Resolver, TargetAddr, TokioConnect,
+};
+use proxy_rules::Rule;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+/// The upstream proxy which is never reachable.
+#[derive(Clone)]
+struct Unreachable;
+
+impl Service<TargetAddr> for Unreachable {
+    type Response = ProxyStream<TcpStream>;
+
+    type Error = io::Error;
+
+    type Future<'a> = Ready<io::Result<Self::Response>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: TargetAddr) -> Self::Future<'_> {
+        ready(Err(io::ErrorKind::ConnectionRefused.into()))
+    }
+}
+
+fn pools() -> FakeIp {
+    FakeIp::new(vec![
+        "198.18.0.0/15".parse().unwrap(),
+        "fc00::/18".parse().unwrap(),
+    ])
+}
+
+#[test]
+fn allocate_fake_ip() {
+    let fake_ip = pools();
+    let ip = fake_ip.allocate("Example.com.", false).unwrap();
+    assert_eq!(ip, "198.18.0.1".parse::<IpAddr>().unwrap());
+    assert_eq!(fake_ip.allocate("example.com", false), Some(ip));
+    assert_eq!(fake_ip.domain(ip).as_deref(), Some("example.com"));
+
+    let ip6 = fake_ip.allocate("example.com", true).unwrap();
+    assert_eq!(ip6, "fc00::1".parse::<IpAddr>().unwrap());
+    assert_eq!(fake_ip.domain(ip6).as_deref(), Some("example.com"));
+
+    let other = fake_ip.allocate("example.org", false).unwrap();
+    assert_eq!(other, "198.18.0.2".parse::<IpAddr>().unwrap());
+    assert!(fake_ip.contains("198.19.255.255".parse().unwrap()));
+    assert!(fake_ip.domain("198.18.0.3".parse().unwrap()).is_none());
+    assert!(FakeIp::new(vec![]).allocate("example.com", false).is_none());
+}
+
+#[test]
+fn reuse_least_recently_used() {
+    let mut fake_ip = pools();
+    fake_ip.set_capacity(NonZeroUsize::new(2).unwrap());
+    let a = fake_ip.allocate("a.com", false).unwrap();
+    let b = fake_ip.allocate("b.com", false).unwrap();
+    // The lookup keeps a.com, so b.com is evicted.
+    assert_eq!(fake_ip.domain(a).as_deref(), Some("a.com"));
+    let c = fake_ip.allocate("c.com", false).unwrap();
+    assert_eq!(c, b);
+    assert_eq!(fake_ip.domain(b).as_deref(), Some("c.com"));
+    assert_eq!(fake_ip.allocate("a.com", false), Some(a));
+
+    // The pool without the spare address reuses it too.
+    let fake_ip = FakeIp::new(vec!["10.0.0.0/30".parse().unwrap()]);
+    let a = fake_ip.allocate("a.com", false).unwrap();
+    let b = fake_ip.allocate("b.com", false).unwrap();
+    assert_eq!(fake_ip.allocate("c.com", false), Some(a));
+    assert_eq!(fake_ip.domain(b).as_deref(), Some("b.com"));
+}
+
+#[test]
+fn persist_fake_ip() {
+    let file = std::env::temp_dir().join(format!("fakeip-{}", std::process::id()));
+    let fake_ip = pools();
+    assert!(!fake_ip.is_dirty());
+    let a = fake_ip.allocate("a.com", false).unwrap();
+    let b = fake_ip.allocate("b.com", true).unwrap();
+    assert!(fake_ip.is_dirty());
+    fake_ip.save(&file).unwrap();
+    // Only the new domains need saving again.
+    assert!(!fake_ip.is_dirty());
+    fake_ip.allocate("a.com", false);
+    assert!(!fake_ip.is_dirty());
+
+    let restored = pools();
+    restored.load(&file).unwrap();
+    std::fs::remove_file(&file).unwrap();
+    assert!(!restored.is_dirty());
+    assert_eq!(restored.domain(a).as_deref(), Some("a.com"));
+    assert_eq!(restored.domain(b).as_deref(), Some("b.com"));
+    assert_eq!(restored.allocate("b.com", true), Some(b));
+    assert!(!restored.is_dirty());
+    // The new domain does not take the restored address.
+    let c = restored.allocate("c.com", false).unwrap();
+    assert_ne!(c, a);
+}
+
+#[tokio::test]
+async fn answer_fake_ip() {
+    let fake_ip = pools();
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let udp = socket.local_addr().unwrap();
+    tokio::spawn(serve_dns_udp(socket, fake_ip.clone()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let tcp = listener.local_addr().unwrap();
+    tokio::spawn(serve_dns_tcp(listener, fake_ip.clone()));
+
+    let client = DnsClient::new(vec![Nameserver::Udp(udp)]);
+    let ips = client.resolve("example.com").await.unwrap();
+    assert!(ips.iter().all(|ip| fake_ip.contains(*ip)));
+    assert_eq!(ips.len(), 2);
+
+    let client = DnsClient::new(vec![Nameserver::Tcp(tcp)]);
+    assert_eq!(client.resolve("example.com").await.unwrap(), ips);
+    assert_eq!(fake_ip.domain(ips[0]).as_deref(), Some("example.com"));
+}
+
+#[tokio::test]
+async fn translate_before_policy() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let fake_ip = pools();
+    let ip = fake_ip.allocate("localhost", false).unwrap();
+    // Only the translated domain connects directly.
+    let rule: Rule = "DOMAIN,localhost,DIRECT".parse().unwrap();
+    let mut connect = ProxyConnect::new(TokioConnect::new(), Unreachable);
+    connect.set_policy(vec![rule]);
+    connect.set_default_proxy(true);
+    connect.set_fake_ip(fake_ip);
+
+    let stream = connect
+        .call(TargetAddr::SocketAddr((ip, addr.port()).into()))
+        .await
+        .unwrap();
+    match stream {
+        Connection::Direct(stream) => assert_eq!(stream.peer_addr().unwrap(), addr),
+        Connection::Proxy(_) => panic!("connect by the proxy"),
+    }
+
+    let stream = connect
+        .call(TargetAddr::Domain(ip.to_string(), addr.port()))
+        .await
+        .unwrap();
+    assert!(matches!(stream, Connection::Direct(_)));
+
+    let err = connect
+        .call(TargetAddr::SocketAddr(([198, 18, 1, 1], 80).into()))
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::HostUnreachable);
+}
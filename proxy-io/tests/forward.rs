@@ -0,0 +1,203 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::future::{ready, BoxFuture};
+use proxy::Service;
+use proxy_io::{
+    answer_ips, serve_dns_tcp, serve_dns_udp, Dial, DnsCache, DnsClient, DnsForwarder, DnsStream,
+    FakeIp, Lookup, Nameserver, Resolver, TargetAddr, TokioConnect,
+};
+use proxy_rules::Rule;
+use tokio::net::{TcpListener, UdpSocket};
+use trust_dns_proto::{
+    op::{Message, ResponseCode},
+    rr::{rdata::MX, Name, RData, Record, RecordType},
+};
+
+/// Resolves any host to the addresses.
+struct StaticResolver(Vec<IpAddr>);
+
+impl Resolver for StaticResolver {
+    fn resolve<'a>(&'a self, _host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        Box::pin(ready(Ok(self.0.clone())))
+    }
+
+    fn lookup<'a>(&'a self, _host: &'a str) -> BoxFuture<'a, io::Result<Lookup>> {
+        Box::pin(ready(Ok(Lookup {
+            ips: self.0.clone(),
+            ttl: Some(Duration::from_secs(30)),
+        })))
+    }
+}
+
+/// Answers the MX queries with the exchange, as the remote nameservers.
+struct MxResolver(Name);
+
+impl Resolver for MxResolver {
+    fn resolve<'a>(&'a self, _host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        Box::pin(ready(Ok(Vec::new())))
+    }
+
+    fn query<'a>(
+        &'a self,
+        name: &'a Name,
+        rtype: RecordType,
+    ) -> BoxFuture<'a, io::Result<Message>> {
+        let mut response = Message::new();
+        if rtype == RecordType::MX {
+            let rdata = RData::MX(MX::new(10, self.0.clone()));
+            response.add_answer(Record::from_rdata(name.clone(), 300, rdata));
+        } else {
+            response.set_response_code(ResponseCode::NXDomain);
+        }
+        Box::pin(ready(Ok(response)))
+    }
+}
+
+/// Dials directly, counting the dials.
+#[derive(Default)]
+struct CountDial {
+    count: Arc<AtomicUsize>,
+}
+
+impl Dial for CountDial {
+    fn dial<'a>(&'a self, target: &'a TargetAddr) -> BoxFuture<'a, io::Result<Box<dyn DnsStream>>> {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async move {
+            let stream = TokioConnect::new().call(target.clone()).await?;
+            Ok(Box::new(stream) as Box<dyn DnsStream>)
+        })
+    }
+}
+
+fn ip(ip: &str) -> IpAddr {
+    ip.parse().unwrap()
+}
+
+async fn forwarder<R: Resolver + 'static>(rules: &[&str], remote: R) -> DnsClient {
+    let rules: Vec<Rule> = rules.iter().map(|r| r.parse().unwrap()).collect();
+    let direct = StaticResolver(vec![ip("10.0.0.1"), ip("fd00::1")]);
+    let mut forwarder = DnsForwarder::new(direct, remote);
+    forwarder.set_policy(rules);
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(serve_dns_udp(socket, forwarder));
+    DnsClient::new(vec![Nameserver::Udp(addr)])
+}
+
+#[tokio::test]
+async fn forward_by_rules() {
+    let remote = StaticResolver(vec![ip("10.0.0.2")]);
+    let client = forwarder(
+        &[
+            "DOMAIN-SUFFIX,proxied.test,PROXY",
+            "DOMAIN-SUFFIX,denied.test,DENY",
+        ],
+        remote,
+    )
+    .await;
+
+    assert_eq!(
+        client.resolve("www.direct.test").await.unwrap(),
+        vec![ip("10.0.0.1"), ip("fd00::1")]
+    );
+    assert_eq!(
+        client.resolve("www.proxied.test").await.unwrap(),
+        vec![ip("10.0.0.2")]
+    );
+    let err = client.resolve("www.denied.test").await.unwrap_err();
+    assert!(err.to_string().contains("Refused"), "{}", err);
+
+    // Each query is answered by the family with the TTL of the resolver.
+    let name = Name::from_ascii("www.direct.test.").unwrap();
+    let response = client.query(&name, RecordType::AAAA).await.unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(response.answers().len(), 1);
+    assert_eq!(response.answers()[0].ttl(), 30);
+    // The system-like resolver knows only the addresses.
+    let err = client.query(&name, RecordType::MX).await.unwrap_err();
+    let not_imp = ResponseCode::NotImp.to_string();
+    assert!(err.to_string().contains(&not_imp), "{}", err);
+}
+
+#[tokio::test]
+async fn forward_other_types() {
+    let exchange = Name::from_ascii("mail.proxied.test.").unwrap();
+    let client = forwarder(
+        &[
+            "DOMAIN-SUFFIX,proxied.test,PROXY",
+            "DOMAIN-SUFFIX,denied.test,DENY",
+        ],
+        MxResolver(exchange.clone()),
+    )
+    .await;
+
+    // The answer of the remote resolver is relayed, with its response code.
+    let name = Name::from_ascii("www.proxied.test.").unwrap();
+    let response = client.query(&name, RecordType::MX).await.unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert_eq!(response.answers().len(), 1);
+    assert_eq!(response.answers()[0].ttl(), 300);
+    match response.answers()[0].data() {
+        Some(RData::MX(mx)) => assert_eq!(mx.exchange(), &exchange),
+        data => panic!("unexpected {:?}", data),
+    }
+    let response = client.query(&name, RecordType::TXT).await.unwrap();
+    assert_eq!(response.response_code(), ResponseCode::NXDomain);
+
+    let name = Name::from_ascii("www.denied.test.").unwrap();
+    let err = client.query(&name, RecordType::MX).await.unwrap_err();
+    assert!(err.to_string().contains("Refused"), "{}", err);
+}
+
+#[tokio::test]
+async fn forward_by_ip_rules() {
+    let remote = StaticResolver(vec![ip("10.0.0.2")]);
+    // The direct address matches the rule, so the remote one is answered.
+    let client = forwarder(&["IP-CIDR,10.0.0.0/24,PROXY"], remote).await;
+    assert_eq!(
+        client.resolve("example.test").await.unwrap(),
+        vec![ip("10.0.0.2")]
+    );
+
+    let remote = StaticResolver(vec![ip("10.0.0.2")]);
+    let client = forwarder(&["IP-CIDR,10.1.0.0/16,PROXY"], remote).await;
+    assert_eq!(
+        client.resolve("example.test").await.unwrap(),
+        vec![ip("10.0.0.1"), ip("fd00::1")]
+    );
+}
+
+#[tokio::test]
+async fn forward_over_tcp_by_dial() {
+    // The fake DNS stands for the remote nameserver.
+    let upstream = FakeIp::new(vec!["198.18.0.0/15".parse().unwrap()]);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    tokio::spawn(serve_dns_tcp(listener, upstream.clone()));
+
+    let dial = CountDial::default();
+    let count = dial.count.clone();
+    let mut remote = DnsClient::new(vec![Nameserver::Tcp(addr)]);
+    remote.set_dial(dial);
+    let client = forwarder(&["DOMAIN-SUFFIX,proxied.test,PROXY"], DnsCache::new(remote)).await;
+
+    let name = Name::from_ascii("www.proxied.test.").unwrap();
+    let response = client.query(&name, RecordType::A).await.unwrap();
+    let ips = answer_ips(&response);
+    assert_eq!(ips, vec![ip("198.18.0.1")]);
+    assert_eq!(upstream.domain(ips[0]).as_deref(), Some("www.proxied.test"));
+    // Both A and AAAA are queried on the same dialed stream, and then cached.
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+    let response = client.query(&name, RecordType::AAAA).await.unwrap();
+    assert!(response.answers().is_empty());
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,110 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr},
+    task::{Context, Poll},
+};
+
+use futures::future::{ready, Ready};
+use proxy::Service;
+use proxy_io::{
+    Connection, HostMapping, Hosts, ProxyConnect, ProxyStream, TargetAddr, TokioConnect,
+};
+use proxy_rules::Rule;
+use tokio::net::{TcpListener, TcpStream};
+
+/// The upstream proxy which is never reachable.
+#[derive(Clone)]
+struct Unreachable;
+
+impl Service<TargetAddr> for Unreachable {
+    type Response = ProxyStream<TcpStream>;
+
+    type Error = io::Error;
+
+    type Future<'a> = Ready<io::Result<Self::Response>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: TargetAddr) -> Self::Future<'_> {
+        ready(Err(io::ErrorKind::ConnectionRefused.into()))
+    }
+}
+
+#[test]
+fn parse_hosts_file() {
+    let hosts = Hosts::parse(
+        "# comment\n\
+         127.0.0.1 localhost api.local # inline\n\
+         \n\
+         ::1 localhost ip6-localhost\n\
+         10.0.0.1 *.dev.internal\n",
+    )
+    .unwrap();
+    let localhost = HostMapping::Ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    assert_eq!(hosts.get("localhost"), Some(&localhost));
+    assert_eq!(hosts.get("API.local."), Some(&localhost));
+    assert_eq!(
+        hosts.get("ip6-localhost"),
+        Some(&HostMapping::Ip("::1".parse().unwrap()))
+    );
+    assert!(hosts.get("a.b.dev.internal").is_some());
+    assert!(hosts.get("dev.internal").is_none());
+    assert!(Hosts::parse("localhost 127.0.0.1").is_err());
+}
+
+#[test]
+fn match_wildcards() {
+    let mut hosts = Hosts::new();
+    hosts.insert("*.internal", "10.0.0.1".parse().unwrap());
+    hosts.insert("*.dev.internal", "staging.internal".parse().unwrap());
+    hosts.insert("db.dev.internal", "10.0.0.3".parse().unwrap());
+
+    let target = TargetAddr::Domain("web.dev.internal".to_string(), 443);
+    assert_eq!(
+        hosts.rewrite(&target).unwrap().to_string(),
+        "staging.internal:443"
+    );
+    let target = TargetAddr::Domain("db.dev.internal".to_string(), 5432);
+    assert_eq!(hosts.rewrite(&target).unwrap().to_string(), "10.0.0.3:5432");
+    let target = TargetAddr::Domain("prod.internal".to_string(), 80);
+    assert_eq!(hosts.rewrite(&target).unwrap().to_string(), "10.0.0.1:80");
+    assert!(hosts
+        .rewrite(&TargetAddr::Domain("example.com".to_string(), 80))
+        .is_none());
+    assert!("*.example.com".parse::<HostMapping>().is_err());
+}
+
+#[tokio::test]
+async fn rewrite_before_policy() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut hosts = Hosts::new();
+    hosts.insert("*.dev.internal", HostMapping::Ip(addr.ip()));
+    // Only the rewritten address connects directly.
+    let rule: Rule = "IPV4,127.0.0.1,DIRECT".parse().unwrap();
+    let mut connect = ProxyConnect::new(TokioConnect::new(), Unreachable);
+    connect.set_policy(vec![rule]);
+    connect.set_default_proxy(true);
+    connect.set_hosts(hosts);
+
+    let stream = connect
+        .call(TargetAddr::Domain(
+            "api.dev.internal".to_string(),
+            addr.port(),
+        ))
+        .await
+        .unwrap();
+    match stream {
+        Connection::Direct(stream) => assert_eq!(stream.peer_addr().unwrap(), addr),
+        Connection::Proxy(_) => panic!("connect by the proxy"),
+    }
+
+    let err = connect
+        .call(TargetAddr::Domain("example.com".to_string(), 80))
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+}
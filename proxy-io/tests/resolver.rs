@@ -0,0 +1,408 @@
+use std::{
+    convert::Infallible,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::future::BoxFuture;
+use hyper::{
+    header::CONTENT_TYPE, server::conn::Http, service::service_fn, Body, Method, Request, Response,
+    StatusCode,
+};
+use proxy::Service;
+use proxy_io::{
+    read_tcp_message, write_tcp_message, Dial, DnsClient, DnsStream, Nameserver, Resolver,
+    TargetAddr, TokioConnect,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UdpSocket},
+};
+use tokio_native_tls::{
+    native_tls::{self, Certificate, Identity},
+    TlsAcceptor,
+};
+use trust_dns_proto::{
+    op::{Message, MessageType, ResponseCode},
+    rr::{RData, Record, RecordType},
+};
+
+/// Answers the query like an authoritative nameserver of `.test`.
+fn answer(request: &Message, tcp: bool) -> Message {
+    let mut response = Message::new();
+    response
+        .set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_recursion_available(true);
+    let query = &request.queries()[0];
+    response.add_query(query.clone());
+    let rdata = match (query.name().to_ascii().as_str(), query.query_type()) {
+        ("example.test.", RecordType::A) => Some(RData::A(Ipv4Addr::new(10, 0, 0, 1))),
+        ("example.test.", RecordType::AAAA) => {
+            Some(RData::AAAA(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1)))
+        }
+        ("local.test.", RecordType::A) => Some(RData::A(Ipv4Addr::LOCALHOST)),
+        ("local.test.", RecordType::AAAA) => None,
+        // The response is too large for UDP.
+        ("large.test.", RecordType::A) if !tcp => {
+            response.set_truncated(true);
+            return response;
+        }
+        ("large.test.", RecordType::A) => Some(RData::A(Ipv4Addr::new(10, 0, 0, 2))),
+        ("large.test.", RecordType::AAAA) => None,
+        _ => {
+            response.set_response_code(ResponseCode::NXDomain);
+            return response;
+        }
+    };
+    if let Some(rdata) = rdata {
+        response.add_answer(Record::from_rdata(query.name().clone(), 60, rdata));
+    }
+    response
+}
+
+/// Answers the queries over the stream one by one, until it is closed.
+async fn serve_stream<S>(stream: &mut S) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let request = read_tcp_message(stream).await?;
+        write_tcp_message(stream, &answer(&request, true)).await?;
+    }
+}
+
+/// Serves the stub nameserver on the same port of both UDP and TCP.
+async fn stub_nameserver() -> SocketAddr {
+    let (udp, tcp) = loop {
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        if let Ok(udp) = UdpSocket::bind(tcp.local_addr().unwrap()).await {
+            break (udp, tcp);
+        }
+    };
+    let addr = tcp.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        while let Ok((n, peer)) = udp.recv_from(&mut buf).await {
+            let request = Message::from_vec(&buf[..n]).unwrap();
+            let response = answer(&request, false).to_vec().unwrap();
+            udp.send_to(&response, peer).await.unwrap();
+        }
+    });
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = tcp.accept().await {
+            tokio::spawn(async move { serve_stream(&mut stream).await });
+        }
+    });
+    addr
+}
+
+fn tls_acceptor() -> TlsAcceptor {
+    let identity = Identity::from_pkcs8(
+        include_bytes!("fixtures/localhost.pem"),
+        include_bytes!("fixtures/localhost.key"),
+    )
+    .unwrap();
+    native_tls::TlsAcceptor::new(identity).unwrap().into()
+}
+
+/// Trusts the certificate of the stub nameservers.
+fn tls_connector() -> native_tls::TlsConnector {
+    let cert = Certificate::from_pem(include_bytes!("fixtures/localhost.pem")).unwrap();
+    native_tls::TlsConnector::builder()
+        .add_root_certificate(cert)
+        .build()
+        .unwrap()
+}
+
+/// Serves the stub nameserver over TLS.
+async fn tls_nameserver() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let acceptor = tls_acceptor();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let mut stream = acceptor.accept(stream).await.map_err(io::Error::other)?;
+                serve_stream(&mut stream).await
+            });
+        }
+    });
+    addr
+}
+
+async fn doh(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let valid = req.method() == Method::POST
+        && req.uri().path() == "/dns-query"
+        && req.headers().get(CONTENT_TYPE).map(|v| v.as_bytes())
+            == Some(b"application/dns-message");
+    let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+    let request = match Message::from_vec(&body) {
+        Ok(request) if valid && request.id() == 0 => request,
+        _ => {
+            let mut res = Response::new(Body::empty());
+            *res.status_mut() = StatusCode::BAD_REQUEST;
+            return Ok(res);
+        }
+    };
+    let response = answer(&request, true).to_vec().unwrap();
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/dns-message")
+        .body(Body::from(response))
+        .unwrap())
+}
+
+/// Serves the stub nameserver over HTTPS.
+async fn https_nameserver() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let acceptor = tls_acceptor();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                if let Ok(stream) = acceptor.accept(stream).await {
+                    Http::new()
+                        .serve_connection(stream, service_fn(doh))
+                        .await
+                        .ok();
+                }
+            });
+        }
+    });
+    addr
+}
+
+/// Dials directly, counting the streams.
+#[derive(Default)]
+struct CountDial {
+    count: Arc<AtomicUsize>,
+}
+
+impl Dial for CountDial {
+    fn dial<'a>(&'a self, target: &'a TargetAddr) -> BoxFuture<'a, io::Result<Box<dyn DnsStream>>> {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async move {
+            let stream = TokioConnect::new().call(target.clone()).await?;
+            Ok(Box::new(stream) as Box<dyn DnsStream>)
+        })
+    }
+}
+
+#[tokio::test]
+async fn resolve_over_udp() {
+    let nameserver = stub_nameserver().await;
+    let client = DnsClient::new(vec![nameserver.into()]);
+
+    let ips = client.resolve("example.test").await.unwrap();
+    assert_eq!(
+        ips,
+        vec![
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1)),
+        ]
+    );
+    assert!(client.resolve("missing.test").await.unwrap().is_empty());
+
+    let lookup = client.lookup("example.test").await.unwrap();
+    assert_eq!(lookup.ttl, Some(Duration::from_secs(60)));
+}
+
+#[tokio::test]
+async fn drop_response_of_other_question() {
+    let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let nameserver = udp.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        while let Ok((n, peer)) = udp.recv_from(&mut buf).await {
+            let request = Message::from_vec(&buf[..n]).unwrap();
+            // The forged response guesses the id but asks another name.
+            let mut forged = request.clone();
+            forged.queries_mut()[0].set_name("local.test.".parse().unwrap());
+            let forged = answer(&forged, false).to_vec().unwrap();
+            udp.send_to(&forged, peer).await.unwrap();
+            let response = answer(&request, false).to_vec().unwrap();
+            udp.send_to(&response, peer).await.unwrap();
+        }
+    });
+    let client = DnsClient::new(vec![nameserver.into()]);
+
+    let ips = client.resolve("example.test").await.unwrap();
+    assert_eq!(ips.len(), 2);
+    assert!(!ips.contains(&IpAddr::V4(Ipv4Addr::LOCALHOST)));
+}
+
+#[tokio::test]
+async fn retry_truncated_over_tcp() {
+    let nameserver = stub_nameserver().await;
+    let client = DnsClient::new(vec![nameserver.into()]);
+
+    let ips = client.resolve("large.test").await.unwrap();
+    assert_eq!(ips, vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))]);
+}
+
+#[tokio::test]
+async fn resolve_over_tcp() {
+    let nameserver = stub_nameserver().await;
+    let client = DnsClient::new(vec![Nameserver::Tcp(nameserver)]);
+
+    let ips = client.resolve("example.test").await.unwrap();
+    assert_eq!(ips.len(), 2);
+}
+
+#[tokio::test]
+async fn fallback_to_next_nameserver() {
+    // Nothing listens on the first nameserver.
+    let unused = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let dead = unused.local_addr().unwrap();
+    drop(unused);
+    let nameserver = stub_nameserver().await;
+    let client = DnsClient::new(vec![Nameserver::Tcp(dead), Nameserver::Tcp(nameserver)]);
+
+    let ips = client.resolve("example.test").await.unwrap();
+    assert_eq!(ips.len(), 2);
+}
+
+#[tokio::test]
+async fn connect_with_resolver() {
+    let nameserver = stub_nameserver().await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let mut connect = TokioConnect::new();
+    connect.set_resolver(DnsClient::new(vec![nameserver.into()]));
+    let stream = connect
+        .call(TargetAddr::Domain("local.test".to_string(), port))
+        .await
+        .unwrap();
+    assert_eq!(stream.peer_addr().unwrap().port(), port);
+
+    let err = connect
+        .call(TargetAddr::Domain("missing.test".to_string(), port))
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::HostUnreachable);
+}
+
+#[tokio::test]
+async fn resolve_over_tls() {
+    let nameserver = tls_nameserver().await;
+    let nameserver = format!("tls://localhost:{}", nameserver.port());
+    let mut client = DnsClient::new(vec![nameserver.parse().unwrap()]);
+    client.set_tls_connector(tls_connector());
+
+    let ips = client.resolve("example.test").await.unwrap();
+    assert_eq!(ips.len(), 2);
+    assert!(client.resolve("missing.test").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn resolve_over_https() {
+    let nameserver = https_nameserver().await;
+    let nameserver = format!("https://localhost:{}/dns-query", nameserver.port());
+    let mut client = DnsClient::new(vec![nameserver.parse().unwrap()]);
+    client.set_tls_connector(tls_connector());
+
+    let ips = client.resolve("local.test").await.unwrap();
+    assert_eq!(ips, vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+}
+
+#[tokio::test]
+async fn race_nameservers() {
+    // The first nameserver never responds.
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let nameserver = stub_nameserver().await;
+    let mut client = DnsClient::new(vec![silent.local_addr().unwrap().into(), nameserver.into()]);
+    client.set_race(true);
+
+    let ips = tokio::time::timeout(Duration::from_secs(1), client.resolve("example.test"))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(ips.len(), 2);
+}
+
+#[tokio::test]
+async fn dial_https_nameserver() {
+    let nameserver = https_nameserver().await;
+    let nameserver = format!("https://127.0.0.1:{}/dns-query", nameserver.port());
+    let dial = CountDial::default();
+    let count = dial.count.clone();
+    let mut client = DnsClient::new(vec![nameserver.parse().unwrap()]);
+    client.set_tls_connector(tls_connector());
+    client.set_dial(dial);
+
+    let ips = client.resolve("example.test").await.unwrap();
+    assert_eq!(ips.len(), 2);
+    // Both A and AAAA are queried through the dial at the same time.
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+
+    // The later queries reuse the kept connections.
+    for _ in 0..3 {
+        client.resolve("example.test").await.unwrap();
+    }
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn pipeline_over_tls_connection() {
+    let nameserver = tls_nameserver().await;
+    let nameserver = format!("tls://localhost:{}", nameserver.port());
+    let dial = CountDial::default();
+    let count = dial.count.clone();
+    let mut client = DnsClient::new(vec![nameserver.parse().unwrap()]);
+    client.set_tls_connector(tls_connector());
+    client.set_dial(dial);
+
+    for _ in 0..3 {
+        let ips = client.resolve("example.test").await.unwrap();
+        assert_eq!(ips.len(), 2);
+    }
+    // Both A and AAAA are pipelined on the same connection.
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn dial_again_after_connection_closed() {
+    // Closes the connection after each response.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let nameserver = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let request = read_tcp_message(&mut stream).await?;
+                write_tcp_message(&mut stream, &answer(&request, true)).await?;
+                Ok::<_, io::Error>(())
+            });
+        }
+    });
+    let client = DnsClient::new(vec![Nameserver::Tcp(nameserver)]);
+
+    for _ in 0..2 {
+        let response = client
+            .query(&"local.test.".parse().unwrap(), RecordType::A)
+            .await
+            .unwrap();
+        assert_eq!(response.answers().len(), 1);
+    }
+}
+
+#[test]
+fn parse_nameserver() {
+    let udp: Nameserver = "8.8.8.8".parse().unwrap();
+    assert!(matches!(udp, Nameserver::Udp(addr) if addr.port() == 53));
+    let tcp: Nameserver = "tcp://[2001:4860:4860::8888]:5353".parse().unwrap();
+    assert!(matches!(tcp, Nameserver::Tcp(addr) if addr.port() == 5353));
+    let tls: Nameserver = "tls://dns.google".parse().unwrap();
+    assert!(matches!(tls, Nameserver::Tls(TargetAddr::Domain(host, 853)) if host == "dns.google"));
+    let https: Nameserver = "https://dns.google/dns-query".parse().unwrap();
+    assert_eq!(https.to_string(), "https://dns.google/dns-query");
+    assert!("tcp://dns.google".parse::<Nameserver>().is_err());
+    assert!("quic://dns.google".parse::<Nameserver>().is_err());
+}
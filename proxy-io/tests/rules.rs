@@ -0,0 +1,222 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::future::{ready, BoxFuture, Ready};
+use proxy::Service;
+use proxy_auth::Identity;
+use proxy_io::{
+    ConnectRequest, Connection, ProxyConnect, ProxyStream, Resolver, TargetAddr, TokioConnect,
+};
+use proxy_rules::{AccessControl, AccessList, Decision, Policy, Rule};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Resolves `*.local.test` to the loopback address.
+struct LocalResolver;
+
+impl Resolver for LocalResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        let ips = if host.ends_with("local.test") {
+            vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]
+        } else {
+            vec![]
+        };
+        Box::pin(ready(Ok(ips)))
+    }
+}
+
+/// The upstream proxy which records the targets and never connects.
+#[derive(Clone, Default)]
+struct RecordProxy {
+    targets: Arc<Mutex<Vec<TargetAddr>>>,
+}
+
+impl Service<TargetAddr> for RecordProxy {
+    type Response = ProxyStream<TcpStream>;
+
+    type Error = io::Error;
+
+    type Future<'a> = Ready<io::Result<Self::Response>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: TargetAddr) -> Self::Future<'_> {
+        self.targets.lock().unwrap().push(req);
+        ready(Err(io::ErrorKind::ConnectionRefused.into()))
+    }
+}
+
+fn proxy_connect(
+    rules: &[&str],
+) -> (
+    ProxyConnect<TokioConnect, RecordProxy, Vec<Rule>>,
+    RecordProxy,
+) {
+    let rules = rules.iter().map(|r| r.parse().unwrap()).collect();
+    let mut direct = TokioConnect::new();
+    direct.set_resolver(LocalResolver);
+    let proxy = RecordProxy::default();
+    let mut connect = ProxyConnect::new(direct, proxy.clone());
+    connect.set_policy(rules);
+    connect.set_default_proxy(true);
+    connect.set_resolver(LocalResolver);
+    (connect, proxy)
+}
+
+#[test]
+fn parse_ip_rules() {
+    let rule: Rule = "IP-CIDR,192.168.1.0/24,DIRECT,no-resolve".parse().unwrap();
+    assert!(rule.no_resolve);
+    assert_eq!(rule.to_string(), "IP-CIDR,192.168.1.0/24,DIRECT,no-resolve");
+    assert!(rule.pattern.is_match("192.168.1.7:80"));
+    assert!(!rule.pattern.is_match("192.168.2.7:80"));
+
+    let rule: Rule = "IP-CIDR6,fd00::/8,DIRECT".parse().unwrap();
+    assert!(rule.pattern.is_match("[fd12::1]:443"));
+    assert!(!rule.pattern.is_match("[fe80::1]:443"));
+
+    // The domain is resolved only if no rule matches before the IP rule.
+    let rules: Vec<Rule> = vec![
+        "DOMAIN-SUFFIX,example.com,PROXY".parse().unwrap(),
+        "IP-CIDR,10.0.0.0/8,DIRECT".parse().unwrap(),
+    ];
+    assert!(!rules.needs_resolve("www.example.com:443"));
+    assert!(rules.needs_resolve("www.example.org:443"));
+    assert!(!rules.needs_resolve("10.0.0.1:443"));
+}
+
+#[tokio::test]
+async fn resolve_for_ip_rules() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (mut connect, proxy) = proxy_connect(&["IP-CIDR,127.0.0.0/8,DIRECT"]);
+
+    let stream = connect
+        .call(TargetAddr::Domain("api.local.test".to_string(), port))
+        .await
+        .unwrap();
+    assert!(matches!(stream, Connection::Direct(_)));
+    assert!(proxy.targets.lock().unwrap().is_empty());
+
+    let (mut connect, proxy) = proxy_connect(&["IP-CIDR,127.0.0.0/8,DIRECT,no-resolve"]);
+    connect
+        .call(TargetAddr::Domain("api.local.test".to_string(), port))
+        .await
+        .unwrap_err();
+    assert_eq!(proxy.targets.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn resolve_for_proxy() {
+    let (mut connect, proxy) = proxy_connect(&[
+        "DOMAIN-SUFFIX,remote.local.test,PROXY,force-remote-dns",
+        "DOMAIN-SUFFIX,local.test,PROXY",
+    ]);
+
+    for host in ["www.remote.local.test", "www.local.test"] {
+        connect
+            .call(TargetAddr::Domain(host.to_string(), 443))
+            .await
+            .unwrap_err();
+    }
+    let targets = proxy.targets.lock().unwrap();
+    assert!(
+        matches!(&targets[0], TargetAddr::Domain(host, 443) if host == "www.remote.local.test")
+    );
+    let local: SocketAddr = "127.0.0.1:443".parse().unwrap();
+    assert!(matches!(&targets[1], TargetAddr::SocketAddr(addr) if *addr == local));
+}
+
+#[tokio::test]
+async fn force_proxy_over_access_control() {
+    let (mut connect, proxy) = proxy_connect(&[]);
+    let mut access = AccessControl::new();
+    let rules = vec![
+        "DOMAIN,denied.local.test,DENY".parse().unwrap(),
+        "IPV4,127.0.0.1,DIRECT".parse().unwrap(),
+    ];
+    access.add_user("alice".to_string(), AccessList::new(rules));
+    connect.set_access_control(access);
+    connect.set_force_proxy(true);
+
+    let identity = Some(Identity::new("alice".to_string()));
+    let target = TargetAddr::SocketAddr("127.0.0.1:443".parse().unwrap());
+    connect
+        .call(ConnectRequest::new(target, identity.clone()))
+        .await
+        .unwrap_err();
+    assert_eq!(proxy.targets.lock().unwrap().len(), 1);
+
+    let target = TargetAddr::Domain("denied.local.test".to_string(), 443);
+    let err = connect
+        .call(ConnectRequest::new(target, identity))
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    assert_eq!(proxy.targets.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn match_access_list_by_whole_host() {
+    let rules = vec![
+        "DOMAIN,intranet.corp,DIRECT".parse().unwrap(),
+        "DOMAIN-SUFFIX,example.com,DIRECT".parse().unwrap(),
+    ];
+    let list = AccessList::new(rules);
+    for dst in [
+        "intranet.corp:443",
+        "INTRANET.corp:443",
+        "example.com:443",
+        "www.example.com:443",
+    ] {
+        assert_eq!(list.enforce(dst), Decision::Direct, "{}", dst);
+    }
+    for dst in [
+        "intranet.corp.evil.com:443",
+        "intranet.corporate:443",
+        "evilexample.com:443",
+        "example.com.evil.com:443",
+    ] {
+        assert_eq!(list.enforce(dst), Decision::Deny, "{}", dst);
+    }
+}
+
+#[tokio::test]
+async fn resolve_for_access_control() {
+    let acl = |rules: &[&str]| {
+        let rules = rules.iter().map(|r| r.parse().unwrap()).collect();
+        let mut access = AccessControl::new();
+        access.add_user("alice".to_string(), AccessList::new(rules));
+        access
+    };
+    let identity = Some(Identity::new("alice".to_string()));
+    let target = TargetAddr::Domain("api.local.test".to_string(), 1);
+
+    // The domain resolving into the denied network is denied.
+    let (mut connect, _) = proxy_connect(&[]);
+    connect.set_access_control(acl(&[
+        "IP-CIDR,127.0.0.0/8,DENY",
+        "DOMAIN-SUFFIX,local.test,DIRECT",
+    ]));
+    let err = connect
+        .call(ConnectRequest::new(target.clone(), identity.clone()))
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+    let (mut connect, _) = proxy_connect(&[]);
+    connect.set_access_control(acl(&[
+        "IP-CIDR,127.0.0.0/8,DENY,no-resolve",
+        "DOMAIN-SUFFIX,local.test,DIRECT",
+    ]));
+    let err = connect
+        .call(ConnectRequest::new(target, identity))
+        .await
+        .unwrap_err();
+    assert_ne!(err.kind(), io::ErrorKind::PermissionDenied);
+}
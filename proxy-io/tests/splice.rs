@@ -0,0 +1,135 @@
+#![cfg(target_os = "linux")]
+use std::{io, time::Duration};
+
+use bytes::Bytes;
+use proxy_io::{Connection, DuplexTimeouts, Expired, Relay, Rewind, Side, SpliceDuplex};
+use tokio::{
+    io::{duplex, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Returns both ends of a loopback connection.
+async fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (connected, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+    (connected.unwrap(), accepted.unwrap().0)
+}
+
+/// Sends the request from the client and the response from the server,
+/// each side shuts down after sending. Returns the bytes the server reads.
+async fn exchange<C, S>(client: &mut C, server: &mut S, request: &[u8], response: &[u8]) -> Vec<u8>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    client.write_all(request).await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    server.read_to_end(&mut received).await.unwrap();
+
+    server.write_all(response).await.unwrap();
+    server.shutdown().await.unwrap();
+    let mut buf = Vec::new();
+    client.read_to_end(&mut buf).await.unwrap();
+    assert!(buf == response, "the response is corrupted");
+    received
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+#[tokio::test]
+async fn splice_both_ways() {
+    let (mut client, relay_in) = tcp_pair().await;
+    let (relay_out, mut server) = tcp_pair().await;
+    let duplex = SpliceDuplex::new(relay_in, relay_out).unwrap();
+    let counters = duplex.counters();
+    let relay = tokio::spawn(duplex);
+
+    let (request, response) = (pattern(3 << 20), pattern(1 << 20));
+    let received = exchange(&mut client, &mut server, &request, &response).await;
+    assert!(received == request, "the request is corrupted");
+    let stats = relay.await.unwrap().unwrap();
+    assert_eq!(stats.sent, 3 << 20);
+    assert_eq!(stats.received, 1 << 20);
+    assert_eq!(stats.first_closed, Some(Side::In));
+    assert_eq!((counters.sent(), counters.received()), (3 << 20, 1 << 20));
+}
+
+#[tokio::test]
+async fn write_buffered_before_splice() {
+    let (mut client, relay_in) = tcp_pair().await;
+    let (relay_out, mut server) = tcp_pair().await;
+    let mut duplex = SpliceDuplex::new(relay_in, relay_out).unwrap();
+    duplex.set_buffered(Side::In, Bytes::from_static(b"GET "));
+    duplex.set_buffered(Side::Out, Bytes::from_static(b"HTTP/1.1 "));
+    let relay = tokio::spawn(duplex);
+
+    client.write_all(b"/ HTTP/1.1\r\n\r\n").await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut buf = Vec::new();
+    server.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"GET / HTTP/1.1\r\n\r\n");
+    server.write_all(b"200 OK\r\n\r\n").await.unwrap();
+    server.shutdown().await.unwrap();
+    buf.clear();
+    client.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"HTTP/1.1 200 OK\r\n\r\n");
+
+    let stats = relay.await.unwrap().unwrap();
+    assert_eq!((stats.sent, stats.received), (18, 19));
+}
+
+#[tokio::test]
+async fn close_idle_splice() {
+    let (mut client, relay_in) = tcp_pair().await;
+    let (relay_out, mut server) = tcp_pair().await;
+    let mut duplex = SpliceDuplex::new(relay_in, relay_out).unwrap();
+    duplex.set_timeouts(DuplexTimeouts {
+        idle: Some(Duration::from_millis(100)),
+        max_lifetime: None,
+    });
+    let relay = tokio::spawn(duplex);
+
+    let mut buf = Vec::new();
+    client.read_to_end(&mut buf).await.unwrap();
+    server.read_to_end(&mut buf).await.unwrap();
+    assert!(buf.is_empty());
+    let err = relay.await.unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert_eq!(
+        Expired::of(&err),
+        Some(Expired::Idle(Duration::from_millis(100)))
+    );
+}
+
+#[tokio::test]
+async fn relay_the_streams_of_servers() {
+    let relay = Relay::new();
+
+    // The peeked bytes of the mixed listener are replayed to the remote.
+    let (mut client, relay_in) = tcp_pair().await;
+    let (relay_out, mut server) = tcp_pair().await;
+    client.write_all(b"\x05").await.unwrap();
+    let mut rewind = Rewind::new(relay_in);
+    assert_eq!(rewind.peek(1).await.unwrap(), b"\x05");
+    let remote = Connection::Direct(relay_out);
+    let (stats, received) = tokio::join!(
+        relay.run(rewind, remote, None),
+        exchange(&mut client, &mut server, b"hello", b"world")
+    );
+    assert_eq!(received, b"\x05hello");
+    assert_eq!(stats.unwrap().sent, 6);
+
+    // The other streams are copied.
+    let (mut client, relay_in) = duplex(1024);
+    let (relay_out, mut server) = tcp_pair().await;
+    let (stats, received) = tokio::join!(
+        relay.run(relay_in, relay_out, None),
+        exchange(&mut client, &mut server, b"hello", b"world")
+    );
+    assert_eq!(received, b"hello");
+    assert_eq!(stats.unwrap().received, 5);
+}
@@ -0,0 +1,131 @@
+use std::{collections::HashMap, time::Duration};
+
+use proxy_auth::Identity;
+use proxy_io::{Bandwidth, Throttle};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt},
+    time::Instant,
+};
+
+const KIB: u64 = 1024;
+
+fn download(rate: u64) -> Bandwidth {
+    Bandwidth {
+        upload: None,
+        download: Some(rate),
+    }
+}
+
+/// Writes the bytes to the client through the throttle, returning the time
+/// it takes.
+async fn send(throttle: &Throttle, identity: Option<&Identity>, len: usize) -> Duration {
+    let (client, server) = duplex(64 * 1024);
+    let mut server = throttle.wrap(server, identity);
+    let reader = tokio::spawn(async move {
+        let mut client = client;
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        buf.len()
+    });
+    let start = Instant::now();
+    server.write_all(&vec![0u8; len]).await.unwrap();
+    server.shutdown().await.unwrap();
+    assert_eq!(reader.await.unwrap(), len);
+    start.elapsed()
+}
+
+/// Asserts the elapsed time, give or take the wait for a chunk.
+fn assert_about(elapsed: Duration, secs: u64) {
+    let expected = Duration::from_secs(secs);
+    assert!(
+        elapsed + Duration::from_millis(300) >= expected
+            && elapsed <= expected + Duration::from_millis(300),
+        "{:?} is not about {:?}",
+        elapsed,
+        expected
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn throttle_each_connection() {
+    let throttle = Throttle::new();
+    throttle.connection().set_bandwidth(download(16 * KIB));
+
+    // The first 16KiB is the burst.
+    assert_about(send(&throttle, None, 64 * KIB as usize).await, 3);
+    // Each connection has its own bucket.
+    let (a, b) = tokio::join!(
+        send(&throttle, None, 32 * KIB as usize),
+        send(&throttle, None, 32 * KIB as usize)
+    );
+    assert_about(a, 1);
+    assert_about(b, 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn throttle_remote_reads_as_download() {
+    let throttle = Throttle::new();
+    throttle.connection().set_bandwidth(download(16 * KIB));
+
+    let (remote, server) = duplex(64 * 1024);
+    let mut remote = throttle.wrap_remote(remote, None);
+    let writer = tokio::spawn(async move {
+        let mut server = server;
+        server.write_all(&[0u8; 32 * KIB as usize]).await.unwrap();
+        server
+    });
+    let start = Instant::now();
+    let mut buf = vec![0u8; 32 * KIB as usize];
+    remote.read_exact(&mut buf).await.unwrap();
+    let _server = writer.await.unwrap();
+    // The first 16KiB is the burst.
+    assert_about(start.elapsed(), 1);
+
+    // The upload is not limited.
+    let start = Instant::now();
+    remote.write_all(&buf).await.unwrap();
+    assert_about(start.elapsed(), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn share_user_and_global_limits() {
+    let throttle = Throttle::new();
+    throttle.set_users(HashMap::from([("alice".to_string(), download(16 * KIB))]));
+    let alice = Identity::new("alice".to_string());
+    let bob = Identity::new("bob".to_string());
+
+    // The connections of the same user share the bucket.
+    let (a, b) = tokio::join!(
+        send(&throttle, Some(&alice), 32 * KIB as usize),
+        send(&throttle, Some(&alice), 32 * KIB as usize)
+    );
+    assert_about(a.max(b), 3);
+    assert_about(send(&throttle, Some(&bob), 64 * KIB as usize).await, 0);
+
+    // The other listener shares the global limit.
+    throttle.global().set_bandwidth(download(16 * KIB));
+    let other = throttle.new_listener();
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let (a, b) = tokio::join!(
+        send(&throttle, Some(&bob), 32 * KIB as usize),
+        send(&other, None, 32 * KIB as usize)
+    );
+    assert_about(a.max(b), 3);
+}
+
+#[tokio::test(start_paused = true)]
+async fn change_rate_at_runtime() {
+    let throttle = Throttle::new();
+    throttle.listener().set_bandwidth(download(16 * KIB));
+
+    let change = {
+        let throttle = throttle.clone();
+        async move {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            throttle.listener().set_bandwidth(Bandwidth::default());
+        }
+    };
+    // The connection is not dropped, and the rest is sent at once.
+    let (elapsed, _) = tokio::join!(send(&throttle, None, 1024 * KIB as usize), change);
+    assert_about(elapsed, 2);
+}
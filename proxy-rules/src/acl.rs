@@ -0,0 +1,172 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+};
+
+use crate::{Decision, Policy, Rule};
+
+/// The access control list of a user or a group.
+///
+/// The rules are in the same syntax as the rule sets, like
+/// `DOMAIN-SUFFIX,example.com,DIRECT`, and the destination which does not
+/// match any rule is denied. Unlike the rule sets, `DOMAIN` matches the host
+/// exactly and `DOMAIN-SUFFIX` matches whole labels only.
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    rules: Vec<Rule>,
+    proxy: Option<String>,
+}
+
+impl AccessList {
+    pub fn new(rules: Vec<Rule>) -> AccessList {
+        AccessList { rules, proxy: None }
+    }
+
+    /// Sets the name of the upstream proxy which the destinations matching
+    /// this list are proxied through.
+    pub fn set_proxy(&mut self, proxy: String) {
+        self.proxy = Some(proxy)
+    }
+
+    pub fn proxy(&self) -> Option<&str> {
+        self.proxy.as_deref()
+    }
+
+    fn find(&self, dst: &str) -> Option<Decision> {
+        self.find_resolved(dst, &[])
+    }
+
+    /// Finds the first rule which matches the host, or any of the addresses
+    /// it is resolved to by the IP rules.
+    fn find_resolved(&self, dst: &str, ips: &[IpAddr]) -> Option<Decision> {
+        self.rules
+            .iter()
+            .find(|r| {
+                r.pattern.is_match_host(dst)
+                    || (!r.no_resolve && ips.iter().any(|ip| r.pattern.is_match_ip(*ip)))
+            })
+            .map(|r| r.decision)
+    }
+}
+
+impl Policy for AccessList {
+    fn enforce(&self, dst: &str) -> Decision {
+        self.find(dst).unwrap_or(Decision::Deny)
+    }
+
+    fn needs_resolve(&self, dst: &str) -> bool {
+        if dst.parse::<SocketAddr>().is_ok() {
+            return false;
+        }
+        for rule in &self.rules {
+            if rule.pattern.is_match_host(dst) {
+                return false;
+            }
+            if rule.pattern.is_ip() && !rule.no_resolve {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn enforce_resolved(&self, dst: &str, ips: &[IpAddr]) -> Decision {
+        self.find_resolved(dst, ips).unwrap_or(Decision::Deny)
+    }
+}
+
+/// The access control lists keyed by the user or the group.
+///
+/// The user without any list is not restricted.
+#[derive(Debug, Clone, Default)]
+pub struct AccessControl {
+    users: HashMap<String, AccessList>,
+    groups: HashMap<String, AccessList>,
+}
+
+impl AccessControl {
+    pub fn new() -> AccessControl {
+        AccessControl::default()
+    }
+
+    pub fn add_user(&mut self, username: String, list: AccessList) {
+        self.users.insert(username, list);
+    }
+
+    pub fn add_group(&mut self, group: String, list: AccessList) {
+        self.groups.insert(group, list);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.groups.is_empty()
+    }
+
+    /// Returns the names of the upstream proxies referred by the lists.
+    pub fn proxies(&self) -> impl Iterator<Item = &str> {
+        self.users
+            .values()
+            .chain(self.groups.values())
+            .filter_map(|l| l.proxy())
+    }
+
+    /// Returns the list of the user, then the lists of its groups in order.
+    fn lists<'a: 'b, 'b>(
+        &'a self,
+        username: &str,
+        groups: &'b [String],
+    ) -> impl Iterator<Item = &'a AccessList> + 'b {
+        self.users
+            .get(username)
+            .into_iter()
+            .chain(groups.iter().filter_map(|g| self.groups.get(g)))
+    }
+
+    /// Enforces the lists of the user and its groups for the destination,
+    /// returning the decision and the upstream proxy of the list which
+    /// makes the decision.
+    ///
+    /// The list of the user is checked first, then the lists of the groups
+    /// in order, and the first matching rule wins. The destination which
+    /// matches none of them is denied. Returns `None` if neither the user
+    /// nor its groups have a list.
+    pub fn enforce(
+        &self,
+        username: &str,
+        groups: &[String],
+        dst: &str,
+    ) -> Option<(Decision, Option<&str>)> {
+        self.enforce_resolved(username, groups, dst, &[])
+    }
+
+    /// Returns whether the domain of the destination should be resolved for
+    /// the IP rules, which is the case if one is reached before any other
+    /// rule of the lists matches.
+    pub fn needs_resolve(&self, username: &str, groups: &[String], dst: &str) -> bool {
+        for list in self.lists(username, groups) {
+            if list.needs_resolve(dst) {
+                return true;
+            }
+            if list.find(dst).is_some() {
+                return false;
+            }
+        }
+        false
+    }
+
+    /// Enforces the lists like [`AccessControl::enforce`], for the
+    /// destination whose domain is resolved to the IP addresses.
+    pub fn enforce_resolved(
+        &self,
+        username: &str,
+        groups: &[String],
+        dst: &str,
+        ips: &[IpAddr],
+    ) -> Option<(Decision, Option<&str>)> {
+        let mut lists = self.lists(username, groups).peekable();
+        lists.peek()?;
+        Some(
+            lists
+                .find_map(|l| l.find_resolved(dst, ips).map(|d| (d, l.proxy())))
+                .unwrap_or((Decision::Deny, None)),
+        )
+    }
+}
@@ -1,12 +1,16 @@
 use std::{
     fmt,
-    net::{IpAddr, SocketAddrV4, SocketAddrV6},
+    net::{IpAddr, SocketAddr},
     sync::Arc,
 };
 
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+mod acl;
+
+pub use acl::*;
+
 /// Decision is the result for policy enforcement.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(untagged)]
@@ -64,11 +68,38 @@ pub trait Policy {
     ///
     /// Returns the decision for the Switch or Router to decide which proxy to use.
     fn enforce(&self, dst: &str) -> Decision;
+
+    /// Returns whether the domain of the destination should be resolved
+    /// before enforcing, which is the case if an IP rule is reached before
+    /// any other rule matches.
+    fn needs_resolve(&self, _dst: &str) -> bool {
+        false
+    }
+
+    /// Try to enforce the rules for the destination whose domain is resolved
+    /// to the IP addresses, so that the IP rules can match the domain.
+    fn enforce_resolved(&self, dst: &str, _ips: &[IpAddr]) -> Decision {
+        self.enforce(dst)
+    }
 }
 
 impl<P: Policy> Policy for Vec<P> {
     fn enforce(&self, dst: &str) -> Decision {
-        for p in self {
+        self.as_slice().enforce(dst)
+    }
+
+    fn needs_resolve(&self, dst: &str) -> bool {
+        self.as_slice().needs_resolve(dst)
+    }
+
+    fn enforce_resolved(&self, dst: &str, ips: &[IpAddr]) -> Decision {
+        self.as_slice().enforce_resolved(dst, ips)
+    }
+}
+
+impl<P: Policy> Policy for &[P] {
+    fn enforce(&self, dst: &str) -> Decision {
+        for p in *self {
             let d = p.enforce(dst);
             if !d.is_default() {
                 return d;
@@ -76,12 +107,22 @@ impl<P: Policy> Policy for Vec<P> {
         }
         Decision::Default
     }
-}
 
-impl<P: Policy> Policy for &[P] {
-    fn enforce(&self, dst: &str) -> Decision {
+    fn needs_resolve(&self, dst: &str) -> bool {
         for p in *self {
-            let d = p.enforce(dst);
+            if p.needs_resolve(dst) {
+                return true;
+            }
+            if !p.enforce(dst).is_default() {
+                return false;
+            }
+        }
+        false
+    }
+
+    fn enforce_resolved(&self, dst: &str, ips: &[IpAddr]) -> Decision {
+        for p in *self {
+            let d = p.enforce_resolved(dst, ips);
             if !d.is_default() {
                 return d;
             }
@@ -94,6 +135,14 @@ impl<P: Policy> Policy for Arc<P> {
     fn enforce(&self, dst: &str) -> Decision {
         self.as_ref().enforce(dst)
     }
+
+    fn needs_resolve(&self, dst: &str) -> bool {
+        self.as_ref().needs_resolve(dst)
+    }
+
+    fn enforce_resolved(&self, dst: &str, ips: &[IpAddr]) -> Decision {
+        self.as_ref().enforce_resolved(dst, ips)
+    }
 }
 
 impl Policy for (Pattern, Decision) {
@@ -153,96 +202,105 @@ impl Pattern {
     pub fn is_match(&self, dst: &str) -> bool {
         match self {
             Pattern::Exact(domain) => dst.starts_with(domain),
-            Pattern::Suffix(suffix) => dst.ends_with(suffix),
+            // The destination carries the port, which no suffix ends with.
+            Pattern::Suffix(suffix) => host(dst).ends_with(suffix),
             Pattern::Regex(reg) => reg.is_match(dst),
             Pattern::Keyword(keyword) => dst.contains(keyword),
-            Pattern::IpExact(ip_addr) => match ip_addr {
-                IpAddr::V4(v4) => {
-                    if let Ok(addr) = dst.parse::<SocketAddrV4>() {
-                        addr.ip() == v4
-                    } else {
-                        false
-                    }
-                }
-                IpAddr::V6(v6) => {
-                    if let Ok(addr) = dst.parse::<SocketAddrV6>() {
-                        addr.ip() == v6
-                    } else {
-                        false
-                    }
-                }
-            },
-            Pattern::IpCIDR { ip_addr, mask } => match ip_addr {
-                IpAddr::V4(_) => {
-                    if let Ok(addr) = dst.parse::<SocketAddrV4>() {
-                        is_subnet(IpAddr::V4(*addr.ip()), *ip_addr, *mask)
-                    } else {
-                        false
-                    }
-                }
-                IpAddr::V6(_) => {
-                    if let Ok(addr) = dst.parse::<SocketAddrV6>() {
-                        is_subnet(IpAddr::V6(*addr.ip()), *ip_addr, *mask)
-                    } else {
-                        false
-                    }
-                }
+            Pattern::IpExact(_) | Pattern::IpCIDR { .. } => match dst.parse::<SocketAddr>() {
+                Ok(addr) => self.is_match_ip(addr.ip()),
+                Err(_) => false,
             },
         }
     }
+
+    /// Returns whether the pattern matches the host of the destination as a
+    /// whole, ignoring the case, so that `DOMAIN,a.com` never matches
+    /// `a.com.evil.com` and `DOMAIN-SUFFIX,a.com` never matches `evila.com`.
+    ///
+    /// The access control lists match by it, where the loose match of the
+    /// rule sets would let the other destinations through.
+    pub fn is_match_host(&self, dst: &str) -> bool {
+        let host = host(dst).trim_end_matches('.');
+        match self {
+            Pattern::Exact(domain) => host.eq_ignore_ascii_case(domain),
+            Pattern::Suffix(suffix) => is_subdomain(host, suffix.trim_start_matches('.')),
+            _ => self.is_match(dst),
+        }
+    }
+
+    /// Returns whether the pattern matches the IP address, which is only
+    /// for the IP patterns.
+    pub fn is_match_ip(&self, ip: IpAddr) -> bool {
+        match self {
+            Pattern::IpExact(ip_addr) => *ip_addr == ip,
+            Pattern::IpCIDR { ip_addr, mask } => is_subnet(ip, *ip_addr, *mask),
+            _ => false,
+        }
+    }
+
+    pub fn is_ip(&self) -> bool {
+        matches!(self, Pattern::IpExact(_) | Pattern::IpCIDR { .. })
+    }
 }
 
-fn is_subnet(ip: IpAddr, subnet: IpAddr, mask: usize) -> bool {
-    match subnet {
-        IpAddr::V4(v4) => match ip {
-            IpAddr::V4(ipv4) => {
-                let sub_mask = ipv4_subnet_mask(mask);
-                let sub_net = v4.octets();
-                let net = ipv4.octets();
-                net[0] & sub_mask[0] == sub_net[0] & sub_mask[0]
-                    && net[1] & sub_mask[1] == sub_net[1] & sub_mask[1]
-                    && net[2] & sub_mask[2] == sub_net[2] & sub_mask[2]
-                    && net[3] & sub_mask[3] == sub_net[3] & sub_mask[3]
-            }
-            IpAddr::V6(_) => false,
-        },
-        IpAddr::V6(_) => unimplemented!(),
+/// Returns the host of the destination without the port.
+fn host(dst: &str) -> &str {
+    match dst.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => dst,
     }
 }
 
-fn ipv4_subnet_mask(mask: usize) -> [u8; 4] {
-    let n = mask / 8;
-    let m = mask % 8;
-    let mut sub_mask = match n {
-        0 => [0u8, 0, 0, 0],
-        1 => [0xff, 0, 0, 0],
-        2 => [0xff, 0xff, 0, 0],
-        3 => [0xff, 0xff, 0xff, 0],
-        _ => return [0xff, 0xff, 0xff, 0xff],
+/// Returns whether the host is the domain or any subdomain of it.
+fn is_subdomain(host: &str, domain: &str) -> bool {
+    let start = match host.len().checked_sub(domain.len()) {
+        Some(start) => start,
+        None => return false,
     };
-    match m {
-        0 => sub_mask[n + 1] = 0,
-        1 => sub_mask[n + 1] = 0x80,
-        2 => sub_mask[n + 1] = 0xc0,
-        3 => sub_mask[n + 1] = 0xe0,
-        4 => sub_mask[n + 1] = 0xf0,
-        5 => sub_mask[n + 1] = 0xf8,
-        6 => sub_mask[n + 1] = 0xfc,
-        7 => sub_mask[n + 1] = 0xfe,
-        _ => unreachable!(),
-    }
-    sub_mask
+    match host.get(start..) {
+        Some(tail) if tail.eq_ignore_ascii_case(domain) => {
+            start == 0 || host.as_bytes()[start - 1] == b'.'
+        }
+        _ => false,
+    }
+}
+
+/// Returns whether the ip is in the subnet of the mask length, for both
+/// families, where the prefixes longer than the address match it exactly.
+fn is_subnet(ip: IpAddr, subnet: IpAddr, mask: usize) -> bool {
+    match (ip, subnet) {
+        (IpAddr::V4(ip), IpAddr::V4(subnet)) => {
+            let mask = u32::MAX
+                .checked_shl(32u32.saturating_sub(mask as u32))
+                .unwrap_or(0);
+            u32::from(ip) & mask == u32::from(subnet) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(subnet)) => {
+            let mask = u128::MAX
+                .checked_shl(128u32.saturating_sub(mask as u32))
+                .unwrap_or(0);
+            u128::from(ip) & mask == u128::from(subnet) & mask
+        }
+        _ => false,
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Rule {
     pub pattern: Pattern,
     pub decision: Decision,
+    /// Never resolves the domain to match the IP pattern, which is the
+    /// `no-resolve` option of the rule.
+    pub no_resolve: bool,
 }
 
 impl Rule {
     pub fn new(pattern: Pattern, decision: Decision) -> Rule {
-        Rule { pattern, decision }
+        Rule {
+            pattern,
+            decision,
+            no_resolve: false,
+        }
     }
 }
 
@@ -254,6 +312,20 @@ impl Policy for Rule {
             Decision::Default
         }
     }
+
+    fn needs_resolve(&self, dst: &str) -> bool {
+        !self.no_resolve && self.pattern.is_ip() && dst.parse::<SocketAddr>().is_err()
+    }
+
+    fn enforce_resolved(&self, dst: &str, ips: &[IpAddr]) -> Decision {
+        if self.pattern.is_match(dst)
+            || (!self.no_resolve && ips.iter().any(|ip| self.pattern.is_match_ip(*ip)))
+        {
+            self.decision
+        } else {
+            Decision::Default
+        }
+    }
 }
 
 impl std::str::FromStr for Rule {
@@ -291,7 +363,7 @@ impl std::str::FromStr for Rule {
         } else if tag.eq_ignore_ascii_case("IPV4") || tag.eq_ignore_ascii_case("IPV6") {
             let addr = pat.unwrap().parse::<IpAddr>()?;
             Pattern::IpExact(addr)
-        } else if tag.eq_ignore_ascii_case("IP-CIDR") {
+        } else if tag.eq_ignore_ascii_case("IP-CIDR") || tag.eq_ignore_ascii_case("IP-CIDR6") {
             let slice = pat.unwrap();
             if let Some(n) = slice.find('/') {
                 let addr = &slice[..n];
@@ -323,17 +395,27 @@ impl std::str::FromStr for Rule {
             return Err(anyhow::anyhow!("unknown decision: {}", dec));
         };
 
-        Ok(Self { pattern, decision })
+        let no_resolve = args.iter().any(|i| i.eq_ignore_ascii_case("no-resolve"));
+        Ok(Self {
+            pattern,
+            decision,
+            no_resolve,
+        })
     }
 }
 
 impl fmt::Display for Rule {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{},{}", &self.pattern, &self.decision)
+        write!(f, "{},{}", self.pattern, self.decision)?;
+        if self.no_resolve {
+            f.write_str(",no-resolve")?;
+        }
+        Ok(())
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "RuleSetDef")]
 pub struct RuleSet {
     pub name: Option<String>,
     pub rules: Vec<String>,
@@ -341,6 +423,32 @@ pub struct RuleSet {
     parsed: Vec<Rule>,
 }
 
+/// The rule set as it is written, whose rules are parsed when it is
+/// deserialized. Deserializing `RuleSet` directly would leave the skipped
+/// `parsed` empty, so that none of the rules loaded from the file matched.
+#[derive(Deserialize)]
+struct RuleSetDef {
+    name: Option<String>,
+    rules: Vec<String>,
+}
+
+impl TryFrom<RuleSetDef> for RuleSet {
+    type Error = anyhow::Error;
+
+    fn try_from(def: RuleSetDef) -> Result<Self, Self::Error> {
+        let parsed = def
+            .rules
+            .iter()
+            .map(|r| r.parse())
+            .collect::<anyhow::Result<Vec<Rule>>>()?;
+        Ok(RuleSet {
+            name: def.name,
+            rules: def.rules,
+            parsed,
+        })
+    }
+}
+
 impl RuleSet {
     pub fn new(name: String, rules: Vec<Rule>) -> RuleSet {
         RuleSet {
@@ -355,6 +463,14 @@ impl Policy for RuleSet {
     fn enforce(&self, dst: &str) -> Decision {
         self.parsed.enforce(dst)
     }
+
+    fn needs_resolve(&self, dst: &str) -> bool {
+        self.parsed.needs_resolve(dst)
+    }
+
+    fn enforce_resolved(&self, dst: &str, ips: &[IpAddr]) -> Decision {
+        self.parsed.enforce_resolved(dst, ips)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -366,4 +482,12 @@ impl Policy for Rules {
     fn enforce(&self, dst: &str) -> Decision {
         self.rules.enforce(dst)
     }
+
+    fn needs_resolve(&self, dst: &str) -> bool {
+        self.rules.needs_resolve(dst)
+    }
+
+    fn enforce_resolved(&self, dst: &str, ips: &[IpAddr]) -> Decision {
+        self.rules.enforce_resolved(dst, ips)
+    }
 }
@@ -1,12 +1,14 @@
 use std::io;
 use std::net::SocketAddr;
 use std::task::Poll;
+use std::time::Duration;
 use std::{future::Future, task::Context};
 
 use log::{debug, error};
 use proxy::Service;
-use proxy_io::{ProxyStream, TargetAddr, TokioConnect};
-use proxy_tunnel::client::HttpConnector;
+use proxy_io::{
+    ProxyStream, TargetAddr, TokioConnect, PROXY_HANDSHAKE_TIMEOUT, TLS_HANDSHAKE_TIMEOUT,
+};
 use tokio::net::TcpStream;
 
 use crate::config::{Authorization, Proxy};
@@ -14,21 +16,48 @@ use crate::config::{Authorization, Proxy};
 #[derive(Debug, Clone)]
 pub struct Client {
     proxy: Option<Proxy>,
+    tls_timeout: Duration,
+    handshake_timeout: Duration,
+    connect: TokioConnect,
 }
 
 impl Client {
-    #[allow(dead_code)]
     pub fn new(proxy: Proxy) -> Client {
-        Client { proxy: Some(proxy) }
+        Client {
+            proxy: Some(proxy),
+            tls_timeout: TLS_HANDSHAKE_TIMEOUT,
+            handshake_timeout: PROXY_HANDSHAKE_TIMEOUT,
+            connect: TokioConnect::new(),
+        }
     }
 
     pub fn empty() -> Client {
-        Client { proxy: None }
+        Client {
+            proxy: None,
+            tls_timeout: TLS_HANDSHAKE_TIMEOUT,
+            handshake_timeout: PROXY_HANDSHAKE_TIMEOUT,
+            connect: TokioConnect::new(),
+        }
     }
 
     pub fn set_proxy(&mut self, proxy: Proxy) {
         self.proxy = Some(proxy)
     }
+
+    /// Sets the connect to the proxy server, which resolves its host.
+    pub fn set_connect(&mut self, connect: TokioConnect) {
+        self.connect = connect
+    }
+
+    /// Sets the time of the TLS handshake with the HTTPS proxy.
+    pub fn set_tls_timeout(&mut self, timeout: Duration) {
+        self.tls_timeout = timeout
+    }
+
+    /// Sets the time of the SOCKS or HTTP CONNECT handshake with the proxy.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout
+    }
 }
 
 impl Service<TargetAddr> for Client {
@@ -52,6 +81,10 @@ impl Service<TargetAddr> for Client {
             .proxy
             .clone()
             .expect("the proxy target does not setup, please check you configuration firstly");
+        let connect = self.connect.clone();
+        let resolver = self.connect.resolver().clone();
+        let tls_timeout = self.tls_timeout;
+        let handshake_timeout = self.handshake_timeout;
         Box::pin(async move {
             debug!(
                 "try to proxy {} with {}://{}:{}",
@@ -59,20 +92,34 @@ impl Service<TargetAddr> for Client {
             );
             let target = parse_target(&proxy.host, proxy.port);
             if proxy.scheme.eq_ignore_ascii_case("socks5") {
-                let mut connect = proxy_socks::client::Client::new(target, TokioConnect::new());
+                let mut connect = proxy_socks::client::Client::new(target, connect);
                 if let Some(Authorization::Basic { username, password }) = proxy.authorization {
                     connect.set_authorization(username, password);
                 }
-
+                connect.set_handshake_timeout(handshake_timeout);
+                connect.call(req).await
+            } else if proxy.scheme.eq_ignore_ascii_case("socks4")
+                || proxy.scheme.eq_ignore_ascii_case("socks4a")
+            {
+                let mut connect = proxy_socks::client::Socks4Client::new(target, connect);
+                // SOCKS4 has no password, so the username is sent as the USERID.
+                if let Some(Authorization::Basic { username, .. }) = proxy.authorization {
+                    connect.set_user_id(username);
+                }
+                connect.set_remote_dns(proxy.scheme.eq_ignore_ascii_case("socks4a"));
+                connect.set_resolver(resolver);
+                connect.set_handshake_timeout(handshake_timeout);
                 connect.call(req).await
             } else {
-                let mut connect = proxy_tunnel::client::Client::new(target, HttpConnector::new());
+                let mut connect = proxy_tunnel::client::Client::new(target, connect);
                 if let Some(Authorization::Basic { username, password }) = proxy.authorization {
                     connect.set_authorization(username, password);
                 }
                 if proxy.scheme.eq_ignore_ascii_case("https") {
                     connect.enable_tls()
                 }
+                connect.set_tls_timeout(tls_timeout);
+                connect.set_handshake_timeout(handshake_timeout);
                 connect.call(req).await
             }
         })
@@ -1,16 +1,690 @@
-use std::path::PathBuf;
+use std::{
+    collections::BTreeMap, io, net::IpAddr, num::NonZeroUsize, path::PathBuf, sync::Arc,
+    time::Duration,
+};
 
 use etcetera::base_strategy::{choose_base_strategy, BaseStrategy};
-use log::info;
+use ipnet::IpNet;
+use log::{info, warn};
+use proxy_auth::{
+    Authenticators, Cached, CommandAuthenticator, Htpasswd, HttpAuthenticator, Password, Users,
+};
+use proxy_io::{
+    Bandwidth, DnsCache, DnsClient, DuplexTimeouts, FakeIp, Hosts, Nameserver, Resolver,
+    ServiceDial, SystemResolver, TokioConnect, CLIENT_HANDSHAKE_TIMEOUT, CONNECT_TIMEOUT,
+    PROXY_HANDSHAKE_TIMEOUT, TLS_HANDSHAKE_TIMEOUT,
+};
+use proxy_rules::{AccessControl, AccessList, Rule};
 use serde::{Deserialize, Serialize};
+use tokio_native_tls::native_tls::{Certificate, TlsConnector};
+
+use crate::{
+    client::Client,
+    limit::{Connections, Guard, Throttles},
+};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Config {
-    pub http_listen: String,
-    pub socks5_listen: String,
+    pub http_listen: Option<String>,
+    pub socks5_listen: Option<String>,
+    /// Serves both SOCKS and HTTP on the same address.
+    pub mixed_listen: Option<String>,
     pub proxy_mode: ProxyMode,
     pub proxy: String,
+    /// The `/etc/hosts`-style file which maps the hosts to the IP addresses.
+    pub hosts_file: Option<String>,
     pub proxies: Vec<Proxy>,
+    #[serde(default)]
+    pub auth: ListenAuth,
+    #[serde(default)]
+    pub acl: Acl,
+    #[serde(default)]
+    pub limit: ListenLimit,
+    #[serde(default)]
+    pub dns: Dns,
+    /// Maps the hosts or the wildcards like `*.dev.internal` to the IP
+    /// addresses or the other domains, which wins over the hosts file.
+    #[serde(default)]
+    pub hosts: BTreeMap<String, String>,
+    /// Answers the DNS queries with the fake addresses, which are translated
+    /// back to the domains when they are connected.
+    pub fake_dns: Option<FakeDns>,
+    #[serde(default)]
+    pub timeout: Timeouts,
+    /// Limits the bandwidth of the relayed connections, which is reloaded
+    /// once the config file changes.
+    pub throttle: Option<ThrottleConfig>,
+}
+
+impl Config {
+    /// Loads the static mapping of the hosts.
+    pub fn load_hosts(&self) -> anyhow::Result<Hosts> {
+        let mut hosts = match &self.hosts_file {
+            Some(file) => Hosts::load(shellexpand::tilde(file).as_ref())
+                .map_err(|e| anyhow::anyhow!("unable to load hosts from {}, {}", file, e))?,
+            None => Hosts::new(),
+        };
+        for (pattern, mapping) in &self.hosts {
+            hosts.insert(pattern, mapping.parse()?);
+        }
+        Ok(hosts)
+    }
+}
+
+/// The local DNS server answering the fake addresses.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FakeDns {
+    /// The address to serve both UDP and TCP, like `127.0.0.1:5353`.
+    pub listen: String,
+    /// The pool of the A records, `198.18.0.0/15` by default.
+    pub ipv4_pool: Option<String>,
+    /// The pool of the AAAA records, which are answered without records if
+    /// it is not set.
+    pub ipv6_pool: Option<String>,
+    /// The maximum number of the domains in each pool.
+    pub size: Option<usize>,
+    /// The file to persist the domains behind the fake addresses, the
+    /// `fakeip` file in the cache directory by default.
+    pub cache_file: Option<String>,
+}
+
+impl FakeDns {
+    /// Builds the pools, restoring the domains from the cache file.
+    pub fn load(&self) -> anyhow::Result<FakeIp> {
+        let mut nets = vec![self
+            .ipv4_pool
+            .as_deref()
+            .unwrap_or("198.18.0.0/15")
+            .parse::<IpNet>()?];
+        if let Some(pool) = &self.ipv6_pool {
+            nets.push(pool.parse()?);
+        }
+        let mut fake_ip = FakeIp::new(nets);
+        if let Some(size) = self.size.and_then(NonZeroUsize::new) {
+            fake_ip.set_capacity(size);
+        }
+        let file = self.cache_file();
+        match fake_ip.load(&file) {
+            Ok(()) => info!("loaded fake ip from {}", file.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("unable to load fake ip from {}, {}", file.display(), e),
+        }
+        Ok(fake_ip)
+    }
+
+    pub fn cache_file(&self) -> PathBuf {
+        match &self.cache_file {
+            Some(file) => PathBuf::from(shellexpand::tilde(file).as_ref()),
+            None => cache_dir().join("fakeip"),
+        }
+    }
+}
+
+/// The inbound authentication for each listener, the listener without
+/// authentication accepts any client.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListenAuth {
+    pub socks5: Option<Auth>,
+    pub http: Option<Auth>,
+    pub mixed: Option<Auth>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Auth {
+    /// The htpasswd file with a `username:password` pair per line, the
+    /// password is either plaintext or hashed by bcrypt, argon2 or SHA-1.
+    pub file: Option<String>,
+    /// The interval in seconds to check whether the file changes.
+    pub reload_interval: Option<u64>,
+    /// The checkpassword-style command, which reads `username\0password\0`
+    /// from the stdin and exits with 0 to accept the user.
+    pub command: Option<Vec<String>>,
+    /// The HTTP endpoint which the credentials are posted to as JSON, the
+    /// user is accepted if it responds with 2xx.
+    pub endpoint: Option<String>,
+    /// The timeout in seconds of the command and the endpoint.
+    pub timeout: Option<u64>,
+    /// The time in seconds to cache the results of the command and the
+    /// endpoint.
+    pub cache_ttl: Option<u64>,
+    #[serde(default)]
+    pub users: Vec<User>,
+    /// The members of each group, which is assigned to the identity of the
+    /// authenticated user.
+    #[serde(default)]
+    pub groups: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct User {
+    pub username: String,
+    /// The password in the same formats as the htpasswd file.
+    pub password: String,
+}
+
+impl Auth {
+    /// Builds the authenticator accepting the users from the users list, the
+    /// htpasswd file which is reloaded once it changes, the command and the
+    /// HTTP endpoint, in that order.
+    pub fn load(&self) -> io::Result<Authenticators> {
+        let mut authenticators = Authenticators::new();
+        if !self.users.is_empty() {
+            let mut users = Users::new();
+            for user in &self.users {
+                let password = Password::parse(&user.password).map_err(|e| {
+                    io::Error::new(e.kind(), format!("{} for {}", e, user.username))
+                })?;
+                users.insert_password(user.username.clone(), password);
+            }
+            info!("loaded {} users", users.len());
+            authenticators.push(users);
+        }
+        if let Some(file) = &self.file {
+            let mut htpasswd = Htpasswd::load(shellexpand::tilde(file).as_ref())?;
+            if let Some(interval) = self.reload_interval {
+                htpasswd.set_reload_interval(Duration::from_secs(interval));
+            }
+            info!("loaded users from {}", htpasswd.path().display());
+            authenticators.push(htpasswd);
+        }
+        if let Some(command) = &self.command {
+            let (program, args) = command.split_first().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "the command is empty")
+            })?;
+            let mut command =
+                CommandAuthenticator::new(shellexpand::tilde(program).into_owned(), args.to_vec());
+            if let Some(timeout) = self.timeout {
+                command.set_timeout(Duration::from_secs(timeout));
+            }
+            info!("authenticate users by {}", program);
+            match self.cache_ttl {
+                Some(ttl) => authenticators.push(Cached::new(command, Duration::from_secs(ttl))),
+                None => authenticators.push(command),
+            }
+        }
+        if let Some(endpoint) = &self.endpoint {
+            let uri = endpoint
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let mut http = HttpAuthenticator::new(uri);
+            if let Some(timeout) = self.timeout {
+                http.set_timeout(Duration::from_secs(timeout));
+            }
+            info!("authenticate users by {}", endpoint);
+            match self.cache_ttl {
+                Some(ttl) => authenticators.push(Cached::new(http, Duration::from_secs(ttl))),
+                None => authenticators.push(http),
+            }
+        }
+        if authenticators.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no users for the authentication",
+            ));
+        }
+        for (group, members) in &self.groups {
+            for member in members {
+                authenticators.add_group(group.clone(), member.clone());
+            }
+        }
+        Ok(authenticators)
+    }
+}
+
+/// The nameservers to resolve the domains, the system resolver is used if
+/// there are no nameservers.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Dns {
+    /// The nameservers like `8.8.8.8`, `tcp://8.8.8.8:53`, `tls://dns.google`
+    /// or `https://dns.google/dns-query`.
+    #[serde(default)]
+    pub nameservers: Vec<String>,
+    /// The transport of the nameservers without the scheme.
+    #[serde(default)]
+    pub protocol: DnsProtocol,
+    /// The timeout in seconds of each query.
+    pub timeout: Option<u64>,
+    /// Queries all the nameservers at the same time, the first response wins.
+    #[serde(default)]
+    pub race: bool,
+    /// The PEM file of the root certificate which the DNS-over-TLS and
+    /// DNS-over-HTTPS nameservers are trusted by.
+    pub ca: Option<String>,
+    /// The name of the proxy which the queries over TCP, TLS and HTTPS are
+    /// sent through, the nameservers without the scheme are queried over TCP
+    /// then, and the ones over UDP are refused.
+    pub proxy: Option<String>,
+    /// The delay in milliseconds between the attempts to connect the
+    /// addresses of the host, which is 250 by default.
+    pub happy_eyeballs_delay: Option<u64>,
+    #[serde(default)]
+    pub cache: DnsCacheConfig,
+    /// Serves the DNS queries of the other applications, so that they get
+    /// the same answers as the connections routed by the rules.
+    pub forward: Option<DnsForward>,
+}
+
+/// The cache of the resolved addresses, the durations are in seconds.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DnsCacheConfig {
+    /// The maximum number of the hosts, zero disables the cache.
+    pub size: Option<usize>,
+    pub min_ttl: Option<u64>,
+    pub max_ttl: Option<u64>,
+    /// The maximum TTL of the hosts without addresses.
+    pub negative_ttl: Option<u64>,
+    /// Resolves the hot hosts again before they expire, which is enabled by
+    /// default.
+    pub prefetch: Option<bool>,
+}
+
+impl DnsCacheConfig {
+    fn load<R>(&self, mut cache: DnsCache<R>) -> DnsCache<R> {
+        if let Some(size) = self.size.and_then(NonZeroUsize::new) {
+            cache.set_capacity(size);
+        }
+        if let Some(ttl) = self.min_ttl {
+            cache.set_min_ttl(Duration::from_secs(ttl));
+        }
+        if let Some(ttl) = self.max_ttl {
+            cache.set_max_ttl(Duration::from_secs(ttl));
+        }
+        if let Some(ttl) = self.negative_ttl {
+            cache.set_negative_ttl(Duration::from_secs(ttl));
+        }
+        if let Some(prefetch) = self.prefetch {
+            cache.set_prefetch(prefetch);
+        }
+        cache
+    }
+}
+
+/// The local DNS forwarder, the domains routed to the proxy are resolved by
+/// the remote nameservers through the proxy, and the others by the
+/// nameservers above.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DnsForward {
+    /// The address to serve both UDP and TCP, like `127.0.0.1:53`.
+    pub listen: String,
+    /// The remote nameservers queried over TCP unless the scheme is given,
+    /// `8.8.8.8` by default.
+    #[serde(default)]
+    pub nameservers: Vec<String>,
+}
+
+impl DnsForward {
+    /// Builds the cached resolver of the remote nameservers, which are
+    /// dialed through the proxy, or directly without the proxy.
+    pub fn load(
+        &self,
+        proxy: Option<&Proxy>,
+        direct: TokioConnect,
+        cache: &DnsCacheConfig,
+        timeout: &Timeouts,
+    ) -> anyhow::Result<Box<dyn Resolver>> {
+        let nameservers = if self.nameservers.is_empty() {
+            vec!["8.8.8.8".to_string()]
+        } else {
+            self.nameservers.clone()
+        };
+        let nameservers = nameservers
+            .iter()
+            .map(|n| {
+                let nameserver = if n.contains("://") {
+                    n.parse::<Nameserver>()
+                } else {
+                    format!("tcp://{}", n).parse()
+                };
+                match nameserver {
+                    Ok(Nameserver::Udp(_)) => Err(anyhow::anyhow!(
+                        "invalid nameserver {}, which must not be over UDP",
+                        n
+                    )),
+                    Ok(nameserver) => Ok(nameserver),
+                    Err(e) => Err(anyhow::anyhow!("invalid nameserver {}, {}", n, e)),
+                }
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut client = DnsClient::new(nameservers);
+        match proxy {
+            Some(proxy) => {
+                let mut upstream = Client::new(proxy.clone());
+                upstream.set_connect(direct);
+                timeout.apply(&mut upstream);
+                client.set_dial(ServiceDial::new(upstream));
+            }
+            None => client.set_dial(ServiceDial::new(direct)),
+        }
+        info!("forward the proxied domains to {:?}", client.nameservers());
+        if cache.size == Some(0) {
+            return Ok(Box::new(client));
+        }
+        Ok(Box::new(cache.load(DnsCache::new(client))))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DnsProtocol {
+    /// Queries over UDP, and retries over TCP if the response is truncated.
+    #[default]
+    #[serde(rename = "udp")]
+    Udp,
+    #[serde(rename = "tcp")]
+    Tcp,
+}
+
+impl Dns {
+    /// Builds the connect which resolves the domains by the nameservers.
+    pub fn load(&self, proxies: &[Proxy], timeout: &Timeouts) -> anyhow::Result<TokioConnect> {
+        let mut connect = TokioConnect::new();
+        if let Some(delay) = self.happy_eyeballs_delay {
+            connect.set_happy_eyeballs_delay(Duration::from_millis(delay));
+        }
+        connect.set_connect_timeout(timeout.connect());
+        let cached = self.cache.size != Some(0);
+        if self.nameservers.is_empty() {
+            if cached {
+                connect.set_resolver(self.cache.load(DnsCache::system()));
+            } else {
+                connect.set_resolver(SystemResolver::new());
+            }
+            return Ok(connect);
+        }
+        let mut client = DnsClient::new(self.load_nameservers()?);
+        client.set_race(self.race);
+        if let Some(timeout) = self.timeout {
+            client.set_timeout(Duration::from_secs(timeout));
+        }
+        if let Some(ca) = &self.ca {
+            let pem = std::fs::read(shellexpand::tilde(ca).as_ref())
+                .map_err(|e| anyhow::anyhow!("unable to read {}, {}", ca, e))?;
+            let connector = TlsConnector::builder()
+                .add_root_certificate(Certificate::from_pem(&pem)?)
+                .build()?;
+            client.set_tls_connector(connector);
+        }
+        if let Some(name) = &self.proxy {
+            let proxy = proxies
+                .iter()
+                .find(|p| p.name.eq_ignore_ascii_case(name))
+                .ok_or_else(|| anyhow::anyhow!("no proxy {} for the nameservers", name))?;
+            // The proxy itself is connected by the system resolver, as the
+            // nameservers are only reachable through it.
+            let mut upstream = Client::new(proxy.clone());
+            upstream.set_connect(connect.clone());
+            timeout.apply(&mut upstream);
+            client.set_dial(ServiceDial::new(upstream));
+        }
+        info!("resolve domains by {:?}", client.nameservers());
+        if cached {
+            connect.set_resolver(self.cache.load(DnsCache::new(client)));
+        } else {
+            connect.set_resolver(client);
+        }
+        Ok(connect)
+    }
+
+    /// Parses the nameservers, which are over TCP without the scheme if the
+    /// protocol is TCP or the queries are sent through the proxy.
+    fn load_nameservers(&self) -> anyhow::Result<Vec<Nameserver>> {
+        let tcp = self.protocol == DnsProtocol::Tcp || self.proxy.is_some();
+        self.nameservers
+            .iter()
+            .map(|n| {
+                let nameserver = if tcp && !n.contains("://") {
+                    format!("tcp://{}", n)
+                } else {
+                    n.clone()
+                };
+                match nameserver.parse::<Nameserver>() {
+                    Ok(Nameserver::Udp(_)) if self.proxy.is_some() => Err(anyhow::anyhow!(
+                        "invalid nameserver {}, which must not be over UDP through the proxy",
+                        n
+                    )),
+                    Ok(nameserver) => Ok(nameserver),
+                    Err(e) => Err(anyhow::anyhow!("invalid nameserver {}, {}", n, e)),
+                }
+            })
+            .collect()
+    }
+}
+
+/// The timeouts in seconds, the handshakes and the connects time out in 10
+/// seconds by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timeouts {
+    /// The time to connect the host or the proxy, including resolving it.
+    pub connect: Option<u64>,
+    /// The time of the TLS handshake with the HTTPS proxy.
+    pub tls_handshake: Option<u64>,
+    /// The time of the SOCKS or HTTP CONNECT handshake with the proxy.
+    pub proxy_handshake: Option<u64>,
+    /// The time for the clients to send the SOCKS handshake or the HTTP
+    /// request head.
+    pub client_handshake: Option<u64>,
+    /// Closes the relayed connections without any byte in either direction
+    /// for the time, which never happens by default. The connections which
+    /// the plain HTTP requests keep alive to the origins are closed once
+    /// idle between the requests for the time.
+    pub idle: Option<u64>,
+    /// Closes the relayed connections living for the time, however active
+    /// they are, which never happens by default. So are the connections of
+    /// the plain HTTP requests to the origins.
+    pub max_lifetime: Option<u64>,
+}
+
+impl Timeouts {
+    pub fn connect(&self) -> Duration {
+        self.connect
+            .map(Duration::from_secs)
+            .unwrap_or(CONNECT_TIMEOUT)
+    }
+
+    pub fn tls_handshake(&self) -> Duration {
+        self.tls_handshake
+            .map(Duration::from_secs)
+            .unwrap_or(TLS_HANDSHAKE_TIMEOUT)
+    }
+
+    pub fn proxy_handshake(&self) -> Duration {
+        self.proxy_handshake
+            .map(Duration::from_secs)
+            .unwrap_or(PROXY_HANDSHAKE_TIMEOUT)
+    }
+
+    pub fn client_handshake(&self) -> Duration {
+        self.client_handshake
+            .map(Duration::from_secs)
+            .unwrap_or(CLIENT_HANDSHAKE_TIMEOUT)
+    }
+
+    pub fn duplex(&self) -> DuplexTimeouts {
+        DuplexTimeouts {
+            idle: self.idle.map(Duration::from_secs),
+            max_lifetime: self.max_lifetime.map(Duration::from_secs),
+        }
+    }
+
+    /// Applies the timeouts of the proxy to the client.
+    pub fn apply(&self, client: &mut Client) {
+        client.set_tls_timeout(self.tls_handshake());
+        client.set_handshake_timeout(self.proxy_handshake());
+    }
+}
+
+/// The bandwidth of the relayed connections, each limit applies on top of
+/// the others and is unlimited if absent.
+///
+/// The plain HTTP requests are throttled on their connections to the
+/// origins, where `connection` applies to each of them. The datagrams of
+/// SOCKS5 UDP ASSOCIATE are not throttled.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThrottleConfig {
+    /// The bandwidth of all the listeners in total.
+    pub global: Option<Rate>,
+    /// The bandwidth of each connection.
+    pub connection: Option<Rate>,
+    /// The bandwidth of each authenticated user unless it is in `users`,
+    /// which is shared by all of its connections.
+    pub user: Option<Rate>,
+    #[serde(default)]
+    pub listeners: ListenThrottle,
+    #[serde(default)]
+    pub users: BTreeMap<String, Rate>,
+}
+
+/// The bandwidth of each listener.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListenThrottle {
+    pub socks5: Option<Rate>,
+    pub http: Option<Rate>,
+    pub mixed: Option<Rate>,
+}
+
+/// The rates in bytes per second, which are unlimited if absent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rate {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+}
+
+impl Rate {
+    fn bandwidth(rate: &Option<Rate>) -> Bandwidth {
+        let rate = rate.unwrap_or_default();
+        Bandwidth {
+            upload: rate.upload,
+            download: rate.download,
+        }
+    }
+}
+
+impl ThrottleConfig {
+    /// Changes the limits of the throttles, which apply to the connections
+    /// being relayed.
+    pub fn apply(&self, throttles: &Throttles) {
+        throttles
+            .socks5
+            .global()
+            .set_bandwidth(Rate::bandwidth(&self.global));
+        let connection = Rate::bandwidth(&self.connection);
+        for (throttle, rate) in [
+            (&throttles.socks5, &self.listeners.socks5),
+            (&throttles.http, &self.listeners.http),
+            (&throttles.mixed, &self.listeners.mixed),
+        ] {
+            throttle.listener().set_bandwidth(Rate::bandwidth(rate));
+            throttle.connection().set_bandwidth(connection);
+        }
+        throttles
+            .socks5
+            .set_user_default(Rate::bandwidth(&self.user));
+        throttles.socks5.set_users(
+            self.users
+                .iter()
+                .map(|(user, rate)| (user.clone(), Rate::bandwidth(&Some(*rate))))
+                .collect(),
+        );
+    }
+}
+
+/// The limits of the peers for each listener.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListenLimit {
+    /// The maximum number of the concurrent connections of all listeners.
+    pub max_connections: Option<usize>,
+    pub socks5: Option<Limit>,
+    pub http: Option<Limit>,
+    pub mixed: Option<Limit>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limit {
+    /// The CIDRs of the peers which are allowed, all of the peers are
+    /// allowed if it is empty.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// The CIDRs of the peers which are denied even if they are allowed.
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// The maximum number of the concurrent connections of each peer.
+    pub max_connections_per_ip: Option<usize>,
+}
+
+impl Limit {
+    /// Builds the guard of the listener, which shares the connections with
+    /// the other listeners.
+    pub fn load(&self, connections: Arc<Connections>) -> anyhow::Result<Guard> {
+        Ok(Guard::with_limit(
+            parse_nets(&self.allow)?,
+            parse_nets(&self.deny)?,
+            self.max_connections_per_ip,
+            connections,
+        ))
+    }
+}
+
+/// Parses the CIDRs, the IP without the prefix length is a single host.
+fn parse_nets(nets: &[String]) -> anyhow::Result<Vec<IpNet>> {
+    nets.iter()
+        .map(|n| match n.parse::<IpNet>() {
+            Ok(net) => Ok(net),
+            Err(e) => n
+                .parse::<IpAddr>()
+                .map(IpNet::from)
+                .map_err(|_| anyhow::anyhow!("invalid cidr {}, {}", n, e)),
+        })
+        .collect()
+}
+
+/// The access control lists keyed by the authenticated user or group,
+/// the user without any list is not restricted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Acl {
+    #[serde(default)]
+    pub users: BTreeMap<String, AclRules>,
+    #[serde(default)]
+    pub groups: BTreeMap<String, AclRules>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AclRules {
+    /// The name of the upstream proxy for the user or the group.
+    pub proxy: Option<String>,
+    /// The rules like `DOMAIN-SUFFIX,example.com,DIRECT`, the destination
+    /// which does not match any of them is denied.
+    pub rules: Vec<String>,
+}
+
+impl Acl {
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.groups.is_empty()
+    }
+
+    pub fn load(&self) -> anyhow::Result<AccessControl> {
+        let mut access = AccessControl::new();
+        for (username, rules) in &self.users {
+            access.add_user(username.clone(), rules.load()?);
+        }
+        for (group, rules) in &self.groups {
+            access.add_group(group.clone(), rules.load()?);
+        }
+        Ok(access)
+    }
+}
+
+impl AclRules {
+    fn load(&self) -> anyhow::Result<AccessList> {
+        let rules = self
+            .rules
+            .iter()
+            .map(|r| r.parse::<Rule>())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut list = AccessList::new(rules);
+        if let Some(proxy) = &self.proxy {
+            list.set_proxy(proxy.clone());
+        }
+        Ok(list)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -175,16 +849,43 @@ fn merge_toml_values(left: toml::Value, right: toml::Value, merge_depth: usize)
 #[cfg(test)]
 mod tests {
 
-    use super::{Config, Proxy, ProxyMode};
+    use std::collections::BTreeMap;
+
+    use super::{
+        Acl, AclRules, Auth, Config, Dns, DnsCacheConfig, DnsForward, DnsProtocol, FakeDns, Limit,
+        ListenAuth, ListenLimit, ListenThrottle, Proxy, ProxyMode, Rate, ThrottleConfig, Timeouts,
+        User,
+    };
+
+    #[test]
+    fn test_dns_proxy_over_tcp() {
+        let mut dns = Dns {
+            nameservers: vec!["8.8.8.8".to_string(), "tls://dns.google".to_string()],
+            ..Default::default()
+        };
+        let nameservers = |dns: &Dns| -> Vec<String> {
+            let nameservers = dns.load_nameservers().unwrap();
+            nameservers.iter().map(|n| n.to_string()).collect()
+        };
+        assert_eq!(nameservers(&dns)[0], "udp://8.8.8.8:53");
+
+        dns.proxy = Some("hk".to_string());
+        assert_eq!(nameservers(&dns)[0], "tcp://8.8.8.8:53");
+        assert!(nameservers(&dns)[1].starts_with("tls://"));
+
+        dns.nameservers.push("udp://1.1.1.1".to_string());
+        assert!(dns.load_nameservers().is_err());
+    }
 
     #[test]
     fn test_config() {
         let config = Config {
-            log_level: "debug".to_string(),
-            http_listen: "127.0.0.1:1235".to_string(),
-            socks5_listen: "127.0.0.1:1080".to_string(),
+            http_listen: Some("127.0.0.1:1235".to_string()),
+            socks5_listen: Some("127.0.0.1:1080".to_string()),
+            mixed_listen: Some("127.0.0.1:7890".to_string()),
             proxy_mode: ProxyMode::Proxy,
             proxy: "cn".to_string(),
+            hosts_file: Some("/etc/hosts".to_string()),
             proxies: vec![
                 Proxy {
                     name: "cn".to_string(),
@@ -207,6 +908,128 @@ mod tests {
                     }),
                 },
             ],
+            auth: ListenAuth {
+                socks5: None,
+                http: Some(Auth {
+                    file: Some("~/.config/lightway/users".to_string()),
+                    reload_interval: Some(10),
+                    command: Some(vec!["checkpassword".to_string(), "-q".to_string()]),
+                    endpoint: Some("http://127.0.0.1:8080/auth".to_string()),
+                    timeout: Some(3),
+                    cache_ttl: Some(60),
+                    users: vec![],
+                    groups: BTreeMap::new(),
+                }),
+                mixed: Some(Auth {
+                    file: None,
+                    reload_interval: None,
+                    command: None,
+                    endpoint: None,
+                    timeout: None,
+                    cache_ttl: None,
+                    users: vec![User {
+                        username: "u".to_string(),
+                        password: "p".to_string(),
+                    }],
+                    groups: BTreeMap::from([("admin".to_string(), vec!["u".to_string()])]),
+                }),
+            },
+            acl: Acl {
+                users: BTreeMap::from([(
+                    "u".to_string(),
+                    AclRules {
+                        proxy: Some("x".to_string()),
+                        rules: vec!["DOMAIN-SUFFIX,example.com,PROXY".to_string()],
+                    },
+                )]),
+                groups: BTreeMap::from([(
+                    "admin".to_string(),
+                    AclRules {
+                        proxy: None,
+                        rules: vec!["IP-CIDR,10.0.0.0/8,DENY".to_string()],
+                    },
+                )]),
+            },
+            limit: ListenLimit {
+                max_connections: Some(1024),
+                socks5: None,
+                http: None,
+                mixed: Some(Limit {
+                    allow: vec!["192.168.0.0/16".to_string()],
+                    deny: vec!["192.168.1.1".to_string()],
+                    max_connections_per_ip: Some(64),
+                }),
+            },
+            dns: Dns {
+                nameservers: vec![
+                    "8.8.8.8".to_string(),
+                    "[2001:4860:4860::8888]:53".to_string(),
+                    "tls://dns.google".to_string(),
+                    "https://dns.google/dns-query".to_string(),
+                ],
+                protocol: DnsProtocol::Tcp,
+                timeout: Some(3),
+                race: true,
+                ca: Some("~/.config/lightway/ca.pem".to_string()),
+                proxy: Some("hk".to_string()),
+                happy_eyeballs_delay: Some(100),
+                cache: DnsCacheConfig {
+                    size: Some(4096),
+                    min_ttl: Some(10),
+                    max_ttl: Some(600),
+                    negative_ttl: Some(5),
+                    prefetch: Some(false),
+                },
+                forward: Some(DnsForward {
+                    listen: "127.0.0.1:5353".to_string(),
+                    nameservers: vec!["1.1.1.1".to_string(), "tls://dns.google".to_string()],
+                }),
+            },
+            hosts: BTreeMap::from([
+                ("api.local".to_string(), "127.0.0.1".to_string()),
+                ("*.dev.internal".to_string(), "staging.internal".to_string()),
+            ]),
+            fake_dns: Some(FakeDns {
+                listen: "127.0.0.1:5353".to_string(),
+                ipv4_pool: Some("198.18.0.0/15".to_string()),
+                ipv6_pool: Some("fc00::/18".to_string()),
+                size: Some(65536),
+                cache_file: Some("~/.cache/lightway/fakeip".to_string()),
+            }),
+            timeout: Timeouts {
+                connect: Some(5),
+                tls_handshake: Some(5),
+                proxy_handshake: Some(10),
+                client_handshake: Some(30),
+                idle: Some(300),
+                max_lifetime: Some(86400),
+            },
+            throttle: Some(ThrottleConfig {
+                global: Some(Rate {
+                    upload: Some(10 << 20),
+                    download: Some(100 << 20),
+                }),
+                connection: None,
+                user: Some(Rate {
+                    upload: None,
+                    download: Some(10 << 20),
+                }),
+                listeners: ListenThrottle {
+                    socks5: None,
+                    http: None,
+                    mixed: Some(Rate {
+                        upload: Some(5 << 20),
+                        download: None,
+                    }),
+                },
+                users: BTreeMap::from([(
+                    "u".to_string(),
+                    Rate {
+                        upload: Some(1 << 20),
+                        download: Some(1 << 20),
+                    },
+                )]),
+            }),
         };
 
         let data = toml::to_string_pretty(&config).unwrap();
@@ -0,0 +1,226 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use ipnet::IpNet;
+use proxy_io::Throttle;
+
+/// The number of the concurrent connections which is shared by all of the
+/// listeners.
+#[derive(Debug, Default)]
+pub struct Connections {
+    max: Option<usize>,
+    count: AtomicUsize,
+}
+
+impl Connections {
+    pub fn new(max: Option<usize>) -> Self {
+        Connections {
+            max,
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    fn acquire(&self) -> bool {
+        let count = self.count.fetch_add(1, Ordering::AcqRel);
+        match self.max {
+            Some(max) if count >= max => {
+                self.count.fetch_sub(1, Ordering::AcqRel);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn release(&self) {
+        self.count.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Why the peer is not admitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// The peer is not in the allow list, or is in the deny list.
+    Denied,
+    /// The peer has too many concurrent connections.
+    TooManyFromPeer,
+    /// The listeners have too many concurrent connections in total.
+    TooManyConnections,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Denied => f.write_str("the peer is denied"),
+            Rejection::TooManyFromPeer => f.write_str("too many connections from the peer"),
+            Rejection::TooManyConnections => f.write_str("too many connections"),
+        }
+    }
+}
+
+/// Admits the peers of a listener by their source IP, and limits the
+/// concurrent connections of them.
+#[derive(Debug, Clone)]
+pub struct Guard {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    max_per_ip: Option<usize>,
+    peers: Mutex<HashMap<IpAddr, usize>>,
+    connections: Arc<Connections>,
+}
+
+impl Guard {
+    pub fn new(connections: Arc<Connections>) -> Self {
+        Guard::with_limit(Vec::new(), Vec::new(), None, connections)
+    }
+
+    /// Creates the guard which admits the peers in the `allow` list but not
+    /// in the `deny` list, all of the peers are allowed if `allow` is empty.
+    pub fn with_limit(
+        allow: Vec<IpNet>,
+        deny: Vec<IpNet>,
+        max_per_ip: Option<usize>,
+        connections: Arc<Connections>,
+    ) -> Self {
+        Guard {
+            inner: Arc::new(Inner {
+                allow,
+                deny,
+                max_per_ip,
+                peers: Mutex::new(HashMap::new()),
+                connections,
+            }),
+        }
+    }
+
+    /// Admits the peer, returning the permit which is held for the lifetime
+    /// of the connection.
+    pub fn admit(&self, ip: IpAddr) -> Result<Permit, Rejection> {
+        // The IPv4 peer may be accepted on the dual-stack socket.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        let inner = &self.inner;
+        if inner.deny.iter().any(|n| n.contains(&ip))
+            || (!inner.allow.is_empty() && !inner.allow.iter().any(|n| n.contains(&ip)))
+        {
+            return Err(Rejection::Denied);
+        }
+
+        {
+            let mut peers = inner.peers.lock().unwrap();
+            let count = peers.entry(ip).or_default();
+            if matches!(inner.max_per_ip, Some(max) if *count >= max) {
+                if *count == 0 {
+                    peers.remove(&ip);
+                }
+                return Err(Rejection::TooManyFromPeer);
+            }
+            *count += 1;
+        }
+        if !inner.connections.acquire() {
+            self.release(ip);
+            return Err(Rejection::TooManyConnections);
+        }
+        Ok(Permit {
+            guard: self.clone(),
+            ip,
+        })
+    }
+
+    fn release(&self, ip: IpAddr) {
+        let mut peers = self.inner.peers.lock().unwrap();
+        if let Some(count) = peers.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                peers.remove(&ip);
+            }
+        }
+    }
+}
+
+/// The admission of a connection, which is released once it is dropped.
+#[derive(Debug)]
+pub struct Permit {
+    guard: Guard,
+    ip: IpAddr,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.guard.release(self.ip);
+        self.guard.inner.connections.release();
+    }
+}
+
+/// The throttles of the listeners, which share the global and the user
+/// limits.
+#[derive(Debug, Clone)]
+pub struct Throttles {
+    pub socks5: Throttle,
+    pub http: Throttle,
+    pub mixed: Throttle,
+}
+
+impl Throttles {
+    pub fn new() -> Self {
+        let socks5 = Throttle::new();
+        Throttles {
+            http: socks5.new_listener(),
+            mixed: socks5.new_listener(),
+            socks5,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{Connections, Guard, Rejection};
+
+    #[test]
+    fn test_guard() {
+        let connections = Arc::new(Connections::new(Some(3)));
+        let guard = Guard::with_limit(
+            vec!["10.0.0.0/8".parse().unwrap()],
+            vec!["10.0.0.1/32".parse().unwrap()],
+            Some(2),
+            connections.clone(),
+        );
+        let peer = "10.0.0.2".parse().unwrap();
+        assert_eq!(
+            guard.admit("10.0.0.1".parse().unwrap()).unwrap_err(),
+            Rejection::Denied
+        );
+        assert_eq!(
+            guard.admit("192.168.0.1".parse().unwrap()).unwrap_err(),
+            Rejection::Denied
+        );
+
+        let first = guard.admit(peer).unwrap();
+        let _second = guard.admit(peer).unwrap();
+        assert_eq!(guard.admit(peer).unwrap_err(), Rejection::TooManyFromPeer);
+        drop(first);
+        let _third = guard.admit(peer).unwrap();
+
+        let other = Guard::new(connections);
+        let _fourth = other.admit("10.0.0.3".parse().unwrap()).unwrap();
+        assert_eq!(
+            guard.admit("10.0.0.4".parse().unwrap()).unwrap_err(),
+            Rejection::TooManyConnections
+        );
+    }
+}
@@ -1,21 +1,28 @@
 #![feature(type_alias_impl_trait)]
 mod client;
 mod config;
+mod limit;
+mod server;
 
-use std::{convert::Infallible, path::PathBuf};
+use std::{path::PathBuf, sync::Arc};
 
 use anyhow::anyhow;
 use clap::Parser;
 use client::Client;
-use config::{cache_dir, config_dir, Config, ProxyMode};
+use config::{cache_dir, config_dir, Auth, Config, Limit, ProxyMode};
 use daemonize::Daemonize;
-use hyper::service::{make_service_fn, service_fn};
-use log::{error, info};
-use proxy::Service;
-use proxy_auth::Authentication;
-use proxy_io::{ProxyConnect, TokioConnect};
+use limit::{Connections, Guard, Throttles};
+use log::{info, warn};
+use proxy_io::{DnsForwarder, ProxyConnect};
 use proxy_rules::Rules;
-use tokio::net::TcpListener;
+use server::{
+    reload_throttle, save_fake_ip, serve_dns, serve_fake_dns, serve_http, serve_mixed, serve_socks,
+    HttpServer, SocksServer, UserAuth,
+};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    signal::unix::{signal, SignalKind},
+};
 
 use crate::config::user_rules;
 
@@ -99,9 +106,12 @@ fn main() -> anyhow::Result<()> {
             .map_err(|e| anyhow!("{} does not exist, {}", configfile.display(), e))?,
     )?;
 
+    let direct = config.dns.load(&config.proxies, &config.timeout)?;
     let mut client = Client::empty();
-    let connect = match &config.proxy_mode {
-        ProxyMode::Direct => ProxyConnect::<_, _, Rules>::new(TokioConnect::new(), client),
+    client.set_connect(direct.clone());
+    config.timeout.apply(&mut client);
+    let mut connect = match &config.proxy_mode {
+        ProxyMode::Direct => ProxyConnect::<_, _, Rules>::new(direct.clone(), client),
         ProxyMode::Proxy => {
             let proxy = config
                 .proxies
@@ -109,7 +119,7 @@ fn main() -> anyhow::Result<()> {
                 .find(|p| p.name.eq_ignore_ascii_case(&config.proxy))
                 .expect("no proxy for proxy mode");
             client.set_proxy(proxy.clone());
-            let mut proxy_connect = ProxyConnect::<_, _, Rules>::new(TokioConnect::new(), client);
+            let mut proxy_connect = ProxyConnect::<_, _, Rules>::new(direct.clone(), client);
             proxy_connect.set_force_proxy(true);
             proxy_connect
         }
@@ -120,80 +130,223 @@ fn main() -> anyhow::Result<()> {
                 .find(|p| p.name.eq_ignore_ascii_case(&config.proxy))
                 .expect("no proxy for auto mode");
             client.set_proxy(proxy.clone());
-            let mut proxy_connect = ProxyConnect::<_, _, Rules>::new(TokioConnect::new(), client);
-            proxy_connect.set_policy(rules);
+            let mut proxy_connect = ProxyConnect::<_, _, Rules>::new(direct.clone(), client);
+            proxy_connect.set_policy(rules.clone());
             proxy_connect
         }
     };
 
+    connect.set_resolver(direct.resolver().clone());
+    let hosts = config.load_hosts()?;
+    if !hosts.is_empty() {
+        connect.set_hosts(hosts);
+    }
+    let forwarder = match &config.dns.forward {
+        Some(forward) => {
+            let proxy = config
+                .proxies
+                .iter()
+                .find(|p| p.name.eq_ignore_ascii_case(&config.proxy));
+            let remote = forward.load(proxy, direct.clone(), &config.dns.cache, &config.timeout)?;
+            let mut forwarder = DnsForwarder::new(direct.resolver().clone(), remote);
+            match &config.proxy_mode {
+                ProxyMode::Direct => {}
+                ProxyMode::Proxy => forwarder.set_force_proxy(true),
+                ProxyMode::Auto => forwarder.set_policy(rules),
+            }
+            Some((forward.listen.clone(), forwarder))
+        }
+        None => None,
+    };
+    let fake_ip = config.fake_dns.as_ref().map(|f| f.load()).transpose()?;
+    if let Some(fake_ip) = &fake_ip {
+        connect.set_fake_ip(fake_ip.clone());
+    }
+
+    if !config.acl.is_empty() {
+        let access = config.acl.load()?;
+        for name in access.proxies() {
+            let proxy = config
+                .proxies
+                .iter()
+                .find(|p| p.name.eq_ignore_ascii_case(name))
+                .ok_or_else(|| anyhow!("no proxy {} for the access control lists", name))?;
+            let mut upstream = Client::new(proxy.clone());
+            upstream.set_connect(direct.clone());
+            config.timeout.apply(&mut upstream);
+            connect.add_upstream(name.to_string(), upstream);
+        }
+        connect.set_access_control(access);
+    }
+
+    // The throttle is reloadable only if it is configured at startup, so that
+    // the connections are not throttled at all otherwise.
+    let throttles = config.throttle.as_ref().map(|throttle| {
+        let throttles = Throttles::new();
+        throttle.apply(&throttles);
+        throttles
+    });
+
     // Must create the Tokio runtime after daemonizing it. The Tokio runtime can't survive a fork.
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .unwrap()
         .block_on(async move {
-            info!("listen socks on {}", &config.socks5_listen);
-            let socks_listener = TcpListener::bind(&config.socks5_listen).await?;
-            let socks_server =
-                proxy_socks::server::Server::<Authentication, _>::new(connect.clone());
-            let socks_join = tokio::spawn(async move {
-                loop {
-                    match socks_listener.accept().await {
-                        Ok((stream, addr)) => {
-                            let mut server = socks_server.clone();
-                            tokio::spawn(async move {
-                                match server.call(stream).await {
-                                    Ok(()) => {
-                                        info!("completed socks proxy({})", &addr);
-                                    }
-                                    Err(e) => {
-                                        error!(
-                                            "an error occurs during socks proxy({}), {}",
-                                            &addr, e
-                                        );
-                                    }
-                                }
-                            });
-                        }
-                        Err(e) => {
-                            error!("unable to accept socks5, {}", e);
-                            break;
-                        }
-                    }
+            let connections = Arc::new(Connections::new(config.limit.max_connections));
+            let mut joins = Vec::new();
+            if let Some(addr) = &config.socks5_listen {
+                info!("listen socks on {}", addr);
+                let listener = TcpListener::bind(addr).await?;
+                let mut socks_server = SocksServer::new(connect.clone());
+                socks_server.set_router(connect.clone());
+                socks_server.set_handshake_timeout(config.timeout.client_handshake());
+                socks_server.set_duplex_timeouts(config.timeout.duplex());
+                if let Some(throttles) = &throttles {
+                    socks_server.set_throttle(throttles.socks5.clone());
                 }
-            });
-
-            info!("listen http on {}", &config.http_listen);
-            let http_server = proxy_tunnel::Server::<Authentication, _>::new(connect.clone());
-            let server = hyper::Server::bind(&config.http_listen.parse()?)
-                .http1_preserve_header_case(true)
-                .http1_title_case_headers(true)
-                .serve(make_service_fn(move |_| {
-                    let server = http_server.clone();
-                    async move {
-                        Ok::<_, Infallible>(service_fn(move |req| {
-                            let mut server = server.clone();
-                            async move { server.call(req).await }
-                        }))
-                    }
-                }));
+                if let Some(users) = load_users(&config.auth.socks5)? {
+                    socks_server.set_authenticate(users);
+                }
+                let guard = load_guard(&config.limit.socks5, &connections)?;
+                joins.push(tokio::spawn(serve_socks(listener, socks_server, guard)));
+            }
 
-            let http_join = tokio::spawn(async move {
-                if let Err(e) = server.await {
-                    error!("unable to serve http, {}", &e);
+            if let Some(addr) = &config.http_listen {
+                info!("listen http on {}", addr);
+                let listener = TcpListener::bind(addr).await?;
+                let mut http_server = HttpServer::new(connect.clone());
+                http_server.set_handshake_timeout(config.timeout.client_handshake());
+                http_server.set_duplex_timeouts(config.timeout.duplex());
+                if let Some(throttles) = &throttles {
+                    http_server.set_throttle(throttles.http.clone());
                 }
-            });
+                if let Some(users) = load_users(&config.auth.http)? {
+                    http_server.set_authenticate(users);
+                }
+                let guard = load_guard(&config.limit.http, &connections)?;
+                joins.push(tokio::spawn(serve_http(listener, http_server, guard)));
+            }
 
-            match tokio::try_join!(socks_join, http_join) {
-                Ok(_) => Ok(()),
-                Err(e) => {
-                    log::error!("exit error: {}", e);
-                    std::process::abort()
+            if let Some(addr) = &config.mixed_listen {
+                info!("listen socks and http on {}", addr);
+                let listener = TcpListener::bind(addr).await?;
+                let mut socks_server = SocksServer::new(connect.clone());
+                let mut http_server = HttpServer::new(connect.clone());
+                socks_server.set_router(connect.clone());
+                socks_server.set_handshake_timeout(config.timeout.client_handshake());
+                socks_server.set_duplex_timeouts(config.timeout.duplex());
+                http_server.set_handshake_timeout(config.timeout.client_handshake());
+                http_server.set_duplex_timeouts(config.timeout.duplex());
+                if let Some(throttles) = &throttles {
+                    socks_server.set_throttle(throttles.mixed.clone());
+                    http_server.set_throttle(throttles.mixed.clone());
+                }
+                if let Some(users) = load_users(&config.auth.mixed)? {
+                    socks_server.set_authenticate(users.clone());
+                    http_server.set_authenticate(users);
                 }
+                let guard = load_guard(&config.limit.mixed, &connections)?;
+                joins.push(tokio::spawn(serve_mixed(
+                    listener,
+                    socks_server,
+                    http_server,
+                    guard,
+                )));
             }
+
+            if let Some((addr, forwarder)) = forwarder {
+                info!("listen dns on {}", &addr);
+                let socket = UdpSocket::bind(&addr).await?;
+                let listener = TcpListener::bind(&addr).await?;
+                joins.push(tokio::spawn(serve_dns(socket, listener, forwarder)));
+            }
+
+            let mut fake_ip_file = None;
+            if let (Some(fake_dns), Some(fake_ip)) = (&config.fake_dns, fake_ip) {
+                info!("listen fake dns on {}", &fake_dns.listen);
+                let socket = UdpSocket::bind(&fake_dns.listen).await?;
+                let listener = TcpListener::bind(&fake_dns.listen).await?;
+                fake_ip_file = Some((fake_ip.clone(), fake_dns.cache_file()));
+                joins.push(tokio::spawn(serve_fake_dns(
+                    socket,
+                    listener,
+                    fake_ip,
+                    fake_dns.cache_file(),
+                )));
+            }
+
+            if joins.is_empty() {
+                return Err(anyhow!("no listener is configured"));
+            }
+
+            if let Some(throttles) = throttles {
+                joins.push(tokio::spawn(reload_throttle(configfile, throttles)));
+            }
+
+            tokio::select! {
+                joined = futures::future::try_join_all(joins) => {
+                    if let Err(e) = joined {
+                        log::error!("exit error: {}", e);
+                        std::process::abort()
+                    }
+                }
+                _ = shutdown() => info!("shut down"),
+            }
+            // Saves the domains allocated since the last periodic save.
+            if let Some((fake_ip, file)) = &fake_ip_file {
+                save_fake_ip(fake_ip, file).await;
+            }
+            Ok(())
         })
 }
 
+/// Waits for SIGINT or SIGTERM.
+async fn shutdown() {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("unable to listen to SIGTERM, {}", e);
+                futures::future::pending::<()>().await
+            }
+        }
+    };
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("unable to listen to SIGINT, {}", e);
+            futures::future::pending::<()>().await
+        }
+    };
+    tokio::select! {
+        _ = terminate => {}
+        _ = interrupt => {}
+    }
+}
+
+fn load_users(auth: &Option<Auth>) -> anyhow::Result<Option<UserAuth>> {
+    match auth {
+        Some(auth) => {
+            let users = auth
+                .load()
+                .map_err(|e| anyhow!("unable to load the users, {}", e))?;
+            Ok(Some(users))
+        }
+        None => Ok(None),
+    }
+}
+
+fn load_guard(limit: &Option<Limit>, connections: &Arc<Connections>) -> anyhow::Result<Guard> {
+    match limit {
+        Some(limit) => limit
+            .load(connections.clone())
+            .map_err(|e| anyhow!("unable to load the limit, {}", e)),
+        None => Ok(Guard::new(connections.clone())),
+    }
+}
+
 fn setup_logging(logpath: PathBuf, verbosity: u8) -> anyhow::Result<()> {
     let mut base_config = fern::Dispatch::new();
 
@@ -0,0 +1,372 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use http::{header, StatusCode};
+use hyper::{server::conn::Http, service::service_fn, Body, Response};
+use log::{debug, error, info, warn};
+use proxy::Service;
+use proxy_auth::Authenticators;
+use proxy_io::{
+    serve_dns_tcp, serve_dns_udp, DnsHandler, Expired, FakeIp, ProxyConnect, Rewind, SocketStream,
+    TokioConnect,
+};
+use proxy_rules::Rules;
+use proxy_socks::{
+    server::reject,
+    types::{Rep, SOCKS4_VERSION, SOCKS_VERSION},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UdpSocket},
+    sync::{Semaphore, SemaphorePermit},
+};
+
+use crate::{
+    client::Client,
+    config::Config,
+    limit::{Guard, Rejection, Throttles},
+};
+
+pub type Connect = ProxyConnect<TokioConnect, Client, Rules>;
+
+/// The authenticator shared by the listener's servers.
+pub type UserAuth = Authenticators;
+
+pub type SocksServer = proxy_socks::server::Server<UserAuth, Connect>;
+
+pub type HttpServer = proxy_tunnel::Server<UserAuth, Connect>;
+
+/// The time to wait for the request of the rejected client, so that the
+/// client which never sends is not kept.
+const REJECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most rejected clients which are replied at the same time, the others
+/// are closed at once so that a flood of them holds no more tasks.
+const MAX_REJECTING: usize = 256;
+
+static REJECTING: Semaphore = Semaphore::const_new(MAX_REJECTING);
+
+/// The time to wait after the listener fails to accept, which is mostly
+/// out of the file descriptors, so that the loop does not spin meanwhile.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// The interval to check whether the config file changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The interval to save the domains behind the fake addresses.
+const FAKE_IP_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Accepts the connections on the listener and serves them as SOCKS.
+pub async fn serve_socks(listener: TcpListener, server: SocksServer, guard: Guard) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => match guard.admit(addr.ip()) {
+                Ok(permit) => {
+                    let server = server.clone();
+                    tokio::spawn(async move {
+                        socks_connection(stream, addr, server).await;
+                        drop(permit)
+                    });
+                }
+                Err(rejection) => match rejecting() {
+                    Some(rejecting) => {
+                        warn!("reject socks connection({}), {}", &addr, rejection);
+                        tokio::spawn(async move {
+                            reject_socks(stream, addr, rejection).await;
+                            drop(rejecting)
+                        });
+                    }
+                    None => warn!("close socks connection({}), {}", &addr, rejection),
+                },
+            },
+            Err(e) => {
+                error!("unable to accept socks5, {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Accepts the connections on the listener and serves them as HTTP.
+pub async fn serve_http(listener: TcpListener, server: HttpServer, guard: Guard) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => match guard.admit(addr.ip()) {
+                Ok(permit) => {
+                    let server = server.clone();
+                    tokio::spawn(async move {
+                        http_connection(stream, addr, server).await;
+                        drop(permit)
+                    });
+                }
+                Err(rejection) => match rejecting() {
+                    Some(rejecting) => {
+                        warn!("reject http connection({}), {}", &addr, rejection);
+                        tokio::spawn(async move {
+                            reject_http(stream, addr, rejection).await;
+                            drop(rejecting)
+                        });
+                    }
+                    None => warn!("close http connection({}), {}", &addr, rejection),
+                },
+            },
+            Err(e) => {
+                error!("unable to accept http, {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Accepts the connections on the listener, and dispatches them to either
+/// the SOCKS server or the HTTP server by peeking the first byte.
+///
+/// The first byte of SOCKS is the version, which never starts an HTTP
+/// request line.
+pub async fn serve_mixed(
+    listener: TcpListener,
+    socks: SocksServer,
+    http: HttpServer,
+    guard: Guard,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                let admission = guard.admit(addr.ip());
+                let rejecting = match &admission {
+                    Ok(_) => None,
+                    Err(rejection) => match rejecting() {
+                        Some(rejecting) => {
+                            warn!("reject mixed connection({}), {}", &addr, rejection);
+                            Some(rejecting)
+                        }
+                        None => {
+                            warn!("close mixed connection({}), {}", &addr, rejection);
+                            continue;
+                        }
+                    },
+                };
+                let socks = socks.clone();
+                let http = http.clone();
+                tokio::spawn(async move {
+                    let mut stream = Rewind::new(stream);
+                    let timeout = match admission {
+                        Ok(_) => http.handshake_timeout(),
+                        Err(_) => REJECT_TIMEOUT,
+                    };
+                    let peek = match tokio::time::timeout(timeout, stream.peek(1)).await {
+                        Ok(peek) => peek,
+                        Err(_) => {
+                            debug!("connection({}) timed out before any request", &addr);
+                            return;
+                        }
+                    };
+                    match (peek.map(|b| b.first().copied()), admission) {
+                        (Ok(Some(SOCKS4_VERSION | SOCKS_VERSION)), Ok(_permit)) => {
+                            socks_connection(stream, addr, socks).await
+                        }
+                        (Ok(Some(SOCKS4_VERSION | SOCKS_VERSION)), Err(rejection)) => {
+                            reject_socks(stream, addr, rejection).await
+                        }
+                        (Ok(Some(_)), Ok(_permit)) => http_connection(stream, addr, http).await,
+                        (Ok(Some(_)), Err(rejection)) => reject_http(stream, addr, rejection).await,
+                        (Ok(None), _) => {
+                            debug!("connection({}) closed before any request", &addr)
+                        }
+                        (Err(e), _) => error!("unable to peek connection({}), {}", &addr, e),
+                    }
+                    drop(rejecting)
+                });
+            }
+            Err(e) => {
+                error!("unable to accept mixed, {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Serves the fake DNS over both UDP and TCP, and saves the domains behind
+/// the fake addresses to the file periodically so that the connections
+/// after the restart are still translated.
+pub async fn serve_fake_dns(
+    socket: UdpSocket,
+    listener: TcpListener,
+    fake_ip: FakeIp,
+    file: PathBuf,
+) {
+    let save = {
+        let fake_ip = fake_ip.clone();
+        async move {
+            let mut interval = tokio::time::interval(FAKE_IP_SAVE_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                save_fake_ip(&fake_ip, &file).await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = serve_dns(socket, listener, fake_ip) => {}
+        _ = save => {}
+    }
+}
+
+/// Saves the domains behind the fake addresses to the file, unless none is
+/// allocated since the last save.
+pub async fn save_fake_ip(fake_ip: &FakeIp, file: &Path) {
+    if !fake_ip.is_dirty() {
+        return;
+    }
+    let fake_ip = fake_ip.clone();
+    let file = file.to_path_buf();
+    let saved = tokio::task::spawn_blocking(move || {
+        if let Some(dir) = file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        fake_ip.save(&file).map(|_| file)
+    })
+    .await;
+    match saved {
+        Ok(Ok(file)) => debug!("saved fake ip to {}", file.display()),
+        Ok(Err(e)) => warn!("unable to save fake ip, {}", e),
+        Err(e) => warn!("unable to save fake ip, {}", e),
+    }
+}
+
+/// Reloads the throttle once the config file changes, so that the limits
+/// are changed without dropping the connections.
+///
+/// The limits are removed if the throttle is removed from the file, and are
+/// kept if the file fails to load.
+pub async fn reload_throttle(file: PathBuf, throttles: Throttles) {
+    let mut loaded = modified(&file);
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let current = modified(&file);
+        if current == loaded {
+            continue;
+        }
+        loaded = current;
+        let config = std::fs::read(&file)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| toml::from_slice::<Config>(&data).map_err(anyhow::Error::from));
+        match config {
+            Ok(config) => {
+                info!("reload the throttle from {}", file.display());
+                config.throttle.unwrap_or_default().apply(&throttles);
+            }
+            Err(e) => warn!("unable to reload {}, {}", file.display(), e),
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    path.metadata().and_then(|m| m.modified()).ok()
+}
+
+/// Serves the DNS queries over both UDP and TCP.
+pub async fn serve_dns<H>(socket: UdpSocket, listener: TcpListener, handler: H)
+where
+    H: DnsHandler + Clone + 'static,
+{
+    tokio::select! {
+        Err(e) = serve_dns_udp(socket, handler.clone()) => error!("unable to serve dns over udp, {}", e),
+        Err(e) = serve_dns_tcp(listener, handler) => error!("unable to serve dns over tcp, {}", e),
+    }
+}
+
+async fn socks_connection<I>(stream: I, addr: SocketAddr, mut server: SocksServer)
+where
+    I: AsyncRead + AsyncWrite + SocketStream + Send + Unpin + 'static,
+{
+    match server.call(stream).await {
+        Ok(()) => {
+            info!("completed socks proxy({})", &addr);
+        }
+        Err(e) if Expired::of(&e).is_some() => {
+            info!("closed socks proxy({}), {}", &addr, e);
+        }
+        Err(e) => {
+            error!("an error occurs during socks proxy({}), {}", &addr, e);
+        }
+    }
+}
+
+async fn http_connection<I>(stream: I, addr: SocketAddr, server: HttpServer)
+where
+    I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let handshake_timeout = server.handshake_timeout();
+    let server = server.for_connection();
+    let service = service_fn(move |req| {
+        let mut server = server.clone();
+        async move { server.call(req).await }
+    });
+    if let Err(e) = Http::new()
+        .http1_preserve_header_case(true)
+        .http1_title_case_headers(true)
+        .http1_header_read_timeout(handshake_timeout)
+        .serve_connection(stream, service)
+        .with_upgrades()
+        .await
+    {
+        error!("an error occurs during http proxy({}), {}", &addr, e);
+    }
+}
+
+/// Acquires the permit to reply a rejected client, or `None` if there are
+/// too many of them already.
+fn rejecting() -> Option<SemaphorePermit<'static>> {
+    REJECTING.try_acquire().ok()
+}
+
+/// Replies the SOCKS failure to the rejected client.
+async fn reject_socks<I>(stream: I, addr: SocketAddr, rejection: Rejection)
+where
+    I: AsyncRead + AsyncWrite + Unpin,
+{
+    let rep = match rejection {
+        Rejection::Denied => Rep::ConnectionNotAllowedByRuleset,
+        Rejection::TooManyFromPeer | Rejection::TooManyConnections => {
+            Rep::GeneralSocksServerFailure
+        }
+    };
+    match tokio::time::timeout(REJECT_TIMEOUT, reject(stream, rep)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => debug!("unable to reject socks connection({}), {}", &addr, e),
+        Err(_) => debug!("socks connection({}) timed out before rejected", &addr),
+    }
+}
+
+/// Responds 403, 429 or 503 to the rejected client and closes the
+/// connection.
+async fn reject_http<I>(stream: I, addr: SocketAddr, rejection: Rejection)
+where
+    I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let status = match rejection {
+        Rejection::Denied => StatusCode::FORBIDDEN,
+        Rejection::TooManyFromPeer => StatusCode::TOO_MANY_REQUESTS,
+        Rejection::TooManyConnections => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    let service = service_fn(move |_req| async move {
+        Response::builder()
+            .status(status)
+            .header(header::CONNECTION, "close")
+            .body(Body::empty())
+    });
+    let conn = Http::new()
+        .http1_keep_alive(false)
+        .serve_connection(stream, service);
+    match tokio::time::timeout(REJECT_TIMEOUT, conn).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => debug!("unable to reject http connection({}), {}", &addr, e),
+        Err(_) => debug!("http connection({}) timed out before rejected", &addr),
+    }
+}
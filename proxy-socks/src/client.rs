@@ -1,15 +1,18 @@
-use std::{future::Future, task::{Poll, Context}, io};
+use std::{fmt, future::Future, task::{Poll, Context}, io, net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr}, sync::Arc, time::Duration};
 
-use log::{error, debug};
+use bytes::BufMut;
+use log::{error, debug, trace, warn};
 use futures::TryFutureExt;
 use proxy::Service;
-use proxy_io::{StreamConnect, TargetAddr, ProxyStream};
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use proxy_io::{with_timeout, DnsCache, Resolver, StreamConnect, TargetAddr, ProxyStream, PROXY_HANDSHAKE_TIMEOUT};
+use tokio::{io::{AsyncRead, AsyncWrite, AsyncWriteExt}, net::UdpSocket};
 
 use crate::{types::*, error::Kind, io_err, check_valid};
 
+#[derive(Debug, Clone)]
 pub struct Client<C> {
     authorization: Option<(String, String)>,
+    handshake_timeout: Duration,
     connect: StreamConnect<C>,
 }
 
@@ -17,6 +20,7 @@ impl <C> Client<C> {
 	pub fn new(target: TargetAddr, connect: C) -> Self {
 		Client{
 			authorization: None,
+			handshake_timeout: PROXY_HANDSHAKE_TIMEOUT,
 			connect: StreamConnect::new(connect, target),
 		}
 	}
@@ -28,6 +32,194 @@ impl <C> Client<C> {
 	pub fn enable_tls(&mut self) {
 		self.connect.set_tls(true)
 	}
+
+	/// Sets the time of the TLS handshake with the proxy server.
+	pub fn set_tls_timeout(&mut self, timeout: Duration) {
+		self.connect.set_tls_timeout(timeout)
+	}
+
+	/// Sets the time of the SOCKS handshake until the proxy server replies,
+	/// which fails with `TimedOut` once it elapses.
+	pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+		self.handshake_timeout = timeout
+	}
+}
+
+impl<C> Client<C>
+where
+    C: Service<TargetAddr> + Send + 'static,
+    C::Response: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    C::Error: Into<io::Error> + Send,
+{
+	/// Requests a UDP association from the SOCKS server, returning a socket
+	/// which relays the datagrams through it.
+	///
+	/// The association is kept alive as long as the returned [`Datagram`].
+	pub async fn associate(&mut self) -> io::Result<Datagram<ProxyStream<C::Response>>> {
+		let proxy = self.connect.target().clone();
+		let mut socket = self.connect.call(()).await?;
+		let associate = proxy_associate(&mut socket, self.authorization.clone());
+		match with_timeout(self.handshake_timeout, || format!("udp associate with {}", proxy), associate).await {
+			Ok(bound) => {
+				let relay = bound_addr(bound, &proxy).await?;
+				let unspecified = match relay {
+					SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+					SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+				};
+				let udp = UdpSocket::bind(SocketAddr::new(unspecified, 0)).await?;
+				debug!("udp associate with {} relayed by {}", &proxy, &relay);
+				Ok(Datagram { control: socket, socket: udp, relay })
+			}
+			Err(e) => {
+				error!("unable to udp associate with {}, {}", &proxy, &e);
+				if let Err(e) = socket.shutdown().await {
+					error!("unable to shutdown proxy stream, {}", &e);
+				}
+				Err(e)
+			}
+		}
+	}
+
+	/// Asks the SOCKS server to listen for an inbound connection from the
+	/// target, returning once the server has bound the listening socket.
+	///
+	/// The address that the server listens on is reported by
+	/// [`Bind::bound_addr`], and it should be passed to the target via the
+	/// primary connection.
+	pub async fn bind(&mut self, target: TargetAddr) -> io::Result<Bind<ProxyStream<C::Response>>> {
+		let proxy = self.connect.target().clone();
+		let mut socket = self.connect.call(()).await?;
+		let bind = proxy_bind(&mut socket, target.clone(), self.authorization.clone());
+		match with_timeout(self.handshake_timeout, || format!("bind with {}", proxy), bind).await {
+			Ok(bound) => {
+				let bound = bound_addr(bound, &proxy).await?;
+				debug!("bind for {} on {}", &target, &bound);
+				Ok(Bind { socket, bound })
+			}
+			Err(e) => {
+				error!("unable to bind with {} for {}, {}", &proxy, &target, &e);
+				if let Err(e) = socket.shutdown().await {
+					error!("unable to shutdown proxy stream, {}", &e);
+				}
+				Err(e)
+			}
+		}
+	}
+}
+
+/// Returns the address the SOCKS server bound for the client.
+///
+/// The server listens on the same host as the proxy if it replies with the
+/// unspecified address.
+async fn bound_addr(bound: TargetAddr, proxy: &TargetAddr) -> io::Result<SocketAddr> {
+	match bound {
+		TargetAddr::SocketAddr(addr) if addr.ip().is_unspecified() => {
+			let proxy = proxy.resolve_dns().await?;
+			Ok(SocketAddr::new(proxy.ip(), addr.port()))
+		}
+		TargetAddr::SocketAddr(addr) => Ok(addr),
+		domain => domain.resolve_dns().await,
+	}
+}
+
+/// A pending inbound connection which the SOCKS server listens for.
+#[derive(Debug)]
+pub struct Bind<S> {
+	socket: S,
+	bound: SocketAddr,
+}
+
+impl<S> Bind<S>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	/// Returns the address that the SOCKS server listens on.
+	pub fn bound_addr(&self) -> SocketAddr {
+		self.bound
+	}
+
+	/// Waits for the inbound connection, returning the stream relayed by the
+	/// server along with the address of the connected host.
+	pub async fn accept(mut self) -> io::Result<(S, TargetAddr)> {
+		let reply = match reply(&mut self.socket).await {
+			Ok(reply) => reply,
+			Err(e) => {
+				error!("unable to accept connection on {}, {}", &self.bound, &e);
+				if let Err(e) = self.socket.shutdown().await {
+					error!("unable to shutdown proxy stream, {}", &e);
+				}
+				return Err(e);
+			}
+		};
+		let peer = reply.target.ok_or_else(|| io_err!(Kind::AddressTypeNotSupported))?;
+		debug!("accept connection from {} on {}", &peer, &self.bound);
+		Ok((self.socket, peer))
+	}
+}
+
+/// A UDP socket which relays datagrams through the SOCKS server.
+///
+/// Dropping it closes the control connection and so terminates the
+/// association on the server.
+#[derive(Debug)]
+pub struct Datagram<S> {
+	control: S,
+	socket: UdpSocket,
+	relay: SocketAddr,
+}
+
+impl<S> Datagram<S> {
+	/// Returns the address of the UDP relay server.
+	pub fn relay_addr(&self) -> SocketAddr {
+		self.relay
+	}
+
+	/// Returns the local address that this socket is bound to.
+	pub fn local_addr(&self) -> io::Result<SocketAddr> {
+		self.socket.local_addr()
+	}
+
+	/// Returns the control connection of the association.
+	pub fn control(&self) -> &S {
+		&self.control
+	}
+
+	/// Sends the data to the target via the relay server, returning the
+	/// number of bytes of data sent.
+	pub async fn send_to(&self, buf: &[u8], target: &TargetAddr) -> io::Result<usize> {
+		let header = UdpHeader::new(target.clone());
+		let mut datagram = Vec::with_capacity(header.encoded_len() + buf.len());
+		header.write_to(&mut datagram)?;
+		datagram.put_slice(buf);
+		trace!("send {}B to {} via {}", buf.len(), target, &self.relay);
+		let n = self.socket.send_to(&datagram, self.relay).await?;
+		Ok(n.saturating_sub(header.encoded_len()))
+	}
+
+	/// Receives a datagram relayed by the server, returning the number of
+	/// bytes of data read and the address it came from.
+	///
+	/// Datagrams which do not come from the relay server, are malformed or
+	/// fragmented are dropped.
+	pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, TargetAddr)> {
+		let mut datagram = vec![0u8; buf.len() + MAX_UDP_HEADER_SIZE];
+		loop {
+			let (n, src) = self.socket.recv_from(&mut datagram).await?;
+			if src != self.relay {
+				warn!("drop udp datagram from unknown source {}", &src);
+				continue;
+			}
+			match UdpHeader::parse(&datagram[..n]) {
+				Ok((header, data)) if !header.is_fragment() => {
+					let n = std::cmp::min(data.len(), buf.len());
+					buf[..n].copy_from_slice(&data[..n]);
+					return Ok((n, header.target));
+				}
+				Ok(_) => warn!("drop fragmented udp datagram from {}", &src),
+				Err(e) => warn!("drop malformed udp datagram from {}, {}", &src, e),
+			}
+		}
+	}
 }
 
 impl<C> Service<TargetAddr> for Client<C>
@@ -51,9 +243,11 @@ where
 	fn call(&mut self, target: TargetAddr) -> Self::Future<'_> {
 		let future = self.connect.call(()).map_err(Into::<io::Error>::into);
 		let authorization = self.authorization.clone();
+		let handshake_timeout = self.handshake_timeout;
 		Box::pin(async move {
 			let mut socket  = future.await?;
-			match proxy_socks(&mut socket, target.clone(), authorization).await {
+			let handshake = proxy_socks(&mut socket, target.clone(), authorization);
+			match with_timeout(handshake_timeout, || format!("proxy socks to {}", target), handshake).await {
 				Ok(()) => Ok(socket),
 				Err(e) => {
 					error!("unable to proxy socks to {}, {}",&target, &e);
@@ -68,29 +262,207 @@ where
 	}
 }
 
+/// A client for the SOCKS4 server, which speaks SOCKS4a if the remote dns is
+/// enabled.
+#[derive(Clone)]
+pub struct Socks4Client<C> {
+	user_id: String,
+	remote_dns: bool,
+	resolver: Arc<dyn Resolver>,
+	handshake_timeout: Duration,
+	connect: StreamConnect<C>,
+}
+
+impl<C: fmt::Debug> fmt::Debug for Socks4Client<C> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("Socks4Client")
+			.field("user_id", &self.user_id)
+			.field("remote_dns", &self.remote_dns)
+			.field("handshake_timeout", &self.handshake_timeout)
+			.field("connect", &self.connect)
+			.finish_non_exhaustive()
+	}
+}
+
+impl<C> Socks4Client<C> {
+	pub fn new(target: TargetAddr, connect: C) -> Self {
+		Socks4Client {
+			user_id: String::new(),
+			remote_dns: false,
+			resolver: Arc::new(DnsCache::system()),
+			handshake_timeout: PROXY_HANDSHAKE_TIMEOUT,
+			connect: StreamConnect::new(connect, target),
+		}
+	}
+
+	pub fn set_user_id(&mut self, user_id: String) {
+		self.user_id = user_id
+	}
+
+	/// Sends the domain to the server to resolve as SOCKS4a does, otherwise
+	/// the domain is resolved locally since SOCKS4 only accepts IPv4 address.
+	pub fn set_remote_dns(&mut self, remote_dns: bool) {
+		self.remote_dns = remote_dns
+	}
+
+	/// Sets the resolver to resolve the domain locally if the remote dns is
+	/// disabled, which is the cached system resolver by default.
+	pub fn set_resolver<R: Resolver + 'static>(&mut self, resolver: R) {
+		self.resolver = Arc::new(resolver)
+	}
+
+	pub fn enable_tls(&mut self) {
+		self.connect.set_tls(true)
+	}
+
+	/// Sets the time of the TLS handshake with the proxy server.
+	pub fn set_tls_timeout(&mut self, timeout: Duration) {
+		self.connect.set_tls_timeout(timeout)
+	}
+
+	/// Sets the time of the SOCKS handshake until the proxy server replies,
+	/// which fails with `TimedOut` once it elapses.
+	pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+		self.handshake_timeout = timeout
+	}
+}
+
+impl<C> Service<TargetAddr> for Socks4Client<C>
+where
+    C: Service<TargetAddr> + Send + 'static,
+    C::Response: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    C::Error: Into<io::Error> + Send,
+{
+    type Response = ProxyStream<C::Response>;
+
+    type Error = io::Error;
+
+    type Future<'a> = impl Future<Output = Result<Self::Response, Self::Error>> + Send +'a 
+    where
+        Self: 'a;
+
+	fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		self.connect.poll_ready(cx)
+	}
+
+	fn call(&mut self, target: TargetAddr) -> Self::Future<'_> {
+		let future = self.connect.call(()).map_err(Into::<io::Error>::into);
+		let user_id = self.user_id.clone();
+		let remote_dns = self.remote_dns;
+		let resolver = self.resolver.clone();
+		let handshake_timeout = self.handshake_timeout;
+		Box::pin(async move {
+			let target = if remote_dns {
+				target
+			} else {
+				let resolve = resolve_v4(&resolver, &target);
+				with_timeout(handshake_timeout, || format!("resolve {} for socks4", target), resolve).await?
+			};
+			let mut socket  = future.await?;
+			let handshake = proxy_socks4(&mut socket, target.clone(), user_id);
+			match with_timeout(handshake_timeout, || format!("proxy socks4 to {}", target), handshake).await {
+				Ok(()) => Ok(socket),
+				Err(e) => {
+					error!("unable to proxy socks4 to {}, {}",&target, &e);
+					if let Err(e) = socket.shutdown().await {
+						error!("unable to shutdown proxy stream, {}", &e);
+					}
+					Err(e)
+				}
+			}
+		})
+	}
+}
+
+/// Resolves the target to an IPv4 address for SOCKS4 by the resolver.
+async fn resolve_v4(resolver: &Arc<dyn Resolver>, target: &TargetAddr) -> io::Result<TargetAddr> {
+	match target {
+		TargetAddr::SocketAddr(SocketAddr::V4(_)) => Ok(target.clone()),
+		TargetAddr::SocketAddr(SocketAddr::V6(_)) => Err(io_err!(Kind::AddressTypeNotSupported)),
+		TargetAddr::Domain(domain, _) => {
+			let addr = target
+				.resolve_all(resolver)
+				.await?
+				.into_iter()
+				.find(|addr| addr.is_ipv4())
+				.ok_or_else(|| io_err!(Kind::AddressTypeNotSupported, "no ipv4 address for {}", domain))?;
+			Ok(TargetAddr::SocketAddr(addr))
+		}
+	}
+}
+
+async fn proxy_socks4<S>(socket: &mut S, target: TargetAddr, user_id: String) -> io::Result<()>
+where 
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	debug!("write socks4 request for {}", &target);
+	Socks4Request::new(target, user_id).write(socket).await?;
+	let reply = check_valid!(Socks4Reply::read(socket).await);
+	match reply.code {
+		SOCKS4_GRANTED => Ok(()),
+		SOCKS4_REJECTED => Err(io_err!(Kind::GeneralSocksServerFailure)),
+		SOCKS4_IDENTD_UNREACHABLE | SOCKS4_IDENTD_MISMATCH => Err(io_err!(Kind::Unauthorized)),
+		_ => Err(io_err!(Kind::UnknownRep)),
+	}
+}
+
 async fn proxy_socks<S>(socket: &mut S, target: TargetAddr, authorization: Option<(String, String)>) -> io::Result<()>
 where 
 	S: AsyncRead + AsyncWrite + Unpin,
 {
 	debug!("proxy socks for {}", &target);
+	negotiate(socket, authorization).await?;
+
+	debug!("write request for {}", &target);
+	request(socket, Request::new(target)).await?;
+	Ok(())
+}
+
+async fn proxy_associate<S>(socket: &mut S, authorization: Option<(String, String)>) -> io::Result<TargetAddr>
+where 
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	negotiate(socket, authorization).await?;
+
+	// The client does not know the address it will send datagrams from yet,
+	// so it uses the address and port of all zeros.
+	let unknown = TargetAddr::SocketAddr(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+	let reply = request(socket, Request::with_command(Command::Associate, unknown)).await?;
+	reply.target.ok_or_else(|| io_err!(Kind::AddressTypeNotSupported))
+}
+
+async fn proxy_bind<S>(socket: &mut S, target: TargetAddr, authorization: Option<(String, String)>) -> io::Result<TargetAddr>
+where 
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	negotiate(socket, authorization).await?;
+
+	let reply = request(socket, Request::with_command(Command::Bind, target)).await?;
+	reply.target.ok_or_else(|| io_err!(Kind::AddressTypeNotSupported))
+}
+
+async fn negotiate<S>(socket: &mut S, authorization: Option<(String, String)>) -> io::Result<()>
+where 
+	S: AsyncRead + AsyncWrite + Unpin,
+{
 	let mut candidate = CandidateMethods::new(vec![Method::NoAuthenticationRequired]);
 	if authorization.is_some() {
 		candidate.add(Method::UsernameAndPassword);
 	}
 	
-	debug!("write methods for {}", &target);
+	debug!("write methods");
 	candidate.write(socket).await?;
 	let selection = check_valid!(Selection::read(socket).await);
 	if let Some(method) = selection.method() {
 		match method {
     		Method::NoAuthenticationRequired => Ok(()),
     		Method::UsernameAndPassword => if let Some((u, p)) = authorization {
-				UsernameAndPassword::new(u, p).write(socket).await?;
+				UsernameAndPassword::new(u.clone(), p).write(socket).await?;
 				let status = Status::read(socket).await?;
 				if status.is_succeed() {
 					Ok(())
 				} else {
-					error!("authenticate failed for {}", &target);
+					error!("authenticate failed for {}", &u);
 					Err(io_err!(Kind::Unauthorized))
 				}
 			} else {
@@ -106,13 +478,24 @@ where
 	} else {
 		error!("unknown method({})", selection.method);
 		Err(io_err!(Kind::UnknownMethod))
-	}?;
-	
-	debug!("write request for {}", &target);
-	Request::new(target).write(socket).await?;
+	}
+}
+
+async fn request<S>(socket: &mut S, request: Request) -> io::Result<Reply>
+where 
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	request.write(socket).await?;
+	reply(socket).await
+}
+
+async fn reply<S>(socket: &mut S) -> io::Result<Reply>
+where 
+	S: AsyncRead + Unpin,
+{
 	let reply = check_valid!(Reply::read(socket).await);
 	match reply.rep() {
-    	Some(Rep::Succeeded) => Ok(()),
+    	Some(Rep::Succeeded) => Ok(reply),
 		Some(Rep::NetworkUnreachable) => Err(io::ErrorKind::NetworkUnreachable.into()),
 		Some(Rep::HostUnreachable) => Err(io::ErrorKind::HostUnreachable.into()),
 		Some(Rep::ConnectionRefused) => Err(io::ErrorKind::ConnectionRefused.into()),
@@ -123,4 +506,4 @@ where
 		Some(Rep::ConnectionNotAllowedByRuleset) => Err(io_err!(Kind::ConnectionNotAllowedByRuleset)),
 		None => Err(io_err!(Kind::UnknownRep)),
 	}
-}
\ No newline at end of file
+}
@@ -44,7 +44,6 @@ impl Error {
         }
     }
 
-    #[allow(dead_code)]
     pub(crate) fn with<C: Into<Box<dyn StdError + Sync + Send>>>(mut self, cause: C) -> Error {
         self.inner.cause = Some(cause.into());
         self
@@ -97,7 +96,7 @@ impl From<Kind> for Error {
 
 #[derive(Debug, Clone)]
 pub struct Message {
-    message: String,
+    pub(crate) message: String,
 }
 
 impl fmt::Display for Message {
@@ -110,18 +109,18 @@ impl StdError for Message {}
 
 #[macro_export]
 macro_rules! efmt {
-    ($($args:tt)*) => {
+    ($($args:tt)*) => {{
         let message = format!($($args)*);
         $crate::error::Message { message }
-    };
+    }};
 }
 
 #[macro_export]
 macro_rules! socks_err {
-    ($kind:expr, $($args:tt)+) => {
+    ($kind:expr, $($args:tt)+) => {{
         let message = format!($($args)*);
         $crate::error::Error::new($kind).with($crate::error::Message { message })
-    };
+    }};
 
     ($kind:expr) => {
         $crate::error::Error::new($kind)
@@ -1,34 +1,82 @@
 use std::{
+    collections::HashMap,
+    fmt,
     future::Future,
     io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4},
+    sync::Arc,
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
-use log::{debug, error, warn};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{future::BoxFuture, stream::FuturesUnordered, StreamExt};
+use log::{debug, error, trace, warn};
 use proxy::Service;
-use proxy_auth::Authenticator;
-use proxy_io::{Duplex, TargetAddr};
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use proxy_auth::{AsyncAuthenticator, Identity};
+use proxy_io::{
+    with_timeout, ConnectRequest, DuplexTimeouts, Relay, Route, Router, SocketStream, TargetAddr,
+    Throttle, CLIENT_HANDSHAKE_TIMEOUT,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
 
 use crate::{
     check_valid,
     error::Kind,
     io_err,
     types::{
-        CandidateMethods, Method, Rep, Reply, Request, Selection, Status, UsernameAndPassword,
+        CandidateMethods, Command, Method, Rep, Reply, Request, Selection, Socks4Reply,
+        Socks4Request, Status, UdpHeader, UsernameAndPassword, SOCKS4_GRANTED, SOCKS4_REJECTED,
+        SOCKS4_VERSION,
     },
 };
 
-#[derive(Debug, Clone)]
+/// The largest payload that fits in a UDP datagram.
+const UDP_BUFFER_SIZE: usize = 65535;
+
+/// How long the route of a target of the UDP association is cached.
+const UDP_ROUTE_TTL: Duration = Duration::from_secs(60);
+
+/// The most targets whose routes are cached for a UDP association.
+const MAX_UDP_ROUTES: usize = 1024;
+
+/// The most datagrams which wait for the route of a target.
+const MAX_PENDING_DATAGRAMS: usize = 16;
+
+#[derive(Clone)]
 pub struct Server<A, C> {
     authenticate: Option<A>,
+    handshake_timeout: Duration,
+    relay: Relay,
+    router: Option<Arc<dyn Router>>,
     connect: C,
 }
 
+impl<A, C> fmt::Debug for Server<A, C>
+where
+    A: fmt::Debug,
+    C: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Server")
+            .field("authenticate", &self.authenticate)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .field("relay", &self.relay)
+            .field("connect", &self.connect)
+            .finish_non_exhaustive()
+    }
+}
+
 impl<A, C> Server<A, C> {
     pub fn new(connect: C) -> Self {
         Self {
             authenticate: None,
+            handshake_timeout: CLIENT_HANDSHAKE_TIMEOUT,
+            relay: Relay::new(),
+            router: None,
             connect,
         }
     }
@@ -36,13 +84,38 @@ impl<A, C> Server<A, C> {
     pub fn set_authenticate(&mut self, authenticate: A) {
         self.authenticate = Some(authenticate)
     }
+
+    /// Sets the time for the client to finish the handshake and send its
+    /// request, after which the connection is closed.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout
+    }
+
+    /// Sets the timeouts of the relay after the CONNECT or BIND succeeds.
+    pub fn set_duplex_timeouts(&mut self, timeouts: DuplexTimeouts) {
+        self.relay.set_timeouts(timeouts)
+    }
+
+    /// Throttles the relay after the CONNECT or BIND succeeds.
+    pub fn set_throttle(&mut self, throttle: Throttle) {
+        self.relay.set_throttle(throttle)
+    }
+
+    /// Sets the router which decides the BIND and UDP ASSOCIATE requests by
+    /// the same rules as the connector, those are relayed directly so the
+    /// targets which are proxied or denied are refused.
+    ///
+    /// The requests are not checked if it is not set.
+    pub fn set_router<R: Router + 'static>(&mut self, router: R) {
+        self.router = Some(Arc::new(router))
+    }
 }
 
 impl<I, A, C> Service<I> for Server<A, C>
 where
-    A: Authenticator + Send + Sync,
-    I: AsyncWrite + AsyncRead + Send + Unpin + 'static,
-    C: Service<TargetAddr> + Send + 'static,
+    A: AsyncAuthenticator,
+    I: AsyncWrite + AsyncRead + SocketStream + Send + Unpin + 'static,
+    C: Service<ConnectRequest> + Send + 'static,
     C::Response: AsyncWrite + AsyncRead + Send + Unpin + 'static,
     C::Error: Into<io::Error> + Send,
 {
@@ -60,74 +133,78 @@ where
 
     fn call(&mut self, mut socket: I) -> Self::Future<'_> {
         Box::pin(async move {
-            if let Err(e) = if let Some(auth) = &self.authenticate {
-                prepare_with(&mut socket, auth).await
-            } else {
-                prepare(&mut socket).await
-            } {
-                if let Err(ioe) = socket.shutdown().await {
-                    error!("unable to shutdown the socket, {}", ioe);
-                }
-
-                return Err(e);
+            let handshake = async {
+                // SOCKS4 and SOCKS5 are served on the same socket, so the
+                // protocol is detected by the version which comes first.
+                let version = socket.read_u8().await?;
+                let req = if version == SOCKS4_VERSION {
+                    handle_v4(&mut socket, self.authenticate.is_some())
+                        .await
+                        .map(|(command, target)| (command, target, None))
+                } else {
+                    match if let Some(auth) = &self.authenticate {
+                        prepare_with(&mut socket, version, auth).await.map(Some)
+                    } else {
+                        prepare(&mut socket, version).await.map(|_| None)
+                    } {
+                        Ok(identity) => handle(&mut socket)
+                            .await
+                            .map(|(command, target)| (command, target, identity)),
+                        Err(e) => Err(e),
+                    }
+                };
+                req.map(|(command, target, identity)| (version, command, target, identity))
             };
+            let req = with_timeout(
+                self.handshake_timeout,
+                || "socks handshake".to_string(),
+                handshake,
+            )
+            .await;
 
-            match handle(&mut socket).await {
-                Ok(target) => {
-                    debug!("proxy connect to {}", &target);
-                    match self.connect.call(target.clone()).await {
-                        Ok(mut conn) => match Reply::new(Rep::Succeeded).write(&mut socket).await {
-                            Ok(()) => {
-                                debug!("bidirectional copy for {}", &target);
-                                // let (a, b) =
-                                //     tokio::io::copy_bidirectional(&mut socket, &mut conn).await?;
-                                // debug!("{} <> {}", a, b);
-                                // Ok(())
-                                Duplex::new(socket, conn).await
-                            }
-                            Err(e) => {
-                                error!("unable write succeeded reply to socket, {}", &e);
-                                if let Err(ioe) = conn.shutdown().await {
-                                    error!("unable to shutdown the proxy socket, {}", ioe);
-                                }
-                                Err(e)
-                            }
-                        },
-                        Err(e) => {
-                            let ioe: io::Error = e.into();
-                            error!("proxy connect to {}, {}", &target, &ioe);
-                            let rep = match ioe.kind() {
-                                io::ErrorKind::ConnectionRefused => Rep::ConnectionRefused,
-                                io::ErrorKind::HostUnreachable => Rep::HostUnreachable,
-                                io::ErrorKind::NetworkUnreachable => Rep::NetworkUnreachable,
-                                _ => Rep::GeneralSocksServerFailure,
-                            };
-                            if let Err(e) = Reply::new(rep).write(&mut socket).await {
-                                error!("unable to write reply to socket, {}", e);
-                            } else if let Err(e) = socket.shutdown().await {
-                                error!("unable to shutdown the socket, {}", e);
-                            }
-                            Err(ioe)
-                        }
-                    }
-                }
+            let (version, command, target, identity) = match req {
+                Ok(req) => req,
                 Err(e) => {
                     if let Err(ioe) = socket.shutdown().await {
                         error!("unable to shutdown the socket, {}", ioe);
                     }
-                    Err(e)
+                    return Err(e);
+                }
+            };
+
+            match command {
+                Command::Connect => {
+                    let req = ConnectRequest::new(target, identity);
+                    debug!("proxy connect to {}", &req);
+                    connect(socket, version, &mut self.connect, req, &self.relay).await
+                }
+                Command::Bind => {
+                    debug!("bind for {}", &target);
+                    let req = ConnectRequest::new(target, identity);
+                    if let Some(router) = &self.router {
+                        if let Err(e) = route_direct(router.as_ref(), &req).await {
+                            error!("refuse bind for {}, {}", &req, &e);
+                            reply(&mut socket, version, rep_of(&e), None).await?;
+                            return Err(e);
+                        }
+                    }
+                    bind(socket, version, req.target, req.identity, &self.relay).await
+                }
+                Command::Associate => {
+                    debug!("udp associate for {}", &target);
+                    associate(&mut socket, target, identity, self.router.clone()).await
                 }
             }
         })
     }
 }
 
-async fn prepare<S>(socket: &mut S) -> io::Result<()>
+async fn prepare<S>(socket: &mut S, version: u8) -> io::Result<()>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
     debug!("serve without authorization");
-    let methods = check_valid!(CandidateMethods::read(socket).await);
+    let methods = check_valid!(CandidateMethods::read_with_version(version, socket).await);
     if !methods.has(Method::NoAuthenticationRequired) {
         Selection::new(Method::NoAcceptableMethods)
             .write(socket)
@@ -140,13 +217,13 @@ where
     Ok(())
 }
 
-async fn prepare_with<S, A>(socket: &mut S, auth: &A) -> io::Result<()>
+async fn prepare_with<S, A>(socket: &mut S, version: u8, auth: &A) -> io::Result<Identity>
 where
     S: AsyncRead + AsyncWrite + Unpin,
-    A: Authenticator,
+    A: AsyncAuthenticator,
 {
     debug!("serve with authorization");
-    let methods = check_valid!(CandidateMethods::read(socket).await);
+    let methods = check_valid!(CandidateMethods::read_with_version(version, socket).await);
     if !methods.has(Method::UsernameAndPassword) {
         warn!("no authorization method provided");
         Selection::new(Method::NoAcceptableMethods)
@@ -159,17 +236,27 @@ where
         .await?;
 
     let user_pass = check_valid!(UsernameAndPassword::read(socket).await);
-    if !auth.authenticate(&user_pass.username, &user_pass.password) {
-        error!("unable to authenticate the socket");
-        Status::new(0x01).write(socket).await?;
-        return Err(io_err!(Kind::Unauthorized));
+    match auth.verify(&user_pass.username, &user_pass.password).await {
+        Ok(Some(identity)) => {
+            Status::new(0x00).write(socket).await?;
+            Ok(identity)
+        }
+        Ok(None) => {
+            error!("unable to authenticate the socket");
+            Status::new(0x01).write(socket).await?;
+            Err(io_err!(Kind::Unauthorized))
+        }
+        // SOCKS5 has no status for the failure of the server, so the client
+        // is told to be rejected all the same.
+        Err(e) => {
+            error!("unable to authenticate {}, {}", &user_pass.username, &e);
+            Status::new(0x01).write(socket).await?;
+            Err(e)
+        }
     }
-
-    Status::new(0x00).write(socket).await?;
-    Ok(())
 }
 
-async fn handle<S>(socket: &mut S) -> io::Result<TargetAddr>
+async fn handle<S>(socket: &mut S) -> io::Result<(Command, TargetAddr)>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
@@ -182,10 +269,552 @@ where
         return Err(io_err!(Kind::AddressTypeNotSupported));
     }
 
-    if req.is_connect() {
-        Ok(req.target.unwrap())
+    match req.command() {
+        Some(command) => Ok((command, req.target.unwrap())),
+        None => {
+            Reply::new(Rep::CommandNotSupported).write(socket).await?;
+            Err(io_err!(Kind::CommandNotSupported))
+        }
+    }
+}
+
+/// Rejects the client with the failure reply once its request is read, so
+/// that the client knows the reason rather than seeing the connection closed.
+pub async fn reject<S>(mut socket: S, rep: Rep) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let handshake = async {
+        let version = socket.read_u8().await?;
+        if version == SOCKS4_VERSION {
+            handle_v4(&mut socket, false).await?;
+        } else {
+            prepare(&mut socket, version).await?;
+            handle(&mut socket).await?;
+        }
+        Ok(version)
+    };
+    let version = with_timeout(
+        CLIENT_HANDSHAKE_TIMEOUT,
+        || "socks handshake".to_string(),
+        handshake,
+    )
+    .await?;
+    reply(&mut socket, version, rep, None).await?;
+    socket.shutdown().await
+}
+
+/// Handles the SOCKS4 and SOCKS4a requests whose version was already read.
+///
+/// SOCKS4 has no way to authenticate the client but the USERID, so all of
+/// the requests are rejected if the server requires authorization.
+async fn handle_v4<S>(socket: &mut S, authorization: bool) -> io::Result<(Command, TargetAddr)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    debug!("handle socks4 socket");
+    let req = check_valid!(Socks4Request::read_with_version(SOCKS4_VERSION, socket).await);
+    if authorization {
+        warn!("socks4 is not allowed for {}", &req.user_id);
+        Socks4Reply::new(SOCKS4_REJECTED).write(socket).await?;
+        return Err(io_err!(Kind::Unauthorized));
+    }
+
+    match req.command() {
+        Some(command) => Ok((command, req.target)),
+        None => {
+            Socks4Reply::new(SOCKS4_REJECTED).write(socket).await?;
+            Err(io_err!(Kind::CommandNotSupported))
+        }
+    }
+}
+
+/// Writes the reply in the format of the protocol version.
+async fn reply<S>(
+    socket: &mut S,
+    version: u8,
+    rep: Rep,
+    bound: Option<SocketAddr>,
+) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    if version == SOCKS4_VERSION {
+        let code = if rep == Rep::Succeeded {
+            SOCKS4_GRANTED
+        } else {
+            SOCKS4_REJECTED
+        };
+        let bound = match bound {
+            Some(SocketAddr::V4(v4)) => v4,
+            _ => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+        };
+        Socks4Reply::with_target(code, bound).write(socket).await
     } else {
-        Reply::new(Rep::CommandNotSupported).write(socket).await?;
-        Err(io_err!(Kind::CommandNotSupported))
+        match bound {
+            Some(addr) => Reply::with_target(rep, addr.into()),
+            None => Reply::new(rep),
+        }
+        .write(socket)
+        .await
+    }
+}
+
+/// Serves the CONNECT request.
+async fn connect<S, C>(
+    mut socket: S,
+    version: u8,
+    connect: &mut C,
+    req: ConnectRequest,
+    relay: &Relay,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + 'static,
+    C: Service<ConnectRequest>,
+    C::Response: AsyncWrite + AsyncRead + Unpin + 'static,
+    C::Error: Into<io::Error>,
+{
+    let target = req.target.clone();
+    let identity = req.identity.clone();
+    match connect.call(req).await {
+        Ok(mut conn) => match reply(&mut socket, version, Rep::Succeeded, None).await {
+            Ok(()) => {
+                debug!("bidirectional copy for {}", &target);
+                let stats = relay.run(socket, conn, identity.as_ref()).await?;
+                debug!("completed copy for {}, {}", &target, stats);
+                Ok(())
+            }
+            Err(e) => {
+                error!("unable write succeeded reply to socket, {}", &e);
+                if let Err(ioe) = conn.shutdown().await {
+                    error!("unable to shutdown the proxy socket, {}", ioe);
+                }
+                Err(e)
+            }
+        },
+        Err(e) => {
+            let ioe: io::Error = e.into();
+            error!("proxy connect to {}, {}", &target, &ioe);
+            if let Err(e) = reply(&mut socket, version, rep_of(&ioe), None).await {
+                error!("unable to write reply to socket, {}", e);
+            } else if let Err(e) = socket.shutdown().await {
+                error!("unable to shutdown the socket, {}", e);
+            }
+            Err(ioe)
+        }
+    }
+}
+
+/// Returns the reply for the error of connecting the target.
+fn rep_of(e: &io::Error) -> Rep {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused => Rep::ConnectionRefused,
+        io::ErrorKind::HostUnreachable => Rep::HostUnreachable,
+        io::ErrorKind::NetworkUnreachable => Rep::NetworkUnreachable,
+        io::ErrorKind::PermissionDenied => Rep::ConnectionNotAllowedByRuleset,
+        // SOCKS5 has no reply for the timeout, and TTL expired is the
+        // closest one which clients take as the host is unreachable.
+        io::ErrorKind::TimedOut => Rep::TtlExpired,
+        _ => Rep::GeneralSocksServerFailure,
+    }
+}
+
+/// Routes the request which is relayed directly, so that it is refused with
+/// `PermissionDenied` if the router proxies it or denies it.
+async fn route_direct(router: &dyn Router, req: &ConnectRequest) -> io::Result<TargetAddr> {
+    match router.route(req).await? {
+        Route::Direct(target) => Ok(target),
+        Route::Proxy(target) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is proxied, which is not relayed directly", target),
+        )),
+    }
+}
+
+/// Serves the UDP ASSOCIATE request.
+///
+/// A UDP association terminates when the TCP connection that the UDP
+/// ASSOCIATE request arrived on terminates.
+async fn associate<S>(
+    socket: &mut S,
+    client: TargetAddr,
+    identity: Option<Identity>,
+    router: Option<Arc<dyn Router>>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + SocketStream + Unpin,
+{
+    let local = socket.local_addr()?;
+    let peer = socket.peer_addr()?;
+    let relay = match UdpSocket::bind(SocketAddr::new(local.ip(), 0)).await {
+        Ok(relay) => relay,
+        Err(e) => {
+            error!("unable to bind udp relay on {}, {}", local.ip(), &e);
+            Reply::new(Rep::GeneralSocksServerFailure)
+                .write(socket)
+                .await?;
+            return Err(e);
+        }
+    };
+    let bound = relay.local_addr()?;
+    Reply::with_target(Rep::Succeeded, bound.into())
+        .write(socket)
+        .await?;
+
+    debug!("relay udp for {} on {}", &peer, &bound);
+    let mut relay = UdpRelay::new(relay, peer.ip(), client, identity, router);
+    tokio::select! {
+        res = relay.run() => res,
+        res = wait_closed(socket) => {
+            debug!("udp association for {} terminated", &peer);
+            res
+        }
+    }
+}
+
+/// Serves the BIND request.
+///
+/// The server sends two replies to the client: the first one after it binds
+/// the socket to listen for an incoming connection, and the second one after
+/// the anticipated incoming connection succeeds or fails.
+async fn bind<S>(
+    mut socket: S,
+    version: u8,
+    expected: TargetAddr,
+    identity: Option<Identity>,
+    relay: &Relay,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + SocketStream + Unpin + 'static,
+{
+    let local = socket.local_addr()?;
+    let listener = match TcpListener::bind(SocketAddr::new(local.ip(), 0)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("unable to bind listener on {}, {}", local.ip(), &e);
+            reply(&mut socket, version, Rep::GeneralSocksServerFailure, None).await?;
+            return Err(e);
+        }
+    };
+    let bound = listener.local_addr()?;
+    reply(&mut socket, version, Rep::Succeeded, Some(bound)).await?;
+
+    debug!("listen on {} for {}", &bound, &expected);
+    let (inbound, from) = tokio::select! {
+        res = accept(&listener, &expected) => match res {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("unable to accept connection on {}, {}", &bound, &e);
+                reply(&mut socket, version, Rep::GeneralSocksServerFailure, None).await?;
+                return Err(e);
+            }
+        },
+        res = wait_closed(&mut socket) => {
+            debug!("bind for {} terminated", &expected);
+            return res;
+        }
+    };
+    drop(listener);
+
+    debug!("accept connection from {} on {}", &from, &bound);
+    reply(&mut socket, version, Rep::Succeeded, Some(from)).await?;
+    let stats = relay.run(socket, inbound, identity.as_ref()).await?;
+    debug!("completed bind for {}, {}", &expected, stats);
+    Ok(())
+}
+
+/// Accepts the incoming connection from the host the client expects.
+///
+/// Connections from other hosts are dropped if the client told the address
+/// of the host.
+async fn accept(
+    listener: &TcpListener,
+    expected: &TargetAddr,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    loop {
+        let (stream, from) = listener.accept().await?;
+        match expected {
+            TargetAddr::SocketAddr(addr)
+                if !addr.ip().is_unspecified() && addr.ip() != from.ip() =>
+            {
+                warn!("drop unexpected connection from {}", &from);
+            }
+            _ => return Ok((stream, from)),
+        }
+    }
+}
+
+/// Waits for the peer to close the connection, discarding any data it sends.
+async fn wait_closed<S>(socket: &mut S) -> io::Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = [0u8; 64];
+    while socket.read(&mut buf).await? > 0 {}
+    Ok(())
+}
+
+/// The route of a target of the UDP association.
+enum UdpRoute {
+    /// The route is being decided, with the datagrams which wait for it.
+    Pending(Vec<Bytes>),
+    /// The target is relayed directly to the address.
+    Resolved(SocketAddr, Instant),
+    /// The target is proxied or denied by the router.
+    Refused(Instant),
+}
+
+/// The route decided for the target, which is keyed by the target.
+type Routed = (String, io::Result<SocketAddr>);
+
+/// Relays datagrams between the client and the remote hosts.
+///
+/// The client-facing socket is bound to the same address as the control
+/// connection, while datagrams to the remote hosts are sent from sockets
+/// bound to the unspecified address, one per address family.
+///
+/// The targets are routed and resolved aside, so that a slow one does not
+/// hold the datagrams to the others, and the routes are cached for a while.
+struct UdpRelay {
+    inbound: UdpSocket,
+    outbound_v4: Option<UdpSocket>,
+    outbound_v6: Option<UdpSocket>,
+    peer: IpAddr,
+    client: Option<SocketAddr>,
+    identity: Option<Identity>,
+    router: Option<Arc<dyn Router>>,
+    routes: HashMap<String, UdpRoute>,
+}
+
+impl UdpRelay {
+    fn new(
+        inbound: UdpSocket,
+        peer: IpAddr,
+        client: TargetAddr,
+        identity: Option<Identity>,
+        router: Option<Arc<dyn Router>>,
+    ) -> Self {
+        // The DST.ADDR and DST.PORT fields contain the address and port that
+        // the client expects to use to send UDP datagrams on for the
+        // association. If the client is not in possesion of the information
+        // at the time of the UDP ASSOCIATE, the client MUST use a port number
+        // and address of all zeros.
+        let client = match client {
+            TargetAddr::SocketAddr(addr) if !addr.ip().is_unspecified() && addr.port() != 0 => {
+                Some(addr)
+            }
+            _ => None,
+        };
+        Self {
+            inbound,
+            outbound_v4: None,
+            outbound_v6: None,
+            peer,
+            client,
+            identity,
+            router,
+            routes: HashMap::new(),
+        }
+    }
+
+    async fn run(&mut self) -> io::Result<()> {
+        let mut inbound_buf = vec![0u8; UDP_BUFFER_SIZE];
+        let mut outbound_v4_buf = vec![0u8; UDP_BUFFER_SIZE];
+        let mut outbound_v6_buf = vec![0u8; UDP_BUFFER_SIZE];
+        let mut routing = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                res = self.inbound.recv_from(&mut inbound_buf) => {
+                    let (n, src) = res?;
+                    if let Some(route) = self.send_outbound(&inbound_buf[..n], src).await {
+                        routing.push(route);
+                    }
+                }
+                Some((key, res)) = routing.next() => {
+                    self.routed(key, res).await;
+                }
+                res = recv_from(&self.outbound_v4, &mut outbound_v4_buf) => {
+                    let (n, src) = res?;
+                    self.send_inbound(&outbound_v4_buf[..n], src).await;
+                }
+                res = recv_from(&self.outbound_v6, &mut outbound_v6_buf) => {
+                    let (n, src) = res?;
+                    self.send_inbound(&outbound_v6_buf[..n], src).await;
+                }
+            }
+        }
+    }
+
+    /// Unwraps the datagram from the client and sends it to the remote host,
+    /// or returns the future to route the target which is not routed yet.
+    ///
+    /// Datagrams which can not be relayed are dropped silently.
+    async fn send_outbound(
+        &mut self,
+        datagram: &[u8],
+        src: SocketAddr,
+    ) -> Option<BoxFuture<'static, Routed>> {
+        if !self.is_client(src) {
+            warn!("drop udp datagram from unknown source {}", &src);
+            return None;
+        }
+
+        let (header, data) = match UdpHeader::parse(datagram) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                warn!("drop malformed udp datagram from {}, {}", &src, e);
+                return None;
+            }
+        };
+        if header.is_fragment() {
+            warn!("drop fragmented udp datagram from {}", &src);
+            return None;
+        }
+
+        let key = header.target.to_string();
+        match self.routes.get_mut(&key) {
+            Some(UdpRoute::Resolved(dst, at)) if at.elapsed() < UDP_ROUTE_TTL => {
+                let dst = *dst;
+                self.send_to(data, dst).await;
+                None
+            }
+            Some(UdpRoute::Refused(at)) if at.elapsed() < UDP_ROUTE_TTL => {
+                trace!("drop udp datagram to refused {}", &key);
+                None
+            }
+            Some(UdpRoute::Pending(pending)) => {
+                if pending.len() < MAX_PENDING_DATAGRAMS {
+                    pending.push(Bytes::copy_from_slice(data));
+                } else {
+                    warn!("drop udp datagram to {}, too many are pending", &key);
+                }
+                None
+            }
+            _ => {
+                if self.routes.len() >= MAX_UDP_ROUTES {
+                    self.routes.retain(|_, route| match route {
+                        UdpRoute::Pending(_) => true,
+                        UdpRoute::Resolved(_, at) | UdpRoute::Refused(at) => {
+                            at.elapsed() < UDP_ROUTE_TTL
+                        }
+                    });
+                }
+                if self.routes.len() >= MAX_UDP_ROUTES {
+                    warn!("drop udp datagram to {}, too many targets", &key);
+                    return None;
+                }
+                self.routes.insert(
+                    key.clone(),
+                    UdpRoute::Pending(vec![Bytes::copy_from_slice(data)]),
+                );
+                Some(self.route(key, header.target))
+            }
+        }
+    }
+
+    /// Returns the future to route the target and resolve it, the targets
+    /// which the router proxies or denies are refused.
+    fn route(&self, key: String, target: TargetAddr) -> BoxFuture<'static, Routed> {
+        let req = ConnectRequest::new(target, self.identity.clone());
+        let router = self.router.clone();
+        Box::pin(async move {
+            let resolved = match router {
+                Some(router) => match route_direct(router.as_ref(), &req).await {
+                    Ok(target) => router.resolve(&target).await,
+                    Err(e) => Err(e),
+                },
+                None => req.target.resolve_dns().await,
+            };
+            (key, resolved)
+        })
+    }
+
+    /// Caches the route of the target, and sends the datagrams which wait
+    /// for it.
+    async fn routed(&mut self, key: String, resolved: io::Result<SocketAddr>) {
+        let pending = match (self.routes.remove(&key), &resolved) {
+            (Some(UdpRoute::Pending(pending)), _) => pending,
+            _ => Vec::new(),
+        };
+        match resolved {
+            Ok(dst) => {
+                self.routes
+                    .insert(key, UdpRoute::Resolved(dst, Instant::now()));
+                for data in pending {
+                    self.send_to(&data, dst).await;
+                }
+            }
+            Err(e) => {
+                warn!("drop {} udp datagrams to {}, {}", pending.len(), &key, e);
+                // The failure to resolve is not cached, as it may recover.
+                if e.kind() == io::ErrorKind::PermissionDenied {
+                    self.routes.insert(key, UdpRoute::Refused(Instant::now()));
+                }
+            }
+        }
+    }
+
+    async fn send_to(&mut self, data: &[u8], dst: SocketAddr) {
+        let outbound = match self.outbound(&dst).await {
+            Ok(outbound) => outbound,
+            Err(e) => {
+                error!("unable to bind outbound udp socket for {}, {}", &dst, e);
+                return;
+            }
+        };
+        trace!("relay {}B to {}", data.len(), &dst);
+        if let Err(e) = outbound.send_to(data, dst).await {
+            warn!("unable to relay udp datagram to {}, {}", &dst, e);
+        }
+    }
+
+    /// Wraps the datagram from the remote host and sends it to the client.
+    ///
+    /// The datagram which fails to be sent is dropped, and the association
+    /// goes on.
+    async fn send_inbound(&mut self, data: &[u8], src: SocketAddr) {
+        if let Some(client) = self.client {
+            let header = UdpHeader::new(TargetAddr::SocketAddr(src));
+            let mut datagram = BytesMut::with_capacity(header.encoded_len() + data.len());
+            if let Err(e) = header.write_to(&mut datagram) {
+                warn!("drop udp datagram from {}, {}", &src, e);
+                return;
+            }
+            datagram.put_slice(data);
+            trace!("relay {}B from {} to {}", data.len(), &src, &client);
+            if let Err(e) = self.inbound.send_to(&datagram, client).await {
+                warn!("unable to relay udp datagram to {}, {}", &client, e);
+            }
+        }
+    }
+
+    fn is_client(&mut self, src: SocketAddr) -> bool {
+        match self.client {
+            Some(client) => client == src,
+            None if src.ip() == self.peer => {
+                self.client = Some(src);
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn outbound(&mut self, dst: &SocketAddr) -> io::Result<&UdpSocket> {
+        let (outbound, unspecified) = match dst {
+            SocketAddr::V4(_) => (&mut self.outbound_v4, IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            SocketAddr::V6(_) => (&mut self.outbound_v6, IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        };
+        if outbound.is_none() {
+            *outbound = Some(UdpSocket::bind(SocketAddr::new(unspecified, 0)).await?);
+        }
+        Ok(outbound.as_ref().unwrap())
+    }
+}
+
+/// Receives a datagram from the socket, or waits forever if it is not bound.
+async fn recv_from(socket: &Option<UdpSocket>, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => futures::future::pending().await,
     }
 }
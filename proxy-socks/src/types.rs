@@ -4,6 +4,7 @@ use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
 use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
 
+use bytes::BufMut;
 use proxy_io::{AsyncFixedReadExt, AsyncFixedWriteExt, TargetAddr};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
@@ -48,6 +49,24 @@ pub const AUTH_VERSION: u8 = 0x01;
 // Auth Status
 pub const AUTH_SUCCEED: u8 = 0x00;
 
+// Socks4 Version
+pub const SOCKS4_VERSION: u8 = 0x04;
+
+// Socks4 Reply Version
+pub const SOCKS4_REPLY_VERSION: u8 = 0x00;
+
+// Socks4 RESPONSE CODEs
+pub const SOCKS4_GRANTED: u8 = 0x5a;
+pub const SOCKS4_REJECTED: u8 = 0x5b;
+pub const SOCKS4_IDENTD_UNREACHABLE: u8 = 0x5c;
+pub const SOCKS4_IDENTD_MISMATCH: u8 = 0x5d;
+
+// The longest USERID or domain name accepted in the Socks4 request
+pub const SOCKS4_MAX_FIELD_SIZE: usize = 255;
+
+// UDP Header, RSV + FRAG + ATYP + the longest DST.ADDR + DST.PORT
+pub const MAX_UDP_HEADER_SIZE: usize = 2 + 1 + 1 + 256 + 2;
+
 /// Socks authentication method.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Method {
@@ -105,7 +124,6 @@ pub enum Command {
 }
 
 impl Command {
-    #[allow(dead_code)]
     pub(crate) fn from_u8(code: u8) -> Option<Command> {
         match code {
             CONNECT | BIND | UDP_ASSOCIATE => Some(unsafe { std::mem::transmute(code) }),
@@ -348,6 +366,85 @@ pub struct Reply {
     pub target: Option<TargetAddr>,
 }
 
+/// A UDP-based client MUST send its datagrams to the UDP relay server at
+/// the UDP port indicated by BND.PORT in the reply to the UDP ASSOCIATE
+/// request. Each UDP datagram carries a UDP request header with it:
+///
+/// +----+------+------+----------+----------+----------+
+/// |RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
+/// +----+------+------+----------+----------+----------+
+/// | 2  |  1   |  1   | Variable |    2     | Variable |
+/// +----+------+------+----------+----------+----------+
+///
+/// Where:
+/// o  RSV  Reserved X0000
+/// o  FRAG Current fragment number
+/// o  ATYP address type of following addresses:
+///    o  IP V4 address: X01
+///    o  DOMAINNAME: X03
+///    o  IP V6 address: X04
+/// o  DST.ADDR desired destination address
+/// o  DST.PORT desired destination port
+/// o  DATA user data
+///
+/// An implementation that does not support fragmentation MUST drop any
+/// datagram whose FRAG field is other than X00.
+#[derive(Debug, Clone)]
+pub struct UdpHeader {
+    pub frag: u8,
+    pub target: TargetAddr,
+}
+
+/// The client connects to the SOCKS4 server and sends a request when it
+/// wants to establish a connection to an application server:
+///
+/// +----+----+----+----+----+----+----+----+----+----+....+----+
+/// | VN | CD | DSTPORT |      DSTIP        | USERID       |NULL|
+/// +----+----+----+----+----+----+----+----+----+----+....+----+
+/// | 1  | 1  |    2    |         4         | variable     | 1  |
+/// +----+----+----+----+----+----+----+----+----+----+....+----+
+///
+/// VN is the SOCKS protocol version number and should be X04. CD is the
+/// SOCKS command code and should be X01 for CONNECT request and X02 for
+/// BIND request. NULL is a byte of all zero bits.
+///
+/// SOCKS4a extends the request for the client which cannot resolve the
+/// destination host's domain name. The client sets the DSTIP to
+/// 0.0.0.x with x nonzero, and sends the domain name of the destination
+/// host terminated by NULL after the USERID.
+#[derive(Debug, Clone)]
+pub struct Socks4Request {
+    pub version: u8,
+    pub command: u8,
+    pub target: TargetAddr,
+    pub user_id: String,
+}
+
+/// The SOCKS4 server replies the request with:
+///
+/// +----+----+----+----+----+----+----+----+
+/// | VN | CD | DSTPORT |      DSTIP        |
+/// +----+----+----+----+----+----+----+----+
+/// | 1  | 1  |    2    |         4         |
+/// +----+----+----+----+----+----+----+----+
+///
+/// VN is the version of the reply code and should be X00. CD is the
+/// result code with one of the following values:
+/// o  X5A request granted
+/// o  X5B request rejected or failed
+/// o  X5C request rejected becasue SOCKS server cannot connect to
+///    identd on the client
+/// o  X5D request rejected because the client program and identd
+///    report different user-ids
+///
+/// DSTPORT and DSTIP are meaningful in the replies to the BIND request.
+#[derive(Debug, Clone)]
+pub struct Socks4Reply {
+    pub version: u8,
+    pub code: u8,
+    pub target: SocketAddrV4,
+}
+
 impl CandidateMethods {
     pub fn empty() -> Self {
         CandidateMethods {
@@ -373,6 +470,14 @@ impl CandidateMethods {
 
     pub async fn read<S: AsyncRead + Unpin>(socket: &mut S) -> io::Result<Self> {
         let version = socket.read_u8().await?;
+        Self::read_with_version(version, socket).await
+    }
+
+    /// Reads the methods after the version was read to detect the protocol.
+    pub async fn read_with_version<S: AsyncRead + Unpin>(
+        version: u8,
+        socket: &mut S,
+    ) -> io::Result<Self> {
         let mut methods = Vec::new();
         socket.read_to_fixed_bytes(&mut methods).await?;
         Ok(Self { version, methods })
@@ -424,7 +529,7 @@ impl Selection {
 impl UsernameAndPassword {
     pub fn new(username: String, password: String) -> Self {
         Self {
-            version: SOCKS_VERSION,
+            version: AUTH_VERSION,
             username,
             password,
         }
@@ -495,6 +600,18 @@ impl Request {
         validate!(self.version == SOCKS_VERSION, Kind::InvalidVersion)
     }
 
+    pub fn with_command(command: Command, target: TargetAddr) -> Self {
+        Self {
+            version: SOCKS_VERSION,
+            command: command.as_u8(),
+            target: Some(target),
+        }
+    }
+
+    pub fn command(&self) -> Option<Command> {
+        Command::from_u8(self.command)
+    }
+
     pub fn is_connect(&self) -> bool {
         self.command == Command::Connect.as_u8()
     }
@@ -577,6 +694,19 @@ impl Reply {
         }
     }
 
+    pub fn with_target(rep: Rep, target: TargetAddr) -> Self {
+        Self {
+            version: SOCKS_VERSION,
+            rep: rep.as_u8(),
+            atyp: match &target {
+                TargetAddr::SocketAddr(SocketAddr::V4(_)) => DST_IPV4,
+                TargetAddr::SocketAddr(SocketAddr::V6(_)) => DST_IPV6,
+                TargetAddr::Domain(..) => DST_DOMAIN,
+            },
+            target: Some(target),
+        }
+    }
+
     pub fn rep(&self) -> Option<Rep> {
         Rep::from_u8(self.rep)
     }
@@ -654,6 +784,267 @@ impl Reply {
     }
 }
 
+impl UdpHeader {
+    pub fn new(target: TargetAddr) -> Self {
+        Self { frag: 0, target }
+    }
+
+    pub fn is_fragment(&self) -> bool {
+        self.frag != 0
+    }
+
+    /// Parses the header at the front of the datagram, returning it along
+    /// with the user data that follows.
+    pub fn parse(datagram: &[u8]) -> io::Result<(Self, &[u8])> {
+        validate!(
+            datagram.len() > 4,
+            Kind::GeneralSocksServerFailure,
+            "truncated udp header"
+        )?;
+        let frag = datagram[2];
+        let (target, rest) = match datagram[3] {
+            DST_IPV4 => {
+                validate!(
+                    datagram.len() >= 10,
+                    Kind::GeneralSocksServerFailure,
+                    "truncated ipv4 address"
+                )?;
+                let ip = Ipv4Addr::new(datagram[4], datagram[5], datagram[6], datagram[7]);
+                let port = u16::from_be_bytes([datagram[8], datagram[9]]);
+                (
+                    TargetAddr::SocketAddr(SocketAddr::V4(SocketAddrV4::new(ip, port))),
+                    &datagram[10..],
+                )
+            }
+            DST_IPV6 => {
+                validate!(
+                    datagram.len() >= 22,
+                    Kind::GeneralSocksServerFailure,
+                    "truncated ipv6 address"
+                )?;
+                let mut ipv6 = [0u8; 16];
+                ipv6.copy_from_slice(&datagram[4..20]);
+                let port = u16::from_be_bytes([datagram[20], datagram[21]]);
+                (
+                    TargetAddr::SocketAddr(SocketAddr::V6(SocketAddrV6::new(
+                        Ipv6Addr::from(ipv6),
+                        port,
+                        0,
+                        0,
+                    ))),
+                    &datagram[22..],
+                )
+            }
+            DST_DOMAIN => {
+                let len = datagram[4] as usize;
+                validate!(
+                    datagram.len() >= 7 + len,
+                    Kind::GeneralSocksServerFailure,
+                    "truncated domain"
+                )?;
+                let domain = std::str::from_utf8(&datagram[5..(5 + len)])
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                let port = u16::from_be_bytes([datagram[5 + len], datagram[6 + len]]);
+                (
+                    TargetAddr::Domain(domain.to_string(), port),
+                    &datagram[(7 + len)..],
+                )
+            }
+            _ => return Err(crate::io_err!(Kind::AddressTypeNotSupported)),
+        };
+        Ok((Self { frag, target }, rest))
+    }
+
+    /// Returns the number of bytes the header takes on the wire.
+    pub fn encoded_len(&self) -> usize {
+        4 + match &self.target {
+            TargetAddr::SocketAddr(SocketAddr::V4(_)) => 4 + 2,
+            TargetAddr::SocketAddr(SocketAddr::V6(_)) => 16 + 2,
+            TargetAddr::Domain(domain, _) => 1 + domain.len() + 2,
+        }
+    }
+
+    /// Writes the header in front of the user data, or returns
+    /// `InvalidInput` if the domain does not fit in its length byte.
+    pub fn write_to<B: BufMut>(&self, buf: &mut B) -> io::Result<()> {
+        if let TargetAddr::Domain(domain, _) = &self.target {
+            if domain.len() > u8::MAX as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("domain of {}B is too long", domain.len()),
+                ));
+            }
+        }
+        buf.put_u16(0x0000);
+        buf.put_u8(self.frag);
+        match &self.target {
+            TargetAddr::SocketAddr(SocketAddr::V4(v4)) => {
+                buf.put_u8(DstAtyp::IPv4.as_u8());
+                buf.put_slice(&v4.ip().octets());
+                buf.put_u16(v4.port());
+            }
+            TargetAddr::SocketAddr(SocketAddr::V6(v6)) => {
+                buf.put_u8(DstAtyp::IPv6.as_u8());
+                buf.put_slice(&v6.ip().octets());
+                buf.put_u16(v6.port());
+            }
+            TargetAddr::Domain(domain, port) => {
+                buf.put_u8(DstAtyp::DomainName.as_u8());
+                buf.put_u8(domain.len() as u8);
+                buf.put_slice(domain.as_bytes());
+                buf.put_u16(*port);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Socks4Request {
+    pub fn new(target: TargetAddr, user_id: String) -> Self {
+        Self {
+            version: SOCKS4_VERSION,
+            command: Command::Connect.as_u8(),
+            target,
+            user_id,
+        }
+    }
+
+    pub fn command(&self) -> Option<Command> {
+        match Command::from_u8(self.command) {
+            Some(Command::Associate) => None,
+            command => command,
+        }
+    }
+
+    pub fn is_valid(&self) -> io::Result<()> {
+        validate!(self.version == SOCKS4_VERSION, Kind::InvalidVersion)?;
+        match &self.target {
+            TargetAddr::SocketAddr(SocketAddr::V6(_)) => {
+                Err(crate::io_err!(Kind::AddressTypeNotSupported))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn read<S: AsyncRead + Unpin>(socket: &mut S) -> io::Result<Self> {
+        let version = socket.read_u8().await?;
+        Self::read_with_version(version, socket).await
+    }
+
+    /// Reads the request after the version was read to detect the protocol.
+    pub async fn read_with_version<S: AsyncRead + Unpin>(
+        version: u8,
+        socket: &mut S,
+    ) -> io::Result<Self> {
+        let command = socket.read_u8().await?;
+        let port = socket.read_u16().await?;
+        let mut ipv4 = [0u8; 4];
+        socket.read_exact(&mut ipv4).await?;
+        let user_id = read_nul_terminated(socket).await?;
+        let target = match ipv4 {
+            // SOCKS4a uses 0.0.0.x with x nonzero to mark the domain name.
+            [0, 0, 0, x] if x != 0 => {
+                let domain = read_nul_terminated(socket).await?;
+                TargetAddr::Domain(domain, port)
+            }
+            _ => TargetAddr::SocketAddr(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(ipv4),
+                port,
+            ))),
+        };
+        Ok(Self {
+            version,
+            command,
+            target,
+            user_id,
+        })
+    }
+
+    pub async fn write<S: AsyncWrite + Unpin>(&self, socket: &mut S) -> io::Result<()> {
+        socket.write_u8(self.version).await?;
+        socket.write_u8(self.command).await?;
+        match &self.target {
+            TargetAddr::SocketAddr(SocketAddr::V4(v4)) => {
+                socket.write_u16(v4.port()).await?;
+                socket.write_all(&v4.ip().octets()).await?;
+                socket.write_all(self.user_id.as_bytes()).await?;
+                socket.write_u8(0x00).await
+            }
+            TargetAddr::SocketAddr(SocketAddr::V6(_)) => {
+                Err(crate::io_err!(Kind::AddressTypeNotSupported))
+            }
+            TargetAddr::Domain(domain, port) => {
+                socket.write_u16(*port).await?;
+                socket.write_all(&[0, 0, 0, 1]).await?;
+                socket.write_all(self.user_id.as_bytes()).await?;
+                socket.write_u8(0x00).await?;
+                socket.write_all(domain.as_bytes()).await?;
+                socket.write_u8(0x00).await
+            }
+        }
+    }
+}
+
+impl Socks4Reply {
+    pub fn new(code: u8) -> Self {
+        Self::with_target(code, SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+    }
+
+    pub fn with_target(code: u8, target: SocketAddrV4) -> Self {
+        Self {
+            version: SOCKS4_REPLY_VERSION,
+            code,
+            target,
+        }
+    }
+
+    pub fn is_granted(&self) -> bool {
+        self.code == SOCKS4_GRANTED
+    }
+
+    pub fn is_valid(&self) -> io::Result<()> {
+        validate!(self.version == SOCKS4_REPLY_VERSION, Kind::InvalidVersion)
+    }
+
+    pub async fn read<S: AsyncRead + Unpin>(socket: &mut S) -> io::Result<Self> {
+        let version = socket.read_u8().await?;
+        let code = socket.read_u8().await?;
+        let port = socket.read_u16().await?;
+        let mut ipv4 = [0u8; 4];
+        socket.read_exact(&mut ipv4).await?;
+        Ok(Self {
+            version,
+            code,
+            target: SocketAddrV4::new(Ipv4Addr::from(ipv4), port),
+        })
+    }
+
+    pub async fn write<S: AsyncWrite + Unpin>(&self, socket: &mut S) -> io::Result<()> {
+        socket.write_u8(self.version).await?;
+        socket.write_u8(self.code).await?;
+        socket.write_u16(self.target.port()).await?;
+        socket.write_all(&self.target.ip().octets()).await
+    }
+}
+
+/// Reads a string terminated by NULL, which is used by the SOCKS4 request.
+async fn read_nul_terminated<S: AsyncRead + Unpin>(socket: &mut S) -> io::Result<String> {
+    let mut buf = Vec::new();
+    loop {
+        match socket.read_u8().await? {
+            0x00 => break,
+            b => buf.push(b),
+        }
+        if buf.len() > SOCKS4_MAX_FIELD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "field is too long",
+            ));
+        }
+    }
+    String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
 #[macro_export]
 macro_rules! check_valid {
     ($expr:expr) => {
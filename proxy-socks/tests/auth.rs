@@ -0,0 +1,248 @@
+#![feature(type_alias_impl_trait)]
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use proxy::Service;
+use proxy_auth::{Authenticators, Identity, Users};
+use proxy_io::{ConnectRequest, ProxyConnect, TargetAddr, TokioConnect};
+use proxy_rules::{AccessControl, AccessList, Rule};
+use proxy_socks::{
+    client::{Client, Socks4Client},
+    server::Server,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+
+async fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                tokio::io::copy(&mut r, &mut w).await.ok();
+            });
+        }
+    });
+    addr
+}
+
+async fn socks_server() -> SocketAddr {
+    let mut users = Users::new();
+    users.insert("alice".to_string(), "secret".to_string());
+    let mut server = Server::<Arc<Users>, _>::new(TokioConnect::new());
+    server.set_authenticate(Arc::new(users));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let mut server = server.clone();
+            tokio::spawn(async move { server.call(stream).await });
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn reject_unauthenticated_client() {
+    let echo = echo_server().await;
+    let proxy = socks_server().await;
+
+    let mut client = Client::new(TargetAddr::SocketAddr(proxy), TokioConnect::new());
+    assert!(client.call(TargetAddr::SocketAddr(echo)).await.is_err());
+}
+
+#[tokio::test]
+async fn reject_wrong_password() {
+    let echo = echo_server().await;
+    let proxy = socks_server().await;
+
+    let mut client = Client::new(TargetAddr::SocketAddr(proxy), TokioConnect::new());
+    client.set_authorization("alice".to_string(), "guess".to_string());
+    assert!(client.call(TargetAddr::SocketAddr(echo)).await.is_err());
+}
+
+#[tokio::test]
+async fn reject_socks4_client() {
+    let echo = echo_server().await;
+    let proxy = socks_server().await;
+
+    let mut client = Socks4Client::new(TargetAddr::SocketAddr(proxy), TokioConnect::new());
+    client.set_user_id("alice".to_string());
+    assert!(client.call(TargetAddr::SocketAddr(echo)).await.is_err());
+}
+
+#[tokio::test]
+async fn accept_authenticated_client() {
+    let echo = echo_server().await;
+    let proxy = socks_server().await;
+
+    let mut client = Client::new(TargetAddr::SocketAddr(proxy), TokioConnect::new());
+    client.set_authorization("alice".to_string(), "secret".to_string());
+    let mut stream = client.call(TargetAddr::SocketAddr(echo)).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+/// Connects directly, recording the identity of each request.
+#[derive(Clone, Default)]
+struct RecordConnect {
+    identities: Arc<Mutex<Vec<Option<Identity>>>>,
+}
+
+impl Service<ConnectRequest> for RecordConnect {
+    type Response = TcpStream;
+
+    type Error = io::Error;
+
+    type Future<'a> = impl Future<Output = Result<Self::Response, Self::Error>> + Send + 'a
+    where
+        Self: 'a;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: ConnectRequest) -> Self::Future<'_> {
+        self.identities.lock().unwrap().push(req.identity.clone());
+        Box::pin(async move { TokioConnect::new().call(req.target).await })
+    }
+}
+
+#[tokio::test]
+async fn pass_identity_to_connect() {
+    let echo = echo_server().await;
+
+    let mut users = Users::new();
+    users.insert("alice".to_string(), "secret".to_string());
+    let mut authenticators = Authenticators::new();
+    authenticators.push(users);
+    authenticators.add_group("admin".to_string(), "alice".to_string());
+    let connect = RecordConnect::default();
+    let mut server = Server::new(connect.clone());
+    server.set_authenticate(authenticators);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        server.call(stream).await
+    });
+
+    let mut client = Client::new(TargetAddr::SocketAddr(proxy), TokioConnect::new());
+    client.set_authorization("alice".to_string(), "secret".to_string());
+    client.call(TargetAddr::SocketAddr(echo)).await.unwrap();
+
+    let identities = connect.identities.lock().unwrap();
+    let identity = identities[0].as_ref().unwrap();
+    assert_eq!(identity.username, "alice");
+    assert!(identity.in_group("admin"));
+}
+
+/// Serves alice who is allowed to reach 127.0.0.1 only, but not the domains
+/// resolving to it.
+async fn access_server() -> SocketAddr {
+    let mut users = Users::new();
+    users.insert("alice".to_string(), "secret".to_string());
+    let mut access = AccessControl::new();
+    let rule: Rule = "IPV4,127.0.0.1,DIRECT,no-resolve".parse().unwrap();
+    access.add_user("alice".to_string(), AccessList::new(vec![rule]));
+    let mut connect = ProxyConnect::<_, _, Vec<Rule>>::new(
+        TokioConnect::new(),
+        Client::new(
+            TargetAddr::SocketAddr("127.0.0.1:1".parse().unwrap()),
+            TokioConnect::new(),
+        ),
+    );
+    connect.set_access_control(access);
+    let mut server = Server::new(connect.clone());
+    server.set_authenticate(Arc::new(users));
+    server.set_router(connect);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let mut server = server.clone();
+            tokio::spawn(async move { server.call(stream).await });
+        }
+    });
+    proxy
+}
+
+#[tokio::test]
+async fn deny_by_access_control() {
+    let echo = echo_server().await;
+    let proxy = access_server().await;
+
+    let mut client = Client::new(TargetAddr::SocketAddr(proxy), TokioConnect::new());
+    client.set_authorization("alice".to_string(), "secret".to_string());
+    client.call(TargetAddr::SocketAddr(echo)).await.unwrap();
+    let err = client
+        .call(TargetAddr::Domain("localhost".to_string(), echo.port()))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("not allowed by ruleset"));
+}
+
+#[tokio::test]
+async fn deny_bind_by_access_control() {
+    let proxy = access_server().await;
+
+    let mut client = Client::new(TargetAddr::SocketAddr(proxy), TokioConnect::new());
+    client.set_authorization("alice".to_string(), "secret".to_string());
+    let err = match client
+        .bind(TargetAddr::Domain("localhost".to_string(), 0))
+        .await
+    {
+        Ok(_) => panic!("bind for localhost is not denied"),
+        Err(e) => e,
+    };
+    assert!(err.to_string().contains("not allowed by ruleset"));
+    client
+        .bind(TargetAddr::SocketAddr("127.0.0.1:0".parse().unwrap()))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn deny_udp_by_access_control() {
+    let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 64];
+        while let Ok((n, from)) = echo.recv_from(&mut buf).await {
+            echo.send_to(&buf[..n], from).await.unwrap();
+        }
+    });
+    let proxy = access_server().await;
+
+    let mut client = Client::new(TargetAddr::SocketAddr(proxy), TokioConnect::new());
+    client.set_authorization("alice".to_string(), "secret".to_string());
+    let datagram = client.associate().await.unwrap();
+    let denied = TargetAddr::Domain("localhost".to_string(), echo_addr.port());
+    datagram.send_to(b"denied", &denied).await.unwrap();
+    datagram
+        .send_to(b"allowed", &TargetAddr::SocketAddr(echo_addr))
+        .await
+        .unwrap();
+
+    let mut buf = [0u8; 64];
+    let (n, _) = datagram.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"allowed");
+    let recv = datagram.recv_from(&mut buf);
+    assert!(tokio::time::timeout(Duration::from_millis(200), recv)
+        .await
+        .is_err());
+}
@@ -0,0 +1,99 @@
+use std::net::SocketAddr;
+
+use proxy::Service;
+use proxy_auth::Users;
+use proxy_io::{TargetAddr, TokioConnect};
+use proxy_socks::{
+    client::Client,
+    server::Server,
+    types::{Socks4Reply, Socks4Request, SOCKS4_GRANTED},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+async fn socks_server() -> SocketAddr {
+    let server = Server::<Users, _>::new(TokioConnect::new());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let mut server = server.clone();
+            tokio::spawn(async move { server.call(stream).await });
+        }
+    });
+    addr
+}
+
+/// Exchanges a message each way between the client and the inbound host.
+async fn exchange(client: &mut (impl AsyncReadExt + AsyncWriteExt + Unpin), host: &mut TcpStream) {
+    let mut buf = [0u8; 4];
+    host.write_all(b"ping").await.unwrap();
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+    client.write_all(b"pong").await.unwrap();
+    host.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"pong");
+}
+
+#[tokio::test]
+async fn bind_replies_twice() {
+    let proxy = socks_server().await;
+
+    let mut client = Client::new(TargetAddr::SocketAddr(proxy), TokioConnect::new());
+    let expected = TargetAddr::SocketAddr("127.0.0.1:0".parse().unwrap());
+    let bind = client.bind(expected).await.unwrap();
+    let bound = bind.bound_addr();
+    assert_eq!(bound.ip(), proxy.ip());
+
+    // The second reply comes once the inbound host connects.
+    let mut host = TcpStream::connect(bound).await.unwrap();
+    let (mut stream, peer) = bind.accept().await.unwrap();
+    assert_eq!(peer.to_string(), host.local_addr().unwrap().to_string());
+    exchange(&mut stream, &mut host).await;
+}
+
+#[tokio::test]
+async fn socks4_bind_replies_twice() {
+    let proxy = socks_server().await;
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    let expected = TargetAddr::SocketAddr("127.0.0.1:0".parse().unwrap());
+    let mut req = Socks4Request::new(expected, String::new());
+    req.command = 0x02;
+    req.write(&mut stream).await.unwrap();
+    let reply = Socks4Reply::read(&mut stream).await.unwrap();
+    assert_eq!(reply.code, SOCKS4_GRANTED);
+    assert_eq!(SocketAddr::V4(reply.target).ip(), proxy.ip());
+
+    let mut host = TcpStream::connect(SocketAddr::V4(reply.target))
+        .await
+        .unwrap();
+    let reply = Socks4Reply::read(&mut stream).await.unwrap();
+    assert_eq!(reply.code, SOCKS4_GRANTED);
+    assert_eq!(SocketAddr::V4(reply.target), host.local_addr().unwrap());
+    exchange(&mut stream, &mut host).await;
+}
+
+#[tokio::test]
+async fn bind_drops_unexpected_host() {
+    let proxy = socks_server().await;
+
+    let mut client = Client::new(TargetAddr::SocketAddr(proxy), TokioConnect::new());
+    let expected = TargetAddr::SocketAddr("127.0.0.2:0".parse().unwrap());
+    let bind = client.bind(expected).await.unwrap();
+    let bound = bind.bound_addr();
+
+    // The host from 127.0.0.1 is dropped, while the one from 127.0.0.2 is
+    // relayed.
+    let mut unexpected = TcpStream::connect(bound).await.unwrap();
+    let mut buf = [0u8; 1];
+    assert_eq!(unexpected.read(&mut buf).await.unwrap(), 0);
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+    let mut host = socket.connect(bound).await.unwrap();
+    let (mut stream, peer) = bind.accept().await.unwrap();
+    assert_eq!(peer.to_string(), host.local_addr().unwrap().to_string());
+    exchange(&mut stream, &mut host).await;
+}
@@ -0,0 +1,138 @@
+use std::{io, net::SocketAddrV4};
+
+use proxy_io::TargetAddr;
+use proxy_socks::types::{
+    Command, Socks4Reply, Socks4Request, UdpHeader, SOCKS4_GRANTED, SOCKS4_REJECTED, SOCKS4_VERSION,
+};
+
+#[test]
+fn udp_header_round_trip() {
+    let targets = [
+        TargetAddr::SocketAddr("127.0.0.1:53".parse().unwrap()),
+        TargetAddr::SocketAddr("[::1]:53".parse().unwrap()),
+        TargetAddr::Domain("example.com".to_string(), 53),
+    ];
+    for target in targets {
+        let header = UdpHeader::new(target.clone());
+        let mut datagram = Vec::new();
+        header.write_to(&mut datagram).unwrap();
+        assert_eq!(datagram.len(), header.encoded_len());
+        datagram.extend_from_slice(b"query");
+
+        let (parsed, data) = UdpHeader::parse(&datagram).unwrap();
+        assert!(!parsed.is_fragment());
+        assert_eq!(parsed.target.to_string(), target.to_string());
+        assert_eq!(data, b"query");
+    }
+
+    // Neither the truncated header nor the fragment is relayed.
+    let mut datagram = Vec::new();
+    UdpHeader::new(TargetAddr::Domain("example.com".to_string(), 53))
+        .write_to(&mut datagram)
+        .unwrap();
+    assert!(UdpHeader::parse(&datagram[..datagram.len() - 1]).is_err());
+    datagram[2] = 1;
+    assert!(UdpHeader::parse(&datagram).unwrap().0.is_fragment());
+}
+
+#[test]
+fn udp_header_rejects_long_domain() {
+    let header = UdpHeader::new(TargetAddr::Domain("a".repeat(255), 53));
+    let mut datagram = Vec::new();
+    header.write_to(&mut datagram).unwrap();
+    let (parsed, _) = UdpHeader::parse(&datagram).unwrap();
+    assert!(matches!(parsed.target, TargetAddr::Domain(domain, 53) if domain.len() == 255));
+
+    let header = UdpHeader::new(TargetAddr::Domain("a".repeat(256), 53));
+    let mut datagram = Vec::new();
+    let err = header.write_to(&mut datagram).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(datagram.is_empty());
+}
+
+#[tokio::test]
+async fn socks4_request_round_trip() {
+    let target = TargetAddr::SocketAddr("192.168.1.7:8080".parse().unwrap());
+    let req = Socks4Request::new(target, "alice".to_string());
+    let mut buf = Vec::new();
+    req.write(&mut buf).await.unwrap();
+    assert_eq!(
+        buf,
+        [
+            &[SOCKS4_VERSION, 0x01, 0x1f, 0x90, 192, 168, 1, 7][..],
+            b"alice\0"
+        ]
+        .concat()
+    );
+
+    let read = Socks4Request::read(&mut buf.as_slice()).await.unwrap();
+    read.is_valid().unwrap();
+    assert_eq!(read.command(), Some(Command::Connect));
+    assert_eq!(read.target.to_string(), "192.168.1.7:8080");
+    assert_eq!(read.user_id, "alice");
+}
+
+#[tokio::test]
+async fn socks4a_request_round_trip() {
+    let target = TargetAddr::Domain("example.com".to_string(), 80);
+    let mut req = Socks4Request::new(target, String::new());
+    req.command = 0x02;
+    let mut buf = Vec::new();
+    req.write(&mut buf).await.unwrap();
+    assert_eq!(
+        buf,
+        [
+            &[SOCKS4_VERSION, 0x02, 0x00, 0x50, 0, 0, 0, 1, 0][..],
+            b"example.com\0"
+        ]
+        .concat()
+    );
+
+    let read = Socks4Request::read(&mut buf.as_slice()).await.unwrap();
+    assert_eq!(read.command(), Some(Command::Bind));
+    assert_eq!(read.target.to_string(), "example.com:80");
+    assert!(read.user_id.is_empty());
+
+    // SOCKS4 has no IPv6, nor UDP ASSOCIATE.
+    let target = TargetAddr::SocketAddr("[::1]:80".parse().unwrap());
+    let req = Socks4Request::new(target, String::new());
+    assert!(req.is_valid().is_err());
+    assert!(req.write(&mut Vec::new()).await.is_err());
+    let buf = [&[SOCKS4_VERSION, 0x03, 0x00, 0x50, 127, 0, 0, 1][..], b"\0"].concat();
+    let read = Socks4Request::read(&mut buf.as_slice()).await.unwrap();
+    assert_eq!(read.command(), None);
+}
+
+#[tokio::test]
+async fn socks4_request_rejects_long_user_id() {
+    let buf = [
+        &[SOCKS4_VERSION, 0x01, 0x00, 0x50, 127, 0, 0, 1][..],
+        &[b'a'; 300][..],
+        b"\0",
+    ]
+    .concat();
+    let err = Socks4Request::read(&mut buf.as_slice()).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[tokio::test]
+async fn socks4_reply_round_trip() {
+    let bound: SocketAddrV4 = "10.0.0.1:1080".parse().unwrap();
+    let reply = Socks4Reply::with_target(SOCKS4_GRANTED, bound);
+    let mut buf = Vec::new();
+    reply.write(&mut buf).await.unwrap();
+    assert_eq!(buf, [0x00, SOCKS4_GRANTED, 0x04, 0x38, 10, 0, 0, 1]);
+
+    let read = Socks4Reply::read(&mut buf.as_slice()).await.unwrap();
+    read.is_valid().unwrap();
+    assert!(read.is_granted());
+    assert_eq!(read.target, bound);
+
+    let rejected = Socks4Reply::new(SOCKS4_REJECTED);
+    let mut buf = Vec::new();
+    rejected.write(&mut buf).await.unwrap();
+    assert!(!Socks4Reply::read(&mut buf.as_slice())
+        .await
+        .unwrap()
+        .is_granted());
+}
@@ -0,0 +1,154 @@
+#![feature(type_alias_impl_trait)]
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::future::{self, BoxFuture};
+use proxy::Service;
+use proxy_auth::Users;
+use proxy_io::{with_timeout, ConnectRequest, Resolver, TargetAddr, TokioConnect};
+use proxy_socks::{
+    client::{Client, Socks4Client},
+    server::Server,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Never connects, timing out as the connect to an unresponsive host does.
+#[derive(Clone)]
+struct StalledConnect;
+
+impl Service<ConnectRequest> for StalledConnect {
+    type Response = TcpStream;
+
+    type Error = io::Error;
+
+    type Future<'a> = impl Future<Output = Result<Self::Response, Self::Error>> + Send + 'a
+    where
+        Self: 'a;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: ConnectRequest) -> Self::Future<'_> {
+        let target = req.target;
+        with_timeout(
+            Duration::from_millis(50),
+            move || format!("connect {}", target),
+            futures::future::pending(),
+        )
+    }
+}
+
+async fn socks_server<C>(server: Server<Arc<Users>, C>) -> SocketAddr
+where
+    C: Service<ConnectRequest> + Clone + Send + 'static,
+    C::Response: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    C::Error: Into<io::Error> + Send,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let mut server = server.clone();
+            tokio::spawn(async move { server.call(stream).await });
+        }
+    });
+    addr
+}
+
+/// Accepts the connections but never replies.
+async fn silent_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut streams = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            streams.push(stream);
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn close_stalled_client() {
+    let mut server = Server::new(TokioConnect::new());
+    server.set_handshake_timeout(Duration::from_millis(100));
+    let proxy = socks_server(server).await;
+
+    // The client only sends the version and then waits.
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&[0x05]).await.unwrap();
+    let mut buf = [0u8; 16];
+    let n = tokio::time::timeout(Duration::from_secs(1), stream.read(&mut buf))
+        .await
+        .unwrap()
+        .unwrap_or(0);
+    assert_eq!(n, 0);
+}
+
+#[tokio::test]
+async fn time_out_silent_proxy() {
+    let proxy = silent_server().await;
+    let target = TargetAddr::Domain("example.test".to_string(), 80);
+
+    let mut client = Client::new(TargetAddr::SocketAddr(proxy), TokioConnect::new());
+    client.set_handshake_timeout(Duration::from_millis(100));
+    let err = client.call(target.clone()).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+    let mut client = Socks4Client::new(TargetAddr::SocketAddr(proxy), TokioConnect::new());
+    client.set_remote_dns(true);
+    client.set_handshake_timeout(Duration::from_millis(100));
+    let err = client.call(target).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+}
+
+/// Never answers, as the nameserver behind a lossy network does.
+struct StalledResolver;
+
+impl Resolver for StalledResolver {
+    fn resolve<'a>(&'a self, _host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        Box::pin(future::pending())
+    }
+}
+
+#[tokio::test]
+async fn time_out_socks4_resolve() {
+    let proxy = silent_server().await;
+    let target = TargetAddr::Domain("example.test".to_string(), 80);
+
+    // The domain is resolved locally by the resolver of the client.
+    let mut client = Socks4Client::new(TargetAddr::SocketAddr(proxy), TokioConnect::new());
+    client.set_resolver(StalledResolver);
+    client.set_handshake_timeout(Duration::from_millis(100));
+    let err = client.call(target).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+}
+
+#[tokio::test]
+async fn reply_ttl_expired_on_connect_timeout() {
+    let proxy = socks_server(Server::new(StalledConnect)).await;
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut selection = [0u8; 2];
+    stream.read_exact(&mut selection).await.unwrap();
+    assert_eq!(selection, [0x05, 0x00]);
+    stream
+        .write_all(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0, 80])
+        .await
+        .unwrap();
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await.unwrap();
+    // TTL expired
+    assert_eq!(reply, [0x05, 0x06]);
+}
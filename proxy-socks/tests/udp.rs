@@ -0,0 +1,78 @@
+use std::{io, net::SocketAddr, time::Duration};
+
+use futures::future::BoxFuture;
+use proxy::Service;
+use proxy_auth::Users;
+use proxy_io::{ConnectRequest, Route, Router, TargetAddr, TokioConnect};
+use proxy_socks::{client::Client, server::Server};
+use tokio::net::{TcpListener, UdpSocket};
+
+/// Routes `slow.test` directly after a while, and the others at once.
+struct SlowRouter;
+
+impl Router for SlowRouter {
+    fn route<'a>(&'a self, req: &'a ConnectRequest) -> BoxFuture<'a, io::Result<Route>> {
+        Box::pin(async move {
+            if matches!(&req.target, TargetAddr::Domain(host, _) if host == "slow.test") {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+            }
+            Ok(Route::Direct(req.target.clone()))
+        })
+    }
+
+    fn resolve<'a>(&'a self, target: &'a TargetAddr) -> BoxFuture<'a, io::Result<SocketAddr>> {
+        Box::pin(async move {
+            match target {
+                TargetAddr::SocketAddr(addr) => Ok(*addr),
+                TargetAddr::Domain(_, port) => Ok(SocketAddr::from(([127, 0, 0, 1], *port))),
+            }
+        })
+    }
+}
+
+async fn udp_echo() -> SocketAddr {
+    let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 64];
+        while let Ok((n, from)) = echo.recv_from(&mut buf).await {
+            echo.send_to(&buf[..n], from).await.unwrap();
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn slow_route_holds_no_other_target() {
+    let echo = udp_echo().await;
+    let mut server = Server::<Users, _>::new(TokioConnect::new());
+    server.set_router(SlowRouter);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let mut server = server.clone();
+            tokio::spawn(async move { server.call(stream).await });
+        }
+    });
+
+    let mut client = Client::new(TargetAddr::SocketAddr(proxy), TokioConnect::new());
+    let datagram = client.associate().await.unwrap();
+    let slow = TargetAddr::Domain("slow.test".to_string(), echo.port());
+    datagram.send_to(b"slow", &slow).await.unwrap();
+    datagram
+        .send_to(b"fast", &TargetAddr::SocketAddr(echo))
+        .await
+        .unwrap();
+
+    // The datagrams to the slow target wait for its route, and are relayed
+    // in order once it is routed.
+    let mut buf = [0u8; 64];
+    let (n, _) = datagram.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"fast");
+    datagram.send_to(b"slower", &slow).await.unwrap();
+    let (n, _) = datagram.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"slow");
+    let (n, _) = datagram.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"slower");
+}
@@ -2,6 +2,7 @@ use std::future::Future;
 use std::io;
 use std::pin::Pin;
 use std::task::{ready, Context, Poll};
+use std::time::Duration;
 
 use bytes::{Buf, Bytes, BytesMut};
 use futures::TryFutureExt;
@@ -13,7 +14,7 @@ use hyper::service::Service as HyperService;
 use log::{error, debug};
 use pin_project_lite::pin_project;
 use proxy::Service;
-use proxy_io::{ProxyStream, StreamConnect, TargetAddr};
+use proxy_io::{with_timeout, ProxyStream, StreamConnect, TargetAddr, PROXY_HANDSHAKE_TIMEOUT};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio_util::io::{poll_read_buf, poll_write_buf};
@@ -70,6 +71,7 @@ impl Service<TargetAddr> for HttpConnector {
 #[derive(Debug, Clone)]
 pub struct Client<C> {
     authorization: Option<Basic>,
+    handshake_timeout: Duration,
     connect: StreamConnect<C>,
 }
 
@@ -77,6 +79,7 @@ impl<C> Client<C> {
     pub fn new(target: TargetAddr, connect: C) -> Self {
         Client {
             authorization: None,
+            handshake_timeout: PROXY_HANDSHAKE_TIMEOUT,
             connect: StreamConnect::new(connect, target),
         }
     }
@@ -88,6 +91,17 @@ impl<C> Client<C> {
     pub fn enable_tls(&mut self) {
         self.connect.set_tls(true)
     }
+
+    /// Sets the time of the TLS handshake with the proxy server.
+    pub fn set_tls_timeout(&mut self, timeout: Duration) {
+        self.connect.set_tls_timeout(timeout)
+    }
+
+    /// Sets the time of the CONNECT request until the proxy server
+    /// responds, which fails with `TimedOut` once it elapses.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout
+    }
 }
 
 impl<C> Service<TargetAddr> for Client<C>
@@ -111,13 +125,15 @@ where
     fn call(&mut self, target: TargetAddr) -> Self::Future<'_> {
         let future = self.connect.call(()).map_err(Into::<io::Error>::into);
         let authorization = self.authorization.clone();
+        let handshake_timeout = self.handshake_timeout;
         Box::pin(async move {
             let mut socket  = future.await?;
             let (host, port) = match target {
                 TargetAddr::SocketAddr(addr) => (addr.ip().to_string(), addr.port()),
                 TargetAddr::Domain(d, p) => (d, p) 
             };
-            match proxy_tunnel(&mut socket, &host, port, authorization).await {
+            let tunnel = proxy_tunnel(&mut socket, &host, port, authorization);
+            match with_timeout(handshake_timeout, || format!("proxy tunnel to {}:{}", host, port), tunnel).await {
                 Ok(()) => Ok(socket),
                 Err(e) => {
                     error!("unable to proxy tunnel to {}:{}, {}", &host, port, &e);
@@ -207,6 +223,27 @@ where
                     {
                         return Poll::Ready(Ok(()));
                     }
+                    // The proxy may keep the connection alive after refusing
+                    // the tunnel, so fail once the response head is complete.
+                    if !(read.starts_with(b"HTTP/1.1 200") || read.starts_with(b"HTTP/1.0 200"))
+                        && read.windows(4).any(|w| w == b"\r\n\r\n")
+                    {
+                        let status = String::from_utf8_lossy(&read[9..12]).into_owned();
+                        // The proxy refusing the credentials is not the
+                        // rules denying the target, which is answered 403.
+                        let e = if status == "407" {
+                            io::Error::new(
+                                io::ErrorKind::ConnectionRefused,
+                                "proxy tunnel refused the authentication with status 407",
+                            )
+                        } else {
+                            io::Error::other(format!(
+                                "proxy tunnel refused with status {}",
+                                status
+                            ))
+                        };
+                        return Poll::Ready(Err(e));
+                    }
                 }
 
                 let n = ready!(poll_read_buf(Pin::new(&mut me.io), cx, me.buf))?;
@@ -233,6 +270,14 @@ impl Basic {
         let decoded = format!("{}:{}", username, password);
         Basic { decoded, colon_pos }
     }
+
+    pub fn username(&self) -> &str {
+        &self.decoded[..self.colon_pos]
+    }
+
+    pub fn password(&self) -> &str {
+        &self.decoded[self.colon_pos + 1..]
+    }
 }
 
 impl Credentials for Basic {
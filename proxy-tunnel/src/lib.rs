@@ -3,50 +3,108 @@
 pub mod client;
 
 use std::{
-    future::Future,
+    future::{poll_fn, Future},
     io,
+    sync::{Arc, Mutex, Weak},
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
+use client::Basic;
+use headers::authorization::Credentials;
 use http::{header, uri::Scheme, StatusCode};
-use hyper::{client::HttpConnector, Body, Method, Request, Response};
-use log::{debug, error};
+use hyper::{client::conn::SendRequest, Body, Method, Request, Response};
+use log::{debug, error, info};
 use proxy::Service;
-use proxy_auth::Authenticator;
-use proxy_io::{Duplex, TargetAddr};
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use proxy_auth::{AsyncAuthenticator, Identity};
+use proxy_io::{
+    ConnectRequest, DuplexTimeouts, Either, Expired, Relay, TargetAddr, Throttle,
+    CLIENT_HANDSHAKE_TIMEOUT,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
 
+/// The clones share the upstream connection which the plain HTTP requests
+/// keep alive, so each client connection is served by its own server from
+/// [`Server::for_connection`].
 #[derive(Debug, Clone)]
 pub struct Server<A, C> {
     authenticate: Option<A>,
+    handshake_timeout: Duration,
+    relay: Relay,
     connect: C,
-    client: hyper::Client<HttpConnector>,
+    upstream: Arc<Mutex<Option<Upstream>>>,
+}
+
+/// The upstream connection of the plain HTTP requests, which the next
+/// request of the client to the same origin reuses.
+#[derive(Debug)]
+struct Upstream {
+    host: String,
+    port: u16,
+    username: Option<String>,
+    sender: SendRequest<Body>,
+    dialed: Instant,
+    kept_at: Instant,
 }
 
 impl<A, C> Server<A, C> {
     pub fn new(connect: C) -> Self {
-        let client = hyper::Client::builder()
-            .http1_title_case_headers(true)
-            .http1_preserve_header_case(true)
-            .build_http();
         Self {
             authenticate: None,
+            handshake_timeout: CLIENT_HANDSHAKE_TIMEOUT,
+            relay: Relay::new(),
             connect,
-            client,
+            upstream: Arc::default(),
+        }
+    }
+
+    /// Returns the server for a new client connection, which keeps its own
+    /// upstream connection alive and closes it once the client is gone.
+    pub fn for_connection(&self) -> Self
+    where
+        A: Clone,
+        C: Clone,
+    {
+        Self {
+            upstream: Arc::default(),
+            ..self.clone()
         }
     }
 
     pub fn set_authenticate(&mut self, authenticate: A) {
         self.authenticate = Some(authenticate)
     }
+
+    /// Sets the time for the client to send the request head, which is
+    /// enforced by the connection serving the server.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+
+    /// Sets the timeouts of the tunnel after the CONNECT succeeds. The
+    /// connections of the plain HTTP requests are kept alive for the idle
+    /// timeout between the requests, and closed after the max lifetime.
+    pub fn set_duplex_timeouts(&mut self, timeouts: DuplexTimeouts) {
+        self.relay.set_timeouts(timeouts)
+    }
+
+    /// Throttles the tunnel after the CONNECT succeeds, and the connections
+    /// to the origins which the plain HTTP requests are forwarded on.
+    pub fn set_throttle(&mut self, throttle: Throttle) {
+        self.relay.set_throttle(throttle)
+    }
 }
 
 impl<A, C> Service<Request<Body>> for Server<A, C>
 where
-    A: Authenticator + Send + Sync,
-    C: Service<TargetAddr> + Send + Clone + 'static,
+    A: AsyncAuthenticator,
+    C: Service<ConnectRequest> + Send + Clone + 'static,
     C::Error: Into<io::Error> + Send,
-    C::Response: AsyncWrite + AsyncRead + Send + Unpin,
+    C::Response: AsyncWrite + AsyncRead + Send + Unpin + 'static,
 {
     type Response = Response<Body>;
 
@@ -61,10 +119,17 @@ where
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future<'_> {
-        let client = self.client.clone();
         let mut connect = self.connect.clone();
         Box::pin(async move {
             debug!("handle req: {:?}", &req);
+            let identity = match &self.authenticate {
+                Some(auth) => match authorize(auth, &req).await {
+                    Ok(identity) => Some(identity),
+                    Err(resp) => return Ok(resp),
+                },
+                None => None,
+            };
+
             if Method::CONNECT == req.method() {
                 // Received an HTTP request like:
                 // ```
@@ -81,71 +146,40 @@ where
                 // connection be upgraded, so we can't return a response inside
                 // `on_upgrade` future.
                 if let Some((host, port)) = host_addr(req.uri()) {
-                    if let Some(status) = match &self.authenticate {
-                        Some(auth) => {
-                            let basic = &req.headers()[header::PROXY_AUTHORIZATION];
-                            if let Some((u, p)) = basic.to_str().ok().and_then(|a| {
-                                a.trim().strip_prefix("Basic ").and_then(|b| {
-                                    if let Some(n) = b.find(':') {
-                                        Some((&b[..n], &b[(n + 1)..]))
-                                    } else {
-                                        None
-                                    }
-                                })
-                            }) {
-                                if auth.authenticate(u, p) {
-                                    None
-                                } else {
-                                    Some(StatusCode::UNAUTHORIZED)
-                                }
-                            } else {
-                                Some(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
-                            }
-                        }
-                        None => None,
-                    } {
-                        error!("the proxy authentication is required, but it does not provide");
-                        match status {
-                            StatusCode::PROXY_AUTHENTICATION_REQUIRED => Ok(Response::builder()
-                                .status(status.clone())
-                                .header(header::PROXY_AUTHENTICATE, "Basic realm=Proxy Server")
-                                .body(Body::empty())
-                                .unwrap()),
-                            _ => Ok(Response::builder()
-                                .status(status.clone())
-                                .body(Body::empty())
-                                .unwrap()),
+                    // Connects before responding, so that the client knows
+                    // why the tunnel is not established.
+                    let target = TargetAddr::Domain(host.clone(), port);
+                    let req_identity = identity.clone();
+                    let stream = match connect
+                        .call(ConnectRequest::new(target, req_identity))
+                        .await
+                    {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            let e = e.into();
+                            error!("connect {}:{} failed, error: {}", &host, port, &e);
+                            return Ok(connect_failed(&e));
                         }
-                    } else {
-                        tokio::task::spawn(async move {
-                            match hyper::upgrade::on(req).await {
-                                Ok(mut upgraded) => {
-                                    match connect.call(TargetAddr::Domain(host.clone(), port)).await
-                                    {
-                                        Ok(stream) => {
-                                            if let Err(e) = Duplex::new(upgraded, stream).await {
-                                                error!("copy bidirectional failed, error:{}", e)
-                                            }
-                                        }
-                                        Err(e) => {
-                                            error!(
-                                                "connect {}:{} failed, error: {}",
-                                                &host,
-                                                port,
-                                                e.into()
-                                            );
-                                            if let Err(e) = upgraded.shutdown().await {
-                                                error!("unable to shutdown the connection, {}", &e);
-                                            }
-                                        }
+                    };
+                    let relay = self.relay.clone();
+                    tokio::task::spawn(async move {
+                        match hyper::upgrade::on(req).await {
+                            Ok(upgraded) => {
+                                match relay.run(upgraded, stream, identity.as_ref()).await {
+                                    Ok(stats) => {
+                                        debug!("close the tunnel to {}:{}, {}", &host, port, stats)
                                     }
+                                    Err(e) if Expired::of(&e).is_some() => {
+                                        info!("close the tunnel to {}:{}, {}", &host, port, e)
+                                    }
+                                    Err(e) => error!("copy bidirectional failed, error:{}", e),
                                 }
-                                Err(e) => error!("upgrade error: {}", e),
                             }
-                        });
+                            Err(e) => error!("upgrade error: {}", e),
+                        }
+                    });
 
-                        Ok(Response::new(Body::empty()))
-                    }
+                    Ok(Response::new(Body::empty()))
                 } else {
                     error!("CONNECT host is not socket addr: {:?}", req.uri());
                     let mut resp = Response::new(Body::from("CONNECT must be to a socket address"));
@@ -154,15 +188,285 @@ where
                     Ok(resp)
                 }
             } else {
-                Ok(client
-                    .request(req)
-                    .await
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?)
+                forward(connect, req, identity, &self.relay, &self.upstream).await
             }
         })
     }
 }
 
+/// Checks the Proxy-Authorization of the request, returning the identity of
+/// the client, or the response to challenge the client if it is missing or
+/// not accepted.
+///
+/// The client is not challenged but responded 503 if the authenticator fails
+/// to give the verdict, since its credentials may be right.
+async fn authorize<A>(auth: &A, req: &Request<Body>) -> Result<Identity, Response<Body>>
+where
+    A: AsyncAuthenticator,
+{
+    let basic = req
+        .headers()
+        .get(header::PROXY_AUTHORIZATION)
+        .filter(|v| v.as_bytes().starts_with(b"Basic "))
+        .and_then(Basic::decode);
+    match basic {
+        Some(basic) => match auth.verify(basic.username(), basic.password()).await {
+            Ok(Some(identity)) => Ok(identity),
+            Ok(None) => {
+                error!("the proxy authentication failed for {}", basic.username());
+                Err(proxy_authentication_required())
+            }
+            Err(e) => {
+                error!("unable to authenticate {}, {}", basic.username(), e);
+                Err(Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .body(Body::empty())
+                    .unwrap())
+            }
+        },
+        None => {
+            error!("the proxy authentication is required, but it does not provide");
+            Err(proxy_authentication_required())
+        }
+    }
+}
+
+fn proxy_authentication_required() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+        .header(header::PROXY_AUTHENTICATE, "Basic realm=Proxy Server")
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Responds 403 if the destination is denied by the rules, 504 if it timed
+/// out, otherwise 502.
+fn connect_failed(e: &io::Error) -> Response<Body> {
+    let status = match e.kind() {
+        io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        io::ErrorKind::TimedOut => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::BAD_GATEWAY,
+    };
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = status;
+    resp
+}
+
+/// Forwards the request in absolute-form to the origin server via the
+/// connect service, so the policy is enforced the same as CONNECT.
+///
+/// The upstream connection is kept alive for the next request of the client
+/// to the same origin by the same user, and dialed again once it is closed.
+/// It is throttled as the tunnels are, with the reads from the origin as the
+/// download. The requests are forwarded by hyper rather than the relay, so
+/// the idle timeout applies only between the requests, the max lifetime
+/// closes the connection even in the middle of a response, and they are not
+/// counted in the stats of the tunnels.
+async fn forward<C>(
+    mut connect: C,
+    mut req: Request<Body>,
+    identity: Option<Identity>,
+    relay: &Relay,
+    upstream: &Arc<Mutex<Option<Upstream>>>,
+) -> io::Result<Response<Body>>
+where
+    C: Service<ConnectRequest>,
+    C::Error: Into<io::Error>,
+    C::Response: AsyncWrite + AsyncRead + Send + Unpin + 'static,
+{
+    let (host, port) = match host_addr(req.uri()) {
+        Some(addr) if req.uri().scheme() == Some(&Scheme::HTTP) => addr,
+        _ => {
+            error!("request is not in absolute-form: {:?}", req.uri());
+            let mut resp = Response::new(Body::from("request must be in absolute-form"));
+            *resp.status_mut() = http::StatusCode::BAD_REQUEST;
+            return Ok(resp);
+        }
+    };
+
+    let username = identity.as_ref().map(|i| i.username.clone());
+    let mut kept = upstream
+        .lock()
+        .unwrap()
+        .take()
+        .filter(|u| u.host == host && u.port == port && u.username == username);
+    if let Some(kept_upstream) = &mut kept {
+        // The origin server may have closed the connection meanwhile.
+        if poll_fn(|cx| kept_upstream.sender.poll_ready(cx))
+            .await
+            .is_err()
+        {
+            kept = None;
+        }
+    }
+    let (mut sender, dialed) = match kept {
+        Some(kept) => {
+            debug!("reuse the connection to {}:{}", &host, port);
+            (kept.sender, kept.dialed)
+        }
+        None => {
+            let target = TargetAddr::Domain(host.clone(), port);
+            let req_identity = identity.clone();
+            let stream = match connect
+                .call(ConnectRequest::new(target, req_identity))
+                .await
+            {
+                Ok(stream) => stream,
+                Err(e) => {
+                    let e = e.into();
+                    error!("connect {}:{} failed, error: {}", &host, port, &e);
+                    return Ok(connect_failed(&e));
+                }
+            };
+            let stream = match relay.throttle() {
+                Some(throttle) => Either::Left(throttle.wrap_remote(stream, identity.as_ref())),
+                None => Either::Right(stream),
+            };
+            let handshake = hyper::client::conn::Builder::new()
+                .http1_title_case_headers(true)
+                .http1_preserve_header_case(true)
+                .handshake(stream)
+                .await;
+            let (sender, conn) = match handshake {
+                Ok(handshake) => handshake,
+                Err(e) => {
+                    let e = io::Error::other(e);
+                    error!("handshake with {}:{} failed, error: {}", &host, port, &e);
+                    return Ok(connect_failed(&e));
+                }
+            };
+            let (host, port) = (host.clone(), port);
+            let max_lifetime = relay.timeouts().max_lifetime;
+            tokio::task::spawn(async move {
+                let result = match max_lifetime {
+                    Some(lifetime) => match tokio::time::timeout(lifetime, conn).await {
+                        Ok(result) => result,
+                        Err(_) => {
+                            info!(
+                                "close the connection to {}:{}, {}",
+                                &host,
+                                port,
+                                Expired::Lifetime(lifetime)
+                            );
+                            return;
+                        }
+                    },
+                    None => conn.await,
+                };
+                if let Err(e) = result {
+                    error!("connection to {}:{} failed, error: {}", &host, port, e);
+                }
+            });
+            (sender, Instant::now())
+        }
+    };
+
+    // The origin server expects the request target in origin-form.
+    let origin = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/")
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if !req.headers().contains_key(header::HOST) {
+        if let Some(authority) = req.uri().authority() {
+            let host = header::HeaderValue::from_str(authority.as_str())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            req.headers_mut().insert(header::HOST, host);
+        }
+    }
+    *req.uri_mut() = origin;
+    remove_hop_by_hop_headers(req.headers_mut());
+
+    let mut resp = match sender.send_request(req).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            let e = io::Error::other(e);
+            error!("forward to {}:{} failed, error: {}", &host, port, &e);
+            return Ok(connect_failed(&e));
+        }
+    };
+    let keep_alive = keep_alive(relay.timeouts(), dialed);
+    if keep_alive != Some(Duration::ZERO) {
+        let kept_at = Instant::now();
+        *upstream.lock().unwrap() = Some(Upstream {
+            host,
+            port,
+            username,
+            sender,
+            dialed,
+            kept_at,
+        });
+        if let Some(keep_alive) = keep_alive {
+            tokio::task::spawn(expire(Arc::downgrade(upstream), kept_at, keep_alive));
+        }
+    }
+    remove_hop_by_hop_headers(resp.headers_mut());
+    Ok(resp)
+}
+
+/// Returns how long the upstream connection is kept alive for the next
+/// request, by the idle timeout and what is left of the max lifetime.
+fn keep_alive(timeouts: DuplexTimeouts, dialed: Instant) -> Option<Duration> {
+    let lifetime = timeouts
+        .max_lifetime
+        .map(|lifetime| lifetime.saturating_sub(dialed.elapsed()));
+    match (timeouts.idle, lifetime) {
+        (Some(idle), Some(lifetime)) => Some(idle.min(lifetime)),
+        (idle, lifetime) => idle.or(lifetime),
+    }
+}
+
+/// Drops the kept upstream connection once it is kept alive for the time,
+/// unless the next request has taken it meanwhile.
+async fn expire(upstream: Weak<Mutex<Option<Upstream>>>, kept_at: Instant, after: Duration) {
+    tokio::time::sleep(after).await;
+    if let Some(upstream) = upstream.upgrade() {
+        let mut upstream = upstream.lock().unwrap();
+        if upstream.as_ref().map(|u| u.kept_at) == Some(kept_at) {
+            if let Some(expired) = upstream.take() {
+                debug!(
+                    "close the idle connection to {}:{}",
+                    &expired.host, expired.port
+                );
+            }
+        }
+    }
+}
+
+/// The headers which are meaningful only for a single transport-level
+/// connection, and must not be forwarded by proxies.
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+fn remove_hop_by_hop_headers(headers: &mut header::HeaderMap) {
+    // The headers listed in the Connection header are also hop-by-hop.
+    let listed: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    for name in listed {
+        headers.remove(name.as_str());
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
 fn host_addr(uri: &http::Uri) -> Option<(String, u16)> {
     let tls = uri.scheme() == Some(&Scheme::HTTPS);
     uri.authority().and_then(|auth| {
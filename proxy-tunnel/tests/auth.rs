@@ -0,0 +1,86 @@
+#![feature(type_alias_impl_trait)]
+use std::{io, net::SocketAddr, sync::Arc};
+
+use hyper::{server::conn::Http, service::service_fn};
+use proxy::Service;
+use proxy_auth::Users;
+use proxy_io::{TargetAddr, TokioConnect};
+use proxy_tunnel::{client::Client, Server};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+async fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                tokio::io::copy(&mut r, &mut w).await.ok();
+            });
+        }
+    });
+    addr
+}
+
+async fn http_server() -> SocketAddr {
+    let mut users = Users::new();
+    users.insert("alice".to_string(), "secret".to_string());
+    let mut server = Server::<Arc<Users>, _>::new(TokioConnect::new());
+    server.set_authenticate(Arc::new(users));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let server = server.clone();
+            let service = service_fn(move |req| {
+                let mut server = server.clone();
+                async move { server.call(req).await }
+            });
+            tokio::spawn(
+                Http::new()
+                    .serve_connection(stream, service)
+                    .with_upgrades(),
+            );
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn reject_unauthenticated_client() {
+    let echo = echo_server().await;
+    let proxy = http_server().await;
+
+    let mut client = Client::new(TargetAddr::SocketAddr(proxy), TokioConnect::new());
+    let err = client.call(TargetAddr::SocketAddr(echo)).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+}
+
+#[tokio::test]
+async fn reject_wrong_password() {
+    let echo = echo_server().await;
+    let proxy = http_server().await;
+
+    let mut client = Client::new(TargetAddr::SocketAddr(proxy), TokioConnect::new());
+    client.set_authorization("alice".to_string(), "guess".to_string());
+    let err = client.call(TargetAddr::SocketAddr(echo)).await.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+}
+
+#[tokio::test]
+async fn accept_authenticated_client() {
+    let echo = echo_server().await;
+    let proxy = http_server().await;
+
+    let mut client = Client::new(TargetAddr::SocketAddr(proxy), TokioConnect::new());
+    client.set_authorization("alice".to_string(), "secret".to_string());
+    let mut stream = client.call(TargetAddr::SocketAddr(echo)).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+}
@@ -0,0 +1,165 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use hyper::{
+    client::conn::{self, SendRequest},
+    server::conn::Http,
+    service::service_fn,
+    Body, Request, Response, StatusCode,
+};
+use proxy::Service;
+use proxy_auth::Users;
+use proxy_io::{Bandwidth, DuplexTimeouts, Throttle, TokioConnect};
+use proxy_tunnel::Server;
+use tokio::net::{TcpListener, TcpStream};
+
+/// The size of the body of `/large` on the origin.
+const LARGE: usize = 32 * 1024;
+
+/// Serves the origin, counting the connections. The body is the request
+/// target, or `LARGE` bytes for `/large`.
+async fn origin_server(connections: Arc<AtomicUsize>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            connections.fetch_add(1, Ordering::SeqCst);
+            let service = service_fn(|req: Request<Body>| async move {
+                let body = match req.uri().path() {
+                    "/large" => Body::from(vec![b'x'; LARGE]),
+                    _ => Body::from(req.uri().to_string()),
+                };
+                Ok::<_, Infallible>(Response::new(body))
+            });
+            tokio::spawn(Http::new().serve_connection(stream, service));
+        }
+    });
+    addr
+}
+
+/// Accepts the connections and closes them at once.
+async fn closing_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+    addr
+}
+
+async fn http_server() -> SocketAddr {
+    serve(Server::new(TokioConnect::new())).await
+}
+
+async fn serve(server: Server<Arc<Users>, TokioConnect>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let server = server.for_connection();
+            let service = service_fn(move |req| {
+                let mut server = server.clone();
+                async move { server.call(req).await }
+            });
+            tokio::spawn(Http::new().serve_connection(stream, service));
+        }
+    });
+    addr
+}
+
+async fn proxy_client(proxy: SocketAddr) -> SendRequest<Body> {
+    let stream = TcpStream::connect(proxy).await.unwrap();
+    let (sender, conn) = conn::handshake(stream).await.unwrap();
+    tokio::spawn(conn);
+    sender
+}
+
+async fn get(sender: &mut SendRequest<Body>, uri: String) -> (StatusCode, String) {
+    let req = Request::get(uri).body(Body::empty()).unwrap();
+    let resp = sender.send_request(req).await.unwrap();
+    let status = resp.status();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn keep_upstream_alive() {
+    let connections = Arc::new(AtomicUsize::new(0));
+    let origin = origin_server(connections.clone()).await;
+    let proxy = http_server().await;
+
+    let mut sender = proxy_client(proxy).await;
+    for path in ["/a", "/b?c=d"] {
+        let (status, body) = get(&mut sender, format!("http://{}{}", origin, path)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, path);
+    }
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+    // Another client connection dials its own upstream.
+    let mut sender = proxy_client(proxy).await;
+    get(&mut sender, format!("http://{}/", origin)).await;
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn respond_bad_gateway_on_closed_upstream() {
+    let origin = closing_server().await;
+    let proxy = http_server().await;
+
+    // The client connection survives the failures.
+    let mut sender = proxy_client(proxy).await;
+    for _ in 0..2 {
+        let (status, _) = get(&mut sender, format!("http://{}/", origin)).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+    }
+}
+
+#[tokio::test]
+async fn throttle_forwarded_download() {
+    let origin = origin_server(Arc::default()).await;
+    let throttle = Throttle::new();
+    throttle.connection().set_bandwidth(Bandwidth {
+        upload: None,
+        download: Some(16 * 1024),
+    });
+    let mut server = Server::new(TokioConnect::new());
+    server.set_throttle(throttle);
+    let proxy = serve(server).await;
+
+    let mut sender = proxy_client(proxy).await;
+    let start = Instant::now();
+    let (status, body) = get(&mut sender, format!("http://{}/large", origin)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body.len(), LARGE);
+    // The first 16KiB is the burst.
+    assert!(start.elapsed() >= Duration::from_millis(700));
+}
+
+#[tokio::test]
+async fn close_idle_upstream() {
+    let connections = Arc::new(AtomicUsize::new(0));
+    let origin = origin_server(connections.clone()).await;
+    let mut server = Server::new(TokioConnect::new());
+    server.set_duplex_timeouts(DuplexTimeouts {
+        idle: Some(Duration::from_millis(200)),
+        max_lifetime: None,
+    });
+    let proxy = serve(server).await;
+
+    let mut sender = proxy_client(proxy).await;
+    get(&mut sender, format!("http://{}/", origin)).await;
+    get(&mut sender, format!("http://{}/", origin)).await;
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+    // The upstream connection idle for too long is dialed again.
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    let (status, _) = get(&mut sender, format!("http://{}/", origin)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+}
@@ -0,0 +1,107 @@
+#![feature(type_alias_impl_trait)]
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use hyper::{server::conn::Http, service::service_fn};
+use proxy::Service;
+use proxy_auth::Users;
+use proxy_io::{with_timeout, ConnectRequest, TargetAddr, TokioConnect};
+use proxy_tunnel::{client::Client, Server};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Never connects, timing out as the connect to an unresponsive host does.
+#[derive(Clone)]
+struct StalledConnect;
+
+impl Service<ConnectRequest> for StalledConnect {
+    type Response = TcpStream;
+
+    type Error = io::Error;
+
+    type Future<'a> = impl Future<Output = Result<Self::Response, Self::Error>> + Send + 'a
+    where
+        Self: 'a;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: ConnectRequest) -> Self::Future<'_> {
+        let target = req.target;
+        with_timeout(
+            Duration::from_millis(50),
+            move || format!("connect {}", target),
+            futures::future::pending(),
+        )
+    }
+}
+
+async fn http_server() -> SocketAddr {
+    let server = Server::<Arc<Users>, _>::new(StalledConnect);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let server = server.clone();
+            let service = service_fn(move |req| {
+                let mut server = server.clone();
+                async move { server.call(req).await }
+            });
+            tokio::spawn(
+                Http::new()
+                    .serve_connection(stream, service)
+                    .with_upgrades(),
+            );
+        }
+    });
+    addr
+}
+
+/// Accepts the connections but never replies.
+async fn silent_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut streams = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            streams.push(stream);
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn respond_gateway_timeout() {
+    let proxy = http_server().await;
+
+    let mut stream = TcpStream::connect(proxy).await.unwrap();
+    stream
+        .write_all(b"CONNECT example.test:443 HTTP/1.1\r\nHost: example.test:443\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buf = [0u8; 12];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"HTTP/1.1 504");
+}
+
+#[tokio::test]
+async fn time_out_silent_proxy() {
+    let proxy = silent_server().await;
+
+    let mut client = Client::new(TargetAddr::SocketAddr(proxy), TokioConnect::new());
+    client.set_handshake_timeout(Duration::from_millis(100));
+    let err = client
+        .call(TargetAddr::Domain("example.test".to_string(), 443))
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+}